@@ -0,0 +1,104 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*
+ * C interface to libgustasum, built with `cargo build --release --features ffi`.
+ * Hashes match the gustasum CLI byte for byte when the same options are used.
+ */
+
+#ifndef GUSTASUM_H
+#define GUSTASUM_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return codes */
+#define GUSTASUM_OK 0
+#define GUSTASUM_FAILED 1 /* gustasum_verify: at least one check did not pass */
+#define GUSTASUM_ERR_ARGUMENT (-1)
+#define GUSTASUM_ERR_IO (-2)
+#define GUSTASUM_ERR_UNSTABLE (-3)
+
+/* Per-line status passed to gustasum_check_cb */
+#define GUSTASUM_CHECK_OK 0
+#define GUSTASUM_CHECK_MISMATCH 1
+#define GUSTASUM_CHECK_UNSTABLE 2
+#define GUSTASUM_CHECK_ERROR 3
+#define GUSTASUM_CHECK_MALFORMED 4
+
+/* Longest built-in hex digest (sha512) plus its terminating NUL. */
+#define GUSTASUM_HEX_LEN 129
+
+/* Same meaning as the CLI flags. Zero turns off parallel_read_threshold and file_timeout_ms.
+ * A NULL algorithm means "sha256" when hashing and "as named in the manifest" when verifying. */
+typedef struct gustasum_options {
+    const char *algorithm;
+    size_t partial_bytes;
+    bool include_modtime;
+    bool fat_compat;
+    uint64_t parallel_read_threshold;
+    uint32_t retries;
+    uint64_t retry_delay_ms;
+    uint64_t file_timeout_ms;
+    uint32_t reread_unstable;
+    bool retry_network;
+} gustasum_options;
+
+typedef struct gustasum_verify_summary {
+    size_t total;
+    size_t ok;
+    size_t mismatch;
+    size_t unstable;
+    size_t error;
+    size_t malformed;
+} gustasum_verify_summary;
+
+/* Called once per manifest line, in order. error is NULL unless status is GUSTASUM_CHECK_ERROR.
+ * The strings are only valid during the call. */
+typedef void (*gustasum_check_cb)(void *user_data, const char *path, int status, const char *error);
+
+/* Fill *out with the CLI defaults. */
+void gustasum_options_default(gustasum_options *out);
+
+/* Hash the file at path into out_hex (GUSTASUM_HEX_LEN bytes). opts may be NULL for the defaults;
+ * err (err_len bytes) may be NULL. Returns GUSTASUM_OK, GUSTASUM_ERR_IO, GUSTASUM_ERR_UNSTABLE
+ * (out_hex is still written), or GUSTASUM_ERR_ARGUMENT. */
+int gustasum_hash_file(const char *path, const gustasum_options *opts, char *out_hex, char *err,
+                       size_t err_len);
+
+/* Verify every entry of the manifest at manifest_path, using the algorithm and settings in its
+ * header over opts's (an opts->algorithm that disagrees is GUSTASUM_ERR_ARGUMENT). remap_old/
+ * remap_new are both NULL or both set (like --remap). summary and callback may be NULL. Returns
+ * GUSTASUM_OK if every check passed, GUSTASUM_FAILED if any didn't, GUSTASUM_ERR_IO if the
+ * manifest can't be read, or GUSTASUM_ERR_ARGUMENT (also for a salted or anonymized manifest). */
+int gustasum_verify(const char *manifest_path, const gustasum_options *opts, const char *remap_old,
+                    const char *remap_new, gustasum_verify_summary *summary, gustasum_check_cb callback,
+                    void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* GUSTASUM_H */
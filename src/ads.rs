@@ -0,0 +1,124 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! NTFS alternate data streams. A file's named streams are hashed as separate entries,
+//! `path:stream`, so a copy that silently drops them (e.g., to exFAT or FAT32) fails
+//! verification. Windows opens such paths directly, so hashing and checking need nothing
+//! special; only enumeration does.
+
+use std::{io, path::Path, path::PathBuf};
+
+/// The named data streams of `path` as `path:name` paths, not including the unnamed main
+/// stream. Empty on other platforms, and for files on filesystems without streams.
+pub fn alternate_streams(path: &Path) -> io::Result<Vec<PathBuf>> {
+    #[cfg(windows)]
+    return windows::alternate_streams(path);
+    #[cfg(not(windows))]
+    {
+        let _ = path;
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::{
+        ffi::{c_void, OsString},
+        io,
+        os::windows::ffi::{OsStrExt, OsStringExt},
+        path::{Path, PathBuf},
+    };
+
+    const MAX_PATH: usize = 260;
+    const FIND_STREAM_INFO_STANDARD: i32 = 0;
+    const ERROR_HANDLE_EOF: i32 = 38;
+    const ERROR_INVALID_PARAMETER: i32 = 87;
+    const INVALID_HANDLE_VALUE: *mut c_void = -1isize as *mut c_void;
+
+    #[repr(C)]
+    struct Win32FindStreamData {
+        stream_size: i64,
+        stream_name: [u16; MAX_PATH + 36],
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn FindFirstStreamW(
+            file_name: *const u16,
+            info_level: i32,
+            find_stream_data: *mut c_void,
+            flags: u32,
+        ) -> *mut c_void;
+        fn FindNextStreamW(find_stream: *mut c_void, find_stream_data: *mut c_void) -> i32;
+        fn FindClose(find_file: *mut c_void) -> i32;
+    }
+
+    pub fn alternate_streams(path: &Path) -> io::Result<Vec<PathBuf>> {
+        let native = crate::source::native_path(path);
+        let wide: Vec<u16> = native.as_os_str().encode_wide().chain([0]).collect();
+        let mut data = Win32FindStreamData {
+            stream_size: 0,
+            stream_name: [0; MAX_PATH + 36],
+        };
+
+        let handle = unsafe {
+            FindFirstStreamW(
+                wide.as_ptr(),
+                FIND_STREAM_INFO_STANDARD,
+                &mut data as *mut _ as *mut c_void,
+                0,
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            let err = io::Error::last_os_error();
+            // No streams at all, or a filesystem (FAT, network shares) that has none.
+            return match err.raw_os_error() {
+                Some(ERROR_HANDLE_EOF) | Some(ERROR_INVALID_PARAMETER) => Ok(Vec::new()),
+                _ => Err(err),
+            };
+        }
+
+        let mut streams = Vec::new();
+        loop {
+            // Names look like ":name:$DATA"; the main stream is "::$DATA".
+            let len = data
+                .stream_name
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(data.stream_name.len());
+            let name = String::from_utf16_lossy(&data.stream_name[..len]);
+            if let Some(stream) = name.strip_suffix(":$DATA").filter(|s| *s != ":") {
+                let mut full = path.as_os_str().to_os_string();
+                full.push(OsString::from_wide(
+                    &data.stream_name[..stream.encode_utf16().count()],
+                ));
+                streams.push(PathBuf::from(full));
+            }
+            if unsafe { FindNextStreamW(handle, &mut data as *mut _ as *mut c_void) } == 0 {
+                let err = io::Error::last_os_error();
+                unsafe { FindClose(handle) };
+                return match err.raw_os_error() {
+                    Some(ERROR_HANDLE_EOF) => Ok(streams),
+                    _ => Err(err),
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Digest algorithms. The partial samples are fed to a [`Hasher`] picked by name from a
+//! process-wide registry, so other crates can add algorithms with [`register`]. A manifest
+//! records which one produced it in its header.
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fmt,
+    sync::{OnceLock, RwLock},
+};
+
+/// Incremental digest state for one file.
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+    /// Consume the state and return the digest as lowercase hex.
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
+
+impl Hasher for Sha512 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
+
+impl Hasher for Sha1 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
+
+impl Hasher for Md5 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
+
+/// A named digest algorithm.
+#[derive(Clone, Copy)]
+pub struct Algorithm {
+    /// The name used on the command line and in manifest headers.
+    pub name: &'static str,
+    pub new_hasher: fn() -> Box<dyn Hasher>,
+}
+
+impl Algorithm {
+    pub fn hasher(&self) -> Box<dyn Hasher> {
+        (self.new_hasher)()
+    }
+}
+
+impl fmt::Debug for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Algorithm").field(&self.name).finish()
+    }
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        SHA256
+    }
+}
+
+/// The default, and what manifests without an algorithm header were made with.
+pub const SHA256: Algorithm = Algorithm {
+    name: "sha256",
+    new_hasher: || Box::new(Sha256::new()),
+};
+
+pub const SHA512: Algorithm = Algorithm {
+    name: "sha512",
+    new_hasher: || Box::new(Sha512::new()),
+};
+
+/// For the checksum files of `sha1sum`, checked and imported with `--full`; too weak to
+/// choose for new manifests.
+pub const SHA1: Algorithm = Algorithm {
+    name: "sha1",
+    new_hasher: || Box::new(Sha1::new()),
+};
+
+/// For the checksum files of `md5sum`, like [`SHA1`].
+pub const MD5: Algorithm = Algorithm {
+    name: "md5",
+    new_hasher: || Box::new(Md5::new()),
+};
+
+fn registry() -> &'static RwLock<Vec<Algorithm>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Algorithm>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(vec![SHA256, SHA512, SHA1, MD5]))
+}
+
+/// Make `algorithm` available to [`lookup`]. Returns false, changing nothing, if the name
+/// is already taken.
+pub fn register(algorithm: Algorithm) -> bool {
+    let mut algorithms = registry().write().unwrap();
+    if algorithms.iter().any(|a| a.name == algorithm.name) {
+        return false;
+    }
+    algorithms.push(algorithm);
+    true
+}
+
+pub fn lookup(name: &str) -> Result<Algorithm, UnknownAlgorithm> {
+    registry()
+        .read()
+        .unwrap()
+        .iter()
+        .find(|a| a.name == name)
+        .copied()
+        .ok_or_else(|| UnknownAlgorithm(name.to_string()))
+}
+
+/// Names of all registered algorithms, built-ins first.
+pub fn names() -> Vec<&'static str> {
+    registry().read().unwrap().iter().map(|a| a.name).collect()
+}
+
+/// No algorithm of this name is registered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownAlgorithm(pub String);
+
+impl fmt::Display for UnknownAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown digest algorithm '{}' (available: {})",
+            self.0,
+            names().join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownAlgorithm {}
@@ -0,0 +1,213 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Anonymized paths, for manifests shared with someone who shouldn't learn the names in
+//! them: each component becomes a keyed hash of itself, so the directory structure survives
+//! (files in one directory still share a parent) but the names don't.
+//!
+//! A hash can't be undone, only matched: with the key, whoever has the tree can anonymize
+//! its paths the same way and look each one up, which is what [`restore`] does.
+
+use crate::{
+    algorithm,
+    manifest::{
+        join_fields, parse_line, portable_path, split_fields, CLONE_HEADER, GENERATED_HEADER,
+    },
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+/// The [`GENERATED_HEADER`] field recording the [`PathKey::id`] a manifest was anonymized with.
+pub const ANONYMIZED_FIELD: &str = "anonymized";
+
+/// The secret that path components are hashed with.
+#[derive(Clone)]
+pub struct PathKey(Arc<[u8]>);
+
+impl PathKey {
+    pub fn new(bytes: impl Into<Arc<[u8]>>) -> PathKey {
+        PathKey(bytes.into())
+    }
+
+    /// A short fingerprint of the key, for recording which one a manifest was anonymized with.
+    pub fn id(&self) -> String {
+        let mut hasher = algorithm::SHA256.hasher();
+        hasher.update(b"gustasum path key id\0");
+        hasher.update(&self.0);
+        hasher.finish()[..16].to_string()
+    }
+
+    /// `path` with every name in it replaced by 16 hex digits of a keyed hash of it. The
+    /// root, a drive prefix, `.` and `..` are kept, so relative paths stay relative.
+    pub fn anonymize(&self, path: &Path) -> PathBuf {
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => {
+                    let mut hasher = algorithm::SHA256.hasher();
+                    hasher.update(&(self.0.len() as u64).to_le_bytes());
+                    hasher.update(&self.0);
+                    hasher.update(name.as_encoded_bytes());
+                    PathBuf::from(&hasher.finish()[..16])
+                }
+                other => PathBuf::from(other.as_os_str()),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for PathKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PathKey({})", self.id())
+    }
+}
+
+/// An anonymized manifest with its paths put back, as far as they could be.
+#[derive(Clone, Debug, Default)]
+pub struct Restored {
+    pub contents: String,
+    /// Anonymized paths none of the given real paths matched, left as they were.
+    pub unmatched: Vec<String>,
+}
+
+/// Put the real paths back into the anonymized manifest `contents`, matching its paths
+/// against `real` ones anonymized with `key`. Other lines are kept, except that the
+/// [`ANONYMIZED_FIELD`] is dropped from the generated header once every path is back.
+pub fn restore(contents: &str, key: &PathKey, real: impl IntoIterator<Item = PathBuf>) -> Restored {
+    let known: HashMap<String, PathBuf> = real
+        .into_iter()
+        .map(|path| (portable_path(&key.anonymize(&path)), path))
+        .collect();
+    let mut unmatched = Vec::new();
+    let mut lookup = |anonymized: &str| match known.get(anonymized) {
+        Some(path) => portable_path(path),
+        None => {
+            unmatched.push(anonymized.to_string());
+            anonymized.to_string()
+        }
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    for line in contents.lines() {
+        if let Some(path) = line.strip_prefix(CLONE_HEADER) {
+            lines.push(format!("{}{}", CLONE_HEADER, lookup(path.trim())));
+        } else if line.trim().starts_with('#') || line.trim().is_empty() {
+            lines.push(line.to_string());
+        } else if let Some(mut entry) = parse_line(line.trim()) {
+            entry.path = PathBuf::from(lookup(&portable_path(&entry.path)));
+            lines.push(entry.to_string());
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+
+    if unmatched.is_empty() {
+        for line in &mut lines {
+            if let Some(fields) = line.strip_prefix(GENERATED_HEADER) {
+                let fields: Vec<(String, String)> = split_fields(fields)
+                    .into_iter()
+                    .filter(|(k, _)| k != ANONYMIZED_FIELD)
+                    .collect();
+                *line = format!("{}{}", GENERATED_HEADER, join_fields(&fields));
+            }
+        }
+    }
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    Restored {
+        contents,
+        unmatched,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{provenance_line, Manifest};
+
+    fn key() -> PathKey {
+        PathKey::new(&b"path key"[..])
+    }
+
+    #[test]
+    fn names_are_hidden_but_structure_kept() {
+        let key = key();
+        let a = key.anonymize(Path::new("/data/photos/a.jpg"));
+        let b = key.anonymize(Path::new("/data/photos/b.jpg"));
+        assert!(a.is_absolute());
+        assert_eq!(a.parent(), b.parent());
+        assert_ne!(a, b);
+        assert!(!a.to_string_lossy().contains("photos"));
+        assert_eq!(a.components().count(), 4);
+        assert!(key.anonymize(Path::new("../x")).starts_with(".."));
+    }
+
+    #[test]
+    fn another_key_gives_other_names() {
+        let path = Path::new("/data/a");
+        assert_eq!(key().anonymize(path), key().anonymize(path));
+        assert_ne!(
+            key().anonymize(path),
+            PathKey::new(&b"other"[..]).anonymize(path)
+        );
+        assert_ne!(key().id(), PathKey::new(&b"other"[..]).id());
+    }
+
+    #[test]
+    fn restore_puts_real_paths_back() {
+        let key = key();
+        let real = [PathBuf::from("/data/a"), PathBuf::from("/data/b c")];
+        let header = provenance_line(&[
+            ("partial_bytes", "100".to_string()),
+            (ANONYMIZED_FIELD, key.id()),
+        ]);
+        let contents = format!(
+            "{}\naaaa  {}\nbbbb  {}\n",
+            header,
+            portable_path(&key.anonymize(&real[0])),
+            portable_path(&key.anonymize(&real[1]))
+        );
+        let restored = restore(&contents, &key, real.clone());
+        assert!(restored.unmatched.is_empty());
+        let manifest = Manifest::parse(&restored.contents);
+        let paths: Vec<&Path> = manifest.entries().map(|e| e.path.as_path()).collect();
+        assert_eq!(paths, [Path::new("/data/a"), Path::new("/data/b c")]);
+        assert_eq!(manifest.generated(ANONYMIZED_FIELD), None);
+        assert_eq!(manifest.generated("partial_bytes"), Some("100"));
+    }
+
+    #[test]
+    fn unmatched_paths_stay_anonymized() {
+        let key = key();
+        let hidden = portable_path(&key.anonymize(Path::new("/data/gone")));
+        let header = provenance_line(&[(ANONYMIZED_FIELD, key.id())]);
+        let contents = format!("{}\naaaa  {}\n", header, hidden);
+        let restored = restore(&contents, &key, [PathBuf::from("/data/a")]);
+        assert_eq!(restored.unmatched, [hidden]);
+        let manifest = Manifest::parse(&restored.contents);
+        assert_eq!(
+            manifest.generated(ANONYMIZED_FIELD),
+            Some(key.id().as_str())
+        );
+    }
+}
@@ -0,0 +1,960 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Members of archives as files of their own. [`ArchiveSource`] wraps another source and
+//! lists each member of the archives it walks as `archive.tar!member/path`, reading the
+//! member's bytes straight out of the archive, so members are sampled like any other file
+//! without being extracted. Deflated ZIP members are decompressed as they're read, from the
+//! start up to the last sampled byte.
+
+use crate::inflate::Inflate;
+use crate::source::{civil_time, Source, SourceFile, SourceMetadata};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Separates the archive's path from the member's in entries such as `backup.tar!etc/hosts`.
+pub const MEMBER_SEPARATOR: char = '!';
+
+/// GNU long names and pax headers larger than this are taken to be corruption.
+const MAX_EXTENDED_HEADER: u64 = 1 << 20;
+
+/// The archive formats whose members can be listed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    /// POSIX ustar and pax, GNU, and old V7 tar files, uncompressed.
+    Tar,
+    /// ZIP files (and ZIP64), with stored and deflated members.
+    Zip,
+}
+
+impl ArchiveKind {
+    /// The kind named `name` (as given to `--archive`).
+    pub fn parse(name: &str) -> Option<ArchiveKind> {
+        match name.to_ascii_lowercase().as_str() {
+            "tar" => Some(ArchiveKind::Tar),
+            "zip" => Some(ArchiveKind::Zip),
+            _ => None,
+        }
+    }
+
+    /// Archives of this kind are recognized by this file name extension.
+    fn extension(self) -> &'static str {
+        match self {
+            ArchiveKind::Tar => ".tar",
+            ArchiveKind::Zip => ".zip",
+        }
+    }
+
+    fn matches(self, path: &str) -> bool {
+        let ext = self.extension();
+        path.len() > ext.len()
+            && path.is_char_boundary(path.len() - ext.len())
+            && path[path.len() - ext.len()..].eq_ignore_ascii_case(ext)
+    }
+}
+
+/// Where one member's content lies within its archive.
+#[derive(Clone, Debug)]
+struct Member {
+    /// Where the content starts, or for ZIP members, their local header.
+    offset: u64,
+    /// The content's length, uncompressed.
+    len: u64,
+    modified: Option<SystemTime>,
+    storage: Storage,
+}
+
+#[derive(Clone, Debug)]
+enum Storage {
+    /// The content as is (tar).
+    Plain,
+    /// A ZIP member, to be located through its local header.
+    Zip {
+        method: u16,
+        compressed: u64,
+        encrypted: bool,
+    },
+}
+
+/// An archive's members in archive order, and the archive metadata they were read with.
+#[derive(Debug)]
+struct Index {
+    archive: SourceMetadata,
+    members: Vec<(String, Member)>,
+    by_name: HashMap<String, usize>,
+}
+
+/// `inner`, with each archive of the given kinds also listed and readable member by member.
+/// Other paths pass through unchanged.
+pub struct ArchiveSource {
+    inner: Arc<dyn Source>,
+    kinds: Vec<ArchiveKind>,
+    indexes: Mutex<HashMap<PathBuf, Arc<Index>>>,
+}
+
+impl fmt::Debug for ArchiveSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArchiveSource")
+            .field("inner", &self.inner)
+            .field("kinds", &self.kinds)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ArchiveSource {
+    pub fn new(inner: Arc<dyn Source>, kinds: Vec<ArchiveKind>) -> Self {
+        ArchiveSource {
+            inner,
+            kinds,
+            indexes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn kind_of(&self, path: &str) -> Option<ArchiveKind> {
+        self.kinds.iter().copied().find(|k| k.matches(path))
+    }
+
+    /// `path` as `(archive, member)`, split at the first separator that follows the name of
+    /// an archive, or `None` if it isn't a member path.
+    fn split<'p>(&self, path: &'p Path) -> Option<(&'p Path, String, ArchiveKind)> {
+        let s = path.to_str()?;
+        s.match_indices(MEMBER_SEPARATOR).find_map(|(idx, _)| {
+            let kind = self.kind_of(&s[..idx])?;
+            // Native separators may have been applied to the whole entry.
+            let member = s[idx + 1..].replace('\\', "/");
+            Some((Path::new(&s[..idx]), member, kind))
+        })
+    }
+
+    /// The members of `archive`, read again whenever the archive's size or modtime changes.
+    fn index(&self, archive: &Path, kind: ArchiveKind) -> io::Result<Arc<Index>> {
+        let meta = self.inner.metadata(archive)?;
+        if let Some(index) = self.indexes.lock().unwrap().get(archive) {
+            if index.archive == meta {
+                return Ok(index.clone());
+            }
+        }
+        // Parsed without holding the lock, so different archives are indexed in parallel.
+        let file = self.inner.open(archive)?;
+        let members = match kind {
+            ArchiveKind::Tar => tar_members(file.as_ref()),
+            ArchiveKind::Zip => zip_members(file.as_ref()),
+        }
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", archive.display(), e)))?;
+        let by_name = members
+            .iter()
+            .enumerate()
+            .map(|(idx, (name, _))| (name.clone(), idx))
+            .collect();
+        let index = Arc::new(Index {
+            archive: meta,
+            members,
+            by_name,
+        });
+        self.indexes
+            .lock()
+            .unwrap()
+            .insert(archive.to_path_buf(), index.clone());
+        Ok(index)
+    }
+
+    fn member<'p>(&self, path: &'p Path) -> Option<io::Result<(&'p Path, Arc<Index>, Member)>> {
+        let (archive, name, kind) = self.split(path)?;
+        let found = self.index(archive, kind).and_then(|index| {
+            let member = index
+                .by_name
+                .get(&name)
+                .map(|&idx| index.members[idx].1.clone())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no member '{}' in {}", name, archive.display()),
+                    )
+                })?;
+            Ok((index, member))
+        });
+        Some(found.map(|(index, member)| (archive, index, member)))
+    }
+}
+
+impl Source for ArchiveSource {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        match self.member(path) {
+            Some(found) => found.map(|(_, _, member)| SourceMetadata {
+                len: member.len,
+                modified: member.modified,
+            }),
+            None => self.inner.metadata(path),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>> {
+        match self.member(path) {
+            Some(found) => {
+                let (archive, index, member) = found?;
+                MemberFile::open(self.inner.open(archive)?.into(), &index, member)
+                    .map(|f| Box::new(f) as Box<dyn SourceFile>)
+            }
+            None => self.inner.open(path),
+        }
+    }
+
+    /// Archives are replaced by their members. One that can't be read is listed as
+    /// `archive.tar!`, so the error is reported when that entry is hashed.
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        Box::new(self.inner.walk(root).flat_map(move |path| {
+            let Some(kind) = path.to_str().and_then(|s| self.kind_of(s)) else {
+                return vec![path];
+            };
+            let member_path = |name: &str| {
+                let mut p = path.clone().into_os_string();
+                p.push(format!("{}{}", MEMBER_SEPARATOR, name));
+                PathBuf::from(p)
+            };
+            match self.index(&path, kind) {
+                Ok(index) => index
+                    .members
+                    .iter()
+                    .map(|(name, _)| member_path(name))
+                    .collect(),
+                Err(_) => vec![member_path("")],
+            }
+        }))
+    }
+}
+
+type Decoder = Inflate<BufReader<Range>>;
+
+/// A member, read out of its open archive.
+struct MemberFile {
+    archive: Arc<dyn SourceFile>,
+    archive_meta: SourceMetadata,
+    member: Member,
+    /// Where the member's bytes, compressed or not, start in the archive.
+    data: u64,
+    /// For deflated members: the decoder, if one has been started, and how far it has got.
+    /// Reads before that point start over from the beginning.
+    decoder: Option<Mutex<Option<(u64, Decoder)>>>,
+}
+
+impl MemberFile {
+    fn open(archive: Arc<dyn SourceFile>, index: &Index, member: Member) -> io::Result<Self> {
+        let (data, deflated) = match member.storage {
+            Storage::Plain => (member.offset, false),
+            Storage::Zip {
+                encrypted: true, ..
+            } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "encrypted ZIP member",
+                ))
+            }
+            Storage::Zip { method, .. } if method != 0 && method != 8 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("unsupported ZIP compression method {}", method),
+                ))
+            }
+            Storage::Zip { method, .. } => {
+                let mut header = [0u8; 30];
+                if read_exact_at(archive.as_ref(), member.offset, &mut header)? < 30
+                    || le32(&header[0..]) != 0x0403_4b50
+                {
+                    return Err(invalid(format!(
+                        "bad ZIP local header at offset {}",
+                        member.offset
+                    )));
+                }
+                let skip = 30 + u64::from(le16(&header[26..])) + u64::from(le16(&header[28..]));
+                (member.offset + skip, method == 8)
+            }
+        };
+        Ok(MemberFile {
+            archive,
+            archive_meta: index.archive.clone(),
+            member,
+            data,
+            decoder: deflated.then(|| Mutex::new(None)),
+        })
+    }
+
+    fn read_deflated(
+        &self,
+        slot: &Mutex<Option<(u64, Decoder)>>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let mut slot = slot.lock().unwrap();
+        if slot.as_ref().is_none_or(|(pos, _)| *pos > offset) {
+            let compressed = match self.member.storage {
+                Storage::Zip { compressed, .. } => compressed,
+                Storage::Plain => unreachable!("only ZIP members are deflated"),
+            };
+            let range = Range {
+                file: self.archive.clone(),
+                pos: self.data,
+                end: self.data + compressed,
+            };
+            *slot = Some((0, Inflate::new(BufReader::with_capacity(64 * 1024, range))));
+        }
+        let (pos, decoder) = slot.as_mut().unwrap();
+        let mut skip = [0u8; 8192];
+        while *pos < offset {
+            let want = (offset - *pos).min(skip.len() as u64) as usize;
+            let n = decoder.read(&mut skip[..want])?;
+            if n == 0 {
+                return Ok(0);
+            }
+            *pos += n as u64;
+        }
+        let mut total = 0;
+        while total < buf.len() {
+            let n = decoder.read(&mut buf[total..])?;
+            if n == 0 {
+                break;
+            }
+            total += n;
+        }
+        *pos += total as u64;
+        Ok(total)
+    }
+}
+
+impl SourceFile for MemberFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.member.len.saturating_sub(offset);
+        let n = (buf.len() as u64).min(left) as usize;
+        match &self.decoder {
+            Some(slot) => self.read_deflated(slot, offset, &mut buf[..n]),
+            None => self.archive.read_at(self.data + offset, &mut buf[..n]),
+        }
+    }
+
+    /// The member's metadata, or, once the archive itself has changed, the archive's
+    /// modtime in place of the member's, so the read is seen to be unstable.
+    fn metadata(&self) -> io::Result<SourceMetadata> {
+        let now = self.archive.metadata()?;
+        Ok(SourceMetadata {
+            len: self.member.len,
+            modified: if now == self.archive_meta {
+                self.member.modified
+            } else {
+                now.modified
+            },
+        })
+    }
+}
+
+/// Part of an open archive, as a stream.
+struct Range {
+    file: Arc<dyn SourceFile>,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for Range {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.end.saturating_sub(self.pos)) as usize;
+        let n = self.file.read_at(self.pos, &mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+fn read_exact_at(file: &dyn SourceFile, mut offset: u64, mut buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while !buf.is_empty() {
+        let n = file.read_at(offset, buf)?;
+        if n == 0 {
+            break;
+        }
+        total += n;
+        offset += n as u64;
+        buf = &mut buf[n..];
+    }
+    Ok(total)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A tar numeric field: octal digits padded with spaces or NULs, or GNU base-256 when the
+/// high bit of the first byte is set.
+fn tar_number(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x7f), |n, &b| {
+                n.checked_mul(256).map(|n| n | u64::from(b))
+            });
+    }
+    let digits = std::str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// `<length> <key>=<value>\n` records of a pax extended header.
+fn pax_records(data: &[u8]) -> HashMap<String, String> {
+    let mut records = HashMap::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|&len| len > space && len <= rest.len())
+        else {
+            break;
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+/// A pax `mtime`, which may have a fractional part.
+fn pax_time(value: &str) -> Option<SystemTime> {
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    let nanos = format!("{:0<9}", &frac[..frac.len().min(9)]);
+    Some(UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?))
+}
+
+/// The regular files in a tar archive, in archive order. Directories, links, devices, and
+/// sparse files are left out.
+fn tar_members(file: &dyn SourceFile) -> io::Result<Vec<(String, Member)>> {
+    let archive_len = file.metadata()?.len;
+    let mut members = Vec::new();
+    let mut header = [0u8; 512];
+    let mut offset = 0u64;
+    // Set by GNU long-name and pax headers for the entry that follows them.
+    let mut long_name: Option<String> = None;
+    let mut pax: HashMap<String, String> = HashMap::new();
+
+    loop {
+        match read_exact_at(file, offset, &mut header)? {
+            // Some writers leave out the two zero blocks that end an archive.
+            0 if offset == archive_len => break,
+            512 => {}
+            _ => {
+                return Err(invalid(format!(
+                    "truncated tar header at offset {}",
+                    offset
+                )))
+            }
+        }
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let checksum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    u64::from(b)
+                }
+            })
+            .sum();
+        if tar_number(&header[148..156]) != Some(checksum) {
+            return Err(invalid(format!(
+                "bad tar header checksum at offset {}",
+                offset
+            )));
+        }
+        let size = tar_number(&header[124..136])
+            .ok_or_else(|| invalid(format!("bad tar size field at offset {}", offset)))?;
+        let data = offset + 512;
+        let read_extended = || -> io::Result<Vec<u8>> {
+            if size > MAX_EXTENDED_HEADER {
+                return Err(invalid(format!(
+                    "oversized tar extended header at offset {}",
+                    offset
+                )));
+            }
+            let mut buf = vec![0u8; size as usize];
+            if read_exact_at(file, data, &mut buf)? < buf.len() {
+                return Err(invalid(format!(
+                    "truncated tar extended header at offset {}",
+                    offset
+                )));
+            }
+            Ok(buf)
+        };
+
+        let mut next_size = size;
+        match header[156] {
+            b'L' => long_name = Some(tar_string(&read_extended()?)),
+            b'x' => pax.extend(pax_records(&read_extended()?)),
+            b'g' => {}
+            typeflag => {
+                let name = pax.remove("path").or(long_name.take()).unwrap_or_else(|| {
+                    let name = tar_string(&header[..100]);
+                    let prefix = tar_string(&header[345..500]);
+                    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                        format!("{}/{}", prefix, name)
+                    } else {
+                        name
+                    }
+                });
+                let len = match pax.remove("size") {
+                    Some(len) => len
+                        .parse()
+                        .map_err(|_| invalid(format!("bad pax size at offset {}", offset)))?,
+                    None => size,
+                };
+                next_size = len;
+                // V7 archives mark directories only by the trailing slash.
+                if matches!(typeflag, 0 | b'0' | b'7') && !name.ends_with('/') {
+                    let modified = match pax.remove("mtime") {
+                        Some(mtime) => pax_time(&mtime),
+                        None => tar_number(&header[136..148])
+                            .map(|s| UNIX_EPOCH + Duration::from_secs(s)),
+                    };
+                    let name = name.trim_start_matches("./").to_string();
+                    members.push((
+                        name,
+                        Member {
+                            offset: data,
+                            len,
+                            modified,
+                            storage: Storage::Plain,
+                        },
+                    ));
+                }
+                pax.clear();
+            }
+        }
+        if data + next_size > archive_len {
+            return Err(invalid(format!(
+                "tar entry at offset {} runs past the end",
+                offset
+            )));
+        }
+        offset = data + next_size.div_ceil(512) * 512;
+    }
+    Ok(members)
+}
+
+fn le16(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn le32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn le64(b: &[u8]) -> u64 {
+    u64::from_le_bytes(b[..8].try_into().unwrap())
+}
+
+/// An MS-DOS date and time, which ZIP records in local time without saying which zone; it's
+/// read as UTC.
+fn dos_time(date: u16, time: u16) -> Option<SystemTime> {
+    civil_time(
+        i64::from(date >> 9) + 1980,
+        i64::from((date >> 5) & 0xf),
+        i64::from(date & 0x1f),
+        i64::from(time >> 11),
+        i64::from((time >> 5) & 0x3f),
+        i64::from(time & 0x1f) * 2,
+    )
+}
+
+/// Where the central directory starts, how long it is, and how many entries it has, from the
+/// end of central directory record (or its ZIP64 version).
+fn zip_directory(file: &dyn SourceFile, archive_len: u64) -> io::Result<(u64, u64, u64)> {
+    // The record is 22 bytes plus a comment of up to 64 KiB.
+    let tail_len = archive_len.min(22 + 0xffff);
+    let tail_start = archive_len - tail_len;
+    let mut tail = vec![0u8; tail_len as usize];
+    read_exact_at(file, tail_start, &mut tail)?;
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| le32(&tail[i..]) == 0x0605_4b50)
+        .ok_or_else(|| invalid("no ZIP end of central directory record".to_string()))?;
+    let record = &tail[eocd..];
+    let (count, size, offset) = (
+        u64::from(le16(&record[10..])),
+        u64::from(le32(&record[12..])),
+        u64::from(le32(&record[16..])),
+    );
+    if count != 0xffff && size != 0xffff_ffff && offset != 0xffff_ffff {
+        return Ok((offset, size, count));
+    }
+
+    let locator = (tail_start + eocd as u64)
+        .checked_sub(20)
+        .ok_or_else(|| invalid("no ZIP64 end of central directory locator".to_string()))?;
+    let mut buf = [0u8; 56];
+    read_exact_at(file, locator, &mut buf[..20])?;
+    if le32(&buf) != 0x0706_4b50 {
+        return Err(invalid(
+            "no ZIP64 end of central directory locator".to_string(),
+        ));
+    }
+    let record = le64(&buf[8..]);
+    if read_exact_at(file, record, &mut buf)? < 56 || le32(&buf) != 0x0606_4b50 {
+        return Err(invalid(
+            "bad ZIP64 end of central directory record".to_string(),
+        ));
+    }
+    Ok((le64(&buf[48..]), le64(&buf[40..]), le64(&buf[32..])))
+}
+
+/// The files in a ZIP archive, in central directory order. Directories are left out.
+fn zip_members(file: &dyn SourceFile) -> io::Result<Vec<(String, Member)>> {
+    let archive_len = file.metadata()?.len;
+    let (cd_offset, cd_size, count) = zip_directory(file, archive_len)?;
+    if cd_offset
+        .checked_add(cd_size)
+        .is_none_or(|end| end > archive_len)
+    {
+        return Err(invalid(
+            "ZIP central directory runs past the end".to_string(),
+        ));
+    }
+    let mut cd = vec![0u8; cd_size as usize];
+    read_exact_at(file, cd_offset, &mut cd)?;
+
+    let mut members = Vec::new();
+    let mut pos = 0usize;
+    for _ in 0..count {
+        let bad = || {
+            invalid(format!(
+                "bad ZIP central directory entry at offset {}",
+                cd_offset + pos as u64
+            ))
+        };
+        let entry = cd.get(pos..pos + 46).ok_or_else(bad)?;
+        if le32(entry) != 0x0201_4b50 {
+            return Err(bad());
+        }
+        let flags = le16(&entry[8..]);
+        let method = le16(&entry[10..]);
+        let (time, date) = (le16(&entry[12..]), le16(&entry[14..]));
+        let mut compressed = u64::from(le32(&entry[20..]));
+        let mut len = u64::from(le32(&entry[24..]));
+        let name_len = usize::from(le16(&entry[28..]));
+        let extra_len = usize::from(le16(&entry[30..]));
+        let comment_len = usize::from(le16(&entry[32..]));
+        let mut offset = u64::from(le32(&entry[42..]));
+        let name = cd.get(pos + 46..pos + 46 + name_len).ok_or_else(bad)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        let extra = cd
+            .get(pos + 46 + name_len..pos + 46 + name_len + extra_len)
+            .ok_or_else(bad)?;
+        let mut modified = dos_time(date, time);
+
+        let mut fields = extra;
+        while fields.len() >= 4 {
+            let (id, size) = (le16(fields), usize::from(le16(&fields[2..])));
+            let Some(data) = fields.get(4..4 + size) else {
+                break;
+            };
+            match id {
+                // ZIP64: the full values of whichever fields were saturated, in this order.
+                0x0001 => {
+                    let mut values = data.chunks_exact(8).map(le64);
+                    for field in [&mut len, &mut compressed, &mut offset] {
+                        if *field == 0xffff_ffff {
+                            *field = values.next().ok_or_else(bad)?;
+                        }
+                    }
+                }
+                // Extended timestamp: flags, then the modtime in Unix seconds if bit 0 is set.
+                0x5455 if data.len() >= 5 && data[0] & 1 != 0 => {
+                    let secs = le32(&data[1..]) as i32;
+                    modified = u64::try_from(secs)
+                        .ok()
+                        .map(|s| UNIX_EPOCH + Duration::from_secs(s));
+                }
+                _ => {}
+            }
+            fields = &fields[4 + size..];
+        }
+        pos += 46 + name_len + extra_len + comment_len;
+
+        if !name.ends_with('/') {
+            members.push((
+                name,
+                Member {
+                    offset,
+                    len,
+                    modified,
+                    storage: Storage::Zip {
+                        method,
+                        compressed,
+                        encrypted: flags & 1 != 0,
+                    },
+                },
+            ));
+        }
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MemorySource;
+
+    /// A ustar header for `name`, followed by `data` padded to whole blocks.
+    fn tar_entry(name: &str, typeflag: u8, mtime: u64, data: &[u8]) -> Vec<u8> {
+        let mut header = [0u8; 512];
+        header[..name.len().min(100)].copy_from_slice(&name.as_bytes()[..name.len().min(100)]);
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[136..148].copy_from_slice(format!("{:011o}\0", mtime).as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+        let mut entry = header.to_vec();
+        entry.extend_from_slice(data);
+        entry.resize(entry.len().div_ceil(512) * 512, 0);
+        entry
+    }
+
+    /// A pax extended header record, `<length> <key>=<value>\n`, the length counting itself.
+    fn pax_record(key: &str, value: &str) -> String {
+        let rest = format!(" {}={}\n", key, value);
+        let mut len = rest.len() + 1;
+        while (len.to_string().len() + rest.len()) != len {
+            len += 1;
+        }
+        format!("{}{}", len, rest)
+    }
+
+    fn tar(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = entries.concat();
+        archive.extend_from_slice(&[0; 1024]);
+        archive
+    }
+
+    /// A ZIP of `(name, method, stored bytes, uncompressed length)` members, with its central
+    /// directory and end record.
+    fn zip(members: &[(&str, u16, &[u8], usize)]) -> Vec<u8> {
+        // 2024-05-01 12:00:00, as MS-DOS has it.
+        let (date, time) = ((44u16 << 9) | (5 << 5) | 1, 12u16 << 11);
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, method, data, len) in members {
+            let offset = archive.len() as u32;
+            archive.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            for field in [20u16, 0, method, time, date] {
+                archive.extend_from_slice(&field.to_le_bytes());
+            }
+            for field in [0u32, data.len() as u32, len as u32] {
+                archive.extend_from_slice(&field.to_le_bytes());
+            }
+            archive.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            archive.extend_from_slice(&0u16.to_le_bytes());
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(data);
+
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            for field in [20u16, 20, 0, method, time, date] {
+                directory.extend_from_slice(&field.to_le_bytes());
+            }
+            for field in [0u32, data.len() as u32, len as u32] {
+                directory.extend_from_slice(&field.to_le_bytes());
+            }
+            for field in [name.len() as u16, 0, 0, 0, 0] {
+                directory.extend_from_slice(&field.to_le_bytes());
+            }
+            directory.extend_from_slice(&0u32.to_le_bytes());
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        let count = members.len() as u16;
+        for field in [0u16, 0, count, count] {
+            archive.extend_from_slice(&field.to_le_bytes());
+        }
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&directory_offset.to_le_bytes());
+        archive.extend_from_slice(&0u16.to_le_bytes());
+        archive
+    }
+
+    fn source(name: &str, archive: Vec<u8>) -> ArchiveSource {
+        let mut inner = MemorySource::new();
+        inner.insert(name, archive);
+        ArchiveSource::new(Arc::new(inner), vec![ArchiveKind::Tar, ArchiveKind::Zip])
+    }
+
+    fn walk(source: &ArchiveSource) -> Vec<String> {
+        source
+            .walk(Path::new(""))
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn read(source: &ArchiveSource, path: &str) -> Vec<u8> {
+        let file = source.open(Path::new(path)).unwrap();
+        let mut buf = vec![0; file.metadata().unwrap().len as usize];
+        assert_eq!(
+            read_exact_at(file.as_ref(), 0, &mut buf).unwrap(),
+            buf.len()
+        );
+        buf
+    }
+
+    fn index_error(name: &str, archive: Vec<u8>) -> String {
+        let mut inner = MemorySource::new();
+        inner.insert(name, archive);
+        let file = inner.open(Path::new(name)).unwrap();
+        let members = match ArchiveKind::parse(&name[name.len() - 3..]).unwrap() {
+            ArchiveKind::Tar => tar_members(file.as_ref()),
+            ArchiveKind::Zip => zip_members(file.as_ref()),
+        };
+        members.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn tar_members_are_listed_and_read() {
+        let source = source(
+            "x.tar",
+            tar(&[
+                tar_entry("./a.txt", b'0', 0, b"hello"),
+                tar_entry("d/", b'5', 0, b""),
+                tar_entry("d/b.txt", b'0', 1_000_000, &[7; 600]),
+            ]),
+        );
+        assert_eq!(walk(&source), ["x.tar!a.txt", "x.tar!d/b.txt"]);
+        assert_eq!(read(&source, "x.tar!a.txt"), b"hello");
+        assert_eq!(read(&source, "x.tar!d/b.txt"), [7; 600]);
+        let meta = source.metadata(Path::new("x.tar!d/b.txt")).unwrap();
+        assert_eq!(
+            meta.modified,
+            Some(UNIX_EPOCH + Duration::from_secs(1_000_000))
+        );
+    }
+
+    #[test]
+    fn tar_long_names() {
+        let gnu = format!("{}/gnu.txt", "g".repeat(120));
+        let pax = format!("{}/pax.txt", "p".repeat(120));
+        let source = source(
+            "x.tar",
+            tar(&[
+                tar_entry("././@LongLink", b'L', 0, format!("{}\0", gnu).as_bytes()),
+                tar_entry(&gnu, b'0', 0, b"gnu"),
+                tar_entry("PaxHeader", b'x', 0, pax_record("path", &pax).as_bytes()),
+                tar_entry(&pax, b'0', 0, b"pax"),
+            ]),
+        );
+        assert_eq!(
+            walk(&source),
+            [format!("x.tar!{}", gnu), format!("x.tar!{}", pax)]
+        );
+        assert_eq!(read(&source, &format!("x.tar!{}", pax)), b"pax");
+    }
+
+    #[test]
+    fn truncated_tars_are_errors() {
+        let archive = tar(&[
+            tar_entry("a.txt", b'0', 0, b"hello"),
+            tar_entry("b.txt", b'0', 0, &[1; 2000]),
+        ]);
+        assert!(index_error("x.tar", archive[..1024 + 300].to_vec())
+            .contains("truncated tar header at offset 1024"));
+        assert!(index_error("x.tar", archive[..1024 + 512 + 100].to_vec())
+            .contains("runs past the end"));
+        let mut corrupt = archive.clone();
+        corrupt[0] ^= 1;
+        assert!(index_error("x.tar", corrupt).contains("bad tar header checksum"));
+
+        // An archive that can't be listed is walked as one entry, so its error is reported.
+        assert_eq!(walk(&source("x.tar", archive[..700].to_vec())), ["x.tar!"]);
+    }
+
+    #[test]
+    fn zip_members_are_listed_and_read() {
+        // "abcabcabcabcabc, fixed codes", deflated with the fixed codes.
+        let deflated = [
+            0x4b, 0x4c, 0x4a, 0x4e, 0x44, 0x42, 0x3a, 0x0a, 0x69, 0x99, 0x15, 0xa9, 0x29, 0x0a,
+            0xc9, 0xf9, 0x29, 0xa9, 0xc5, 0x00,
+        ];
+        let source = source(
+            "x.zip",
+            zip(&[
+                ("stored.txt", 0, b"as it is", 8),
+                ("d/", 0, b"", 0),
+                ("d/deflated.txt", 8, &deflated, 28),
+            ]),
+        );
+        assert_eq!(walk(&source), ["x.zip!stored.txt", "x.zip!d/deflated.txt"]);
+        assert_eq!(read(&source, "x.zip!stored.txt"), b"as it is");
+        assert_eq!(
+            read(&source, "x.zip!d/deflated.txt"),
+            b"abcabcabcabcabc, fixed codes"
+        );
+
+        // A read from the middle decompresses up to it.
+        let file = source.open(Path::new("x.zip!d/deflated.txt")).unwrap();
+        let mut buf = [0; 5];
+        assert_eq!(file.read_at(17, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"fixed");
+
+        let meta = source.metadata(Path::new("x.zip!stored.txt")).unwrap();
+        assert_eq!(
+            meta.modified,
+            Some(UNIX_EPOCH + Duration::from_secs(1_714_564_800))
+        );
+    }
+
+    #[test]
+    fn zip_without_central_directory_is_an_error() {
+        let archive = zip(&[("a.txt", 0, b"hello", 5)]);
+        let local_only = archive[..30 + 5 + 5].to_vec();
+        assert!(index_error("x.zip", local_only.clone())
+            .contains("no ZIP end of central directory record"));
+        assert_eq!(walk(&source("x.zip", local_only)), ["x.zip!"]);
+
+        // The end record promises more entries than the directory holds.
+        let mut short = archive;
+        let end = short.len() - 22;
+        short[end + 8..end + 12].copy_from_slice(&[2, 0, 2, 0]);
+        assert!(index_error("x.zip", short).contains("bad ZIP central directory entry"));
+    }
+}
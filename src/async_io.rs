@@ -0,0 +1,98 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tokio backend (the `async-io` feature) for storage where per-request latency, not
+//! bandwidth or CPU, limits throughput: S3FS, NFS over a WAN, and the like. Keeping hundreds
+//! of files in flight hides that latency much better than one read per rayon thread.
+//!
+//! Tokio performs file I/O on its blocking pool, so each in-flight file still occupies a
+//! pool thread while its reads are outstanding. What changes is that the pool grows to the
+//! requested concurrency instead of one thread per core, and that the scheduling, retries,
+//! and timeouts are the same [`hash_file`] the rest of the crate uses, so digests are identical.
+
+use crate::error::HashError;
+use crate::events::EventSink;
+use crate::hasher::{hash_file, report, FileHash, HashOptions};
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::{mpsc, Semaphore};
+
+enum Progress {
+    Started(usize),
+    Done(usize, Result<FileHash, HashError>),
+}
+
+/// Hash `paths` with up to `concurrency` files in flight, returning results in the same
+/// order. Once `stop` returns true no new files are started; those come back as `None`.
+pub fn hash_files_async(
+    paths: &[PathBuf],
+    opts: &HashOptions,
+    concurrency: usize,
+    events: &dyn EventSink,
+    stop: &dyn Fn() -> bool,
+) -> Vec<Option<Result<FileHash, HashError>>> {
+    let concurrency = concurrency.max(1);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .max_blocking_threads(concurrency)
+        .build()
+        .expect("failed to build tokio runtime");
+
+    let opts = Arc::new(opts.clone());
+    let mut results: Vec<Option<Result<FileHash, HashError>>> =
+        paths.iter().map(|_| None).collect();
+
+    runtime.block_on(async {
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        // Tasks report back here, so `events` is only ever called from this thread.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut next = 0;
+        let mut in_flight = 0usize;
+
+        loop {
+            let more = next < paths.len() && !stop();
+            if in_flight == 0 && !more {
+                break;
+            }
+            tokio::select! {
+                permit = Arc::clone(&semaphore).acquire_owned(), if more => {
+                    let permit = permit.expect("semaphore is never closed");
+                    let (idx, path, opts, tx) = (next, paths[next].clone(), Arc::clone(&opts), tx.clone());
+                    tokio::task::spawn_blocking(move || {
+                        let _permit = permit;
+                        let _ = tx.send(Progress::Started(idx));
+                        let _ = tx.send(Progress::Done(idx, hash_file(&path, &opts)));
+                    });
+                    next += 1;
+                    in_flight += 1;
+                }
+                Some(progress) = rx.recv() => match progress {
+                    Progress::Started(idx) => events.file_started(&paths[idx]),
+                    Progress::Done(idx, result) => {
+                        report(&paths[idx], &result, events);
+                        results[idx] = Some(result);
+                        in_flight -= 1;
+                    }
+                },
+            }
+        }
+    });
+
+    results
+}
@@ -0,0 +1,81 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! `--background`: the lowest CPU priority and idle I/O scheduling, as `nice -n 19 ionice -c 3`
+//! would give, so a scheduled scrub only gets the disk and CPU time nothing else wants.
+//!
+//! On Linux both are per thread, and inherited by the threads a thread starts, so they're
+//! set on the main thread before any worker exists. macOS throttles the process's disk I/O
+//! instead, and Windows has a background mode that lowers CPU, I/O, and memory priority at
+//! once.
+
+use std::io;
+
+/// Lower this process's priority, returning each priority that couldn't be lowered, with why.
+pub fn lower() -> Vec<(&'static str, io::Error)> {
+    let mut errors = Vec::new();
+    #[cfg(unix)]
+    {
+        // PRIO_PROCESS with 0 is the calling thread on Linux, the whole process elsewhere.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+            errors.push(("CPU priority", io::Error::last_os_error()));
+        }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+            errors.push(("I/O priority", io::Error::last_os_error()));
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        const IOPOL_TYPE_DISK: libc::c_int = 0;
+        const IOPOL_SCOPE_PROCESS: libc::c_int = 0;
+        const IOPOL_THROTTLE: libc::c_int = 3;
+        extern "C" {
+            fn setiopolicy_np(
+                iotype: libc::c_int,
+                scope: libc::c_int,
+                policy: libc::c_int,
+            ) -> libc::c_int;
+        }
+        if unsafe { setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_PROCESS, IOPOL_THROTTLE) } != 0 {
+            errors.push(("I/O priority", io::Error::last_os_error()));
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::ffi::c_void;
+        const PROCESS_MODE_BACKGROUND_BEGIN: u32 = 0x0010_0000;
+        #[link(name = "kernel32")]
+        extern "system" {
+            fn GetCurrentProcess() -> *mut c_void;
+            fn SetPriorityClass(process: *mut c_void, priority_class: u32) -> i32;
+        }
+        if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+            errors.push(("background mode", io::Error::last_os_error()));
+        }
+    }
+    errors
+}
@@ -0,0 +1,134 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Reflink clones: files that share all their extents, as made by `cp --reflink`, APFS
+//! `clonefile`, or btrfs/XFS snapshots. Their contents are identical by construction, so
+//! one can be hashed for the whole group.
+//!
+//! Extents come from FIEMAP on Linux and `F_LOG2PHYS_EXT` on macOS. Elsewhere, and on
+//! filesystems that don't report physical locations, no clones are found.
+
+use crate::source::native_path;
+use rayon::prelude::*;
+use std::{collections::HashMap, path::Path};
+
+/// A contiguous run of a file: `length` bytes at `logical` in the file, stored at
+/// `physical` on the device.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+}
+
+/// Group `paths` into sets of reflink clones. Each group lists indices into `paths` in
+/// order, with at least two members; files not cloned from any other aren't listed.
+pub fn clone_groups<P: AsRef<Path> + Sync>(paths: &[P]) -> Vec<Vec<usize>> {
+    let maps: Vec<Option<(u64, Vec<Extent>)>> =
+        paths.par_iter().map(|p| extent_map(p.as_ref())).collect();
+
+    let mut groups: HashMap<&(u64, Vec<Extent>), Vec<usize>> = HashMap::new();
+    for (idx, map) in maps.iter().enumerate() {
+        if let Some(map) = map {
+            groups.entry(map).or_default().push(idx);
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    groups.sort_unstable();
+    groups
+}
+
+/// The size and extents of `path`, if it's a candidate for being a clone: non-empty, with
+/// every byte at a known physical location.
+fn extent_map(path: &Path) -> Option<(u64, Vec<Extent>)> {
+    let file = std::fs::File::open(native_path(path)).ok()?;
+    let size = file.metadata().ok()?.len();
+    if size == 0 {
+        return None;
+    }
+    Some((size, extents(&file, size)?))
+}
+
+#[cfg(target_os = "linux")]
+fn extents(file: &std::fs::File, _size: u64) -> Option<Vec<Extent>> {
+    use crate::walk::fiemap::*;
+
+    // Inline, compressed, or not-yet-allocated data has no location of its own to compare.
+    const NO_LOCATION: u32 =
+        EXTENT_UNKNOWN | EXTENT_DELALLOC | EXTENT_ENCODED | EXTENT_DATA_INLINE | EXTENT_DATA_TAIL;
+
+    let mut result = Vec::new();
+    let mut shared = false;
+    let mut start = 0;
+    loop {
+        let batch = extents::<64>(file, start).ok()?;
+        let Some(last) = batch.last() else { break };
+        start = last.fe_logical + last.fe_length;
+        let done = last.fe_flags & EXTENT_LAST != 0;
+        for e in batch {
+            if e.fe_flags & NO_LOCATION != 0 {
+                return None;
+            }
+            shared |= e.fe_flags & EXTENT_SHARED != 0;
+            result.push(Extent {
+                logical: e.fe_logical,
+                physical: e.fe_physical,
+                length: e.fe_length,
+            });
+        }
+        if done {
+            break;
+        }
+    }
+    // A file none of whose extents are shared can't be anyone's clone.
+    shared.then_some(result)
+}
+
+#[cfg(target_os = "macos")]
+fn extents(file: &std::fs::File, size: u64) -> Option<Vec<Extent>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut result = Vec::new();
+    let mut offset = 0u64;
+    while offset < size {
+        let mut l2p = libc::log2phys {
+            l2p_flags: 0,
+            l2p_contigbytes: (size - offset) as libc::off_t,
+            l2p_devoffset: offset as libc::off_t,
+        };
+        let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_LOG2PHYS_EXT, &mut l2p) };
+        let length = l2p.l2p_contigbytes as u64;
+        if ret == -1 || length == 0 {
+            return None;
+        }
+        result.push(Extent {
+            logical: offset,
+            physical: l2p.l2p_devoffset as u64,
+            length,
+        });
+        offset += length;
+    }
+    Some(result)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn extents(_file: &std::fs::File, _size: u64) -> Option<Vec<Extent>> {
+    None
+}
@@ -0,0 +1,159 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Defaults loaded from TOML files, so every script that runs gustasum uses the same
+//! parameters. Keys are the command-line option names without the leading dashes:
+//!
+//! ```toml
+//! algorithm = "sha256"
+//! partial-bytes = 256
+//! exclude = ["*.tmp", ".git"]
+//! threads = 8
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Option values from one or more config files. Values are kept as strings, the way they
+/// would have been typed on the command line; arrays hold one string per element.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    values: BTreeMap<String, Vec<String>>,
+}
+
+impl Config {
+    /// The files [`Config::load`] reads, lowest precedence first: the user's
+    /// `$XDG_CONFIG_HOME/gustasum/config.toml` (default `~/.config/...`), then
+    /// `.gustasum.toml` in the current directory.
+    pub fn default_paths() -> Vec<PathBuf> {
+        let user_dir = env::var_os("XDG_CONFIG_HOME")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+        user_dir
+            .map(|d| d.join("gustasum").join("config.toml"))
+            .into_iter()
+            .chain([PathBuf::from(".gustasum.toml")])
+            .collect()
+    }
+
+    /// Read the default files; see [`Config::default_paths`].
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::load_from(&Config::default_paths())
+    }
+
+    /// Read `paths` in order, later files overriding earlier ones key by key. Missing files
+    /// are skipped.
+    pub fn load_from(paths: &[PathBuf]) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        for path in paths {
+            let contents = match fs::read_to_string(path) {
+                Ok(c) => c,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(ConfigError::Read(path.clone(), e)),
+            };
+            config.merge(Config::parse(&contents, path)?);
+        }
+        Ok(config)
+    }
+
+    /// Parse one file's contents; `origin` is only used in error messages.
+    pub fn parse(contents: &str, origin: &Path) -> Result<Config, ConfigError> {
+        let table: toml::Table = contents.parse().map_err(|e: toml::de::Error| {
+            ConfigError::Parse(origin.to_path_buf(), e.to_string())
+        })?;
+
+        let mut values = BTreeMap::new();
+        for (key, value) in table {
+            let strings = match value {
+                toml::Value::Array(items) => items
+                    .into_iter()
+                    .map(|v| scalar(&key, v, origin))
+                    .collect::<Result<Vec<_>, _>>()?,
+                v => vec![scalar(&key, v, origin)?],
+            };
+            values.insert(key, strings);
+        }
+        Ok(Config { values })
+    }
+
+    /// Take every key set in `other`, replacing ours.
+    pub fn merge(&mut self, other: Config) {
+        self.values.extend(other.values);
+    }
+
+    /// The value of `key`; the last element if it's an array.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key)?.last().map(String::as_str)
+    }
+
+    /// All values of `key`.
+    pub fn get_all(&self, key: &str) -> Option<&[String]> {
+        self.values.get(key).map(Vec::as_slice)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(String::as_str)
+    }
+}
+
+fn scalar(key: &str, value: toml::Value, origin: &Path) -> Result<String, ConfigError> {
+    match value {
+        toml::Value::String(s) => Ok(s),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(ConfigError::Parse(
+            origin.to_path_buf(),
+            format!(
+                "'{}' must be a string, number, boolean, or array of those",
+                key
+            ),
+        )),
+    }
+}
+
+/// A config file exists but couldn't be used.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read '{}': {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config '{}': {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read(_, e) => Some(e),
+            ConfigError::Parse(..) => None,
+        }
+    }
+}
@@ -0,0 +1,411 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Failed reads and the devices they happened on, for the report at the end of a run: a
+//! pile of read errors on one disk is the sign it's time to replace it.
+//!
+//! A file's device is its `st_dev`, named from `/proc/partitions` on Linux (so `8:17` is
+//! `/dev/sdb1`), and given as `MAJOR:MINOR` or a plain number where it can't be named.
+//!
+//! With `--smart`, each named device's disk is asked for its SMART sector counts through
+//! `smartctl`, so the report can say whether the drive itself knows it's failing.
+//!
+//! The same names head the `--plan` report at the start of a run, of the filesystems the
+//! files to be read are on and what kind of storage each is.
+
+use gustasum::HashError;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A read that failed, or stalled, partway through a file.
+pub struct ReadFailure {
+    pub path: String,
+    pub offset: u64,
+    /// The whole error, region and offset included.
+    pub error: String,
+    /// The device the file is on, if it could be told.
+    pub device: Option<String>,
+}
+
+impl ReadFailure {
+    /// The failed read behind `e`, the error hashing `path` (read from `read_from`), if it
+    /// happened at a known offset rather than in stat'ing or opening the file.
+    pub fn from_error(path: &str, read_from: &Path, e: &HashError) -> Option<ReadFailure> {
+        Some(ReadFailure {
+            path: path.to_string(),
+            offset: e.offset()?,
+            error: e.to_string(),
+            device: device_of(read_from),
+        })
+    }
+}
+
+/// The device holding `path`. `None` where there are no device numbers (Windows), or for a
+/// path this machine can't stat, like an `ssh://` one.
+pub fn device_of(path: &Path) -> Option<String> {
+    dev_of(path).map(|dev| name_of(dev, None))
+}
+
+/// The `st_dev` of `path`.
+#[cfg(unix)]
+fn dev_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(std::fs::metadata(path).ok()?.dev())
+}
+
+#[cfg(not(unix))]
+fn dev_of(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// `dev` as `MAJOR:MINOR`, in glibc's encoding of dev_t, which the kernel's 32-bit one is a
+/// subset of.
+#[cfg(target_os = "linux")]
+fn major_minor(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+    (major, minor)
+}
+
+/// The name of device `dev`: its block device, or else what `mounted` (the filesystem on
+/// it) says was mounted, so Btrfs and NFS have names too.
+fn name_of(dev: u64, mounted: Option<&str>) -> String {
+    #[cfg(target_os = "linux")]
+    {
+        let (major, minor) = major_minor(dev);
+        match (partitions().get(&(major, minor)), mounted) {
+            (Some(name), _) => format!("/dev/{}", name),
+            (None, Some(mounted)) => mounted.to_string(),
+            (None, None) => format!("{}:{}", major, minor),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    mounted.map_or_else(|| dev.to_string(), str::to_string)
+}
+
+/// Block device names by major and minor number, read from `/proc/partitions` once.
+#[cfg(target_os = "linux")]
+fn partitions() -> &'static std::collections::HashMap<(u64, u64), String> {
+    static PARTITIONS: std::sync::OnceLock<std::collections::HashMap<(u64, u64), String>> =
+        std::sync::OnceLock::new();
+    PARTITIONS.get_or_init(|| {
+        // "major minor  #blocks  name", after a header line.
+        let table = std::fs::read_to_string("/proc/partitions").unwrap_or_default();
+        table
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let major = fields.next()?.parse().ok()?;
+                let minor = fields.next()?.parse().ok()?;
+                let name = fields.nth(1)?;
+                Some(((major, minor), name.to_string()))
+            })
+            .collect()
+    })
+}
+
+/// Filesystem types whose reads go over the network.
+const NETWORK_FILESYSTEMS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "9p",
+    "afs",
+    "ceph",
+    "glusterfs",
+    "fuse.glusterfs",
+    "fuse.sshfs",
+    "fuse.rclone",
+    "fuse.s3fs",
+    "davfs",
+];
+
+/// The kind of storage a filesystem is on, as far as it can be told.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    Ssd,
+    /// Rotational: reading many files at once spends the time seeking.
+    Hdd,
+    /// Each read waits on a server.
+    Network,
+    Unknown,
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Storage::Ssd => "SSD",
+            Storage::Hdd => "HDD",
+            Storage::Network => "network",
+            Storage::Unknown => "unknown storage",
+        })
+    }
+}
+
+/// A filesystem the files of a run are on, with how many of them and how much.
+pub struct Planned {
+    /// Named as [`device_of`] does, or `None` for files it can't tell the device of.
+    pub device: Option<String>,
+    /// Its mount point and filesystem type, where the mount table has it.
+    pub mount: Option<(PathBuf, String)>,
+    pub storage: Storage,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// The filesystems that `files` (with their sizes) are on, the most bytes first.
+pub fn plan<'a>(files: impl IntoIterator<Item = (&'a Path, u64)>) -> Vec<Planned> {
+    let mut by_dev: HashMap<Option<u64>, (usize, u64)> = HashMap::new();
+    for (path, len) in files {
+        let tally = by_dev.entry(dev_of(path)).or_default();
+        tally.0 += 1;
+        tally.1 += len;
+    }
+    let mut planned: Vec<Planned> = by_dev
+        .into_iter()
+        .map(|(dev, (files, bytes))| {
+            let mount = dev.and_then(mounted);
+            let device =
+                dev.map(|dev| name_of(dev, mount.as_ref().map(|(_, _, from)| from.as_str())));
+            let storage = match (&mount, &device) {
+                (Some((_, fstype, _)), _) if NETWORK_FILESYSTEMS.contains(&fstype.as_str()) => {
+                    Storage::Network
+                }
+                (_, Some(device)) => match rotational(device) {
+                    Some(true) => Storage::Hdd,
+                    Some(false) => Storage::Ssd,
+                    None => Storage::Unknown,
+                },
+                _ => Storage::Unknown,
+            };
+            Planned {
+                device,
+                mount: mount.map(|(point, fstype, _)| (point, fstype)),
+                storage,
+                files,
+                bytes,
+            }
+        })
+        .collect();
+    planned.sort_by_key(|p| std::cmp::Reverse(p.bytes));
+    planned
+}
+
+/// The filesystem mounted from device `dev`: its mount point, its type, and what was mounted.
+#[cfg(target_os = "linux")]
+fn mounted(dev: u64) -> Option<(PathBuf, String, String)> {
+    static MOUNTS: std::sync::OnceLock<Vec<gustasum::snapshot::Mount>> = std::sync::OnceLock::new();
+    let (major, minor) = major_minor(dev);
+    let number = format!("{}:{}", major, minor);
+    let mount = MOUNTS
+        .get_or_init(gustasum::snapshot::mounts)
+        .iter()
+        .find(|m| m.number == number)?;
+    Some((
+        mount.point.clone(),
+        mount.fstype.clone(),
+        mount.device.clone(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mounted(_dev: u64) -> Option<(PathBuf, String, String)> {
+    None
+}
+
+/// Whether the disk `device` is on spins, as its `/sys/class/block` queue says.
+fn rotational(device: &str) -> Option<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        let disk = whole_disk(device);
+        let name = disk.strip_prefix("/dev/")?;
+        let queue = Path::new("/sys/class/block").join(name).join("queue");
+        let flag = std::fs::read_to_string(queue.join("rotational")).ok()?;
+        Some(flag.trim() == "1")
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = device;
+        None
+    }
+}
+
+/// What a disk's SMART data says about its sectors, as far as `smartctl` reports it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Smart {
+    /// The overall self-assessment, `PASSED` or `FAILED!`.
+    pub health: Option<String>,
+    /// Sectors waiting to be remapped once written: the ones reads failed on.
+    pub pending: Option<u64>,
+    pub reallocated: Option<u64>,
+    /// Sectors an offline scan couldn't read.
+    pub uncorrectable: Option<u64>,
+    /// NVMe's media and data integrity errors, the closest it has to bad sectors.
+    pub media_errors: Option<u64>,
+}
+
+impl Smart {
+    /// "SMART health PASSED, 12 pending, 3 reallocated, 0 uncorrectable sectors", with what
+    /// was reported.
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(ref health) = self.health {
+            parts.push(format!("SMART health {}", health));
+        }
+        let sectors: Vec<String> = [
+            (self.pending, "pending"),
+            (self.reallocated, "reallocated"),
+            (self.uncorrectable, "uncorrectable"),
+        ]
+        .iter()
+        .filter_map(|(count, what)| count.map(|count| format!("{} {}", count, what)))
+        .collect();
+        if !sectors.is_empty() {
+            parts.push(format!("{} sectors", sectors.join(", ")));
+        }
+        if let Some(errors) = self.media_errors {
+            parts.push(format!("{} media errors", errors));
+        }
+        if parts.is_empty() {
+            "no SMART attributes reported".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+/// The disk `device` is a partition of, or `device` itself: `/dev/sdb1` is on `/dev/sdb`,
+/// and `/dev/nvme0n1p2` on `/dev/nvme0n1`.
+fn whole_disk(device: &str) -> String {
+    #[cfg(target_os = "linux")]
+    if let Some(name) = device.strip_prefix("/dev/") {
+        let class = Path::new("/sys/class/block").join(name);
+        if class.join("partition").exists() {
+            let parent = class
+                .canonicalize()
+                .ok()
+                .and_then(|p| Some(p.parent()?.file_name()?.to_string_lossy().into_owned()));
+            if let Some(parent) = parent {
+                return format!("/dev/{}", parent);
+            }
+        }
+    }
+    device.to_string()
+}
+
+/// Ask `smartctl` for the health and sector counts of `disk`. It usually needs root.
+pub fn smart(disk: &str) -> io::Result<Smart> {
+    let output = Command::new("smartctl").args(["-H", "-A", disk]).output()?;
+    // Its exit status is a bit mask: bits 0 and 1 mean nothing could be read; the others
+    // are about what was, like a failing disk.
+    if output.status.code().is_none_or(|code| code & 0b11 != 0) {
+        let text = String::from_utf8_lossy(&output.stdout);
+        let reason = text
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or("smartctl failed");
+        return Err(io::Error::other(reason.trim().to_string()));
+    }
+    Ok(parse_smart(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Read `smartctl -H -A` output: the health line, then an ATA attribute table (whose raw
+/// value is the tenth column) or NVMe's `Name: value` lines.
+fn parse_smart(text: &str) -> Smart {
+    let mut smart = Smart::default();
+    let number = |value: &str| {
+        value
+            .split_whitespace()
+            .next()
+            .and_then(|v| v.replace(',', "").parse().ok())
+    };
+    for line in text.lines() {
+        if let Some((_, health)) = line.split_once("self-assessment test result:") {
+            smart.health = Some(health.trim().to_string());
+        } else if let Some((_, errors)) = line.split_once("Media and Data Integrity Errors:") {
+            smart.media_errors = number(errors);
+        } else {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let raw = fields.get(9).and_then(|v| number(v));
+            match fields.first() {
+                Some(&"5") => smart.reallocated = raw,
+                Some(&"197") => smart.pending = raw,
+                Some(&"198") => smart.uncorrectable = raw,
+                _ => {}
+            }
+        }
+    }
+    smart
+}
+
+/// Every failed read, then how many each device had: the "suspect devices". `None` if
+/// there were none. With `query_smart`, each device's line gets what its disk's SMART data
+/// says.
+pub fn report(failures: &[ReadFailure], query_smart: bool) -> Option<String> {
+    if failures.is_empty() {
+        return None;
+    }
+    let unknown = "unknown device";
+    let mut report = format!("\nRead errors ({}):", failures.len());
+    let mut by_device: BTreeMap<&str, (usize, BTreeSet<&str>)> = BTreeMap::new();
+    for failure in failures {
+        let device = failure.device.as_deref().unwrap_or(unknown);
+        report.push_str(&format!(
+            "\n  {}: {} [{}]",
+            failure.path, failure.error, device
+        ));
+        let (reads, files) = by_device.entry(device).or_default();
+        *reads += 1;
+        files.insert(&failure.path);
+    }
+    report.push_str("\nSuspect devices:");
+    // Partitions of one disk share its SMART data; ask once.
+    let mut queried: BTreeMap<String, String> = BTreeMap::new();
+    for (device, (reads, files)) in &by_device {
+        report.push_str(&format!(
+            "\n  {}: {} failed reads in {} files",
+            device,
+            reads,
+            files.len()
+        ));
+        if query_smart && device.starts_with("/dev/") {
+            let disk = whole_disk(device);
+            let status = queried.entry(disk.clone()).or_insert_with(|| {
+                smart(&disk).map_or_else(
+                    |e| format!("SMART data unavailable ({})", e),
+                    |smart| smart.describe(),
+                )
+            });
+            if disk == *device {
+                report.push_str(&format!("; {}", status));
+            } else {
+                report.push_str(&format!("; {}: {}", disk, status));
+            }
+        }
+    }
+    Some(report)
+}
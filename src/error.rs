@@ -0,0 +1,235 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Why hashing a file failed.
+
+use std::{fmt, io, str::FromStr, time::Duration};
+
+/// Which of the three sampled regions an I/O error happened in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    First,
+    Middle,
+    Last,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Region::First => "first bytes",
+            Region::Middle => "middle bytes",
+            Region::Last => "last bytes",
+        })
+    }
+}
+
+impl Region {
+    /// The region's name in manifest fields: `first`, `middle`, or `last`.
+    pub fn key(self) -> &'static str {
+        match self {
+            Region::First => "first",
+            Region::Middle => "middle",
+            Region::Last => "last",
+        }
+    }
+
+    /// The region [`Region::key`] names.
+    pub fn from_key(key: &str) -> Option<Region> {
+        [Region::First, Region::Middle, Region::Last]
+            .into_iter()
+            .find(|r| r.key() == key)
+    }
+}
+
+/// What a failure to read a file comes down to, for counting failures by cause and for
+/// skipping only some of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// Not allowed to stat, open, or read it.
+    Permission,
+    /// Not there (anymore).
+    Missing,
+    /// Anything else: bad sectors, a device gone, a timeout, a network filesystem giving up.
+    Io,
+}
+
+impl ErrorClass {
+    pub fn of(kind: io::ErrorKind) -> ErrorClass {
+        match kind {
+            io::ErrorKind::PermissionDenied => ErrorClass::Permission,
+            io::ErrorKind::NotFound => ErrorClass::Missing,
+            _ => ErrorClass::Io,
+        }
+    }
+
+    /// The name `--skip-errors` takes it by.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorClass::Permission => "permission",
+            ErrorClass::Missing => "missing",
+            ErrorClass::Io => "io",
+        }
+    }
+}
+
+impl FromStr for ErrorClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "permission" => Ok(ErrorClass::Permission),
+            "missing" => Ok(ErrorClass::Missing),
+            "io" => Ok(ErrorClass::Io),
+            _ => Err(format!(
+                "unknown kind of error '{}' (expected permission, missing, or io)",
+                s
+            )),
+        }
+    }
+}
+
+/// A failure to hash one file.
+#[derive(Debug)]
+pub enum HashError {
+    Metadata(io::Error),
+    Open(io::Error),
+    Read {
+        region: Region,
+        offset: u64,
+        source: io::Error,
+    },
+    /// Hashing took longer than `--file-timeout`; `offset` is where the read was stuck.
+    Timeout {
+        after: Duration,
+        offset: u64,
+    },
+}
+
+impl HashError {
+    /// The `io::ErrorKind` behind this failure.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            HashError::Metadata(e) | HashError::Open(e) => e.kind(),
+            HashError::Read { source, .. } => source.kind(),
+            HashError::Timeout { .. } => io::ErrorKind::TimedOut,
+        }
+    }
+
+    /// The [`ErrorClass`] of this failure.
+    pub fn class(&self) -> ErrorClass {
+        ErrorClass::of(self.kind())
+    }
+
+    /// Byte offset in the file where the failure happened, if it happened while reading.
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            HashError::Read { offset, .. } | HashError::Timeout { offset, .. } => Some(*offset),
+            HashError::Metadata(_) | HashError::Open(_) => None,
+        }
+    }
+
+    /// Check if the failure is possibly transient (e.g., read error from failing HDD), so
+    /// another attempt might succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            HashError::Read { source, .. } => {
+                #[cfg(unix)]
+                if source.raw_os_error() == Some(libc::EIO) {
+                    return true;
+                }
+                matches!(
+                    source.kind(),
+                    io::ErrorKind::Interrupted
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::WouldBlock
+                )
+            }
+            HashError::Metadata(e) | HashError::Open(e) => e.kind() == io::ErrorKind::Interrupted,
+            // The same stalled read would just hang again.
+            HashError::Timeout { .. } => false,
+        }
+    }
+
+    /// Check if a network filesystem dropped or timed out on the file (NFS `ESTALE`,
+    /// `ETIMEDOUT`, or `EAGAIN`, or their SMB counterparts), at any step. The open handle is
+    /// useless after that, but stat'ing and opening the path again usually works.
+    pub fn is_network(&self) -> bool {
+        match self {
+            HashError::Metadata(e) | HashError::Open(e) => is_network_error(e),
+            HashError::Read { source, .. } => is_network_error(source),
+            HashError::Timeout { .. } => false,
+        }
+    }
+
+    /// Check if another process has the file open without sharing, or part of it locked
+    /// (Windows `ERROR_SHARING_VIOLATION` and `ERROR_LOCK_VIOLATION`). Never true elsewhere.
+    pub fn is_locked(&self) -> bool {
+        #[cfg(windows)]
+        if let HashError::Metadata(e) | HashError::Open(e) | HashError::Read { source: e, .. } =
+            self
+        {
+            return matches!(e.raw_os_error(), Some(32 | 33));
+        }
+        false
+    }
+}
+
+fn is_network_error(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    const CODES: [i32; 3] = [libc::ESTALE, libc::ETIMEDOUT, libc::EAGAIN];
+    // ERROR_UNEXP_NET_ERR, ERROR_NETNAME_DELETED, ERROR_SEM_TIMEOUT
+    #[cfg(windows)]
+    const CODES: [i32; 3] = [59, 64, 121];
+    e.raw_os_error().is_some_and(|code| CODES.contains(&code))
+}
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashError::Metadata(e) => write!(f, "metadata error: {}", e),
+            HashError::Open(e) => write!(f, "file open error: {}", e),
+            HashError::Read {
+                region,
+                offset,
+                source,
+            } => write!(
+                f,
+                "read error ({}) at offset {}: {}",
+                region, offset, source
+            ),
+            HashError::Timeout { after, offset } => write!(
+                f,
+                "timed out after {}s (read stalled at offset {})",
+                after.as_secs(),
+                offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HashError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HashError::Metadata(e) | HashError::Open(e) => Some(e),
+            HashError::Read { source, .. } => Some(source),
+            HashError::Timeout { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,58 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Hooks for observing a run as it happens, so frontends (GUIs, TUIs, the CLI's own
+//! progress bar) can render progress without scraping stderr.
+
+use crate::error::HashError;
+use crate::hasher::FileHash;
+use std::path::Path;
+
+/// Receives events from hashing and verification. Every method has a default, so
+/// implementors only override what they care about: the retry notices print to stderr, the
+/// rest do nothing.
+///
+/// Events arrive from worker threads concurrently, hence the `Sync` bound.
+pub trait EventSink: Sync {
+    /// A worker is about to read `path`.
+    fn file_started(&self, _path: &Path) {}
+    /// `path` was hashed successfully.
+    fn file_hashed(&self, _path: &Path, _hash: &FileHash) {}
+    /// `path` couldn't be hashed (after any retries).
+    fn file_failed(&self, _path: &Path, _error: &HashError) {}
+    /// `bytes` of content were read from `path`.
+    fn bytes_read(&self, _path: &Path, _bytes: u64) {}
+    /// `path` failed with `error`, which looks transient, and will be tried again.
+    fn file_retrying(&self, path: &Path, error: &HashError) {
+        eprintln!("Retrying file '{}': {}", path.display(), error);
+    }
+    /// `path` changed while being read and will be read again.
+    fn file_rereading(&self, path: &Path) {
+        eprintln!(
+            "Re-reading file '{}': changed while being read",
+            path.display()
+        );
+    }
+}
+
+/// An [`EventSink`] that ignores everything.
+pub struct NoEvents;
+
+impl EventSink for NoEvents {}
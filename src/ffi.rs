@@ -0,0 +1,347 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! C ABI (the `ffi` feature) for callers that can't link Rust directly. Declarations are in
+//! `include/gustasum.h`; the sampling and hashing are the same code the CLI runs, so
+//! manifests produced through it are byte-identical.
+
+use crate::algorithm;
+use crate::hasher::{hash_file, HashOptions};
+use crate::manifest::Manifest;
+use crate::verify::{verify_manifest, CheckOutcome, VerifyOptions};
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fs,
+    path::PathBuf,
+    ptr,
+    time::Duration,
+};
+
+pub const GUSTASUM_OK: c_int = 0;
+/// `gustasum_verify` only: at least one check did not pass.
+pub const GUSTASUM_FAILED: c_int = 1;
+pub const GUSTASUM_ERR_ARGUMENT: c_int = -1;
+pub const GUSTASUM_ERR_IO: c_int = -2;
+pub const GUSTASUM_ERR_UNSTABLE: c_int = -3;
+
+pub const GUSTASUM_CHECK_OK: c_int = 0;
+pub const GUSTASUM_CHECK_MISMATCH: c_int = 1;
+pub const GUSTASUM_CHECK_UNSTABLE: c_int = 2;
+pub const GUSTASUM_CHECK_ERROR: c_int = 3;
+pub const GUSTASUM_CHECK_MALFORMED: c_int = 4;
+
+/// Mirror of [`HashOptions`] with C-friendly types. Zero means "off" for
+/// `parallel_read_threshold` and `file_timeout_ms`; a NULL `algorithm` means SHA-256 when
+/// hashing and "whatever the manifest says" when verifying.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct GustasumOptions {
+    pub algorithm: *const c_char,
+    pub partial_bytes: usize,
+    pub include_modtime: bool,
+    pub fat_compat: bool,
+    pub parallel_read_threshold: u64,
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+    pub file_timeout_ms: u64,
+    pub reread_unstable: u32,
+    pub retry_network: bool,
+}
+
+impl From<&GustasumOptions> for HashOptions {
+    /// Leaves `algorithm` at the default; see [`algorithm_arg`].
+    fn from(o: &GustasumOptions) -> Self {
+        HashOptions {
+            partial_bytes: o.partial_bytes,
+            include_modtime: o.include_modtime,
+            fat_compat: o.fat_compat,
+            parallel_read_threshold: (o.parallel_read_threshold > 0)
+                .then_some(o.parallel_read_threshold),
+            retries: o.retries as usize,
+            retry_delay: Duration::from_millis(o.retry_delay_ms),
+            file_timeout: (o.file_timeout_ms > 0).then(|| Duration::from_millis(o.file_timeout_ms)),
+            reread_unstable: o.reread_unstable as usize,
+            retry_network: o.retry_network,
+            ..HashOptions::default()
+        }
+    }
+}
+
+/// Counts from a `gustasum_verify` run.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GustasumVerifySummary {
+    pub total: usize,
+    pub ok: usize,
+    pub mismatch: usize,
+    pub unstable: usize,
+    pub error: usize,
+    pub malformed: usize,
+}
+
+/// Called once per manifest line, in manifest order. `error` is NULL unless `status` is
+/// `GUSTASUM_CHECK_ERROR`. Both strings are only valid for the duration of the call.
+pub type GustasumCheckCallback = Option<
+    unsafe extern "C" fn(
+        user_data: *mut c_void,
+        path: *const c_char,
+        status: c_int,
+        error: *const c_char,
+    ),
+>;
+
+/// Fill `out` with the defaults the CLI uses.
+///
+/// # Safety
+/// `out` must be NULL or point to writable memory for a `gustasum_options`.
+#[no_mangle]
+pub unsafe extern "C" fn gustasum_options_default(out: *mut GustasumOptions) {
+    if out.is_null() {
+        return;
+    }
+    let d = HashOptions::default();
+    *out = GustasumOptions {
+        algorithm: ptr::null(),
+        partial_bytes: d.partial_bytes,
+        include_modtime: d.include_modtime,
+        fat_compat: d.fat_compat,
+        parallel_read_threshold: d.parallel_read_threshold.unwrap_or(0),
+        retries: d.retries as u32,
+        retry_delay_ms: d.retry_delay.as_millis() as u64,
+        file_timeout_ms: d.file_timeout.map_or(0, |t| t.as_millis() as u64),
+        reread_unstable: d.reread_unstable as u32,
+        retry_network: d.retry_network,
+    };
+}
+
+/// Hash the file at `path` and write its 64 hex digits plus a NUL to `out_hex`.
+///
+/// Returns `GUSTASUM_OK`, `GUSTASUM_ERR_IO` if the file couldn't be read,
+/// `GUSTASUM_ERR_UNSTABLE` if it changed while being read (`out_hex` is still written), or
+/// `GUSTASUM_ERR_ARGUMENT` (including an unknown algorithm). On failure a message is written
+/// to `err` if it isn't NULL. Digests are 64 hex digits for SHA-256 and 128 for SHA-512.
+///
+/// # Safety
+/// `path` must be a NUL-terminated string. `opts` must be NULL (for the defaults) or point to
+/// a valid `gustasum_options`. `out_hex` must have room for the digest plus a NUL
+/// (`GUSTASUM_HEX_LEN` covers every built-in algorithm). `err` must be NULL or
+/// have room for `err_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gustasum_hash_file(
+    path: *const c_char,
+    opts: *const GustasumOptions,
+    out_hex: *mut c_char,
+    err: *mut c_char,
+    err_len: usize,
+) -> c_int {
+    let Some(path) = path_arg(path) else {
+        write_message(err, err_len, "path is NULL or not a valid path");
+        return GUSTASUM_ERR_ARGUMENT;
+    };
+    if out_hex.is_null() {
+        write_message(err, err_len, "out_hex is NULL");
+        return GUSTASUM_ERR_ARGUMENT;
+    }
+
+    let mut hash_opts = options_arg(opts);
+    match algorithm_arg(opts) {
+        Ok(Some(name)) => match algorithm::lookup(&name) {
+            Ok(a) => hash_opts.algorithm = a,
+            Err(e) => {
+                write_message(err, err_len, &e.to_string());
+                return GUSTASUM_ERR_ARGUMENT;
+            }
+        },
+        Ok(None) => {}
+        Err(()) => {
+            write_message(err, err_len, "algorithm is not valid UTF-8");
+            return GUSTASUM_ERR_ARGUMENT;
+        }
+    }
+
+    match hash_file(&path, &hash_opts) {
+        Ok(h) => {
+            write_message(out_hex, h.hash.len() + 1, &h.hash);
+            if h.unstable {
+                write_message(err, err_len, "file changed while being read");
+                GUSTASUM_ERR_UNSTABLE
+            } else {
+                GUSTASUM_OK
+            }
+        }
+        Err(e) => {
+            write_message(err, err_len, &e.to_string());
+            GUSTASUM_ERR_IO
+        }
+    }
+}
+
+/// Check every entry of the manifest at `manifest_path`, calling `callback` (if not NULL)
+/// for each line and filling `summary` (if not NULL) with the counts.
+///
+/// `remap_old` and `remap_new` are both NULL, or both set to do what the CLI's
+/// `--remap` does. The manifest header picks the algorithm, and its other recorded settings
+/// replace `opts`'s; `opts->algorithm` must be NULL or agree. Returns `GUSTASUM_OK` if every
+/// check passed, `GUSTASUM_FAILED` if any didn't, `GUSTASUM_ERR_IO` if the manifest couldn't
+/// be read, or `GUSTASUM_ERR_ARGUMENT`, including for a manifest that can't be checked this
+/// way: a salted or anonymized one.
+///
+/// # Safety
+/// String arguments must be NULL or NUL-terminated. `opts` must be NULL or point to a valid
+/// `gustasum_options`, and `summary` must be NULL or point to a writable
+/// `gustasum_verify_summary`. `callback` is called from the calling thread.
+#[no_mangle]
+pub unsafe extern "C" fn gustasum_verify(
+    manifest_path: *const c_char,
+    opts: *const GustasumOptions,
+    remap_old: *const c_char,
+    remap_new: *const c_char,
+    summary: *mut GustasumVerifySummary,
+    callback: GustasumCheckCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let Some(manifest_path) = path_arg(manifest_path) else {
+        return GUSTASUM_ERR_ARGUMENT;
+    };
+    let remap = match (path_arg(remap_old), path_arg(remap_new)) {
+        (Some(old), Some(new)) => Some((old, new)),
+        (None, None) => None,
+        _ => return GUSTASUM_ERR_ARGUMENT,
+    };
+    let Ok(contents) = fs::read_to_string(&manifest_path) else {
+        return GUSTASUM_ERR_IO;
+    };
+
+    let manifest = Manifest::parse(&contents);
+    let Ok(asked) = algorithm_arg(opts) else {
+        return GUSTASUM_ERR_ARGUMENT;
+    };
+    let algorithm = match (asked, &manifest.algorithm) {
+        (Some(asked), Some(recorded)) if asked != *recorded => return GUSTASUM_ERR_ARGUMENT,
+        (Some(asked), None) => algorithm::lookup(&asked),
+        _ => manifest.algorithm(),
+    };
+    let Ok(algorithm) = algorithm else {
+        return GUSTASUM_ERR_ARGUMENT;
+    };
+
+    let opts = VerifyOptions {
+        hash: HashOptions {
+            algorithm,
+            ..options_arg(opts)
+        },
+        remap,
+    };
+    let Ok(results) = verify_manifest(&manifest, &opts) else {
+        return GUSTASUM_ERR_ARGUMENT;
+    };
+
+    let mut counts = GustasumVerifySummary {
+        total: results.len(),
+        ..Default::default()
+    };
+    for r in &results {
+        let (status, error) = match &r.outcome {
+            CheckOutcome::Ok => {
+                counts.ok += 1;
+                (GUSTASUM_CHECK_OK, None)
+            }
+            CheckOutcome::Mismatch => {
+                counts.mismatch += 1;
+                (GUSTASUM_CHECK_MISMATCH, None)
+            }
+            CheckOutcome::Unstable => {
+                counts.unstable += 1;
+                (GUSTASUM_CHECK_UNSTABLE, None)
+            }
+            CheckOutcome::Error(e) => {
+                counts.error += 1;
+                (GUSTASUM_CHECK_ERROR, Some(e.to_string()))
+            }
+            CheckOutcome::Malformed => {
+                counts.malformed += 1;
+                (GUSTASUM_CHECK_MALFORMED, None)
+            }
+        };
+        if let Some(cb) = callback {
+            let path = c_string(&r.path);
+            let error = error.map(|e| c_string(&e));
+            cb(
+                user_data,
+                path.as_ptr(),
+                status,
+                error.as_ref().map_or(ptr::null(), |e| e.as_ptr()),
+            );
+        }
+    }
+
+    if !summary.is_null() {
+        *summary = counts;
+    }
+    if counts.ok == counts.total {
+        GUSTASUM_OK
+    } else {
+        GUSTASUM_FAILED
+    }
+}
+
+unsafe fn options_arg(opts: *const GustasumOptions) -> HashOptions {
+    opts.as_ref().map(HashOptions::from).unwrap_or_default()
+}
+
+/// The algorithm name in `opts`, if one is set. `Err` if it isn't UTF-8.
+unsafe fn algorithm_arg(opts: *const GustasumOptions) -> Result<Option<String>, ()> {
+    match opts.as_ref() {
+        Some(o) if !o.algorithm.is_null() => CStr::from_ptr(o.algorithm)
+            .to_str()
+            .map(|s| Some(s.to_string()))
+            .map_err(|_| ()),
+        _ => Ok(None),
+    }
+}
+
+/// Borrow a C string as a path. On Unix any bytes are accepted, as in the CLI's arguments.
+unsafe fn path_arg(s: *const c_char) -> Option<PathBuf> {
+    if s.is_null() {
+        return None;
+    }
+    let bytes = CStr::from_ptr(s).to_bytes();
+    #[cfg(unix)]
+    return Some(PathBuf::from(
+        <std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes),
+    ));
+    #[cfg(not(unix))]
+    return std::str::from_utf8(bytes).ok().map(PathBuf::from);
+}
+
+/// Manifest paths come from text, so an interior NUL is the only thing to strip.
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+/// Copy `msg` into a caller-provided buffer of `len` bytes, truncating and NUL-terminating.
+unsafe fn write_message(buf: *mut c_char, len: usize, msg: &str) {
+    if buf.is_null() || len == 0 {
+        return;
+    }
+    let n = msg.len().min(len - 1);
+    ptr::copy_nonoverlapping(msg.as_ptr() as *const c_char, buf, n);
+    *buf.add(n) = 0;
+}
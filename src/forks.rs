@@ -0,0 +1,142 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! macOS resource forks and Finder metadata. A file's resource fork is hashed as
+//! `path/..namedfork/rsrc`, which macOS opens like any other file, and its Finder extended
+//! attributes as `path/..xattr/<name>`, which [`LocalFs`](crate::LocalFs) reads through
+//! `getxattr`. A copy off HFS+/APFS that drops either then fails verification.
+
+use std::{
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// The pseudo-directory under which a file's extended attributes are named.
+pub const XATTR_DIR: &str = "..xattr";
+
+/// Finder flags, type/creator codes, and the like.
+pub const FINDER_INFO: &str = "com.apple.FinderInfo";
+
+/// Where a file was downloaded from. Usually differs between machines, so it's opt-in.
+pub const QUARANTINE: &str = "com.apple.quarantine";
+
+/// The resource fork and Finder attributes `path` actually has, as entries to hash after it.
+/// Empty on other platforms.
+pub fn named_forks(path: &Path, include_quarantine: bool) -> io::Result<Vec<PathBuf>> {
+    #[cfg(target_os = "macos")]
+    return macos::named_forks(path, include_quarantine);
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = (path, include_quarantine);
+        Ok(Vec::new())
+    }
+}
+
+/// Split `file/..xattr/name` into the file and the attribute name.
+pub fn xattr_target(path: &Path) -> Option<(&Path, &OsStr)> {
+    let name = path.file_name()?;
+    let dir = path.parent()?;
+    (dir.file_name()? == XATTR_DIR).then_some((dir.parent()?, name))
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) use macos::read_xattr;
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use super::{FINDER_INFO, QUARANTINE, XATTR_DIR};
+    use std::{
+        ffi::{CString, OsStr},
+        fs, io,
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+    };
+
+    fn c_string(s: &OsStr) -> io::Result<CString> {
+        CString::new(s.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Size of attribute `name` of `path` (without following a final symlink), or `None` if
+    /// it has no such attribute.
+    fn xattr_len(path: &CString, name: &CString) -> io::Result<Option<usize>> {
+        let n = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        if n >= 0 {
+            return Ok(Some(n as usize));
+        }
+        match io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ENOATTR) => Ok(None),
+            e => Err(e),
+        }
+    }
+
+    pub fn read_xattr(path: &Path, name: &OsStr) -> io::Result<Vec<u8>> {
+        let (path, name) = (c_string(path.as_os_str())?, c_string(name)?);
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "no such extended attribute");
+        let len = xattr_len(&path, &name)?.ok_or_else(not_found)?;
+        let mut buf = vec![0u8; len];
+        let n = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(n as usize);
+        Ok(buf)
+    }
+
+    pub fn named_forks(path: &Path, include_quarantine: bool) -> io::Result<Vec<PathBuf>> {
+        let mut forks = Vec::new();
+
+        // Every file has a `..namedfork/rsrc`; only a non-empty one is worth an entry.
+        let rsrc = path.join("..namedfork/rsrc");
+        match fs::metadata(&rsrc) {
+            Ok(meta) if meta.len() > 0 => forks.push(rsrc),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let c_path = c_string(path.as_os_str())?;
+        let names = [Some(FINDER_INFO), include_quarantine.then_some(QUARANTINE)];
+        for name in names.into_iter().flatten() {
+            if xattr_len(&c_path, &c_string(OsStr::new(name))?)?.is_some() {
+                forks.push(path.join(XATTR_DIR).join(name));
+            }
+        }
+        Ok(forks)
+    }
+}
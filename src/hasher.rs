@@ -0,0 +1,1117 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Partial hashing of a single file: the first, middle, and last `partial_bytes` of its
+//! content, its size, and optionally its modtime, folded into one digest (SHA-256 by default).
+
+use crate::algorithm::{self, Algorithm};
+use crate::error::{HashError, Region};
+use crate::events::{EventSink, NoEvents};
+use crate::source::{LocalFs, Source, SourceFile, SourceMetadata};
+use rayon::prelude::*;
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+/// Parameters that determine how a file is sampled and how read failures are handled.
+///
+/// `algorithm`, `partial_bytes`, `include_modtime`, `fat_compat`, `salt`, `abbrev`, `sparse`,
+/// `include_extents`, and `full` change the digest, so a manifest must be checked with the
+/// same values it was generated with. The rest only affect where and how hard we try.
+#[derive(Clone, Debug)]
+pub struct HashOptions {
+    /// Where files are stat'ed and read from.
+    pub source: Arc<dyn Source>,
+    pub algorithm: Algorithm,
+    /// Number of bytes to read from the start, middle, and end.
+    pub partial_bytes: usize,
+    /// Fold the modtime (whole seconds) into the digest.
+    pub include_modtime: bool,
+    /// Round the modtime down to an even second first, as FAT32 and exFAT store it, so
+    /// copies on SD cards and USB sticks still match.
+    pub fat_compat: bool,
+    /// Files at least this large get their three regions read concurrently.
+    pub parallel_read_threshold: Option<u64>,
+    /// How many times a transient read failure is retried before giving up.
+    pub retries: usize,
+    /// Delay before the first retry; doubled for each subsequent attempt.
+    pub retry_delay: Duration,
+    /// Also retry stale handles and timeouts from network filesystems, at any step, by
+    /// stat'ing and opening the file again.
+    pub retry_network: bool,
+    /// Abandon a file whose hashing (including retries) takes longer than this.
+    pub file_timeout: Option<Duration>,
+    /// How many times to re-read a file that changed while it was being hashed.
+    pub reread_unstable: usize,
+    /// Mixed into every digest, so a manifest can't be matched against guessed contents by
+    /// anyone without it.
+    pub salt: Option<Salt>,
+    /// Keep only this many hex digits of each digest, for compact manifests.
+    pub abbrev: Option<usize>,
+    /// Sample a sparse file from its data, skipping holes, where the source can tell where
+    /// they are (see [`SourceFile::data_extents`]).
+    pub sparse: bool,
+    /// Fold the file's layout of data extents and holes into the digest, where the source
+    /// can tell what it is.
+    pub include_extents: bool,
+    /// Hash the whole content and nothing else, instead of sampling it: the digest is then
+    /// what `sha256sum` (or `sha512sum`, `sha1sum`, `md5sum`) prints for the file. There's
+    /// nothing else in it, so `include_modtime`, `salt`, `sparse`, and `include_extents` go
+    /// unused; [`HashOptions::validate`] refuses them.
+    pub full: bool,
+    /// Digests computed at once, however many threads are reading, when there are more
+    /// readers than cores.
+    pub cpu_gate: Option<CpuGate>,
+    /// Bytes of read buffers held at once, across every thread, for a memory budget.
+    pub memory_gate: Option<MemoryGate>,
+    /// Also digest what's read under this algorithm, into [`FileHash::also`], with the other
+    /// settings the same: to move a manifest to it without reading the files twice.
+    pub also: Option<Algorithm>,
+}
+
+/// A secret from `--salt-file`. Its bytes are never shown; [`Salt::id`] tells salts apart.
+#[derive(Clone)]
+pub struct Salt(Arc<[u8]>);
+
+impl Salt {
+    pub fn new(bytes: impl Into<Arc<[u8]>>) -> Salt {
+        Salt(bytes.into())
+    }
+
+    /// A short fingerprint of the salt, for recording which one a manifest was made with.
+    /// Reveals nothing usable about a salt with enough entropy.
+    pub fn id(&self) -> String {
+        let mut hasher = algorithm::SHA256.hasher();
+        hasher.update(b"gustasum salt id\0");
+        hasher.update(&self.0);
+        hasher.finish()[..16].to_string()
+    }
+}
+
+impl fmt::Debug for Salt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Salt({})", self.id())
+    }
+}
+
+impl HashOptions {
+    /// Check that the settings go together: `full` with any of the settings that would be
+    /// left out of its digest is an error naming the command-line options that set them.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.full {
+            return Ok(());
+        }
+        let unused: Vec<&str> = [
+            (self.include_modtime, "--include-modtime"),
+            (self.salt.is_some(), "--salt-file"),
+            (self.sparse, "--sparse"),
+            (self.include_extents, "--include-extents"),
+        ]
+        .into_iter()
+        .filter_map(|(set, option)| set.then_some(option))
+        .collect();
+        if unused.is_empty() {
+            return Ok(());
+        }
+        Err(format!(
+            "--full can't be combined with {}",
+            unused.join(", ")
+        ))
+    }
+}
+
+impl Default for HashOptions {
+    fn default() -> Self {
+        HashOptions {
+            source: Arc::new(LocalFs),
+            algorithm: Algorithm::default(),
+            partial_bytes: 100,
+            include_modtime: false,
+            fat_compat: false,
+            parallel_read_threshold: None,
+            retries: 2,
+            retry_delay: Duration::from_millis(100),
+            retry_network: true,
+            file_timeout: None,
+            reread_unstable: 0,
+            salt: None,
+            abbrev: None,
+            sparse: false,
+            include_extents: false,
+            full: false,
+            cpu_gate: None,
+            memory_gate: None,
+            also: None,
+        }
+    }
+}
+
+/// A cap on how many threads compute digests at once. With hundreds of threads waiting on a
+/// network filesystem, it keeps the hashing itself at one thread per core.
+#[derive(Clone, Debug)]
+pub struct CpuGate(Arc<(Mutex<usize>, Condvar)>);
+
+impl CpuGate {
+    pub fn new(slots: usize) -> CpuGate {
+        CpuGate(Arc::new((Mutex::new(slots.max(1)), Condvar::new())))
+    }
+
+    /// Run `f` once a slot is free. The slot is freed again even if `f` panics.
+    pub fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        let (free, freed) = &*self.0;
+        {
+            let mut free = freed
+                .wait_while(free.lock().unwrap(), |free| *free == 0)
+                .unwrap();
+            *free -= 1;
+        }
+        let _release = Release(|| {
+            *free.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+            freed.notify_one();
+        });
+        f()
+    }
+}
+
+/// Runs its closure when dropped, on a normal return or while unwinding from a panic,
+/// to give back what a gate handed out.
+struct Release<F: FnMut()>(F);
+
+impl<F: FnMut()> Drop for Release<F> {
+    fn drop(&mut self) {
+        (self.0)()
+    }
+}
+
+/// Run `f`, the CPU-bound part of hashing a file, under `opts.cpu_gate` if there is one.
+fn hashing<R>(opts: &HashOptions, f: impl FnOnce() -> R) -> R {
+    match &opts.cpu_gate {
+        Some(gate) => gate.run(f),
+        None => f(),
+    }
+}
+
+/// A cap on the bytes of read buffers held at once. A file whose buffers don't fit waits for
+/// other files to be done with theirs; one with buffers bigger than the whole cap waits for
+/// all of it, so it's still read, alone.
+#[derive(Clone, Debug)]
+pub struct MemoryGate {
+    free: Arc<(Mutex<u64>, Condvar)>,
+    total: u64,
+}
+
+impl MemoryGate {
+    pub fn new(bytes: u64) -> MemoryGate {
+        MemoryGate {
+            free: Arc::new((Mutex::new(bytes), Condvar::new())),
+            total: bytes,
+        }
+    }
+
+    /// Run `f`, which holds `bytes` of buffers, once they fit. They're given back even if `f`
+    /// panics.
+    pub fn run<R>(&self, bytes: u64, f: impl FnOnce() -> R) -> R {
+        let bytes = bytes.min(self.total);
+        let (free, freed) = &*self.free;
+        {
+            let mut free = freed
+                .wait_while(free.lock().unwrap(), |free| *free < bytes)
+                .unwrap();
+            *free -= bytes;
+        }
+        let _release = Release(|| {
+            *free.lock().unwrap_or_else(PoisonError::into_inner) += bytes;
+            freed.notify_all();
+        });
+        f()
+    }
+}
+
+/// Run `f`, reading a file, under `opts.memory_gate` if there is one: with [`FULL_READ_CHUNK`]
+/// at a time for a whole file, or up to three regions of `partial_bytes`.
+fn buffered<R>(opts: &HashOptions, f: impl FnOnce() -> R) -> R {
+    let bytes = if opts.full {
+        FULL_READ_CHUNK as u64
+    } else {
+        3 * opts.partial_bytes as u64
+    };
+    match &opts.memory_gate {
+        Some(gate) => gate.run(bytes, f),
+        None => f(),
+    }
+}
+
+/// A successfully computed partial hash, plus how many content bytes were read for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileHash {
+    pub hash: String,
+    pub bytes: u64,
+    /// Size or modtime differed before and after reading, so the digest can't be trusted.
+    pub unstable: bool,
+    /// Size and modtime as the file was opened, for manifests that record them.
+    pub metadata: SourceMetadata,
+    /// The regions that were read, in order, each with a digest of its own.
+    pub regions: Vec<RegionDigest>,
+    /// The same, digested under [`HashOptions::also`] if it was given.
+    pub also: Option<Box<FileHash>>,
+}
+
+/// A short digest of one sampled region and where it was read from, so a manifest can
+/// record which part of a file it saw and a failed check can tell which part changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionDigest {
+    pub region: Region,
+    pub offset: u64,
+    /// Bytes read: `partial_bytes`, or fewer at the end of a short file.
+    pub len: u64,
+    /// The first 16 hex digits of a digest of the region's bytes, salted like the file's.
+    pub digest: String,
+}
+
+/// Compute partial file hash. By default, we skip modtime. If `include_modtime` is true, we
+/// add modtime.
+pub fn hash_file(path: &Path, opts: &HashOptions) -> Result<FileHash, HashError> {
+    hash_file_with(path, opts, &NoEvents)
+}
+
+/// The modtime that goes into the digest: whole seconds (even seconds with `fat_compat`) if
+/// `include_modtime` is set and the modtime is available, else 0.
+pub fn digest_modtime(meta: &SourceMetadata, opts: &HashOptions) -> u64 {
+    if !opts.include_modtime {
+        return 0;
+    }
+    let secs = meta
+        .modified
+        .unwrap_or(std::time::SystemTime::UNIX_EPOCH)
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if opts.fat_compat {
+        secs & !1
+    } else {
+        secs
+    }
+}
+
+/// Like [`hash_file`], reporting the file's progress to `events`.
+pub fn hash_file_with(
+    path: &Path,
+    opts: &HashOptions,
+    events: &dyn EventSink,
+) -> Result<FileHash, HashError> {
+    events.file_started(path);
+    let result = hash_file_inner(path, opts, events);
+    report(path, &result, events);
+    result
+}
+
+/// Send the events for a finished file.
+pub(crate) fn report(path: &Path, result: &Result<FileHash, HashError>, events: &dyn EventSink) {
+    match result {
+        Ok(h) => {
+            events.bytes_read(path, h.bytes);
+            events.file_hashed(path, h);
+        }
+        Err(e) => events.file_failed(path, e),
+    }
+}
+
+/// Hash `paths` in parallel, returning results in the same order.
+pub fn hash_files(
+    paths: &[PathBuf],
+    opts: &HashOptions,
+    events: &dyn EventSink,
+) -> Vec<Result<FileHash, HashError>> {
+    paths
+        .par_iter()
+        .map(|p| hash_file_with(p, opts, events))
+        .collect()
+}
+
+/// Something that happened partway through a file, to pass on to the caller's [`EventSink`].
+enum Notice {
+    Retrying(HashError),
+    Rereading,
+}
+
+impl Notice {
+    fn send(self, path: &Path, events: &dyn EventSink) {
+        match self {
+            Notice::Retrying(e) => events.file_retrying(path, &e),
+            Notice::Rereading => events.file_rereading(path),
+        }
+    }
+}
+
+/// What a timeout helper thread sends back: notices as they happen, then the result.
+enum Message {
+    Notice(Notice),
+    Done(Result<FileHash, HashError>),
+}
+
+fn hash_file_inner(
+    path: &Path,
+    opts: &HashOptions,
+    events: &dyn EventSink,
+) -> Result<FileHash, HashError> {
+    let Some(timeout) = opts.file_timeout else {
+        return hash_with_retries(path, opts, &AtomicU64::new(0), &|n| n.send(path, events));
+    };
+
+    // A read stuck in the kernel can't be cancelled, so hash on a helper thread and stop
+    // waiting for it once the deadline passes. The helper is left to finish (or hang) on its own.
+    let offset = Arc::new(AtomicU64::new(0));
+    let (tx, rx) = mpsc::channel();
+    {
+        let (path, opts, offset) = (path.to_path_buf(), opts.clone(), Arc::clone(&offset));
+        std::thread::spawn(move || {
+            let notify = |n| {
+                let _ = tx.send(Message::Notice(n));
+            };
+            let _ = tx.send(Message::Done(hash_with_retries(
+                &path, &opts, &offset, &notify,
+            )));
+        });
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Message::Notice(n)) => n.send(path, events),
+            Ok(Message::Done(res)) => return res,
+            Err(_) => {
+                return Err(HashError::Timeout {
+                    after: timeout,
+                    offset: offset.load(Ordering::Relaxed),
+                })
+            }
+        }
+    }
+}
+
+/// Hash `path`, retrying transient failures. Every attempt starts from scratch, with a fresh
+/// stat and open, so a stale handle isn't reused. `offset` is updated with the position of
+/// the read in progress, so a caller that gives up waiting can say where it stalled.
+fn hash_with_retries(
+    path: &Path,
+    opts: &HashOptions,
+    offset: &AtomicU64,
+    notify: &dyn Fn(Notice),
+) -> Result<FileHash, HashError> {
+    let mut attempts = 0;
+    let mut rereads = 0;
+    let mut delay = opts.retry_delay;
+    loop {
+        attempts += 1;
+        let res = buffered(opts, || do_compute_hash_for_file(path, opts, offset));
+        match res {
+            Ok(h) if h.unstable && rereads < opts.reread_unstable => {
+                rereads += 1;
+                notify(Notice::Rereading);
+            }
+            Ok(h) => return Ok(h),
+            Err(e) => {
+                let network = opts.retry_network && e.is_network();
+                if attempts <= opts.retries && (e.is_transient() || network) {
+                    notify(Notice::Retrying(e));
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    continue;
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+fn do_compute_hash_for_file(
+    path: &Path,
+    opts: &HashOptions,
+    offset: &AtomicU64,
+) -> Result<FileHash, HashError> {
+    let partial_bytes = opts.partial_bytes;
+    let meta = opts.source.metadata(path).map_err(HashError::Metadata)?;
+    let size = meta.len;
+
+    // We never include creation time on Linux, it's too unreliable.
+
+    let mod_time_secs = digest_modtime(&meta, opts);
+
+    // File reading
+    let opened = opts.source.open(path).map_err(HashError::Open)?;
+    if opts.full {
+        return hash_whole_file(opened.as_ref(), meta, opts, offset);
+    }
+
+    // With `sparse`, a file with holes is sampled as if they were cut out, so the samples
+    // come from its data rather than all zeros. Offsets below are then into the data alone,
+    // and `physical` maps them back for errors and region digests.
+    let extents = if opts.sparse || opts.include_extents {
+        opened.data_extents().map_err(HashError::Metadata)?
+    } else {
+        None
+    };
+    let layout = extents.clone().filter(|_| opts.include_extents);
+    let data_only = extents
+        .filter(|_| opts.sparse)
+        .filter(|extents| extents.iter().map(|&(_, len)| len).sum::<u64>() < size)
+        .map(|extents| DataOnly {
+            file: opened.as_ref(),
+            extents,
+        });
+    let file: &dyn SourceFile = match &data_only {
+        Some(data_only) => data_only,
+        None => opened.as_ref(),
+    };
+    let physical = |offset: u64| data_only.as_ref().map_or(offset, |d| d.physical(offset));
+    let sampled_len = data_only.as_ref().map_or(size, DataOnly::len);
+
+    let mid_offset = (sampled_len > (partial_bytes as u64 * 2)).then_some(sampled_len / 2);
+    let end_offset = (sampled_len > partial_bytes as u64)
+        .then(|| sampled_len.saturating_sub(partial_bytes as u64));
+
+    let (first_buf, middle_buf, last_buf) = if opts
+        .parallel_read_threshold
+        .is_some_and(|threshold| size >= threshold)
+    {
+        // Large file: issue all three region reads at once, so a high-latency
+        // filesystem costs one round trip per file instead of three. Each read stores its
+        // offset as it starts, so a timeout names one of the reads still in progress.
+        std::thread::scope(|s| {
+            let read = |off: u64| {
+                offset.store(physical(off), Ordering::Relaxed);
+                read_at(file, off, partial_bytes)
+            };
+            let middle = mid_offset.map(|off| s.spawn(move || read(off)));
+            let last = end_offset.map(|off| s.spawn(move || read(off)));
+            let first = read(0);
+
+            let join = |handle: Option<std::thread::ScopedJoinHandle<io::Result<Vec<u8>>>>| {
+                handle
+                    .map(|h| h.join().expect("region read thread panicked"))
+                    .transpose()
+                    .map(|buf| buf.unwrap_or_default())
+            };
+            let middle = join(middle);
+            let last = join(last);
+
+            Ok::<_, HashError>((
+                first.map_err(read_error(Region::First, physical(0)))?,
+                middle.map_err(read_error(
+                    Region::Middle,
+                    physical(mid_offset.unwrap_or(0)),
+                ))?,
+                last.map_err(read_error(Region::Last, physical(end_offset.unwrap_or(0))))?,
+            ))
+        })?
+    } else {
+        // First
+        offset.store(physical(0), Ordering::Relaxed);
+        let first_buf =
+            read_at(file, 0, partial_bytes).map_err(read_error(Region::First, physical(0)))?;
+
+        // Middle
+        let middle_buf = match mid_offset {
+            Some(mid_offset) => {
+                offset.store(physical(mid_offset), Ordering::Relaxed);
+                read_at(file, mid_offset, partial_bytes)
+                    .map_err(read_error(Region::Middle, physical(mid_offset)))?
+            }
+            None => Vec::new(),
+        };
+
+        // Last
+        let last_buf = match end_offset {
+            Some(end_offset) => {
+                offset.store(physical(end_offset), Ordering::Relaxed);
+                read_at(file, end_offset, partial_bytes)
+                    .map_err(read_error(Region::Last, physical(end_offset)))?
+            }
+            None => Vec::new(),
+        };
+
+        (first_buf, middle_buf, last_buf)
+    };
+
+    // Re-stat through the open handle: if size or modtime moved, we raced a writer.
+    let after = file.metadata().map_err(HashError::Metadata)?;
+    let unstable = after.len != size || after.modified != meta.modified;
+
+    let digests = |opts: &HashOptions| {
+        let mut regions = vec![region_digest(opts, Region::First, physical(0), &first_buf)];
+        if let Some(mid_offset) = mid_offset {
+            regions.push(region_digest(
+                opts,
+                Region::Middle,
+                physical(mid_offset),
+                &middle_buf,
+            ));
+        }
+        if let Some(end_offset) = end_offset {
+            regions.push(region_digest(
+                opts,
+                Region::Last,
+                physical(end_offset),
+                &last_buf,
+            ));
+        }
+        let hash = sampled_digest(
+            opts,
+            mod_time_secs,
+            size,
+            data_only.as_ref().map(|d| (d.extents.len(), d.len())),
+            layout.as_deref(),
+            [&first_buf, &middle_buf, &last_buf],
+        );
+        (regions, hash)
+    };
+    let ((regions, hash), also) = hashing(opts, || {
+        let also = opts.also.as_ref().map(|algorithm| {
+            digests(&HashOptions {
+                algorithm: *algorithm,
+                ..opts.clone()
+            })
+        });
+        (digests(opts), also)
+    });
+    let bytes = (first_buf.len() + middle_buf.len() + last_buf.len()) as u64;
+
+    Ok(FileHash {
+        hash,
+        bytes,
+        unstable,
+        metadata: meta.clone(),
+        regions,
+        also: also.map(|(regions, hash)| {
+            Box::new(FileHash {
+                hash,
+                bytes,
+                unstable,
+                metadata: meta,
+                regions,
+                also: None,
+            })
+        }),
+    })
+}
+
+/// The short digest of one sampled region.
+fn region_digest(opts: &HashOptions, region: Region, offset: u64, buf: &[u8]) -> RegionDigest {
+    let mut hasher = salted_hasher(opts);
+    hasher.update(buf);
+    let mut digest = hasher.finish();
+    digest.truncate(16);
+    RegionDigest {
+        region,
+        offset,
+        len: buf.len() as u64,
+        digest,
+    }
+}
+
+/// A file's digest from its samples, `[first, middle, last]`, and what else goes into it:
+/// the modtime, the size, with `sparse` the number of data extents and bytes of data
+/// sampled from, and with `include_extents` the whole layout.
+fn sampled_digest(
+    opts: &HashOptions,
+    mod_time_secs: u64,
+    size: u64,
+    sparse: Option<(usize, u64)>,
+    layout: Option<&[(u64, u64)]>,
+    samples: [&[u8]; 3],
+) -> String {
+    // Combine data
+    let mut hasher = salted_hasher(opts);
+
+    // Possibly zero or actual mod time
+    hasher.update(&mod_time_secs.to_le_bytes());
+
+    // file size
+    hasher.update(&size.to_le_bytes());
+
+    // How the samples were placed: sampled from the data alone, with so many extents of it.
+    if let Some((extents, data_len)) = sparse {
+        hasher.update(b"sparse");
+        hasher.update(&(extents as u64).to_le_bytes());
+        hasher.update(&data_len.to_le_bytes());
+    }
+
+    // The whole layout of data and holes, so a copy that gained or lost holes doesn't match.
+    if let Some(layout) = layout {
+        hasher.update(b"extents");
+        hasher.update(&(layout.len() as u64).to_le_bytes());
+        for &(start, len) in layout {
+            hasher.update(&start.to_le_bytes());
+            hasher.update(&len.to_le_bytes());
+        }
+    }
+
+    // partial contents
+    for sample in samples {
+        hasher.update(sample);
+    }
+
+    let mut hash = hasher.finish();
+    if let Some(digits) = opts.abbrev {
+        hash.truncate(digits);
+    }
+    hash
+}
+
+/// How much of a file `full` hashing reads at a time.
+const FULL_READ_CHUNK: usize = 1 << 20;
+
+/// The digest of all of `file`'s content, read from start to end, with nothing else in it.
+/// Read errors name the third of the file they happened in.
+fn hash_whole_file(
+    file: &dyn SourceFile,
+    meta: SourceMetadata,
+    opts: &HashOptions,
+    offset: &AtomicU64,
+) -> Result<FileHash, HashError> {
+    let size = meta.len;
+    let region_at = |at: u64| match at.saturating_mul(3).checked_div(size) {
+        Some(0) | None => Region::First,
+        Some(1) => Region::Middle,
+        Some(_) => Region::Last,
+    };
+    let mut hasher = opts.algorithm.hasher();
+    let mut also = opts.also.as_ref().map(Algorithm::hasher);
+    let mut buf = vec![0u8; FULL_READ_CHUNK];
+    let mut read = 0u64;
+    loop {
+        offset.store(read, Ordering::Relaxed);
+        let n = file
+            .read_at(read, &mut buf)
+            .map_err(read_error(region_at(read), read))?;
+        if n == 0 {
+            break;
+        }
+        hashing(opts, || {
+            hasher.update(&buf[..n]);
+            if let Some(also) = &mut also {
+                also.update(&buf[..n]);
+            }
+        });
+        read += n as u64;
+    }
+
+    let after = file.metadata().map_err(HashError::Metadata)?;
+    let unstable = read != size || after.len != size || after.modified != meta.modified;
+    let digest = |hasher: Box<dyn algorithm::Hasher>| {
+        let mut hash = hasher.finish();
+        if let Some(digits) = opts.abbrev {
+            hash.truncate(digits);
+        }
+        hash
+    };
+    Ok(FileHash {
+        hash: digest(hasher),
+        bytes: read,
+        unstable,
+        metadata: meta.clone(),
+        regions: Vec::new(),
+        also: also.map(|also| {
+            Box::new(FileHash {
+                hash: digest(also),
+                bytes: read,
+                unstable,
+                metadata: meta,
+                regions: Vec::new(),
+                also: None,
+            })
+        }),
+    })
+}
+
+/// A file's hash worked out from its content as it streams past, start to end, for callers
+/// that read all of it anyway (to copy it, say): the same [`FileHash`] as [`hash_file`]'s,
+/// with no reads of its own. Sampling from the data of a sparse file, and its layout of
+/// extents, need the file itself, so `sparse` and `include_extents` aren't followed, and
+/// neither is `also`.
+pub struct StreamingHash<'a> {
+    opts: &'a HashOptions,
+    meta: SourceMetadata,
+    /// Bytes seen so far.
+    read: u64,
+    /// With `full`, the digest of everything seen so far.
+    whole: Option<Box<dyn algorithm::Hasher>>,
+    /// Each region's offset and the bytes of it seen so far.
+    samples: Vec<(Region, u64, Vec<u8>)>,
+}
+
+impl<'a> StreamingHash<'a> {
+    /// Start on a file that `meta` was stat'ed from before its first byte was read.
+    pub fn new(opts: &'a HashOptions, meta: SourceMetadata) -> StreamingHash<'a> {
+        let (size, partial_bytes) = (meta.len, opts.partial_bytes as u64);
+        let mut samples = vec![(Region::First, 0, Vec::new())];
+        if size > partial_bytes * 2 {
+            samples.push((Region::Middle, size / 2, Vec::new()));
+        }
+        if size > partial_bytes {
+            samples.push((Region::Last, size - partial_bytes, Vec::new()));
+        }
+        StreamingHash {
+            opts,
+            meta,
+            read: 0,
+            whole: opts.full.then(|| opts.algorithm.hasher()),
+            samples: if opts.full { Vec::new() } else { samples },
+        }
+    }
+
+    /// Feed the next `chunk` of the content.
+    pub fn update(&mut self, chunk: &[u8]) {
+        let (start, end) = (self.read, self.read + chunk.len() as u64);
+        if let Some(whole) = &mut self.whole {
+            whole.update(chunk);
+        }
+        for (_, offset, buf) in &mut self.samples {
+            let from = start.max(*offset + buf.len() as u64);
+            let to = end.min(*offset + self.opts.partial_bytes as u64);
+            if from < to {
+                buf.extend_from_slice(&chunk[(from - start) as usize..(to - start) as usize]);
+            }
+        }
+        self.read = end;
+    }
+
+    /// The hash, given the file's metadata once it was read through. As with [`hash_file`],
+    /// it's unstable if the size or modtime moved meanwhile, or the content wasn't as long
+    /// as the size said.
+    pub fn finish(self, after: &SourceMetadata) -> FileHash {
+        let size = self.meta.len;
+        let unstable =
+            self.read != size || after.len != size || after.modified != self.meta.modified;
+        let (hash, bytes) = match self.whole {
+            Some(whole) => {
+                let mut hash = whole.finish();
+                if let Some(digits) = self.opts.abbrev {
+                    hash.truncate(digits);
+                }
+                (hash, self.read)
+            }
+            None => {
+                let sample = |region| {
+                    self.samples
+                        .iter()
+                        .find(|(r, _, _)| *r == region)
+                        .map_or(&[][..], |(_, _, buf)| buf.as_slice())
+                };
+                let hash = sampled_digest(
+                    self.opts,
+                    digest_modtime(&self.meta, self.opts),
+                    size,
+                    None,
+                    None,
+                    [
+                        sample(Region::First),
+                        sample(Region::Middle),
+                        sample(Region::Last),
+                    ],
+                );
+                let bytes = self.samples.iter().map(|(_, _, b)| b.len() as u64).sum();
+                (hash, bytes)
+            }
+        };
+        let regions = self
+            .samples
+            .iter()
+            .map(|(region, offset, buf)| region_digest(self.opts, *region, *offset, buf))
+            .collect();
+        FileHash {
+            hash,
+            bytes,
+            unstable,
+            metadata: self.meta,
+            regions,
+            also: None,
+        }
+    }
+}
+
+/// A sparse file seen through its data extents only, as if its holes were cut out.
+struct DataOnly<'a> {
+    file: &'a dyn SourceFile,
+    extents: Vec<(u64, u64)>,
+}
+
+impl DataOnly<'_> {
+    /// Bytes of data, holes left out.
+    fn len(&self) -> u64 {
+        self.extents.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// The extents holding the data from `offset` into it on, each as where in the file to
+    /// start reading and how many bytes of data are there.
+    fn extents_from(&self, offset: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let mut base = 0;
+        self.extents.iter().filter_map(move |&(start, len)| {
+            let extent_base = base;
+            base += len;
+            (extent_base + len > offset).then(|| {
+                let skip = offset.saturating_sub(extent_base);
+                (start + skip, len - skip)
+            })
+        })
+    }
+
+    /// Where offset `offset` into the data is in the file.
+    fn physical(&self, offset: u64) -> u64 {
+        self.extents_from(offset)
+            .next()
+            .map_or(offset, |(at, _)| at)
+    }
+}
+
+impl SourceFile for DataOnly<'_> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        for (at, available) in self.extents_from(offset) {
+            if filled == buf.len() {
+                break;
+            }
+            let want = (buf.len() - filled).min(available as usize);
+            let n = self.file.read_at(at, &mut buf[filled..filled + want])?;
+            filled += n;
+            if n < want {
+                break;
+            }
+        }
+        Ok(filled)
+    }
+
+    fn metadata(&self) -> io::Result<SourceMetadata> {
+        self.file.metadata()
+    }
+}
+
+/// A hasher for `opts.algorithm` that has been fed the salt, if there is one: first and
+/// length-prefixed, so it can't run into the data after it.
+fn salted_hasher(opts: &HashOptions) -> Box<dyn algorithm::Hasher> {
+    let mut hasher = opts.algorithm.hasher();
+    if let Some(Salt(salt)) = &opts.salt {
+        hasher.update(&(salt.len() as u64).to_le_bytes());
+        hasher.update(salt);
+    }
+    hasher
+}
+
+/// Build a `map_err` adapter for a failed read of `region` at `offset`.
+fn read_error(region: Region, offset: u64) -> impl FnOnce(io::Error) -> HashError {
+    move |source| HashError::Read {
+        region,
+        offset,
+        source,
+    }
+}
+
+/// How many bytes the partial sampling scheme reads from a file of `size` bytes.
+pub fn sampled_bytes(size: u64, partial_bytes: usize) -> u64 {
+    let pb = partial_bytes as u64;
+    let first = size.min(pb);
+    let middle = if size > pb * 2 {
+        pb.min(size - size / 2)
+    } else {
+        0
+    };
+    let last = if size > pb { pb } else { 0 };
+    first + middle + last
+}
+
+/// Single positional read of up to `len` bytes at `offset`.
+pub fn read_at(file: &dyn SourceFile, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    let n = file.read_at(offset, &mut buf)?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MemorySource;
+    use std::time::UNIX_EPOCH;
+
+    fn options(source: MemorySource) -> HashOptions {
+        HashOptions {
+            source: Arc::new(source),
+            ..HashOptions::default()
+        }
+    }
+
+    fn one_file(data: &[u8]) -> HashOptions {
+        let mut source = MemorySource::new();
+        source.insert("f", data);
+        options(source)
+    }
+
+    #[test]
+    fn gates_get_slots_back_from_panics() {
+        let gate = CpuGate::new(1);
+        let panicked = std::panic::catch_unwind(|| gate.run(|| panic!("hashing failed")));
+        assert!(panicked.is_err());
+        assert_eq!(*gate.0 .0.lock().unwrap(), 1);
+        assert_eq!(gate.run(|| 7), 7);
+
+        let gate = MemoryGate::new(100);
+        let panicked = std::panic::catch_unwind(|| gate.run(60, || panic!("reading failed")));
+        assert!(panicked.is_err());
+        assert_eq!(*gate.free.0.lock().unwrap(), 100);
+        assert_eq!(gate.run(100, || 7), 7);
+    }
+
+    fn placement(h: &FileHash) -> Vec<(Region, u64, u64)> {
+        h.regions
+            .iter()
+            .map(|r| (r.region, r.offset, r.len))
+            .collect()
+    }
+
+    #[test]
+    fn small_file_is_read_once_from_the_start() {
+        let h = hash_file(Path::new("f"), &one_file(&[7; 50])).unwrap();
+        assert_eq!(placement(&h), [(Region::First, 0, 50)]);
+        assert_eq!(h.bytes, sampled_bytes(50, 100));
+    }
+
+    #[test]
+    fn empty_file_reads_nothing() {
+        let h = hash_file(Path::new("f"), &one_file(&[])).unwrap();
+        assert_eq!(placement(&h), [(Region::First, 0, 0)]);
+        assert_eq!(h.bytes, 0);
+        assert_eq!(sampled_bytes(0, 100), 0);
+    }
+
+    #[test]
+    fn three_regions_of_a_file_three_samples_long() {
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let h = hash_file(Path::new("f"), &one_file(&data)).unwrap();
+        assert_eq!(
+            placement(&h),
+            [
+                (Region::First, 0, 100),
+                (Region::Middle, 150, 100),
+                (Region::Last, 200, 100)
+            ]
+        );
+        assert_eq!(h.bytes, sampled_bytes(300, 100));
+    }
+
+    #[test]
+    fn parallel_reads_give_the_same_digest() {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let opts = one_file(&data);
+        let sequential = hash_file(Path::new("f"), &opts).unwrap();
+        let parallel = HashOptions {
+            parallel_read_threshold: Some(1),
+            ..opts
+        };
+        let parallel = hash_file(Path::new("f"), &parallel).unwrap();
+        assert_eq!(sequential.hash, parallel.hash);
+        assert_eq!(placement(&sequential), placement(&parallel));
+    }
+
+    #[test]
+    fn unsampled_bytes_do_not_change_the_digest() {
+        let mut data = vec![1u8; 1000];
+        let before = hash_file(Path::new("f"), &one_file(&data)).unwrap();
+        data[120] = 2;
+        let after = hash_file(Path::new("f"), &one_file(&data)).unwrap();
+        assert_eq!(before.hash, after.hash);
+        data[0] = 2;
+        let after = hash_file(Path::new("f"), &one_file(&data)).unwrap();
+        assert_ne!(before.hash, after.hash);
+    }
+
+    #[test]
+    fn modtime_counts_only_when_included() {
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        let mut source = MemorySource::new();
+        source.insert_with_modtime("a", "same", at(1_000));
+        source.insert_with_modtime("b", "same", at(1_001));
+        source.insert_with_modtime("c", "same", at(2_000));
+        let digest =
+            |opts: &HashOptions, path: &str| hash_file(Path::new(path), opts).unwrap().hash;
+
+        let opts = options(source);
+        assert_eq!(digest(&opts, "a"), digest(&opts, "c"));
+
+        let with_modtime = HashOptions {
+            include_modtime: true,
+            ..opts.clone()
+        };
+        assert_ne!(digest(&with_modtime, "a"), digest(&with_modtime, "b"));
+        assert_ne!(digest(&with_modtime, "a"), digest(&opts, "a"));
+
+        let fat = HashOptions {
+            fat_compat: true,
+            ..with_modtime
+        };
+        assert_eq!(digest(&fat, "a"), digest(&fat, "b"));
+        assert_ne!(digest(&fat, "a"), digest(&fat, "c"));
+    }
+
+    #[test]
+    fn full_digest_is_the_plain_content_digest() {
+        let opts = HashOptions {
+            full: true,
+            ..one_file(b"abc")
+        };
+        assert_eq!(
+            hash_file(Path::new("f"), &opts).unwrap().hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn full_refuses_what_it_would_leave_out() {
+        let full = HashOptions {
+            full: true,
+            ..HashOptions::default()
+        };
+        assert!(full.validate().is_ok());
+        let salted = HashOptions {
+            include_modtime: true,
+            salt: Some(Salt::new(&b"secret"[..])),
+            ..full
+        };
+        assert_eq!(
+            salted.validate().unwrap_err(),
+            "--full can't be combined with --include-modtime, --salt-file"
+        );
+        let sampled = HashOptions {
+            full: false,
+            ..salted
+        };
+        assert!(sampled.validate().is_ok());
+    }
+
+    #[test]
+    fn missing_file_is_a_metadata_error() {
+        let err = hash_file(Path::new("missing"), &options(MemorySource::new())).unwrap_err();
+        assert!(matches!(err, HashError::Metadata(_)));
+    }
+}
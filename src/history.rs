@@ -0,0 +1,409 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Verification history: each check run's per-file results, appended to a text database so
+//! trends show up across runs (files that newly started failing, files that flap between OK
+//! and failing, files never verified) rather than one snapshot at a time.
+//!
+//! Each line is `<unix seconds>\t<status>\t<path>`, with `status` one of `ok`, `failed`
+//! (content mismatched or changed while read), or `error` (couldn't be read). Paths are
+//! recorded as the manifest has them. Lines starting with `#` are comments, except that a
+//! run with `--label`s starts with `# run: <unix seconds> key=value ...`, and an `ok`
+//! record of a local file is followed by `# stat: <unix seconds>\t<size>\t<modtime in
+//! nanoseconds>\t<inode>\t<path>`, what the file looked like then, for `check --changed-only`.
+//! Runs only ever append, so the file is safe to keep for years and easy to grep.
+
+use crate::manifest::{join_fields, portable_path, split_fields, Manifest};
+use std::{
+    collections::HashMap,
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// How one file fared in one run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Failed,
+    Error,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Failed => "failed",
+            Status::Error => "error",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Status> {
+        match name {
+            "ok" => Some(Status::Ok),
+            "failed" => Some(Status::Failed),
+            "error" => Some(Status::Error),
+            _ => None,
+        }
+    }
+}
+
+/// One file's result in one run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// When the run finished, in seconds since the Unix epoch.
+    pub time: u64,
+    pub status: Status,
+    pub path: PathBuf,
+}
+
+/// Starts the records of a run with labels, followed by its time and the labels.
+pub const RUN_HEADER: &str = "# run: ";
+
+/// Starts the [`Stamp`] of a file verified OK, after its record.
+pub const STAT_HEADER: &str = "# stat: ";
+
+/// What a local file looked like when it was verified: enough to tell it hasn't been
+/// written, replaced, or restored from a backup since.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    pub len: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub modified: u128,
+    /// The inode number, where there is one.
+    pub inode: Option<u64>,
+}
+
+impl Stamp {
+    /// Stat `path` on the local filesystem.
+    pub fn of(path: &Path) -> io::Result<Stamp> {
+        let meta = fs::metadata(crate::source::native_path(path))?;
+        #[cfg(unix)]
+        let inode = Some(std::os::unix::fs::MetadataExt::ino(&meta));
+        #[cfg(not(unix))]
+        let inode = None;
+        Ok(Stamp {
+            len: meta.len(),
+            modified: meta
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos()),
+            inode,
+        })
+    }
+}
+
+/// A loaded history database, with each file's records in the order they were appended.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    pub files: HashMap<PathBuf, Vec<Record>>,
+    /// Each file's latest [`Stamp`], with the time of the run that recorded it.
+    pub stamps: HashMap<PathBuf, (u64, Stamp)>,
+    /// The labels of each run that had them, by its time.
+    pub labels: HashMap<u64, Vec<(String, String)>>,
+}
+
+impl History {
+    /// Read the database at `path`. A missing file is an empty history; malformed lines are
+    /// skipped.
+    pub fn load(path: &Path) -> io::Result<History> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut history = History::default();
+        for (time, fields) in contents
+            .lines()
+            .filter_map(|l| l.strip_prefix(RUN_HEADER)?.split_once(' '))
+        {
+            if let Ok(time) = time.parse() {
+                history.labels.insert(time, split_fields(fields));
+            }
+        }
+        for (path, time, stamp) in contents
+            .lines()
+            .filter_map(|l| parse_stamp(l.strip_prefix(STAT_HEADER)?))
+        {
+            history.stamps.insert(path, (time, stamp));
+        }
+        for record in contents
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(parse_record)
+        {
+            history
+                .files
+                .entry(record.path.clone())
+                .or_default()
+                .push(record);
+        }
+        Ok(history)
+    }
+
+    /// Append one run's `results`, all stamped `time`, to the database at `path`, under a
+    /// [`RUN_HEADER`] with its `labels` if it has any. A result's [`Stamp`], if it has one, is
+    /// written after it.
+    pub fn append(
+        path: &Path,
+        time: u64,
+        labels: &[(String, String)],
+        results: &[(PathBuf, Status, Option<Stamp>)],
+    ) -> io::Result<()> {
+        let mut out = String::new();
+        if !labels.is_empty() {
+            out.push_str(&format!("{}{} {}\n", RUN_HEADER, time, join_fields(labels)));
+        }
+        for (file, status, stamp) in results {
+            out.push_str(&format!(
+                "{}\t{}\t{}\n",
+                time,
+                status.name(),
+                portable_path(file)
+            ));
+            if let Some(stamp) = stamp {
+                let inode = stamp.inode.map_or("-".to_string(), |i| i.to_string());
+                out.push_str(&format!(
+                    "{}{}\t{}\t{}\t{}\t{}\n",
+                    STAT_HEADER,
+                    time,
+                    stamp.len,
+                    stamp.modified,
+                    inode,
+                    portable_path(file)
+                ));
+            }
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(out.as_bytes())
+    }
+
+    /// When `path` was last read successfully enough to compare (`ok` or `failed`).
+    pub fn last_verified(&self, path: &Path) -> Option<u64> {
+        self.files
+            .get(path)?
+            .iter()
+            .filter(|r| r.status != Status::Error)
+            .map(|r| r.time)
+            .max()
+    }
+
+    /// Whether `path`'s latest record is `ok`, and it still looks as it did then (`now`).
+    pub fn unchanged_since_verified(&self, path: &Path, now: &Stamp) -> bool {
+        let Some(latest) = self.files.get(path).and_then(|records| records.last()) else {
+            return false;
+        };
+        latest.status == Status::Ok
+            && self
+                .stamps
+                .get(path)
+                .is_some_and(|(time, stamp)| *time == latest.time && stamp == now)
+    }
+
+    /// Trends across the recorded runs. With `manifest`, also lists its entries that were
+    /// never verified.
+    pub fn report(&self, manifest: Option<&Manifest>) -> Report {
+        let mut report = Report::default();
+        for (path, records) in &self.files {
+            let good: Vec<bool> = records.iter().map(|r| r.status == Status::Ok).collect();
+            if let [.., true, false] = good[..] {
+                report
+                    .newly_failing
+                    .push(records[records.len() - 1].clone());
+            }
+            let changes = good.windows(2).filter(|w| w[0] != w[1]).count();
+            if changes >= 2 {
+                report.flapping.push((path.clone(), changes, records.len()));
+            }
+        }
+        if let Some(manifest) = manifest {
+            report.never_verified = manifest
+                .entries()
+                .filter(|e| self.last_verified(&e.path).is_none())
+                .map(|e| e.path.clone())
+                .collect();
+        }
+        report.newly_failing.sort_by(|a, b| a.path.cmp(&b.path));
+        report.flapping.sort();
+        report
+    }
+}
+
+/// What [`History::report`] found.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// The latest failing record of each file whose previous run was OK.
+    pub newly_failing: Vec<Record>,
+    /// Files that went between OK and failing at least twice: path, changes, and runs.
+    pub flapping: Vec<(PathBuf, usize, usize)>,
+    /// Manifest entries with no `ok` or `failed` record, in manifest order.
+    pub never_verified: Vec<PathBuf>,
+}
+
+/// The fields of a [`STAT_HEADER`] line, after the header.
+fn parse_stamp(fields: &str) -> Option<(PathBuf, u64, Stamp)> {
+    let mut fields = fields.splitn(5, '\t');
+    let time = fields.next()?.parse().ok()?;
+    let len = fields.next()?.parse().ok()?;
+    let modified = fields.next()?.parse().ok()?;
+    let inode = match fields.next()? {
+        "-" => None,
+        inode => Some(inode.parse().ok()?),
+    };
+    let stamp = Stamp {
+        len,
+        modified,
+        inode,
+    };
+    Some((PathBuf::from(fields.next()?), time, stamp))
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let mut fields = line.splitn(3, '\t');
+    Some(Record {
+        time: fields.next()?.parse().ok()?,
+        status: Status::parse(fields.next()?)?,
+        path: PathBuf::from(fields.next()?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+
+    /// A database path of its own for each test, removed first.
+    fn database(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "gustasum-history-{}-{}.tsv",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// An unstamped result, as for a file that isn't local.
+    fn result(status: Status, path: &str) -> (PathBuf, Status, Option<Stamp>) {
+        (PathBuf::from(path), status, None)
+    }
+
+    #[test]
+    fn missing_database_is_empty() {
+        let history = History::load(&database("missing")).unwrap();
+        assert!(history.files.is_empty());
+    }
+
+    #[test]
+    fn appended_runs_load_back() {
+        let db = database("round-trip");
+        let stamp = Stamp {
+            len: 10,
+            modified: 1_500_000_000_123,
+            inode: None,
+        };
+        let labels = [("site".to_string(), "home".to_string())];
+        History::append(
+            &db,
+            100,
+            &labels,
+            &[(PathBuf::from("/a b"), Status::Ok, Some(stamp))],
+        )
+        .unwrap();
+        History::append(&db, 200, &[], &[result(Status::Error, "/a b")]).unwrap();
+
+        let history = History::load(&db).unwrap();
+        let times: Vec<(u64, Status)> = history.files[Path::new("/a b")]
+            .iter()
+            .map(|r| (r.time, r.status))
+            .collect();
+        assert_eq!(times, [(100, Status::Ok), (200, Status::Error)]);
+        assert_eq!(history.stamps[Path::new("/a b")], (100, stamp));
+        assert_eq!(history.labels[&100], labels);
+        assert_eq!(history.last_verified(Path::new("/a b")), Some(100));
+        // The latest record isn't the OK one the stamp belongs to.
+        assert!(!history.unchanged_since_verified(Path::new("/a b"), &stamp));
+        fs::remove_file(db).unwrap();
+    }
+
+    #[test]
+    fn unchanged_only_with_the_same_stamp() {
+        let db = database("unchanged");
+        let stamp = Stamp {
+            len: 10,
+            modified: 5,
+            inode: Some(7),
+        };
+        History::append(
+            &db,
+            100,
+            &[],
+            &[(PathBuf::from("/a"), Status::Ok, Some(stamp))],
+        )
+        .unwrap();
+        let history = History::load(&db).unwrap();
+        assert!(history.unchanged_since_verified(Path::new("/a"), &stamp));
+        let touched = Stamp {
+            modified: 6,
+            ..stamp
+        };
+        assert!(!history.unchanged_since_verified(Path::new("/a"), &touched));
+        fs::remove_file(db).unwrap();
+    }
+
+    #[test]
+    fn report_finds_new_failures_flapping_and_unverified_files() {
+        let db = database("report");
+        let runs = [
+            [Status::Ok, Status::Ok, Status::Error],
+            [Status::Ok, Status::Failed, Status::Error],
+            [Status::Ok, Status::Ok, Status::Error],
+            [Status::Failed, Status::Failed, Status::Error],
+        ];
+        for (time, statuses) in runs.iter().enumerate() {
+            let results: Vec<_> = ["/steady", "/flaky", "/unread"]
+                .iter()
+                .zip(statuses)
+                .map(|(path, &status)| result(status, path))
+                .collect();
+            History::append(&db, time as u64, &[], &results).unwrap();
+        }
+        let history = History::load(&db).unwrap();
+        let manifest = Manifest::parse("aaaa  /steady\nbbbb  /unread\ncccc  /new\n");
+        let report = history.report(Some(&manifest));
+
+        let newly: Vec<&Path> = report
+            .newly_failing
+            .iter()
+            .map(|r| r.path.as_path())
+            .collect();
+        assert_eq!(newly, [Path::new("/flaky"), Path::new("/steady")]);
+        assert_eq!(report.flapping, [(PathBuf::from("/flaky"), 3, 4)]);
+        assert_eq!(
+            report.never_verified,
+            [PathBuf::from("/unread"), PathBuf::from("/new")]
+        );
+        fs::remove_file(db).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Checksum files from other tools, for folding into gustasum: those of `md5sum`, `sha1sum`,
+//! `sha256sum`, and `sha512sum`, in their default format (`<hex>  <path>`, or `<hex> *<path>`
+//! in binary mode) or the BSD one (`MD5 (<path>) = <hex>`). The default format doesn't name
+//! its algorithm, so it's told from the length of the digests.
+
+use crate::{
+    algorithm::{self, Algorithm},
+    manifest::{parse_line, ManifestEntry},
+};
+use std::path::PathBuf;
+
+/// One entry of a foreign checksum file.
+#[derive(Clone, Debug)]
+pub struct ForeignEntry {
+    pub algorithm: Algorithm,
+    /// In lowercase, as gustasum writes digests.
+    pub digest: String,
+    /// As the file has it; relative paths are usually relative to where the file is.
+    pub path: PathBuf,
+}
+
+/// A parsed foreign checksum file.
+#[derive(Clone, Debug, Default)]
+pub struct ForeignManifest {
+    pub entries: Vec<ForeignEntry>,
+    /// Lines that are neither format, or whose digest is of no known length, verbatim.
+    pub malformed: Vec<String>,
+}
+
+/// The algorithm whose hex digests have `digits` digits.
+pub fn algorithm_for_length(digits: usize) -> Option<Algorithm> {
+    match digits {
+        32 => Some(algorithm::MD5),
+        40 => Some(algorithm::SHA1),
+        64 => Some(algorithm::SHA256),
+        128 => Some(algorithm::SHA512),
+        _ => None,
+    }
+}
+
+/// The algorithm a BSD-format line's tag names.
+fn algorithm_for_tag(tag: &str) -> Option<Algorithm> {
+    match tag {
+        "MD5" => Some(algorithm::MD5),
+        "SHA1" => Some(algorithm::SHA1),
+        "SHA256" => Some(algorithm::SHA256),
+        "SHA512" => Some(algorithm::SHA512),
+        _ => None,
+    }
+}
+
+/// `TAG (path) = hex`, if `line` is of that form.
+fn parse_bsd_line(line: &str) -> Option<(&str, &str, &str)> {
+    let (tag, rest) = line.split_once(" (")?;
+    let (path, digest) = rest.rsplit_once(") = ")?;
+    Some((tag, path, digest))
+}
+
+/// Parse `contents`. Blank lines and `#` comments are skipped.
+pub fn parse(contents: &str) -> ForeignManifest {
+    let mut manifest = ForeignManifest::default();
+    let is_hex = |digest: &str| digest.chars().all(|c| c.is_ascii_hexdigit());
+    for line in contents.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = match parse_bsd_line(line) {
+            Some((tag, path, digest)) => algorithm_for_tag(tag)
+                .filter(|_| is_hex(digest))
+                .map(|algorithm| (algorithm, digest.to_string(), PathBuf::from(path))),
+            None => parse_line(line).and_then(|ManifestEntry { hash, path }| {
+                let algorithm = algorithm_for_length(hash.len()).filter(|_| is_hex(&hash))?;
+                Some((algorithm, hash, path))
+            }),
+        };
+        match entry {
+            Some((algorithm, digest, path)) => manifest.entries.push(ForeignEntry {
+                algorithm,
+                digest: digest.to_ascii_lowercase(),
+                path,
+            }),
+            None => manifest.malformed.push(line.to_string()),
+        }
+    }
+    manifest
+}
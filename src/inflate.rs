@@ -0,0 +1,394 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! A streaming DEFLATE (RFC 1951) decoder, for reading compressed ZIP members. Output is
+//! produced on demand, so a member can be sampled without holding all of it in memory.
+
+use std::io::{self, Read};
+
+const WINDOW: usize = 32 * 1024;
+const MAX_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in.
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt deflate stream: {}", msg),
+    )
+}
+
+/// A canonical Huffman code, as counts of codes per length and symbols in code order.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        // Over-subscribed codes can't be decoded; incomplete ones are allowed (a single
+        // distance code, say).
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(corrupt("over-subscribed code"));
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+}
+
+/// Bits least significant first, as DEFLATE packs them.
+struct Bits<R> {
+    input: R,
+    buf: u32,
+    count: u32,
+}
+
+impl<R: Read> Bits<R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut b = [0u8];
+        match self.input.read(&mut b)? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "deflate stream ends early",
+            )),
+            _ => Ok(b[0]),
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            self.buf |= u32::from(self.byte()?) << self.count;
+            self.count += 8;
+        }
+        let value = self.buf & ((1u32 << n) - 1);
+        self.buf = self.buf.checked_shr(n).unwrap_or(0);
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drop the rest of the current byte, for stored blocks.
+    fn align(&mut self) {
+        let drop = self.count % 8;
+        self.buf >>= drop;
+        self.count -= drop;
+    }
+
+    fn decode(&mut self, code: &Huffman) -> io::Result<u16> {
+        let (mut value, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            value |= self.bits(1)? as i32;
+            let count = i32::from(code.counts[len]);
+            if value - first < count {
+                return Ok(code.symbols[(index + value - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            value <<= 1;
+        }
+        Err(corrupt("invalid code"))
+    }
+}
+
+enum State {
+    /// Between blocks.
+    Header,
+    /// Inside a stored block, with this many bytes left.
+    Stored(u16),
+    /// Inside a compressed block.
+    Codes {
+        lit: Huffman,
+        dist: Huffman,
+    },
+    Done,
+}
+
+/// Decompresses the raw DEFLATE stream read from `input`.
+pub struct Inflate<R> {
+    bits: Bits<R>,
+    window: Vec<u8>,
+    /// Bytes produced so far; the window is indexed by this modulo its size.
+    produced: u64,
+    state: State,
+    final_block: bool,
+    /// A back-reference partly copied out: bytes left and distance.
+    copy: (usize, usize),
+}
+
+impl<R: Read> Inflate<R> {
+    pub fn new(input: R) -> Self {
+        Inflate {
+            bits: Bits {
+                input,
+                buf: 0,
+                count: 0,
+            },
+            window: vec![0; WINDOW],
+            produced: 0,
+            state: State::Header,
+            final_block: false,
+            copy: (0, 0),
+        }
+    }
+
+    fn emit(&mut self, byte: u8, out: &mut [u8], n: &mut usize) {
+        self.window[(self.produced % WINDOW as u64) as usize] = byte;
+        self.produced += 1;
+        out[*n] = byte;
+        *n += 1;
+    }
+
+    fn header(&mut self) -> io::Result<State> {
+        if self.final_block {
+            return Ok(State::Done);
+        }
+        self.final_block = self.bits.bits(1)? == 1;
+        match self.bits.bits(2)? {
+            0 => {
+                self.bits.align();
+                let len = self.bits.bits(16)? as u16;
+                let nlen = self.bits.bits(16)? as u16;
+                if len != !nlen {
+                    return Err(corrupt("stored block length check failed"));
+                }
+                Ok(State::Stored(len))
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                Ok(State::Codes {
+                    lit: Huffman::new(&lengths)?,
+                    dist: Huffman::new(&[5; 30])?,
+                })
+            }
+            2 => self.dynamic(),
+            _ => Err(corrupt("invalid block type")),
+        }
+    }
+
+    fn dynamic(&mut self) -> io::Result<State> {
+        let nlen = self.bits.bits(5)? as usize + 257;
+        let ndist = self.bits.bits(5)? as usize + 1;
+        let ncode = self.bits.bits(4)? as usize + 4;
+        if nlen > 286 || ndist > 30 {
+            return Err(corrupt("too many length or distance codes"));
+        }
+        let mut clen = [0u8; 19];
+        for &idx in &CLEN_ORDER[..ncode] {
+            clen[idx] = self.bits.bits(3)? as u8;
+        }
+        let clen = Huffman::new(&clen)?;
+
+        let mut lengths = vec![0u8; nlen + ndist];
+        let mut idx = 0;
+        while idx < lengths.len() {
+            let symbol = self.bits.decode(&clen)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    let prev = *lengths[..idx]
+                        .last()
+                        .ok_or_else(|| corrupt("repeat with no previous length"))?;
+                    (prev, 3 + self.bits.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.bits.bits(3)? as usize),
+                _ => (0, 11 + self.bits.bits(7)? as usize),
+            };
+            if idx + repeat > lengths.len() {
+                return Err(corrupt("too many code lengths"));
+            }
+            lengths[idx..idx + repeat].fill(value);
+            idx += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(corrupt("no end-of-block code"));
+        }
+        Ok(State::Codes {
+            lit: Huffman::new(&lengths[..nlen])?,
+            dist: Huffman::new(&lengths[nlen..])?,
+        })
+    }
+}
+
+impl<R: Read> Read for Inflate<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < out.len() {
+            if self.copy.0 > 0 {
+                let (left, dist) = self.copy;
+                let from = (self.produced - dist as u64) % WINDOW as u64;
+                let byte = self.window[from as usize];
+                self.emit(byte, out, &mut n);
+                self.copy.0 = left - 1;
+                continue;
+            }
+            match &mut self.state {
+                State::Done => break,
+                State::Header => self.state = self.header()?,
+                State::Stored(0) => self.state = State::Header,
+                State::Stored(left) => {
+                    *left -= 1;
+                    let byte = self.bits.bits(8)? as u8;
+                    self.emit(byte, out, &mut n);
+                }
+                State::Codes { lit, dist } => {
+                    let symbol = self.bits.decode(lit)?;
+                    match symbol {
+                        0..=255 => self.emit(symbol as u8, out, &mut n),
+                        256 => self.state = State::Header,
+                        257..=285 => {
+                            let i = (symbol - 257) as usize;
+                            let len = LENGTH_BASE[i] as usize
+                                + self.bits.bits(u32::from(LENGTH_EXTRA[i]))? as usize;
+                            let d = self.bits.decode(dist)? as usize;
+                            if d >= 30 {
+                                return Err(corrupt("invalid distance code"));
+                            }
+                            let distance = DIST_BASE[d] as usize
+                                + self.bits.bits(u32::from(DIST_EXTRA[d]))? as usize;
+                            if distance as u64 > self.produced {
+                                return Err(corrupt("distance too far back"));
+                            }
+                            self.copy = (len, distance);
+                        }
+                        _ => return Err(corrupt("invalid literal/length code")),
+                    }
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `b"stored, as it is"` in a stored block.
+    const STORED: [u8; 21] = [
+        0x01, 0x10, 0x00, 0xef, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x2c, 0x20, 0x61, 0x73,
+        0x20, 0x69, 0x74, 0x20, 0x69, 0x73,
+    ];
+    /// `b"abcabcabcabcabc, fixed codes"` with the fixed codes, repeats as back-references.
+    const FIXED: [u8; 20] = [
+        0x4b, 0x4c, 0x4a, 0x4e, 0x44, 0x42, 0x3a, 0x0a, 0x69, 0x99, 0x15, 0xa9, 0x29, 0x0a, 0xc9,
+        0xf9, 0x29, 0xa9, 0xc5, 0x00,
+    ];
+    /// [`DYNAMIC_TEXT`] with codes of its own, as zlib -9 wrote it.
+    const DYNAMIC: [u8; 28] = [
+        0x25, 0x88, 0x89, 0x09, 0x00, 0x00, 0x10, 0x82, 0x66, 0xd5, 0xf6, 0xdf, 0xe1, 0x9e, 0x20,
+        0x4c, 0x14, 0x90, 0xb8, 0xd4, 0xb8, 0xcf, 0x97, 0x5b, 0xde, 0x9a, 0xac, 0x0c,
+    ];
+    const DYNAMIC_TEXT: &[u8] = b"baaabacbaabbbcbbaaaaaabaabaacaaababaaaaabbabaaa";
+
+    fn inflate(stream: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Inflate::new(stream).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn decodes_each_block_type() {
+        assert_eq!(inflate(&STORED).unwrap(), b"stored, as it is");
+        assert_eq!(inflate(&FIXED).unwrap(), b"abcabcabcabcabc, fixed codes");
+        assert_eq!(inflate(&DYNAMIC).unwrap(), DYNAMIC_TEXT);
+    }
+
+    #[test]
+    fn back_references_span_reads() {
+        let mut inflate = Inflate::new(&FIXED[..]);
+        let mut out = Vec::new();
+        let mut byte = [0u8];
+        while inflate.read(&mut byte).unwrap() == 1 {
+            out.push(byte[0]);
+        }
+        assert_eq!(out, b"abcabcabcabcabc, fixed codes");
+    }
+
+    #[test]
+    fn truncated_streams_are_errors() {
+        for stream in [&STORED[..], &FIXED, &DYNAMIC] {
+            for end in 0..stream.len() {
+                let e = inflate(&stream[..end]).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof, "cut at {}", end);
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_streams_are_errors() {
+        let invalid = |stream: &[u8]| inflate(stream).unwrap_err().to_string();
+        // Final block of type 3, which doesn't exist.
+        assert!(invalid(&[0x07]).contains("invalid block type"));
+        // A stored block whose length and its complement disagree.
+        assert!(invalid(&[0x01, 0x05, 0x00, 0x00, 0x00]).contains("length check"));
+        // A fixed block that starts with a back-reference to before the start.
+        assert!(invalid(&[0x03, 0x02]).contains("too far back"));
+    }
+
+    #[test]
+    fn damaged_streams_do_not_panic() {
+        for bit in 0..DYNAMIC.len() * 8 {
+            let mut stream = DYNAMIC;
+            stream[bit / 8] ^= 1 << (bit % 8);
+            // An error or wrong output is fine; a panic isn't.
+            let _ = inflate(&stream);
+        }
+    }
+}
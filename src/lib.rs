@@ -0,0 +1,67 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Partial checksumming as a library: sample the start, middle, and end of each file and
+//! hash those samples together with the file size.
+//!
+//! The `gustasum` binary hashes and checks files through these modules; its run-level
+//! features (reports, notifications, remote and replica runs) live in the binary.
+
+pub mod ads;
+pub mod algorithm;
+pub mod anonymize;
+pub mod archive;
+pub mod clones;
+pub mod config;
+pub mod error;
+pub mod events;
+pub mod forks;
+pub mod hasher;
+pub mod history;
+pub mod import;
+mod inflate;
+pub mod manifest;
+pub mod normalize;
+pub mod remote;
+pub mod selftest;
+pub mod source;
+pub mod ssh;
+pub mod structure;
+pub mod verify;
+pub mod walk;
+
+#[cfg(feature = "async-io")]
+pub mod async_io;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(unix)]
+pub mod snapshot;
+#[cfg(windows)]
+pub mod vss;
+
+pub use algorithm::{Algorithm, Hasher};
+pub use error::HashError;
+pub use events::EventSink;
+pub use hasher::{hash_file, hash_file_with, hash_files, FileHash, HashOptions};
+pub use manifest::{Manifest, ManifestChange, ManifestEntry, SettingsConflict};
+pub use source::{LocalFs, MemorySource, Source};
+pub use verify::{verify_manifest, verify_manifest_with, CheckOutcome, CheckResult, VerifyOptions};
@@ -18,25 +18,618 @@
  along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use clap::{Arg, ArgAction, Command};
+use clap::{
+    builder::BoolishValueParser, parser::ValueSource, Arg, ArgAction, ArgGroup, ArgMatches, Command,
+};
+use gustasum::{
+    algorithm::{self, Algorithm},
+    anonymize::{self, PathKey, ANONYMIZED_FIELD},
+    archive::{ArchiveKind, ArchiveSource},
+    config::Config,
+    error::{ErrorClass, Region},
+    hash_file, hash_file_with,
+    hasher::{self, CpuGate, MemoryGate},
+    history::{self, History, Stamp},
+    import::{self, ForeignEntry},
+    manifest::{
+        counts_as_entry, escape_path, labels_line, metadata_line, native_separators, note_line,
+        parse_line, portable_path, provenance_line, totals_line, without_entries, Totals,
+        ALGORITHM_HEADER, CLONE_HEADER, PARTIAL_MARKER, TOTALS_TRAILER, TRAILER_FIELD,
+    },
+    normalize::{self, OnConflict, UnicodeForm},
+    remote::{self, RemoteSource, S3Settings},
+    selftest,
+    source::{LocalFs, Source, SourceMetadata},
+    ssh::{SshSource, SshUrl},
+    structure::{Kind, STRUCTURE_FIELD},
+    verify::{self, CheckOutcome, CheckResult},
+    walk::{self, Order, PathStyle, Recorded},
+    EventSink, FileHash, HashError, HashOptions, Manifest, ManifestChange, ManifestEntry,
+    VerifyOptions,
+};
 use rayon::prelude::*;
-use sha2::{Digest, Sha256};
 use std::{
+    cell::Cell,
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BTreeMap, BinaryHeap, HashMap, HashSet},
     fs,
-    io::{BufReader, Read, Seek, SeekFrom},
+    hash::BuildHasher,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpListener,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use walkdir::WalkDir;
 
+mod background;
+mod device;
+mod metrics;
+mod notify;
+mod par2;
+#[cfg(unix)]
+mod syslog;
+#[cfg(unix)]
+mod systemd;
+mod tui;
+
+use device::{ReadFailure, Storage};
+use par2::{Par2, Verdict};
+
 // For progress bar + TTY detection
 use atty::Stream;
+use console::{style, Term};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 
+/// `eprintln!` for warnings; see [`diagnostic`].
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        diagnostic(Severity::Warning, format!($($arg)*))
+    };
+}
+
+/// `eprintln!` for errors and failed checks; see [`diagnostic`].
+macro_rules! error {
+    ($($arg:tt)*) => {
+        diagnostic(Severity::Error, format!($($arg)*))
+    };
+}
+
+/// `eprintln!` for progress and summary lines; see [`diagnostic`].
+macro_rules! info {
+    ($($arg:tt)*) => {
+        diagnostic(Severity::Info, format!($($arg)*))
+    };
+}
+
+/// Detail shown with `-v`; see [`diagnostic`].
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        diagnostic(Severity::Verbose, format!($($arg)*))
+    };
+}
+
+/// Detail shown with `-vv`; see [`diagnostic`].
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        diagnostic(Severity::Debug, format!($($arg)*))
+    };
+}
+
 #[allow(non_snake_case)]
 fn main() {
-    let matches = Command::new("gustasum")
+    let matches = cli().get_matches();
+    init_log(&matches);
+    let config = load_config(&matches);
+    init_color(&matches, &config);
+
+    install_interrupt_handler();
+    #[cfg(unix)]
+    systemd::start();
+
+    match matches.subcommand() {
+        Some(("generate", sub)) => {
+            if sub.get_flag("stdin") {
+                stdin_mode(
+                    sub.get_one::<u64>("size_hint").copied(),
+                    &options_from(sub, &config),
+                );
+            } else if let Some(file) = sub.get_one::<String>("append") {
+                let contents = fs::read_to_string(file).unwrap_or_else(|e| {
+                    error!("Error: Failed to read manifest '{}': {}", file, e);
+                    std::process::exit(1);
+                });
+                let manifest = Manifest::parse(&contents);
+                require_hashed(file, &manifest);
+                let opts = options_for_manifest(sub, &config, &manifest, file);
+                let existing = Existing::Append {
+                    manifest: &manifest,
+                    file,
+                    contents: &contents,
+                    replace: sub.get_flag("replace"),
+                };
+                generate_mode(&paths_arg(sub), Some(existing), &opts);
+            } else if let Some(file) = sub.get_one::<String>("skip_existing") {
+                let manifest = read_hashed_manifest(file);
+                let opts = options_for_manifest(sub, &config, &manifest, file);
+                generate_mode(&paths_arg(sub), Some(Existing::OnlyNew(&manifest)), &opts);
+            } else if let Some(file) = sub.get_one::<String>("within") {
+                let manifest = read_hashed_manifest(file);
+                // Hashed afresh, with this run's settings rather than the ones it records.
+                let opts = options_from(sub, &config);
+                generate_mode(&paths_arg(sub), Some(Existing::Within(&manifest)), &opts);
+            } else {
+                let opts = options_from(sub, &config);
+                if opts.structure_only {
+                    structure_generate_mode(&paths_arg(sub), &opts);
+                } else {
+                    generate_mode(&paths_arg(sub), None, &opts);
+                }
+            }
+        }
+        Some(("check", sub)) => {
+            let check_file = sub.get_one::<String>("manifest").unwrap();
+            let manifest = read_signed_manifest(sub, check_file);
+            let opts = options_for_manifest(sub, &config, &manifest, check_file);
+            verify_mode(
+                check_file,
+                &manifest,
+                &verify_options_from(sub, &opts, &manifest),
+                &opts,
+            );
+        }
+        Some(("diff", sub)) => diff_mode(
+            sub.get_one::<String>("old").unwrap(),
+            sub.get_one::<String>("new").unwrap(),
+            sub.get_one::<String>("emit_rsync_list").map(Path::new),
+        ),
+        Some(("list", sub)) => list_mode(sub.get_one::<String>("manifest").unwrap()),
+        Some(("grep", sub)) => grep_mode(
+            sub.get_one::<String>("manifest").unwrap(),
+            sub.get_one::<String>("path").map(String::as_str),
+            sub.get_one::<String>("hash").map(String::as_str),
+            sub.get_one::<String>("note").map(String::as_str),
+        ),
+        Some(("normalize", sub)) => normalize_mode(
+            sub.get_one::<String>("manifest").unwrap(),
+            sub.get_one::<String>("output").map(Path::new),
+            sub.get_one::<String>("unicode").unwrap().parse().unwrap(),
+            match sub.get_one::<String>("on_conflict").unwrap().as_str() {
+                "newest" => OnConflict::Newest,
+                _ => OnConflict::Error,
+            },
+        ),
+        Some(("import", sub)) => {
+            let files: Vec<String> = sub.get_many::<String>("files").unwrap().cloned().collect();
+            import_mode(&files, sub.get_flag("rehash"), &options_from(sub, &config));
+        }
+        Some(("deanonymize", sub)) => deanonymize_mode(
+            sub.get_one::<String>("manifest").unwrap(),
+            &read_path_key(sub.get_one::<String>("key").unwrap()),
+            &paths_arg(sub),
+            &options_from(sub, &config),
+        ),
+        Some(("update", sub)) => {
+            let previous_file = sub.get_one::<String>("manifest").unwrap();
+            let previous = read_hashed_manifest(previous_file);
+            let opts = options_for_manifest(sub, &config, &previous, previous_file);
+            generate_mode(&paths_arg(sub), Some(Existing::Update(&previous)), &opts);
+        }
+        Some(("manpage", sub)) => {
+            manpage_mode(sub.get_one::<String>("out_dir").map(Path::new));
+        }
+        Some(("copy", sub)) => copy_mode(
+            Path::new(sub.get_one::<String>("src").unwrap()),
+            Path::new(sub.get_one::<String>("dst").unwrap()),
+            &options_from(sub, &config),
+        ),
+        Some(("compare-replicas", sub)) => {
+            let roots: Vec<PathBuf> = sub
+                .get_many::<String>("roots")
+                .unwrap()
+                .map(PathBuf::from)
+                .collect();
+            compare_replicas_mode(
+                &roots,
+                sub.get_one::<String>("emit_rsync_list").map(Path::new),
+                &options_from(sub, &config),
+            );
+        }
+        Some(("history", sub)) => history_mode(
+            Path::new(sub.get_one::<String>("db").unwrap()),
+            sub.get_one::<String>("manifest").map(String::as_str),
+        ),
+        Some(("par2", sub)) => {
+            let (action, sub) = sub.subcommand().unwrap();
+            par2_mode(action, sub, &config);
+        }
+        Some(("selftest", _)) => selftest_mode(),
+        Some(("estimate", sub)) => estimate_mode(
+            &paths_arg(sub),
+            *sub.get_one::<u64>("sample_size").unwrap() as usize,
+            &options_from(sub, &config),
+        ),
+        Some(("bench", sub)) => {
+            let path = sub.get_one::<String>("path").unwrap();
+            bench_mode(Path::new(path), &options_from(sub, &config));
+        }
+        _ => {
+            // The original single-command form: `gustasum PATHS...` and `gustasum --check FILE`.
+            if let Some(check_file) = matches.get_one::<String>("check") {
+                let manifest = read_signed_manifest(&matches, check_file);
+                let opts = options_for_manifest(&matches, &config, &manifest, check_file);
+                verify_mode(
+                    check_file,
+                    &manifest,
+                    &verify_options_from(&matches, &opts, &manifest),
+                    &opts,
+                );
+            } else if matches.get_flag("stdin") {
+                stdin_mode(
+                    matches.get_one::<u64>("size_hint").copied(),
+                    &options_from(&matches, &config),
+                );
+            } else if matches.contains_id("paths") {
+                generate_mode(&paths_arg(&matches), None, &options_from(&matches, &config));
+            } else {
+                eprintln!("No paths provided and no check file specified. Use --help for usage.");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn cli() -> Command {
+    let manifest_arg = |help: &'static str| {
+        Arg::new("manifest")
+            .help(help)
+            .value_name("MANIFEST")
+            .required(true)
+            .action(ArgAction::Set)
+    };
+
+    Command::new("gustasum")
         .version("0.1.0")
         .about("Generate/check partial checksums")
+        .subcommand(
+            Command::new("generate")
+                .about("Print partial checksums for every file under PATHS")
+                .args(hash_args())
+                .args(run_args())
+                .arg(record_metadata_def())
+                .arg(note_def())
+                .arg(remote_gustasum_def())
+                .arg(anonymize_paths_def())
+                .arg(structure_only_def())
+                .args(append_defs())
+                .args(time_limit_defs())
+                .mut_arg("structure_only", |a| a.conflicts_with_all(["max_duration", "cursor"]))
+                .args(scan_limit_defs())
+                .args(path_style_defs())
+                .args(stdin_defs())
+                .mut_arg("stdin", |a| {
+                    a.conflicts_with_all([
+                        "append",
+                        "skip_existing",
+                        "within",
+                        "structure_only",
+                        "anonymize_paths",
+                        "max_duration",
+                        "cursor",
+                        "max_files",
+                        "max_bytes",
+                        "path_style",
+                        "no_canonicalize",
+                    ])
+                })
+                .arg(paths_def().required_unless_present_any(["stdin", "within"])),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Verify the files listed in a manifest")
+                .args(hash_args())
+                .args(run_args())
+                .arg(remap_def())
+                .arg(url_base_def())
+                .arg(on_fail_def())
+                .args(repair_defs())
+                .arg(db_def())
+                .arg(oldest_first_def())
+                .arg(known_failures_def())
+                .arg(group_output_def())
+                .args(transition_defs())
+                .args(time_limit_defs())
+                .mut_arg("cursor", |a| a.conflicts_with_all(["oldest_first", "two_phase"]))
+                .mut_arg("order", |a| a.conflicts_with_all(["oldest_first", "two_phase"]))
+                .args(metadata_check_defs())
+                .mut_arg("partial_bytes", |a| {
+                    a.help("Number of bytes to read from start, middle, and end. \
+                            Must match the value the manifest was generated with, \
+                            which is used if the manifest records it.")
+                })
+                .mut_arg("include_modtime", |a| {
+                    a.help("Hash modtime too. Must match how the manifest was generated, \
+                            which is followed if the manifest records it.")
+                })
+                .args(signature_defs())
+                .arg(manifest_arg("Manifest to verify, or an http:// or https:// URL to fetch it from")),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Compare two manifests by path and report added, removed, and changed files, without reading any files")
+                .arg(emit_rsync_list_def().help(
+                    "Also write the paths removed or changed since OLD, relative to the directory all of OLD's entries share, \
+                     to FILE for 'rsync --files-from': what to copy from OLD's tree to bring NEW's back in line",
+                ))
+                .arg(Arg::new("old").help("Older manifest").required(true).action(ArgAction::Set))
+                .arg(Arg::new("new").help("Newer manifest").required(true).action(ArgAction::Set)),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("Print a manifest's header settings, entry count, and recorded size, and flag malformed lines, bad digests, and duplicate paths, without reading any files")
+                .arg(manifest_arg("Manifest to inspect, or an http:// or https:// URL to fetch it from")),
+        )
+        .subcommand(
+            Command::new("grep")
+                .about("Print the entries of a manifest whose path matches GLOB, whose digest starts with PREFIX, or whose note contains TEXT, without reading any files")
+                .arg(
+                    Arg::new("path")
+                        .long("path")
+                        .help("Entries whose path matches GLOB. '*' stays within a directory and '**' spans them; \
+                               a GLOB not starting with '/' may match the end of a path, so 'photos/2021/**' finds /srv/photos/2021/...")
+                        .value_name("GLOB")
+                        .num_args(1)
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("hash")
+                        .long("hash")
+                        .help("Entries whose digest starts with PREFIX")
+                        .value_name("PREFIX")
+                        .num_args(1)
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("note")
+                        .long("note")
+                        .help("Entries whose note contains TEXT")
+                        .value_name("TEXT")
+                        .num_args(1)
+                        .action(ArgAction::Set),
+                )
+                .group(ArgGroup::new("query").args(["path", "hash", "note"]).multiple(true).required(true))
+                .arg(manifest_arg("Manifest to search, or an http:// or https:// URL to fetch it from")),
+        )
+        .subcommand(
+            Command::new("normalize")
+                .about("Rewrite a manifest with one entry per path, normalized paths sorted, and a clean header, without reading any files")
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .help("Write the manifest to FILE, replacing it only once it's complete, instead of printing it")
+                        .value_name("FILE")
+                        .num_args(1)
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("on_conflict")
+                        .long("on-conflict")
+                        .help("For a path listed more than once with different digests, refuse ('error'), or keep the entry listed last ('newest')")
+                        .value_parser(["error", "newest"])
+                        .default_value("error")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("unicode")
+                        .long("unicode")
+                        .help("Unicode normalization form to rewrite paths in; 'keep' leaves them as they are")
+                        .value_parser(["nfc", "nfd", "keep"])
+                        .default_value("nfc")
+                        .action(ArgAction::Set),
+                )
+                .arg(manifest_arg("Manifest to normalize, or an http:// or https:// URL to fetch it from")),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Check the files listed in md5sum, sha1sum, sha256sum, or sha512sum checksum files and print the ones that match as a gustasum manifest")
+                .args(hash_args())
+                .args(run_args())
+                .arg(
+                    Arg::new("rehash")
+                        .long("rehash")
+                        .help("Hash each file that matches again with the current settings, for a manifest of the usual kind; \
+                               without it, the manifest keeps the imported digests and is checked with --full")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("files")
+                        .help("Checksum files to import, default or BSD format; relative paths in them are taken from their directories")
+                        .value_name("CHECKSUM_FILE")
+                        .num_args(1..)
+                        .required(true)
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("deanonymize")
+                .about("Reprint a manifest made with --anonymize-paths with the real paths put back, matching it against the files under PATHS")
+                .arg(
+                    Arg::new("key")
+                        .long("key")
+                        .help("The key file the manifest was anonymized with")
+                        .value_name("KEYFILE")
+                        .num_args(1)
+                        .required(true)
+                        .action(ArgAction::Set),
+                )
+                .arg(manifest_arg("Anonymized manifest"))
+                .args(path_style_defs())
+                .arg(paths_def().required(true).help("The paths the manifest was generated for, as given then")),
+        )
+        .subcommand(
+            Command::new("update")
+                .about("Reprint MANIFEST without deleted files and with new files under PATHS hashed (also completes a partial manifest)")
+                .args(hash_args())
+                .args(run_args())
+                .arg(record_metadata_def().help(
+                    "Record each new file's size and modtime (on by default if MANIFEST already has them)",
+                ))
+                .arg(note_def().help("Annotate each new file's entry with TEXT"))
+                .args(scan_limit_defs())
+                .args(path_style_defs())
+                .arg(manifest_arg("Manifest to bring up to date"))
+                .arg(paths_def().required(true)),
+        )
+        .subcommand(
+            Command::new("copy")
+                .about("Copy the files under SRC to DST, hashing each as it's read, check the copies against those hashes, and print a manifest of them")
+                .args(hash_args())
+                .args(run_args())
+                .arg(record_metadata_def())
+                .arg(Arg::new("src").help("File or directory to copy").value_name("SRC").required(true).action(ArgAction::Set))
+                .arg(
+                    Arg::new("dst")
+                        .help("Directory to copy into, created if need be; copies already there from an earlier run are kept")
+                        .value_name("DST")
+                        .required(true)
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(
+            Command::new("compare-replicas")
+                .about("Hash corresponding files under each replica ROOT and, where copies disagree, report which ones are outvoted by the rest")
+                .args(hash_args())
+                .args(run_args())
+                .arg(emit_rsync_list_def().help(
+                    "Also write the paths of every file with an outvoted copy, relative to the roots, \
+                     to FILE for 'rsync --files-from': what to copy from a replica in the majority",
+                ))
+                .arg(
+                    Arg::new("roots")
+                        .help("Replica roots to compare (at least two; three or more can outvote a bad copy)")
+                        .value_name("ROOT")
+                        .num_args(2..)
+                        .required(true)
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("history")
+                .about("Report files that newly started failing or flap between OK and failing across the runs recorded by 'check --db', and with MANIFEST, its files never verified")
+                .arg(
+                    db_def()
+                        .required(true)
+                        .help("Verification history written by 'check --db'"),
+                )
+                .arg(
+                    Arg::new("manifest")
+                        .help("Manifest whose never-verified files to list")
+                        .value_name("MANIFEST")
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(
+            Command::new("par2")
+                .about("Create, verify, or repair PAR2 recovery data for the files in a manifest, using par2cmdline")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("Write a recovery set for each file in MANIFEST")
+                        .args(par2_args())
+                        .arg(
+                            Arg::new("redundancy")
+                                .long("redundancy")
+                                .short('r')
+                                .help("Percent of each file the recovery data can repair")
+                                .value_name("PERCENT")
+                                .value_parser(clap::value_parser!(u32).range(1..=100))
+                                .default_value("10")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(manifest_arg("Manifest listing the files to protect")),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about("Check every file in MANIFEST against its recovery set (reads each file in full)")
+                        .args(par2_args())
+                        .arg(manifest_arg("Manifest listing the protected files")),
+                )
+                .subcommand(
+                    Command::new("repair")
+                        .about("Verify MANIFEST's partial checksums and repair the files that fail from their recovery sets")
+                        .args(hash_args())
+                        .args(par2_args())
+                        .arg(manifest_arg("Manifest listing the protected files")),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measure traversal, stat, read, and hash rates on PATH and recommend settings")
+                .args(hash_args())
+                .arg(
+                    Arg::new("path")
+                        .help("Directory (or file) on the storage to benchmark")
+                        .required(true)
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(
+            Command::new("estimate")
+                .about("Project how long generating a manifest of PATHS would take, and what it'd read, from a random sample of the files")
+                .args(hash_args())
+                .args(run_args())
+                .arg(
+                    Arg::new("sample_size")
+                        .long("sample-size")
+                        .help("How many files, chosen at random, to stat and hash")
+                        .value_name("N")
+                        .default_value("1000")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .action(ArgAction::Set),
+                )
+                .arg(paths_def().required(true)),
+        )
+        .subcommand(Command::new("selftest").about(
+            "Hash built-in synthetic files with every algorithm and digest option and compare against the known answers this version was released with",
+        ))
+        .subcommand(
+            Command::new("manpage")
+                .about("Print the gustasum(1) man page, or write it and one page per command to DIR")
+                .arg(
+                    Arg::new("out_dir")
+                        .long("out-dir")
+                        .help("Write gustasum.1 and gustasum-<command>.1 here instead of printing gustasum.1")
+                        .value_name("DIR")
+                        .num_args(1)
+                        .action(ArgAction::Set),
+                ),
+        )
+        // Aliases for the single-command interface, kept so existing scripts work.
+        .args(hash_args().map(|a| a.hide(true)))
+        .args(run_args().into_iter().map(|a| a.hide(true)))
+        .arg(remap_def().hide(true))
+        .arg(url_base_def().hide(true))
+        .arg(on_fail_def().hide(true))
+        .args(repair_defs().map(|a| a.hide(true)))
+        .args(signature_defs().map(|a| a.hide(true)))
+        .arg(db_def().hide(true))
+        .arg(oldest_first_def().hide(true))
+        .arg(known_failures_def().hide(true))
+        .arg(group_output_def().hide(true))
+        .args(transition_defs().map(|a| a.hide(true)))
+        .args(time_limit_defs().map(|a| a.hide(true)))
+        .args(scan_limit_defs().map(|a| a.hide(true)))
+        .args(path_style_defs().map(|a| a.hide(true)))
+        .mut_arg("cursor", |a| a.conflicts_with_all(["oldest_first", "two_phase"]))
+        .mut_arg("order", |a| a.conflicts_with_all(["oldest_first", "two_phase"]))
+        .arg(record_metadata_def().hide(true))
+        .arg(note_def().hide(true))
+        .arg(remote_gustasum_def().hide(true))
+        .args(metadata_check_defs().map(|a| a.hide(true)))
         .arg(
             Arg::new("check")
                 .short('c')
@@ -44,439 +637,7616 @@ fn main() {
                 .help("Read checksums from the specified file and verify them")
                 .value_name("FILE")
                 .num_args(1)
+                .hide(true)
                 .action(ArgAction::Set),
         )
+        .arg(paths_def().hide(true))
+        .args(stdin_defs().map(|a| a.hide(true)))
         .arg(
-            Arg::new("remap")
-                .long("remap")
-                .help("Remaps old base path to new base path during verification. \
-                       E.g., --remap OLD_BASE NEW_BASE")
-                .num_args(2)
-                .value_names(["OLD_BASE", "NEW_BASE"])
-                .action(ArgAction::Set),
+            Arg::new("no_config")
+                .long("no-config")
+                .help("Ignore ~/.config/gustasum/config.toml and ./.gustasum.toml")
+                .env("GUSTASUM_NO_CONFIG")
+                .global(true)
+                .value_parser(BoolishValueParser::new())
+                .action(ArgAction::SetTrue),
         )
         .arg(
-            Arg::new("skip_errors")
-                .long("skip-errors")
-                .help("Skip files that produce read/metadata errors instead of marking them as FAILED")
-                .action(ArgAction::SetTrue),
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Print more detail: -v for each file hashed and the options in effect, -vv for each file started too")
+                .global(true)
+                .action(ArgAction::Count),
         )
         .arg(
-            Arg::new("partial_bytes")
-                .long("partial-bytes")
-                .help("Number of bytes to read from start, middle, and end")
-                .value_name("N")
+            Arg::new("log_file")
+                .long("log-file")
+                .help("Also append diagnostics (retries, skips, errors, summaries) to PATH as timestamped key=value lines")
+                .env("GUSTASUM_LOG_FILE")
+                .global(true)
+                .value_name("PATH")
                 .num_args(1)
-                .default_value("100")
                 .action(ArgAction::Set),
         )
         .arg(
-            Arg::new("include_modtime")
-                .long("include-modtime")
-                .help("By default, modtime is NOT hashed. Use this flag if you explicitly want to include modtime.")
-                .action(ArgAction::SetTrue),
+            Arg::new("log")
+                .long("log")
+                .help("Also send diagnostics to the system log, at syslog priorities and tagged with the run's ID \
+                       (Unix only)")
+                .env("GUSTASUM_LOG")
+                .global(true)
+                .value_name("LOG")
+                .value_parser(["syslog", "journald"])
+                .num_args(1)
+                .action(ArgAction::Set),
         )
         .arg(
-            Arg::new("paths")
-                .help("Paths to process (directories/files)")
-                .num_args(1..)
-                .action(ArgAction::Append)
-                .required_unless_present("check"),
+            Arg::new("color")
+                .long("color")
+                .help("Color FAILED and errors red, warnings yellow, and OK green. 'auto' colors terminals unless NO_COLOR is set.")
+                .env("GUSTASUM_COLOR")
+                .global(true)
+                .value_name("WHEN")
+                .value_parser(["auto", "always", "never"])
+                .default_value("auto")
+                .action(ArgAction::Set),
         )
+        .arg_required_else_help(true)
         .after_help(
             "EXAMPLES:\n\
              1) Generate partial sums (NO modtime):\n\
-                gustasum some_directory > partialsums.txt\n\n\
+                gustasum generate some_directory > partialsums.txt\n\n\
              2) Verify partial sums:\n\
-                gustasum --check partialsums.txt\n\n\
+                gustasum check partialsums.txt\n\n\
              3) Remap old base to new base:\n\
-                gustasum --check partialsums.txt --remap /old/path /new/path\n\n\
+                gustasum check --remap /old/path /new/path partialsums.txt\n\n\
              4) If you used cp -p / cp -a (preserving modtime), add:\n\
-                gustasum --include-modtime some_directory > partialsums.txt\n\
-                gustasum --check partialsums.txt --include-modtime\n\n\
+                gustasum generate --include-modtime some_directory > partialsums.txt\n\
+                gustasum check --include-modtime partialsums.txt\n\n\
+             5) See what changed since last time, then refresh the manifest:\n\
+                gustasum generate some_directory > today.txt\n\
+                gustasum diff partialsums.txt today.txt\n\
+                gustasum update partialsums.txt some_directory > refreshed.txt\n\n\
              NOTE:\n\
-             - We skip creation time (birth time). If modtime isn't preserved (vanilla cp), you can rely solely on Gustasum's default setting."
+             - We skip creation time (birth time). If modtime isn't preserved (vanilla cp), you can rely solely on Gustasum's default setting.\n\
+             - The older forms `gustasum PATHS...` and `gustasum --check FILE` still work."
         )
-        .get_matches();
-
-    let skip_errors = matches.get_flag("skip_errors");
-    let remap_args = matches.get_many::<String>("remap");
-    let (old_base, new_base) = match remap_args {
-        Some(vals) => {
-            let vec: Vec<String> = vals.map(|s| s.to_string()).collect();
-            if vec.len() == 2 {
-                (Some(PathBuf::from(&vec[0])), Some(PathBuf::from(&vec[1])))
-            } else {
-                (None, None)
-            }
-        }
-        None => (None, None),
-    };
-
-    let partial_bytes_str = matches.get_one::<String>("partial_bytes").unwrap();
-    let partial_bytes = partial_bytes_str.parse::<usize>().unwrap_or(100);
-
-    // By default, we do NOT include modtime. If --include-modtime is set, we include it.
-    let include_modtime = matches.get_flag("include_modtime");
-
-    // Show progress if stderr is a TTY
-    let show_progress = atty::is(Stream::Stderr);
-
-    if let Some(check_file) = matches.get_one::<String>("check") {
-        verify_mode(
-            check_file,
-            skip_errors,
-            old_base,
-            new_base,
-            show_progress,
-            partial_bytes,
-            include_modtime,
-        );
-    } else if let Some(paths) = matches.get_many::<String>("paths") {
-        let path_vec: Vec<PathBuf> = paths.map(PathBuf::from).collect();
-        generate_mode(
-            &path_vec,
-            skip_errors,
-            show_progress,
-            partial_bytes,
-            include_modtime,
-        );
-    } else {
-        eprintln!("No paths provided and no check file specified. Use --help for usage.");
-        std::process::exit(1);
-    }
 }
 
-/// Generate checksums for all files in the given paths, ignoring modtime by default.
-/// Use `include_modtime = true` if the user provided --include-modtime.
-fn generate_mode(
-    paths: &[PathBuf],
-    skip_errors: bool,
-    show_progress: bool,
-    partial_bytes: usize,
-    include_modtime: bool,
-) {
-    let files: Vec<PathBuf> = paths
-        .iter()
-        .map(|p| p.canonicalize().unwrap_or_else(|_| p.to_path_buf()))
-        .flat_map(|p| {
-            WalkDir::new(p)
-                .follow_links(false)
-                .into_iter()
-                .filter_map(|entry| entry.ok())
-                .filter(|e| e.file_type().is_file())
-                .map(|e| e.path().to_path_buf())
-        })
-        .collect();
+/// Options that decide how each file is sampled and read; shared by every mode that hashes.
+fn hash_args() -> [Arg; 18] {
+    [
+        Arg::new("algorithm")
+            .long("algorithm")
+            .env("GUSTASUM_ALGORITHM")
+            .help("Digest algorithm (sha256, sha512; md5 and sha1 for checking and importing their checksum files with --full). When checking or updating, the one named in the manifest header is used.")
+            .value_name("NAME")
+            .num_args(1)
+            .default_value("sha256")
+            .action(ArgAction::Set),
+        Arg::new("partial_bytes")
+            .long("partial-bytes")
+            .env("GUSTASUM_PARTIAL_BYTES")
+            .help("Number of bytes to read from start, middle, and end")
+            .value_name("N")
+            .num_args(1)
+            .default_value("100")
+            .action(ArgAction::Set),
+        Arg::new("include_modtime")
+            .long("include-modtime")
+            .env("GUSTASUM_INCLUDE_MODTIME")
+            .help("By default, modtime is NOT hashed. Use this flag if you explicitly want to include modtime.")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("fat_compat")
+            .long("fat-compat")
+            .env("GUSTASUM_FAT_COMPAT")
+            .help("With --include-modtime, round modtimes down to 2-second granularity, as FAT32 and exFAT \
+                   store them, so copies on SD cards and USB sticks still verify. Use it for both generating and checking.")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("sparse")
+            .long("sparse")
+            .env("GUSTASUM_SPARSE")
+            .help("Sample sparse files (VM images, say) from their data, skipping holes, so the samples aren't \
+                   all zeros (Linux and FreeBSD, local files). A copy checked this way must have the same holes.")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("include_extents")
+            .long("include-extents")
+            .env("GUSTASUM_INCLUDE_EXTENTS")
+            .help("Hash each file's layout of data and holes too, so a copy that was silently made dense, \
+                   or sparse, fails even if its content matches (Linux and FreeBSD, local files).")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("full")
+            .long("full")
+            .env("GUSTASUM_FULL")
+            .help("Hash every byte of each file and nothing else, instead of sampling. The manifest is then \
+                   headerless and byte-compatible with sha256sum (or sha512sum), and checks their files; \
+                   give --full (and --algorithm) when checking it.")
+            .value_parser(BoolishValueParser::new())
+            .conflicts_with_all(["include_modtime", "sparse", "include_extents", "salt_file"])
+            .action(ArgAction::SetTrue),
+        Arg::new("salt_file")
+            .long("salt-file")
+            .env("GUSTASUM_SALT_FILE")
+            .help("Mix the contents of PATH, a secret, into every digest, so the manifest can be shared \
+                   without letting anyone match its hashes against guessed file contents. Checking needs \
+                   the same file.")
+            .value_name("PATH")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("abbrev")
+            .long("abbrev")
+            .env("GUSTASUM_ABBREV")
+            .help("Keep only the first N hex digits of each digest (at least 8), for compact manifests meant \
+                   for people. Refused if N is too short for the number of files; checking compares that many.")
+            .value_name("N")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("parallel_read_threshold")
+            .long("parallel-read-threshold")
+            .env("GUSTASUM_PARALLEL_READ_THRESHOLD")
+            .help("Files of at least this many bytes have their start, middle, and end read concurrently. \
+                   Helps on high-latency network filesystems.")
+            .value_name("BYTES")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("retries")
+            .long("retries")
+            .env("GUSTASUM_RETRIES")
+            .help("Number of times to retry a file after a transient I/O error")
+            .value_name("N")
+            .num_args(1)
+            .default_value("2")
+            .action(ArgAction::Set),
+        Arg::new("retry_delay")
+            .long("retry-delay")
+            .env("GUSTASUM_RETRY_DELAY")
+            .help("Delay before the first retry in milliseconds; doubles with each further attempt")
+            .value_name("MS")
+            .num_args(1)
+            .default_value("100")
+            .action(ArgAction::Set),
+        Arg::new("no_retry_network")
+            .long("no-retry-network")
+            .env("GUSTASUM_NO_RETRY_NETWORK")
+            .help("Don't retry stale handles and timeouts from network filesystems (ESTALE, ETIMEDOUT, \
+                   EAGAIN) by reopening the file; only retry failed reads")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("file_timeout")
+            .long("file-timeout")
+            .env("GUSTASUM_FILE_TIMEOUT")
+            .help("Give up on a file if hashing it takes longer than this many seconds \
+                   (e.g., a read stalled on a bad sector), record it as an error, and continue")
+            .value_name("SECONDS")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("reread_unstable")
+            .long("reread-unstable")
+            .env("GUSTASUM_REREAD_UNSTABLE")
+            .help("Re-read a file up to N times if its size or modtime changed while it was being hashed")
+            .value_name("N")
+            .num_args(1)
+            .default_value("0")
+            .action(ArgAction::Set),
+        Arg::new("archive")
+            .long("archive")
+            .env("GUSTASUM_ARCHIVE")
+            .help("Hash each member of archives of this kind ('tar' for *.tar files, 'zip' for *.zip) as \
+                   'ARCHIVE!MEMBER', reading it from the archive without extracting. Give it when checking too. Repeatable.")
+            .value_name("KIND")
+            .value_parser(["tar", "zip"])
+            .num_args(1)
+            .action(ArgAction::Append),
+        Arg::new("s3_endpoint")
+            .long("s3-endpoint")
+            .env("GUSTASUM_S3_ENDPOINT")
+            .help("For s3://BUCKET/KEY paths, the base URL of an S3-compatible service (e.g., http://minio:9000), \
+                   instead of AWS. Credentials and region come from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, \
+                   AWS_SESSION_TOKEN, and AWS_REGION.")
+            .value_name("URL")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("ssh_command")
+            .long("ssh-command")
+            .env("GUSTASUM_SSH_COMMAND")
+            .help("The ssh program to reach ssh://[USER@]HOST[:PORT]/PATH paths with. Logins, keys, \
+                   and host aliases come from its own configuration.")
+            .value_name("PROGRAM")
+            .num_args(1)
+            .default_value("ssh")
+            .action(ArgAction::Set),
+    ]
+}
 
-    let total_files = files.len();
-    eprintln!(
-        "Found {} files. Computing partial checksums...",
-        total_files
+/// Options for runs over many files: error policy and read scheduling.
+fn run_args() -> Vec<Arg> {
+    #[allow(unused_mut)]
+    let mut args = vec![
+        Arg::new("skip_errors")
+            .long("skip-errors")
+            .env("GUSTASUM_SKIP_ERRORS")
+            .help("Skip files that produce read/metadata errors instead of marking them as FAILED; \
+                   with =KINDS, only errors of those kinds, from permission, missing, and io \
+                   (e.g., --skip-errors=permission,missing)")
+            .value_name("KINDS")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("all")
+            .action(ArgAction::Set),
+        Arg::new("smart")
+            .long("smart")
+            .env("GUSTASUM_SMART")
+            .help("After read errors, ask smartctl for the health and pending, reallocated, and \
+                   uncorrectable sector counts of each disk they happened on, for the report")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("max_memory")
+            .long("max-memory")
+            .env("GUSTASUM_MAX_MEMORY")
+            .help("Keep memory use to about SIZE (e.g. 256M): half for read buffers, which reads wait \
+                   on once it's taken, and half for files listed before hashing, which a run stops at")
+            .value_name("SIZE")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("plan")
+            .long("plan")
+            .env("GUSTASUM_PLAN")
+            .help("Before hashing, list the filesystems the files are on, with each one's device, kind of \
+                   storage, file count, and size, and how many files are read at once")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("background")
+            .long("background")
+            .env("GUSTASUM_BACKGROUND")
+            .help("Run at the lowest CPU priority and in the idle I/O scheduling class, like nice -n 19 \
+                   ionice -c 3, so the run only takes the disk and CPU time nothing else wants")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("optimize_seek")
+            .long("optimize-seek")
+            .env("GUSTASUM_OPTIMIZE_SEEK")
+            .help("Query extent locations (FIEMAP) and read files one at a time in physical on-disk order. \
+                   Intended for rotational media, where random-order reads spend most of the time seeking.")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("order")
+            .long("order")
+            .env("GUSTASUM_ORDER")
+            .help("Start files largest-first (so no big file is left for the end, with one worker busy), \
+                   smallest-first, in path order (so a stopped run has done everything up to some path), \
+                   or in random order (to spread the reads over a striped volume's disks)")
+            .value_name("ORDER")
+            .value_parser(["largest-first", "smallest-first", "path", "random"])
+            .conflicts_with("optimize_seek")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("detect_clones")
+            .long("detect-clones")
+            .env("GUSTASUM_DETECT_CLONES")
+            .help("Find reflink clones (files sharing all their extents, via FIEMAP or F_LOG2PHYS_EXT) \
+                   and hash one file per group, recording the rest with its hash. With --include-modtime, \
+                   clones are still reported but each is hashed.")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("exclude")
+            .long("exclude")
+            .env("GUSTASUM_EXCLUDE")
+            .help("Skip files matching this glob. Without a '/', it matches file and directory names \
+                   (e.g., '*.tmp', '.git'); with one, whole paths. Repeatable.")
+            .value_name("PATTERN")
+            .num_args(1)
+            .action(ArgAction::Append),
+        Arg::new("skip_vcs_and_junk")
+            .long("skip-vcs-and-junk")
+            .env("GUSTASUM_SKIP_VCS_AND_JUNK")
+            .help("Also exclude version control directories, package caches, and trash: \
+                   .git, .svn, .hg, .bzr, CVS, _darcs, node_modules, __pycache__, .Trash*, .Trashes, $RECYCLE.BIN, and lost+found")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("junk")
+            .long("junk")
+            .env("GUSTASUM_JUNK")
+            .help("A pattern for --skip-vcs-and-junk to exclude instead of its own list. Repeatable.")
+            .value_name("PATTERN")
+            .num_args(1)
+            .action(ArgAction::Append),
+        Arg::new("progress")
+            .long("progress")
+            .env("GUSTASUM_PROGRESS")
+            .help("How to show progress: 'bar' draws a progress bar, 'plain' prints a status line every \
+                   --progress-interval seconds (for CI logs and nohup), 'json' writes an event per file \
+                   and a heartbeat per interval as JSON lines (for frontends), 'none' shows nothing. \
+                   'auto' draws the bar when stderr is a terminal.")
+            .value_name("STYLE")
+            .value_parser(["auto", "bar", "plain", "json", "none"])
+            .default_value("auto")
+            .action(ArgAction::Set),
+        Arg::new("progress_interval")
+            .long("progress-interval")
+            .env("GUSTASUM_PROGRESS_INTERVAL")
+            .help("Seconds between status lines with --progress plain, or heartbeats with --progress json")
+            .value_name("SECS")
+            .num_args(1)
+            .default_value("10")
+            .action(ArgAction::Set),
+        Arg::new("progress_file")
+            .long("progress-file")
+            .env("GUSTASUM_PROGRESS_FILE")
+            .help("Write --progress json events to PATH (e.g., a named pipe) instead of stderr")
+            .value_name("PATH")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("tui")
+            .long("tui")
+            .env("GUSTASUM_TUI")
+            .help("Show a full-screen dashboard instead of the progress bar: each worker's current file \
+                   and how long it's been reading it, the slowest files, an error tally, and throughput \
+                   graphs. Needs stderr to be a terminal.")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("max_errors")
+            .long("max-errors")
+            .env("GUSTASUM_MAX_ERRORS")
+            .help("Stop after N files fail (errors, mismatches, unstable files, or malformed lines), \
+                   finish the files in flight, and write what's done. A generated manifest is marked partial.")
+            .value_name("N")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("summary_file")
+            .long("summary-file")
+            .env("GUSTASUM_SUMMARY_FILE")
+            .help("Write OK/failed/error/skipped counts and bytes read per top-level directory \
+                   to PATH as tab-separated values")
+            .value_name("PATH")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("label")
+            .long("label")
+            .env("GUSTASUM_LABEL")
+            .help("Tag the run with KEY=VALUE (e.g., ticket=INC-1234), recorded in the manifest's \
+                   header, JSON reports, and the --db history. Repeatable.")
+            .value_name("KEY=VALUE")
+            .num_args(1)
+            .action(ArgAction::Append),
+        Arg::new("summary_json")
+            .long("summary-json")
+            .env("GUSTASUM_SUMMARY_JSON")
+            .help("When the run ends, write its totals (ok, failed, missing, errors, skipped, bytes, \
+                   duration, and exit code) to PATH as JSON, whatever is shown on the console")
+            .value_name("PATH")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("metrics_textfile")
+            .long("metrics-textfile")
+            .env("GUSTASUM_METRICS_TEXTFILE")
+            .help("When the run ends, write its file counts, bytes read, duration, and finish time to PATH \
+                   as Prometheus metrics, for node_exporter's textfile collector")
+            .value_name("PATH")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("metrics_listen")
+            .long("metrics-listen")
+            .env("GUSTASUM_METRICS_LISTEN")
+            .help("While the run lasts, serve its live progress as Prometheus metrics over HTTP on ADDR \
+                   (e.g., 127.0.0.1:9469), for scraping a long scrub")
+            .value_name("ADDR")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("notify_webhook")
+            .long("notify-webhook")
+            .env("GUSTASUM_NOTIFY_WEBHOOK")
+            .help("When the run ends, POST a JSON summary and the list of failed files to URL")
+            .value_name("URL")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("notify_email")
+            .long("notify-email")
+            .env("GUSTASUM_NOTIFY_EMAIL")
+            .help("When the run ends, mail a summary and the list of failed files to ADDR, \
+                   through sendmail. Repeatable.")
+            .value_name("ADDR")
+            .num_args(1)
+            .action(ArgAction::Append),
+        Arg::new("notify_threshold")
+            .long("notify-threshold")
+            .env("GUSTASUM_NOTIFY_THRESHOLD")
+            .help("Also notify as soon as N files have failed, without waiting for the run to end")
+            .value_name("N")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("threads")
+            .short('j')
+            .long("threads")
+            .env("GUSTASUM_JOBS")
+            .help("Number of worker threads (default: one per CPU)")
+            .value_name("N")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("io_concurrency")
+            .long("io-concurrency")
+            .env("GUSTASUM_IO_CONCURRENCY")
+            .help(
+                "Files read at once, each on a thread of its own, while no more than --threads \
+                   compute digests. For NFS, CIFS, and other filesystems where each read waits on the network.",
+            )
+            .value_name("N")
+            .num_args(1)
+            .action(ArgAction::Set),
+    ];
+    #[cfg(unix)]
+    args.push(
+        Arg::new("snapshot")
+            .long("snapshot")
+            .env("GUSTASUM_SNAPSHOT")
+            .help("Read files from read-only snapshots of the btrfs subvolumes, LVM volumes, or ZFS datasets \
+                   they're on, so a changing tree is hashed as of one moment. Paths are recorded as the live ones, \
+                   and snapshots are deleted at exit. Needs root.")
+            .value_parser(["btrfs", "lvm", "zfs"])
+            .action(ArgAction::Set),
     );
-
-    let pb = if show_progress {
-        let bar = ProgressBar::new(total_files as u64);
-        bar.set_draw_target(ProgressDrawTarget::stderr());
-        bar.set_style(
-            ProgressStyle::with_template(
-                "{spinner} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} files ({eta} remaining)",
+    #[cfg(windows)]
+    args.push(
+        Arg::new("include_ads")
+            .long("include-ads")
+            .env("GUSTASUM_INCLUDE_ADS")
+            .help("Also hash each file's NTFS alternate data streams, recorded as 'path:stream' entries")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+    );
+    #[cfg(windows)]
+    args.extend([
+        Arg::new("skip_locked")
+            .long("skip-locked")
+            .env("GUSTASUM_SKIP_LOCKED")
+            .help("Skip files another program has open without sharing or locked (e.g., Outlook PSTs) \
+                   instead of marking them as FAILED")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("vss")
+            .long("vss")
+            .env("GUSTASUM_VSS")
+            .help("Read files from Volume Shadow Copy snapshots of their volumes, so in-use and locked \
+                   files are hashed as of one moment. Snapshots are deleted at exit. Needs an elevated prompt.")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+    ]);
+    #[cfg(target_os = "macos")]
+    args.extend([
+        Arg::new("include_rsrc")
+            .long("include-rsrc")
+            .env("GUSTASUM_INCLUDE_RSRC")
+            .help(
+                "Also hash each file's resource fork and Finder info, recorded as \
+                   'path/..namedfork/rsrc' and 'path/..xattr/com.apple.FinderInfo' entries",
             )
-            .unwrap()
-            .progress_chars("=>-"),
-        );
-        Some(bar)
-    } else {
-        None
-    };
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("include_quarantine")
+            .long("include-quarantine")
+            .env("GUSTASUM_INCLUDE_QUARANTINE")
+            .help("With --include-rsrc, also hash the com.apple.quarantine attribute")
+            .requires("include_rsrc")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+    ]);
+    #[cfg(feature = "async-io")]
+    args.extend([
+        Arg::new("async_io")
+            .long("async-io")
+            .env("GUSTASUM_ASYNC_IO")
+            .help(
+                "Hash on the tokio backend with many files in flight at once. \
+                   For network filesystems where latency, not bandwidth, limits throughput.",
+            )
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("concurrency")
+            .long("concurrency")
+            .env("GUSTASUM_CONCURRENCY")
+            .help("Files in flight with --async-io")
+            .value_name("N")
+            .num_args(1)
+            .default_value("256")
+            .action(ArgAction::Set),
+    ]);
+    args
+}
 
-    let mut results = Vec::with_capacity(total_files);
+fn remap_def() -> Arg {
+    Arg::new("remap")
+        .long("remap")
+        .env("GUSTASUM_REMAP")
+        .help(
+            "Remaps old base path to new base path during verification. \
+               E.g., --remap OLD_BASE NEW_BASE, or --remap 'C:\\data' /mnt/data for a manifest made on Windows",
+        )
+        .num_args(2)
+        .value_names(["OLD_BASE", "NEW_BASE"])
+        .action(ArgAction::Set)
+}
 
-    results.par_extend(files.par_iter().map(|path| {
-        let hash_result = compute_hash_for_file(path, partial_bytes, include_modtime);
-        if let Some(ref bar) = pb {
-            bar.inc(1);
-        }
-        (path.clone(), hash_result)
-    }));
+fn url_base_def() -> Arg {
+    Arg::new("url_base")
+        .long("url-base")
+        .help(
+            "Check the files at URL instead: the directory all entries share maps to URL, \
+               e.g., --url-base https://mirror.example.org/releases to verify a published copy",
+        )
+        .value_name("URL")
+        .conflicts_with("remap")
+        .action(ArgAction::Set)
+}
 
-    if let Some(ref bar) = pb {
-        bar.finish_and_clear();
-    }
+fn on_fail_def() -> Arg {
+    Arg::new("on_fail")
+        .long("on-fail")
+        .env("GUSTASUM_ON_FAIL")
+        .help(
+            "Set aside files that fail verification (mismatched or unstable): 'move:DIR' moves them \
+               under DIR with their paths kept, 'rename-suffix:SUFFIX' renames them in place \
+               (e.g., rename-suffix:.corrupt). Existing files are never replaced.",
+        )
+        .value_name("ACTION")
+        .num_args(1)
+        .action(ArgAction::Set)
+}
 
-    let mut successes = 0usize;
-    let mut failures = 0usize;
+/// Where `gustasum par2` keeps recovery sets, and the `par2` it runs.
+fn par2_args() -> [Arg; 2] {
+    [
+        Arg::new("par2_dir")
+            .long("par2-dir")
+            .env("GUSTASUM_PAR2_DIR")
+            .help("Directory of recovery sets, one per file at its path plus '.par2' (default: MANIFEST.par2)")
+            .value_name("DIR")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("par2_bin")
+            .long("par2-bin")
+            .env("GUSTASUM_PAR2")
+            .help("The par2 program to run")
+            .value_name("PROGRAM")
+            .num_args(1)
+            .default_value("par2")
+            .action(ArgAction::Set),
+    ]
+}
 
-    for (path, result) in results {
-        match result {
-            Ok(hash) => {
-                // output to stdout
-                println!("{}  {}", hash, path.display());
-                successes += 1;
-            }
-            Err(e) => {
-                if skip_errors {
-                    eprintln!("Warning: Skipping file '{}': {}", path.display(), e);
-                } else {
-                    eprintln!("Error: Could not process file '{}': {}", path.display(), e);
-                }
-                failures += 1;
-            }
-        }
-    }
+fn db_def() -> Arg {
+    Arg::new("db")
+        .long("db")
+        .env("GUSTASUM_DB")
+        .help("Append each file's result to this verification history (see 'gustasum history')")
+        .value_name("FILE")
+        .num_args(1)
+        .action(ArgAction::Set)
+}
 
-    eprintln!(
-        "\nSummary: total files = {}, succeeded = {}, errors = {}",
-        total_files, successes, failures
-    );
+fn group_output_def() -> Arg {
+    Arg::new("group_output")
+        .long("group-output")
+        .env("GUSTASUM_GROUP_OUTPUT")
+        .help("Print the failures again, all together, before the summary")
+        .value_parser(BoolishValueParser::new())
+        .action(ArgAction::SetTrue)
+}
 
-    if failures > 0 && !skip_errors {
-        std::process::exit(1);
-    }
+/// `--transition` and `--transition-output`, for moving a manifest to another algorithm as
+/// it's checked.
+fn transition_defs() -> [Arg; 2] {
+    [
+        Arg::new("transition")
+            .long("transition")
+            .env("GUSTASUM_TRANSITION")
+            .help("Check a manifest of the OLD algorithm and, from the same reads, write one of the \
+                   files that verify OK with the NEW one, its other settings the same")
+            .value_name("OLD:NEW")
+            .num_args(1)
+            .conflicts_with_all(["metadata_only", "changed_only", "two_phase", "cursor"])
+            .action(ArgAction::Set),
+        Arg::new("transition_output")
+            .long("transition-output")
+            .env("GUSTASUM_TRANSITION_OUTPUT")
+            .help("Where --transition writes the new manifest (default: MANIFEST.NEW, such as checksums.txt.sha512)")
+            .value_name("FILE")
+            .num_args(1)
+            .requires("transition")
+            .action(ArgAction::Set),
+    ]
 }
 
-/// Verify checksums from `--check`, with optional path remapping & modtime usage.
-#[allow(non_snake_case)]
-fn verify_mode(
-    check_file: &str,
-    skip_errors: bool,
-    old_base: Option<PathBuf>,
-    new_base: Option<PathBuf>,
-    show_progress: bool,
-    partial_bytes: usize,
-    include_modtime: bool,
-) {
-    let contents = match fs::read_to_string(check_file) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Failed to read check file '{}': {}", check_file, e);
-            std::process::exit(1);
-        }
-    };
+fn emit_rsync_list_def() -> Arg {
+    Arg::new("emit_rsync_list")
+        .long("emit-rsync-list")
+        .env("GUSTASUM_EMIT_RSYNC_LIST")
+        .value_name("FILE")
+        .num_args(1)
+        .action(ArgAction::Set)
+}
 
-    let lines: Vec<&str> = contents
-        .lines()
-        .map(|l| l.trim())
-        .filter(|l| !l.is_empty())
-        .collect();
+fn oldest_first_def() -> Arg {
+    Arg::new("oldest_first")
+        .long("oldest-first")
+        .env("GUSTASUM_OLDEST_FIRST")
+        .help("Check files in order of when --db last verified them, never-verified files first")
+        .requires("db")
+        .value_parser(BoolishValueParser::new())
+        .action(ArgAction::SetTrue)
+}
 
-    let total_lines = lines.len();
-    eprintln!("Found {} checks to perform. Verifying...", total_lines);
+fn known_failures_def() -> Arg {
+    Arg::new("known_failures")
+        .long("known-failures")
+        .env("GUSTASUM_KNOWN_FAILURES")
+        .help(
+            "Paths known to fail already, one per line as the manifest lists them: their failures \
+               are reported as known, counted apart, and don't make the run exit 1",
+        )
+        .value_name("FILE")
+        .num_args(1)
+        .action(ArgAction::Set)
+}
 
-    let pb = if show_progress {
-        let bar = ProgressBar::new(total_lines as u64);
-        bar.set_draw_target(ProgressDrawTarget::stderr());
-        bar.set_style(
-            ProgressStyle::with_template(
-                "{spinner} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} lines ({eta} remaining)",
+/// Options for runs that have to fit a fixed window.
+fn time_limit_defs() -> [Arg; 2] {
+    [
+        Arg::new("max_duration")
+            .long("max-duration")
+            .env("GUSTASUM_MAX_DURATION")
+            .help("Stop starting files after this long (e.g., 2h, 90m, 1h30m) and finish the run normally. \
+                   With --cursor, the next run picks up where this one stopped; with --oldest-first and --db, \
+                   each night's scrub picks up the stalest files.")
+            .value_name("DURATION")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("cursor")
+            .long("cursor")
+            .env("GUSTASUM_CURSOR")
+            .help("Go through files in path order, starting after the path recorded in FILE if it exists. \
+                   A run that stops early (--max-duration, --max-errors, Ctrl-C) records the last path it got to; \
+                   one that gets to the end removes FILE.")
+            .value_name("FILE")
+            .num_args(1)
+            .action(ArgAction::Set),
+    ]
+}
+
+/// Guards against walking far more than was meant to be hashed.
+fn scan_limit_defs() -> [Arg; 2] {
+    [
+        Arg::new("max_files")
+            .long("max-files")
+            .env("GUSTASUM_MAX_FILES")
+            .help(
+                "Once more than N files have been found, ask on the terminal whether to go on, \
+                   or without one, stop with an error, so an accidental 'gustasum /' ends early",
             )
-            .unwrap()
-            .progress_chars("=>-"),
-        );
-        Some(bar)
-    } else {
-        None
-    };
+            .value_name("N")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("max_bytes")
+            .long("max-bytes")
+            .env("GUSTASUM_MAX_BYTES")
+            .help(
+                "Likewise, once the files found add up to more than SIZE (e.g., 500G, 2T; \
+                   K, M, G, and T are powers of 1024)",
+            )
+            .value_name("SIZE")
+            .num_args(1)
+            .action(ArgAction::Set),
+    ]
+}
 
-    let mut results = Vec::with_capacity(total_lines);
-    results.par_extend(lines.par_iter().map(|line| {
-        let (expected_hash, file_str) = match split_line(line) {
-            Some(x) => x,
-            None => {
-                if let Some(ref bar) = pb {
-                    bar.inc(1);
-                }
-                return (
-                    "".to_string(),
-                    line.to_string(),
-                    Err("Malformed line".to_string()),
-                );
-            }
-        };
+/// How the paths walked under PATHS are written into the manifest.
+fn path_style_defs() -> [Arg; 2] {
+    [
+        Arg::new("path_style")
+            .long("paths")
+            .env("GUSTASUM_PATHS")
+            .help(
+                "Record paths under each of PATHS as-given (relative if it was), absolute \
+                   (symlinks on the way to it kept), or canonical (symlinks resolved; the default, \
+                   except with --full, which prints them as given)",
+            )
+            .value_name("STYLE")
+            .value_parser(["as-given", "absolute", "canonical"])
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("no_canonicalize")
+            .long("no-canonicalize")
+            .env("GUSTASUM_NO_CANONICALIZE")
+            .help("Same as --paths absolute: keep a symlinked root's own path in the manifest")
+            .value_parser(BoolishValueParser::new())
+            .conflicts_with("path_style")
+            .action(ArgAction::SetTrue),
+    ]
+}
 
-        let original_path = PathBuf::from(&file_str);
-        let remapped = match (&old_base, &new_base) {
-            (Some(ob), Some(nb)) => remap_path(&original_path, ob, nb),
-            _ => original_path.clone(),
-        };
+fn record_metadata_def() -> Arg {
+    Arg::new("record_metadata")
+        .long("record-metadata")
+        .env("GUSTASUM_RECORD_METADATA")
+        .help("Record each file's size, modtime, and a short digest of each region read in a comment above its entry, \
+               for 'check --metadata-only' and --changed-only, and so failed checks can say what changed")
+        .value_parser(BoolishValueParser::new())
+        .action(ArgAction::SetTrue)
+}
 
-        let hash_result = compute_hash_for_file(&remapped, partial_bytes, include_modtime);
+fn note_def() -> Arg {
+    Arg::new("note")
+        .long("note")
+        .env("GUSTASUM_NOTE")
+        .help("Annotate each entry with TEXT (e.g., \"from tape LTO-041\") in a comment above it, \
+               kept when the manifest is updated, appended to, or normalized, and shown when the file fails a check")
+        .value_name("TEXT")
+        .num_args(1)
+        .action(ArgAction::Set)
+}
 
-        if let Some(ref bar) = pb {
-            bar.inc(1);
-        }
+/// Hashing ssh:// paths with gustasum on the remote host, for `generate`.
+fn remote_gustasum_def() -> Arg {
+    Arg::new("remote_gustasum")
+        .long("remote-gustasum")
+        .help(
+            "When every PATH is an ssh:// path on one host, run this gustasum there to hash the files \
+               in place, if it's the same version, and stream its manifest back. Otherwise, or with \
+               'none', the files are read over SFTP.",
+        )
+        .value_name("PROGRAM")
+        .num_args(1)
+        .default_value("gustasum")
+        .action(ArgAction::Set)
+}
 
-        (expected_hash, file_str.to_string(), hash_result)
-    }));
+/// Checks that use the sizes and modtimes recorded by `--record-metadata`.
+fn metadata_check_defs() -> [Arg; 5] {
+    [
+        Arg::new("metadata_only")
+            .long("metadata-only")
+            .help("Compare each file's size (and modtime, with --include-modtime) to the manifest without reading any content")
+            .conflicts_with("changed_only")
+            .action(ArgAction::SetTrue),
+        Arg::new("changed_only")
+            .long("changed-only")
+            .help("Verify the content of only the files whose size or modtime differs from the manifest, or wasn't recorded; \
+                   with --db, of only those whose size, modtime, or inode changed since they last verified OK")
+            .action(ArgAction::SetTrue),
+        Arg::new("force_all")
+            .long("force-all")
+            .help("Verify every file after all, overriding --changed-only (from a configuration file or \
+                   an alias), for the periodic full pass")
+            .action(ArgAction::SetTrue),
+        Arg::new("two_phase")
+            .long("two-phase")
+            .env("GUSTASUM_TWO_PHASE")
+            .help("Verify files whose size or modtime differs from the manifest (or wasn't recorded) first, \
+                   then only a --sample of the unchanged ones (the stalest, with --oldest-first)")
+            .conflicts_with_all(["metadata_only", "changed_only"])
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("sample")
+            .long("sample")
+            .env("GUSTASUM_SAMPLE")
+            .help("With --two-phase, the percentage of files with unchanged metadata to verify")
+            .value_name("PERCENT")
+            .num_args(1)
+            .default_value("10")
+            .action(ArgAction::Set),
+    ]
+}
 
-    if let Some(ref bar) = pb {
-        bar.finish_and_clear();
-    }
+fn repair_defs() -> [Arg; 2] {
+    [
+        Arg::new("repair_from")
+            .long("repair-from")
+            .env("GUSTASUM_REPAIR_FROM")
+            .help(
+                "When a file fails verification, hash its copy under MIRROR_DIR (at the same path \
+                   relative to the --remap base, or to the directory all entries share) and, if that \
+                   matches the manifest, copy it over the damaged file",
+            )
+            .value_name("MIRROR_DIR")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("dry_run")
+            .long("dry-run")
+            .env("GUSTASUM_DRY_RUN")
+            .help("With --repair-from or --on-fail, report what would be repaired or moved without changing any files")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+    ]
+}
 
-    let mut ok_count = 0usize;
-    let mut fail_count = 0usize;
+fn signature_defs() -> [Arg; 2] {
+    [
+        Arg::new("signature")
+            .long("signature")
+            .env("GUSTASUM_SIGNATURE")
+            .help(
+                "Before checking, verify the manifest against this detached OpenPGP signature \
+                   (a file or an http:// or https:// URL) with gpg, and stop if it doesn't verify",
+            )
+            .value_name("SIG")
+            .num_args(1)
+            .action(ArgAction::Set),
+        Arg::new("signing_key")
+            .long("signing-key")
+            .env("GUSTASUM_SIGNING_KEY")
+            .help(
+                "Trust only the keys in KEYRING (e.g., from gpg --export) for --signature, checking \
+                   with gpgv instead of gpg and your keyring. Without --signature, the signature is \
+                   MANIFEST.asc.",
+            )
+            .value_name("KEYRING")
+            .num_args(1)
+            .action(ArgAction::Set),
+    ]
+}
 
-    for (expected, original_path, actual_res) in results {
-        match actual_res {
-            Ok(actual_hash) => {
-                if actual_hash == expected {
-                    println!("{}: OK", original_path);
-                    ok_count += 1;
-                } else {
-                    eprintln!("{}: FAILED (mismatch)", original_path);
-                    fail_count += 1;
-                }
-            }
-            Err(e) => {
-                fail_count += 1;
-                if skip_errors {
-                    eprintln!("Warning: Skipping file '{}': {}", original_path, e);
-                } else {
-                    eprintln!("{}: FAILED to compute hash ({})", original_path, e);
-                }
-            }
-        }
-    }
+fn append_defs() -> [Arg; 4] {
+    [
+        Arg::new("append")
+            .long("append")
+            .env("GUSTASUM_APPEND")
+            .help("Hash only the files under PATHS and add them to MANIFEST, in place, with the settings it records. \
+                   Refuses files it lists already, unless --replace is given.")
+            .value_name("MANIFEST")
+            .num_args(1)
+            .conflicts_with_all(["structure_only", "anonymize_paths"])
+            .action(ArgAction::Set),
+        Arg::new("replace")
+            .long("replace")
+            .env("GUSTASUM_REPLACE")
+            .help("With --append, hash files MANIFEST lists already again, replacing their entries")
+            .requires("append")
+            .value_parser(BoolishValueParser::new())
+            .action(ArgAction::SetTrue),
+        Arg::new("skip_existing")
+            .long("skip-existing")
+            .env("GUSTASUM_SKIP_EXISTING")
+            .help("Print entries only for files under PATHS that MANIFEST doesn't list, with the settings it records. \
+                   Nothing is compared: listed files are skipped without a look.")
+            .value_name("MANIFEST")
+            .num_args(1)
+            .conflicts_with_all(["append", "structure_only", "anonymize_paths"])
+            .action(ArgAction::Set),
+        Arg::new("within")
+            .long("within")
+            .env("GUSTASUM_WITHIN")
+            .help("Hash the files MANIFEST lists, the ones under PATHS if any are given, instead of walking, \
+                   and print a new manifest of them with this run's settings. Their notes are kept.")
+            .value_name("MANIFEST")
+            .num_args(1)
+            .conflicts_with_all([
+                "append",
+                "skip_existing",
+                "structure_only",
+                "path_style",
+                "no_canonicalize",
+            ])
+            .action(ArgAction::Set),
+    ]
+}
 
-    eprintln!(
-        "\nSummary: total checks = {}, OK = {}, FAILED = {}",
-        total_lines, ok_count, fail_count
-    );
+/// `--stdin` and `--size-hint`, for hashing a stream.
+fn stdin_defs() -> [Arg; 2] {
+    [
+        Arg::new("stdin")
+            .long("stdin")
+            .help("Hash the data piped in, as a file of that content would be hashed, and print its entry as '-'")
+            .conflicts_with("paths")
+            .action(ArgAction::SetTrue),
+        Arg::new("size_hint")
+            .long("size-hint")
+            .help("The size of the data piped in to --stdin, so it can be sampled as a file would be; \
+                   without it, all of it is hashed, as with --full. A stream of any other size is an error.")
+            .value_name("BYTES")
+            .value_parser(clap::value_parser!(u64))
+            .requires("stdin")
+            .num_args(1)
+            .action(ArgAction::Set),
+    ]
+}
 
-    if fail_count > 0 && !skip_errors {
-        std::process::exit(1);
-    }
+fn structure_only_def() -> Arg {
+    Arg::new("structure_only")
+        .long("structure-only")
+        .env("GUSTASUM_STRUCTURE_ONLY")
+        .help("Record only what's there: every file with its size, directory, and symlink, reading no content. \
+               Checking the manifest takes seconds; a first pass after a large copy.")
+        .value_parser(BoolishValueParser::new())
+        .action(ArgAction::SetTrue)
 }
 
-/// Split a line "<hash>  <path>" into (hash, path).
-fn split_line(line: &str) -> Option<(String, String)> {
-    if let Some(idx) = line.find("  ") {
-        let (hash, path) = line.split_at(idx);
-        let path = &path[2..];
-        Some((hash.to_string(), path.to_string()))
-    } else {
-        None
-    }
+fn anonymize_paths_def() -> Arg {
+    Arg::new("anonymize_paths")
+        .long("anonymize-paths")
+        .env("GUSTASUM_ANONYMIZE_PATHS")
+        .help("Replace every name in the manifest's paths with a hash of it keyed with the contents of KEYFILE, \
+               keeping the directory structure, so it can be shared without giving away the names. \
+               'gustasum deanonymize' puts them back, given the key and the files.")
+        .value_name("KEYFILE")
+        .num_args(1)
+        .action(ArgAction::Set)
 }
 
-/// Remap path if it starts with `old_base`.
-fn remap_path(original: &Path, old_base: &Path, new_base: &Path) -> PathBuf {
-    if original.starts_with(old_base) {
-        if let Ok(stripped) = original.strip_prefix(old_base) {
-            return new_base.join(stripped);
-        }
-    }
-    original.to_path_buf()
+fn paths_def() -> Arg {
+    Arg::new("paths")
+        .help("Paths to process (directories/files)")
+        .num_args(1..)
+        .action(ArgAction::Append)
 }
 
-/// The number of times to retry on a read error (e.g., flakey HDD).
-const READ_RETRIES: usize = 2;
+fn paths_arg(matches: &ArgMatches) -> Vec<PathBuf> {
+    matches
+        .get_many::<String>("paths")
+        .into_iter()
+        .flatten()
+        .map(PathBuf::from)
+        .collect()
+}
 
-/// Compute partial file hash. By default, we skip modtime. If `include_modtime` is true, we add modtime.
-fn compute_hash_for_file(
-    path: &Path,
-    partial_bytes: usize,
-    include_modtime: bool,
-) -> Result<String, String> {
-    let mut attempts = 0;
-    loop {
-        attempts += 1;
-        let res = do_compute_hash_for_file(path, partial_bytes, include_modtime);
-        match res {
-            Ok(h) => return Ok(h),
-            Err(e) => {
-                if attempts <= READ_RETRIES && is_transient_read_error(&e) {
-                    eprintln!("Retrying file '{}': {}", path.display(), e);
-                    continue;
-                }
-                return Err(e);
-            }
-        }
+/// Read the config files unless `--no-config` was given, warning about keys that aren't options.
+fn load_config(matches: &ArgMatches) -> Config {
+    if matches.get_flag("no_config") {
+        return Config::default();
+    }
+    let config = Config::load().unwrap_or_else(|e| {
+        error!("Error: {}", e);
+        std::process::exit(1);
+    });
+    for path in Config::default_paths().iter().filter(|p| p.is_file()) {
+        verbose!("Using config file '{}'", path.display());
     }
+    let known: Vec<String> = CONFIG_OPTIONS
+        .iter()
+        .chain(&["color"])
+        .map(|id| config_key(id))
+        .collect();
+    for key in config
+        .keys()
+        .filter(|k| !known.iter().any(|known| known == k))
+    {
+        warning!("Warning: Ignoring unknown config option '{}'", key);
+    }
+    config
 }
 
-fn do_compute_hash_for_file(
-    path: &Path,
-    partial_bytes: usize,
-    include_modtime: bool,
-) -> Result<String, String> {
-    let meta = fs::metadata(path).map_err(|e| format!("metadata error: {}", e))?;
-    let size = meta.len();
-
-    // We never include creation time on Linux, it's too unreliable.
+/// The arguments [`options_from`] reads, which are the ones a config file can set.
+const CONFIG_OPTIONS: &[&str] = &[
+    "abbrev",
+    "algorithm",
+    "anonymize_paths",
+    "archive",
+    #[cfg(feature = "async-io")]
+    "async_io",
+    "background",
+    "changed_only",
+    #[cfg(feature = "async-io")]
+    "concurrency",
+    "cursor",
+    "detect_clones",
+    "exclude",
+    "fat_compat",
+    "file_timeout",
+    "force_all",
+    "full",
+    "group_output",
+    #[cfg(windows)]
+    "include_ads",
+    "include_extents",
+    "include_modtime",
+    #[cfg(target_os = "macos")]
+    "include_quarantine",
+    #[cfg(target_os = "macos")]
+    "include_rsrc",
+    "io_concurrency",
+    "junk",
+    "known_failures",
+    "label",
+    "max_bytes",
+    "max_errors",
+    "max_files",
+    "max_memory",
+    "metadata_only",
+    "metrics_listen",
+    "metrics_textfile",
+    "no_canonicalize",
+    "no_retry_network",
+    "note",
+    "notify_email",
+    "notify_threshold",
+    "notify_webhook",
+    "optimize_seek",
+    "order",
+    "parallel_read_threshold",
+    "partial_bytes",
+    "path_style",
+    "plan",
+    "progress",
+    "progress_file",
+    "progress_interval",
+    "record_metadata",
+    "reread_unstable",
+    "retries",
+    "retry_delay",
+    "s3_endpoint",
+    "salt_file",
+    "sample",
+    "skip_errors",
+    #[cfg(windows)]
+    "skip_locked",
+    "skip_vcs_and_junk",
+    "smart",
+    "snapshot",
+    "sparse",
+    "ssh_command",
+    "structure_only",
+    "summary_file",
+    "summary_json",
+    "threads",
+    "transition",
+    "transition_output",
+    "tui",
+    "two_phase",
+    #[cfg(windows)]
+    "vss",
+];
 
-    // If user wants to include modtime and it's available, hash it. Otherwise, set to 0.
-    let mod_time_secs = if include_modtime {
-        meta.modified()
-            .unwrap_or(std::time::SystemTime::UNIX_EPOCH)
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
-    } else {
-        0
-    };
+/// `s` as the number `--<option>` takes, or exit with an error: a value that can't be used
+/// mustn't quietly become the default.
+fn whole_number<T: std::str::FromStr>(option: &str, s: &str) -> T {
+    s.parse().unwrap_or_else(|_| {
+        error!(
+            "Error: invalid --{} '{}' (expected a whole number)",
+            option, s
+        );
+        std::process::exit(1);
+    })
+}
 
-    // File reading
-    let file = fs::File::open(path).map_err(|e| format!("file open error: {}", e))?;
-    let mut reader = BufReader::new(file);
-
-    let mut first_buf = vec![0u8; partial_bytes];
-    let mut middle_buf = vec![0u8; partial_bytes];
-    let mut last_buf = vec![0u8; partial_bytes];
-
-    // First
-    let first_len = reader
-        .read(&mut first_buf)
-        .map_err(|e| format!("read error (first bytes): {}", e))?;
-    first_buf.truncate(first_len);
-
-    // Middle
-    if size > (partial_bytes as u64 * 2) {
-        let mid_offset = size / 2;
-        reader
-            .seek(SeekFrom::Start(mid_offset))
-            .map_err(|e| format!("seek error (middle): {}", e))?;
-        let middle_len = reader
-            .read(&mut middle_buf)
-            .map_err(|e| format!("read error (middle bytes): {}", e))?;
-        middle_buf.truncate(middle_len);
-    } else {
-        middle_buf.clear();
-    }
-
-    // Last
-    if size > partial_bytes as u64 {
-        let end_offset = size.saturating_sub(partial_bytes as u64);
-        reader
-            .seek(SeekFrom::Start(end_offset))
-            .map_err(|e| format!("seek error (end): {}", e))?;
-        let last_len = reader
-            .read(&mut last_buf)
-            .map_err(|e| format!("read error (last bytes): {}", e))?;
-        last_buf.truncate(last_len);
-    } else {
-        last_buf.clear();
+/// The config file key for argument `id`: its option name without the dashes.
+fn config_key(id: &str) -> String {
+    debug_assert!(
+        CONFIG_OPTIONS.contains(&id) || id == "color",
+        "'{}' isn't in CONFIG_OPTIONS",
+        id
+    );
+    match id {
+        "path_style" => "paths".to_string(),
+        _ => id.replace('_', "-"),
     }
+}
+
+/// Build the run options from whichever command was invoked. Each option comes from the
+/// command line if given there, else its `GUSTASUM_*` environment variable, else the config
+/// files, else its default. Arguments a command doesn't define (e.g., `--skip-errors` for
+/// bench) take their defaults.
+fn options_from(matches: &ArgMatches, config: &Config) -> Options {
+    let on_command_line = |id: &str| {
+        matches.try_contains_id(id).is_ok()
+            && matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+    };
+    let flag = |id: &str| {
+        if on_command_line(id) {
+            // An environment variable can also say "false".
+            return matches.get_flag(id);
+        }
+        config.get(&config_key(id)) == Some("true")
+    };
+    let value = |id: &str| -> Option<String> {
+        let from_matches = matches.try_get_one::<String>(id).ok().flatten().cloned();
+        if on_command_line(id) {
+            return from_matches;
+        }
+        config
+            .get(&config_key(id))
+            .map(str::to_string)
+            .or(from_matches)
+    };
+    let values = |id: &str| -> Vec<String> {
+        if on_command_line(id) {
+            return matches
+                .get_many::<String>(id)
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect();
+        }
+        config.get_all(&config_key(id)).unwrap_or_default().to_vec()
+    };
 
-    // Combine data
-    let mut hasher = Sha256::new();
+    let partial_bytes = value("partial_bytes").map_or(100, |s| whole_number("partial-bytes", &s));
 
-    // Possibly zero or actual mod time
-    hasher.update(mod_time_secs.to_le_bytes());
+    // By default, we do NOT include modtime. If --include-modtime is set, we include it.
+    let include_modtime = flag("include_modtime");
 
-    // file size
-    hasher.update(size.to_le_bytes());
+    let retries = value("retries").map_or(2, |s| whole_number("retries", &s));
+    let retry_delay_ms = value("retry_delay").map_or(100, |s| whole_number("retry-delay", &s));
 
-    // partial contents
-    hasher.update(&first_buf);
-    hasher.update(&middle_buf);
-    hasher.update(&last_buf);
+    let algorithm =
+        value("algorithm").map_or(Ok(algorithm::SHA256), |name| algorithm::lookup(&name));
+    let algorithm = algorithm.unwrap_or_else(|e| {
+        error!("Error: {}", e);
+        std::process::exit(1);
+    });
 
-    let final_hash = hasher.finalize();
-    Ok(format!("{:x}", final_hash))
-}
+    let salt = value("salt_file").map(|path| hasher::Salt::new(read_secret(&path, "salt")));
+    let abbrev = value("abbrev").map(|n| match n.parse::<usize>() {
+        Ok(n) if n >= MIN_ABBREV => n,
+        _ => {
+            error!(
+                "Error: invalid --abbrev '{}': expected a number of hex digits, at least {}",
+                n, MIN_ABBREV
+            );
+            std::process::exit(1);
+        }
+    });
 
-/// Check if an error is possibly transient (e.g., read error from failing HDD).
-fn is_transient_read_error(err: &str) -> bool {
-    err.contains("read error") || err.contains("I/O error") || err.contains("EIO")
+    let hash_opts = HashOptions {
+        algorithm,
+        partial_bytes,
+        include_modtime,
+        fat_compat: flag("fat_compat"),
+        sparse: flag("sparse"),
+        include_extents: flag("include_extents"),
+        full: flag("full"),
+        salt,
+        abbrev,
+        parallel_read_threshold: value("parallel_read_threshold")
+            .map(|s| whole_number("parallel-read-threshold", &s)),
+        retries,
+        retry_delay: Duration::from_millis(retry_delay_ms),
+        retry_network: !flag("no_retry_network"),
+        file_timeout: value("file_timeout").map(|s| {
+            Duration::from_secs(s.parse::<u64>().unwrap_or_else(|_| {
+                error!(
+                    "Error: invalid --file-timeout '{}' (expected whole seconds)",
+                    s
+                );
+                std::process::exit(1);
+            }))
+        }),
+        reread_unstable: value("reread_unstable").map_or(0, |s| {
+            s.parse::<usize>().unwrap_or_else(|_| {
+                error!("Error: invalid --reread-unstable '{}'", s);
+                std::process::exit(1);
+            })
+        }),
+        ..HashOptions::default()
+    };
+    // The flags conflict on the command line, but can also come from a config file.
+    if let Err(e) = hash_opts.validate() {
+        error!("Error: {}", e);
+        std::process::exit(1);
+    }
+    #[cfg(windows)]
+    let hash_opts = if flag("vss") {
+        HashOptions {
+            source: vss_source(),
+            ..hash_opts
+        }
+    } else {
+        hash_opts
+    };
+    #[cfg(unix)]
+    let hash_opts = match value("snapshot") {
+        Some(kind) => HashOptions {
+            source: snapshot_source(kind.parse().unwrap_or_else(|e| {
+                error!("Error: {}", e);
+                std::process::exit(1);
+            })),
+            ..hash_opts
+        },
+        None => hash_opts,
+    };
+    // s3:// paths are read through curl; anything else passes through.
+    let hash_opts = HashOptions {
+        source: Arc::new(RemoteSource::new(
+            hash_opts.source.clone(),
+            "curl",
+            S3Settings::from_env(value("s3_endpoint")),
+        )),
+        ..hash_opts
+    };
+    // So are ssh:// paths, over SFTP.
+    let ssh_command = value("ssh_command").unwrap_or_else(|| "ssh".to_string());
+    let hash_opts = HashOptions {
+        source: Arc::new(SshSource::new(hash_opts.source.clone(), &ssh_command)),
+        ..hash_opts
+    };
+    let archive = values("archive");
+    let kinds: Vec<ArchiveKind> = archive
+        .iter()
+        .map(|k| {
+            ArchiveKind::parse(k).unwrap_or_else(|| {
+                error!("Error: unknown --archive kind '{}'", k);
+                std::process::exit(1);
+            })
+        })
+        .collect();
+    let hash_opts = if kinds.is_empty() {
+        hash_opts
+    } else {
+        HashOptions {
+            source: Arc::new(ArchiveSource::new(hash_opts.source.clone(), kinds)),
+            ..hash_opts
+        }
+    };
+
+    let mut exclude = values("exclude");
+    if flag("skip_vcs_and_junk") {
+        let junk = values("junk");
+        if junk.is_empty() {
+            exclude.extend(walk::JUNK.iter().map(|p| p.to_string()));
+        } else {
+            exclude.extend(junk);
+        }
+    }
+    let exclude = exclude
+        .iter()
+        .map(|p| glob::Pattern::new(p))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            error!("Error: invalid --exclude pattern: {}", e);
+            std::process::exit(1);
+        });
+
+    // Threads inherit their priorities, so this comes before any worker is started.
+    if flag("background") {
+        for (priority, e) in background::lower() {
+            warning!(
+                "Warning: --background: cannot lower the {}: {}",
+                priority,
+                e
+            );
+        }
+    }
+    let threads = value("threads").map(|s| whole_number::<usize>("threads", &s));
+    let io_concurrency = value("io_concurrency").map(|s| {
+        s.parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .unwrap_or_else(|| {
+                error!("Error: invalid --io-concurrency '{}'", s);
+                std::process::exit(1);
+            })
+    });
+    if io_concurrency.is_some() && cfg!(feature = "async-io") && flag("async_io") {
+        error!("Error: --io-concurrency and --async-io both set how many files are read at once; give one");
+        std::process::exit(1);
+    }
+    let max_memory = value("max_memory").map(|s| {
+        parse_size(&s).filter(|&n| n > 0).unwrap_or_else(|| {
+            error!(
+                "Error: invalid --max-memory '{}' (expected e.g. 256M, 2G, or 1048576)",
+                s
+            );
+            std::process::exit(1);
+        })
+    });
+    // Half of --max-memory is for read buffers; the other half is for files listed up front.
+    let hash_opts = HashOptions {
+        memory_gate: max_memory.map(|bytes| MemoryGate::new(bytes / 2)),
+        ..hash_opts
+    };
+    // With --io-concurrency the pool has a thread per file in flight, most of them waiting on
+    // reads, and the gate keeps the digests to --threads at a time.
+    let hash_opts = match io_concurrency {
+        Some(_) => HashOptions {
+            cpu_gate: Some(CpuGate::new(threads.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |n| n.get())
+            }))),
+            ..hash_opts
+        },
+        None => hash_opts,
+    };
+    if let Some(threads) = io_concurrency.or(threads) {
+        // Only the first call can size the global pool; options are built once per run.
+        let _ = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global();
+    }
+
+    // A full manifest is what sha256sum prints, which none of these would be. Settings that
+    // would be folded into the digests were refused by `validate` above.
+    if hash_opts.full {
+        for (set, option) in [
+            (flag("record_metadata"), "--record-metadata"),
+            (value("note").is_some(), "--note"),
+            (flag("structure_only"), "--structure-only"),
+            (value("anonymize_paths").is_some(), "--anonymize-paths"),
+        ] {
+            if set {
+                error!("Error: --full can't be combined with {}", option);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let order = if flag("optimize_seek") {
+        Order::Physical
+    } else {
+        value("order").map_or(Order::Given, |name| {
+            name.parse().unwrap_or_else(|e| {
+                error!("Error: invalid --order: {}", e);
+                std::process::exit(1);
+            })
+        })
+    };
+    let path_style = if flag("no_canonicalize") {
+        PathStyle::Absolute
+    } else {
+        value("path_style").map_or(
+            if hash_opts.full {
+                PathStyle::AsGiven
+            } else {
+                PathStyle::Canonical
+            },
+            |name| {
+                name.parse().unwrap_or_else(|e| {
+                    error!("Error: invalid --paths: {}", e);
+                    std::process::exit(1);
+                })
+            },
+        )
+    };
+
+    // A cursor marks a place in path order, which no other order has.
+    if value("cursor").is_some() && !matches!(order, Order::Given | Order::Path) {
+        error!("Error: --cursor goes through files in path order, so it can't be combined with --order or --optimize-seek");
+        std::process::exit(1);
+    }
+
+    verbose!(
+        "Options: algorithm = {}, partial-bytes = {}, include-modtime = {}, fat-compat = {}, retries = {}, \
+         retry-delay = {}ms, file-timeout = {}, threads = {}, io-concurrency = {}",
+        hash_opts.algorithm.name,
+        hash_opts.partial_bytes,
+        hash_opts.include_modtime,
+        hash_opts.fat_compat,
+        hash_opts.retries,
+        hash_opts.retry_delay.as_millis(),
+        hash_opts
+            .file_timeout
+            .map_or("none".to_string(), |t| format!("{}s", t.as_secs())),
+        threads.unwrap_or_else(rayon::current_num_threads),
+        io_concurrency.map_or("none".to_string(), |n| n.to_string())
+    );
+
+    let interval = Duration::from_secs(
+        value("progress_interval")
+            .map_or(10, |s| whole_number("progress-interval", &s))
+            .max(1),
+    );
+    if flag("tui") && !atty::is(Stream::Stderr) {
+        warning!("Warning: Ignoring --tui: stderr is not a terminal");
+    }
+    let progress = match value("progress").as_deref() {
+        _ if flag("tui") && atty::is(Stream::Stderr) => Progress::Tui,
+        Some("bar") => Progress::Bar,
+        Some("plain") => Progress::Plain(interval),
+        Some("json") => Progress::Json(interval),
+        Some("none") => Progress::None,
+        // Show the bar if stderr is a TTY
+        _ if atty::is(Stream::Stderr) => Progress::Bar,
+        _ => Progress::None,
+    };
+
+    Options {
+        skip_errors: value("skip_errors").map_or_else(SkipErrors::default, |kinds| {
+            SkipErrors::parse(&kinds).unwrap_or_else(|e| {
+                error!("Error: invalid --skip-errors: {}", e);
+                std::process::exit(1);
+            })
+        }),
+        progress,
+        progress_file: value("progress_file").map(PathBuf::from),
+        summary_file: value("summary_file").map(PathBuf::from),
+        summary_json: value("summary_json").map(PathBuf::from),
+        labels: parse_labels(&values("label")),
+        note: value("note")
+            .map(|note| {
+                let note = note.trim().to_string();
+                if note.contains(['\n', '\r']) {
+                    error!("Error: invalid --note: it must be one line");
+                    std::process::exit(1);
+                }
+                note
+            })
+            .filter(|note| !note.is_empty()),
+        metrics_textfile: value("metrics_textfile").map(PathBuf::from),
+        metrics_listener: value("metrics_listen").map(|addr| {
+            let listener = TcpListener::bind(&addr).unwrap_or_else(|e| {
+                error!(
+                    "Error: cannot listen on '{}' for --metrics-listen: {}",
+                    addr, e
+                );
+                std::process::exit(1);
+            });
+            Arc::new(listener)
+        }),
+        // Not from the config files: moving files isn't something to leave in one.
+        on_fail: matches
+            .try_get_one::<String>("on_fail")
+            .ok()
+            .flatten()
+            .map(|s| {
+                OnFail::parse(s).unwrap_or_else(|e| {
+                    error!("Error: invalid --on-fail: {}", e);
+                    std::process::exit(1);
+                })
+            }),
+        repair_from: matches
+            .try_get_one::<String>("repair_from")
+            .ok()
+            .flatten()
+            .map(PathBuf::from),
+        dry_run: matches.try_get_one::<bool>("dry_run").ok().flatten() == Some(&true),
+        record_metadata: matches
+            .try_get_one::<bool>("record_metadata")
+            .ok()
+            .flatten()
+            == Some(&true),
+        metadata_only: flag("metadata_only"),
+        changed_only: flag("changed_only") && !flag("force_all"),
+        two_phase: flag("two_phase").then(|| {
+            let sample = value("sample").unwrap_or_default();
+            sample
+                .parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=100.0).contains(p))
+                .unwrap_or_else(|| {
+                    error!(
+                        "Error: invalid --sample '{}' (expected a percentage from 0 to 100)",
+                        sample
+                    );
+                    std::process::exit(1);
+                })
+        }),
+        history_db: matches
+            .try_get_one::<String>("db")
+            .ok()
+            .flatten()
+            .map(PathBuf::from),
+        oldest_first: matches.try_get_one::<bool>("oldest_first").ok().flatten() == Some(&true),
+        known_failures: value("known_failures")
+            .map(|file| read_known_failures(&file))
+            .unwrap_or_default(),
+        group_output: flag("group_output"),
+        smart: flag("smart"),
+        max_memory,
+        plan: flag("plan"),
+        transition: value("transition").map(|value| {
+            let parsed = value.split_once(':').map(|(from, to)| {
+                algorithm::lookup(from).and_then(|from| Ok((from.name, algorithm::lookup(to)?)))
+            });
+            match parsed {
+                Some(Ok((from, to))) if from != to.name => (from.to_string(), to),
+                Some(Err(e)) => {
+                    error!("Error: invalid --transition '{}': {}", value, e);
+                    std::process::exit(1);
+                }
+                _ => {
+                    error!(
+                        "Error: invalid --transition '{}': expected OLD:NEW, two different algorithms",
+                        value
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }),
+        transition_output: value("transition_output").map(PathBuf::from),
+        cursor: matches
+            .try_get_one::<String>("cursor")
+            .ok()
+            .flatten()
+            .map(PathBuf::from),
+        max_duration: matches
+            .try_get_one::<String>("max_duration")
+            .ok()
+            .flatten()
+            .map(|s| {
+                parse_duration(s).unwrap_or_else(|| {
+                    error!(
+                        "Error: invalid --max-duration '{}' (expected e.g. 2h, 90m, 1h30m, or 45s)",
+                        s
+                    );
+                    std::process::exit(1);
+                })
+            }),
+        max_errors: value("max_errors")
+            .map(|s| {
+                s.parse::<usize>().unwrap_or_else(|_| {
+                    error!("Error: invalid --max-errors '{}'", s);
+                    std::process::exit(1);
+                })
+            })
+            .filter(|&n| n > 0),
+        max_files: value("max_files").map(|s| {
+            s.parse::<usize>().unwrap_or_else(|_| {
+                error!("Error: invalid --max-files '{}'", s);
+                std::process::exit(1);
+            })
+        }),
+        max_bytes: value("max_bytes").map(|s| {
+            parse_size(&s).unwrap_or_else(|| {
+                error!(
+                    "Error: invalid --max-bytes '{}' (expected e.g. 500G, 2T, or 1048576)",
+                    s
+                );
+                std::process::exit(1);
+            })
+        }),
+        notify: notify::Notify {
+            webhook: value("notify_webhook"),
+            email: values("notify_email"),
+            threshold: value("notify_threshold")
+                .map(|s| {
+                    s.parse::<usize>().unwrap_or_else(|_| {
+                        error!("Error: invalid --notify-threshold '{}'", s);
+                        std::process::exit(1);
+                    })
+                })
+                .filter(|&n| n > 0),
+        },
+        path_style,
+        order,
+        detect_clones: flag("detect_clones"),
+        async_io: (cfg!(feature = "async-io") && flag("async_io")).then(|| {
+            value("concurrency").map_or(256, |s| whole_number("concurrency", &s))
+        }),
+        exclude,
+        include_ads: cfg!(windows) && flag("include_ads"),
+        skip_locked: cfg!(windows) && flag("skip_locked"),
+        include_rsrc: cfg!(target_os = "macos") && flag("include_rsrc"),
+        include_quarantine: cfg!(target_os = "macos") && flag("include_quarantine"),
+        archive,
+        ssh_command,
+        remote_gustasum: matches
+            .try_get_one::<String>("remote_gustasum")
+            .ok()
+            .flatten()
+            .filter(|p| p.as_str() != "none")
+            .cloned(),
+        anonymize_paths: value("anonymize_paths").map(|path| read_path_key(&path)),
+        structure_only: flag("structure_only"),
+        hash: hash_opts,
+    }
+}
+
+/// The contents of the `what` file at `path`, a secret such as a salt. Exits if it can't be
+/// read or is empty.
+fn read_secret(path: &str, what: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(bytes) if !bytes.is_empty() => bytes,
+        Ok(_) => {
+            error!("Error: {} file '{}' is empty", what, path);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("Error: cannot read {} file '{}': {}", what, path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The paths listed in a `--known-failures` file; blank lines and `#` comments are skipped.
+fn read_known_failures(file: &str) -> HashSet<String> {
+    let contents = fs::read_to_string(file).unwrap_or_else(|e| {
+        error!("Error: cannot read '{}': {}", file, e);
+        std::process::exit(1);
+    });
+    contents
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+fn read_path_key(path: &str) -> PathKey {
+    PathKey::new(read_secret(path, "key"))
+}
+
+/// Read through shadow copies, and delete them however the process exits.
+#[cfg(windows)]
+fn vss_source() -> Arc<dyn gustasum::Source> {
+    extern "C" fn release() {
+        gustasum::vss::release_all();
+    }
+    unsafe { libc::atexit(release) };
+    Arc::new(gustasum::vss::VssSource::new())
+}
+
+/// Read through filesystem snapshots, and delete them however the process exits.
+#[cfg(unix)]
+fn snapshot_source(kind: gustasum::snapshot::SnapshotKind) -> Arc<dyn gustasum::Source> {
+    extern "C" fn release() {
+        gustasum::snapshot::release_all();
+    }
+    unsafe { libc::atexit(release) };
+    Arc::new(gustasum::snapshot::SnapshotSource::new(kind))
+}
+
+/// Like [`options_from`], but hash with the algorithm `manifest` was made with. An explicit
+/// `--algorithm` that disagrees with the manifest header is an error.
+fn options_for_manifest(
+    matches: &ArgMatches,
+    config: &Config,
+    manifest: &Manifest,
+    file: &str,
+) -> Options {
+    let mut opts = options_from(matches, config);
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    // The manifest's labels carry over to runs on it; --label adds to them or overrides one.
+    let mut labels = manifest.labels.clone();
+    for (key, value) in opts.labels.drain(..) {
+        labels.retain(|(k, _)| *k != key);
+        labels.push((key, value));
+    }
+    opts.labels = labels;
+
+    // Settings recorded by the generating run are followed, unless contradicted outright.
+    match manifest.hash_options(opts.hash.clone(), &given) {
+        Ok(hash) => opts.hash = hash,
+        Err(e) => {
+            error!("Error: '{}' {}", file, e);
+            std::process::exit(1);
+        }
+    }
+    // --transition names both algorithms, so a manifest of another is surely a mistake.
+    if let Some((from, to)) = opts.transition.clone() {
+        if from != opts.hash.algorithm.name {
+            error!(
+                "Error: '{}' was made with {}, not {}",
+                file, opts.hash.algorithm.name, from
+            );
+            std::process::exit(1);
+        }
+        opts.hash.also = Some(to);
+    }
+    opts
+}
+
+/// The shortest `--abbrev`: a changed file goes unnoticed with a chance of 1 in 16^N, about
+/// 1 in 4 billion here.
+const MIN_ABBREV: usize = 8;
+/// Past these odds of two files sharing an abbreviated digest, `generate` warns...
+const ABBREV_WARN_ODDS: f64 = 1e-6;
+/// ...and past these it refuses.
+const ABBREV_REFUSE_ODDS: f64 = 1e-2;
+
+/// The chance that some two of `files` digests, abbreviated to `digits` hex digits, are the
+/// same, by the birthday bound.
+fn abbrev_collision_odds(files: usize, digits: usize) -> f64 {
+    let pairs = files as f64 * files.saturating_sub(1) as f64 / 2.0;
+    (pairs / 16f64.powi(digits as i32)).min(1.0)
+}
+
+/// Warn if `digits` is too short for a manifest of `files`, or, with `refuse`, exit if it's
+/// far too short.
+fn check_abbrev(digits: usize, files: usize, refuse: bool) {
+    let odds = abbrev_collision_odds(files, digits);
+    if odds <= ABBREV_WARN_ODDS {
+        return;
+    }
+    let enough = (digits..)
+        .find(|&d| abbrev_collision_odds(files, d) <= ABBREV_WARN_ODDS)
+        .unwrap_or(digits);
+    if refuse && odds > ABBREV_REFUSE_ODDS {
+        error!(
+            "Error: --abbrev {} is too short for {} files: two of them would share a digest with a chance of {:.2}%. Use --abbrev {} or more.",
+            digits,
+            files,
+            odds * 100.0,
+            enough
+        );
+        std::process::exit(1);
+    }
+    warning!(
+        "Warning: with {} files, {}-digit digests have a {:.2e} chance of two being the same, so files may be confused; --abbrev {} or more avoids that.",
+        files, digits, odds, enough
+    );
+}
+
+/// `--label` values as `(key, value)` pairs. A later label with the same key replaces an
+/// earlier one.
+fn parse_labels(values: &[String]) -> Vec<(String, String)> {
+    let mut labels: Vec<(String, String)> = Vec::new();
+    for label in values {
+        match label.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+                labels.retain(|(k, _)| k != key);
+                labels.push((key.to_string(), value.to_string()));
+            }
+            _ => {
+                error!("Error: invalid --label '{}': expected KEY=VALUE", label);
+                std::process::exit(1);
+            }
+        }
+    }
+    labels
+}
+
+/// This machine's name, as recorded in manifests and notifications.
+fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..len]).into_owned();
+        }
+    }
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// The user running gustasum, as recorded in manifests.
+fn username() -> String {
+    if let Some(name) = ["USER", "LOGNAME", "USERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+    {
+        return name;
+    }
+    #[cfg(unix)]
+    unsafe {
+        let entry = libc::getpwuid(libc::geteuid());
+        if !entry.is_null() && !(*entry).pw_name.is_null() {
+            return std::ffi::CStr::from_ptr((*entry).pw_name)
+                .to_string_lossy()
+                .into_owned();
+        }
+    }
+    String::new()
+}
+
+fn verify_options_from(matches: &ArgMatches, opts: &Options, manifest: &Manifest) -> VerifyOptions {
+    let url_base = matches.try_get_one::<String>("url_base").ok().flatten();
+    let remap = if let Some(url) = url_base {
+        let paths = manifest.lines.iter().flatten().map(|e| e.path.as_path());
+        Some((common_dir(paths), PathBuf::from(url.trim_end_matches('/'))))
+    } else {
+        matches.get_many::<String>("remap").map(|vals| {
+            let mut vec: Vec<PathBuf> = vals.map(PathBuf::from).collect();
+            // GUSTASUM_REMAP holds both bases in one value, separated as in PATH.
+            if matches.value_source("remap") == Some(ValueSource::EnvVariable) {
+                vec = vec.iter().flat_map(std::env::split_paths).collect();
+            }
+            if vec.len() == 2 {
+                (vec[0].clone(), vec[1].clone())
+            } else {
+                error!("Error: invalid --remap: expected OLD_BASE and NEW_BASE");
+                std::process::exit(1);
+            }
+        })
+    };
+    VerifyOptions {
+        hash: opts.hash.clone(),
+        remap,
+    }
+}
+
+/// The matches of the innermost subcommand given. Global args are recorded on the
+/// subcommand they were given after, and passed down from there, not up.
+fn leaf(matches: &ArgMatches) -> &ArgMatches {
+    matches.subcommand().map_or(matches, |(_, sub)| leaf(sub))
+}
+
+/// Apply `--color`, falling back to the config file's `color`. For `auto`, it's up to
+/// `console`, which colors terminals only; NO_COLOR turns that off.
+fn init_color(matches: &ArgMatches, config: &Config) {
+    let matches = leaf(matches);
+    let choice = match matches.value_source("color") {
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable) => {
+            matches.get_one::<String>("color").map(String::as_str)
+        }
+        _ => config.get("color"),
+    };
+    let enabled = match choice {
+        Some("always") => true,
+        Some("never") => false,
+        Some("auto") | None => {
+            if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+                false
+            } else {
+                return;
+            }
+        }
+        Some(other) => {
+            warning!(
+                "Warning: Ignoring color = '{}' in config (expected auto, always, or never)",
+                other
+            );
+            return;
+        }
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+/// How important a line on stderr is, most important first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Error,
+    Warning,
+    Info,
+    Verbose,
+    Debug,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warn",
+            Severity::Info => "info",
+            Severity::Verbose => "verbose",
+            Severity::Debug => "debug",
+        }
+    }
+
+    /// The syslog severity: err, warning, info, or debug.
+    #[cfg(unix)]
+    fn priority(self) -> u8 {
+        match self {
+            Severity::Error => 3,
+            Severity::Warning => 4,
+            Severity::Info => 6,
+            Severity::Verbose | Severity::Debug => 7,
+        }
+    }
+}
+
+/// Where diagnostics go, from `-v`, `--log-file`, and `--log`.
+struct Log {
+    /// Least important severity that is printed (and logged).
+    max: Severity,
+    file: Option<Mutex<fs::File>>,
+    #[cfg(unix)]
+    system: Option<syslog::SystemLog>,
+    /// Tags this run's lines in the system log.
+    #[cfg(unix)]
+    run_id: String,
+}
+
+static LOG: OnceLock<Log> = OnceLock::new();
+
+fn log() -> &'static Log {
+    LOG.get_or_init(|| Log {
+        max: Severity::Info,
+        file: None,
+        #[cfg(unix)]
+        system: None,
+        #[cfg(unix)]
+        run_id: String::new(),
+    })
+}
+
+/// Apply `-v`/`-vv`, `--log-file`, and `--log`. Runs first, so config warnings are logged too.
+fn init_log(matches: &ArgMatches) {
+    let matches = leaf(matches);
+    let max = match matches.get_count("verbose") {
+        0 => Severity::Info,
+        1 => Severity::Verbose,
+        _ => Severity::Debug,
+    };
+    let file = matches.get_one::<String>("log_file").map(|path| {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|e| {
+                eprintln!("Error: cannot open log file '{}': {}", path, e);
+                std::process::exit(1);
+            });
+        Mutex::new(file)
+    });
+    let system = matches.get_one::<String>("log");
+    #[cfg(unix)]
+    let system = system.map(|kind| {
+        syslog::SystemLog::open(kind).unwrap_or_else(|e| {
+            eprintln!("Error: cannot log to {}: {}", kind, e);
+            std::process::exit(1);
+        })
+    });
+    #[cfg(not(unix))]
+    if system.is_some() {
+        eprintln!("Error: --log is only supported on Unix");
+        std::process::exit(1);
+    }
+    // Unique enough to tell runs apart in the system log.
+    #[cfg(unix)]
+    let run_id = format!(
+        "{:016x}",
+        RandomState::new().hash_one((SystemTime::now(), std::process::id()))
+    );
+    let _ = LOG.set(Log {
+        max,
+        file,
+        #[cfg(unix)]
+        system,
+        #[cfg(unix)]
+        run_id,
+    });
+}
+
+/// The progress bar being drawn, if any, so diagnostics can be printed around it.
+static PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// The `--tui` dashboard, if it's up; diagnostics go to its messages pane instead.
+static DASHBOARD: Mutex<Option<Arc<tui::Dashboard>>> = Mutex::new(None);
+
+/// Print `line` to stderr, colored for its severity, without tearing the progress bar,
+/// append it to the `--log-file` as a timestamped `ts=... level=... msg="..."` line, and
+/// send it to the `--log` system log. Lines less important than the `-v` level are dropped.
+fn diagnostic(severity: Severity, line: String) {
+    let log = log();
+    if severity > log.max {
+        return;
+    }
+    if let Some(file) = &log.file {
+        let _ = writeln!(
+            file.lock().unwrap(),
+            "ts={} level={} msg={:?}",
+            utc_timestamp(SystemTime::now()),
+            severity.name(),
+            line.trim()
+        );
+    }
+    #[cfg(unix)]
+    if let Some(system) = &log.system {
+        system.send(severity.priority(), &log.run_id, &line);
+    }
+    echo(severity, &line);
+}
+
+/// Print a line as [`diagnostic`] does, without logging it (again).
+fn echo(severity: Severity, line: &str) {
+    if severity > log().max {
+        return;
+    }
+    let line = match severity {
+        Severity::Error => style(line).for_stderr().red(),
+        Severity::Warning => style(line).for_stderr().yellow(),
+        Severity::Info => style(line).for_stderr(),
+        Severity::Verbose | Severity::Debug => style(line).for_stderr().dim(),
+    };
+    eprint_around_bar(&line.to_string());
+}
+
+/// RFC 3339 UTC time with milliseconds, e.g. `2024-05-01T12:34:56.789Z`.
+fn utc_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// `eprintln!`, with the progress bar (if any) cleared first and redrawn after.
+fn eprint_around_bar(text: &str) {
+    if let Some(dashboard) = DASHBOARD.lock().unwrap().as_ref() {
+        dashboard.message(text);
+        return;
+    }
+    match PROGRESS_BAR.lock().unwrap().as_ref() {
+        Some(bar) => bar.suspend(|| eprintln!("{}", text)),
+        None => eprintln!("{}", text),
+    }
+}
+
+/// Exit code after Ctrl-C, matching the shell convention of 128 + SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// How many files per hashing thread a streamed manifest lets be between found and printed.
+const STREAM_DEPTH_PER_THREAD: usize = 8;
+
+/// Raised by the SIGINT handler. Workers stop picking up new files once it's set.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Raised once `--max-errors` failures have accumulated; workers stop as for Ctrl-C.
+static GAVE_UP: AtomicBool = AtomicBool::new(false);
+
+/// Raised once `--max-duration` has passed; workers stop as for Ctrl-C.
+static TIME_UP: AtomicBool = AtomicBool::new(false);
+
+/// Raised once a walk has found more than `--max-files` or `--max-bytes` and going on wasn't
+/// confirmed; workers stop as for Ctrl-C.
+static TOO_BIG: AtomicBool = AtomicBool::new(false);
+
+/// Whether to stop picking up new files, after Ctrl-C, `--max-errors`, `--max-duration`, or
+/// `--max-files` and `--max-bytes`.
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+        || GAVE_UP.load(Ordering::Relaxed)
+        || TIME_UP.load(Ordering::Relaxed)
+        || TOO_BIG.load(Ordering::Relaxed)
+}
+
+/// Raise [`TIME_UP`] once the `--max-duration` has passed, if there is one.
+fn start_time_limit(opts: &Options) {
+    if let Some(limit) = opts.max_duration {
+        std::thread::spawn(move || {
+            std::thread::sleep(limit);
+            TIME_UP.store(true, Ordering::Relaxed);
+        });
+    }
+}
+
+/// `--max-files` and `--max-bytes`: a tally of what a walk has found. Once it passes either,
+/// going on is up to whoever is at the terminal; without one, the run stops with [`TOO_BIG`].
+struct ScanLimit<'a> {
+    source: &'a dyn Source,
+    max_files: Option<usize>,
+    max_bytes: Option<u64>,
+    files: usize,
+    bytes: u64,
+}
+
+impl<'a> ScanLimit<'a> {
+    fn new(source: &'a dyn Source, opts: &Options) -> ScanLimit<'a> {
+        ScanLimit {
+            source,
+            max_files: opts.max_files,
+            max_bytes: opts.max_bytes,
+            files: 0,
+            bytes: 0,
+        }
+    }
+
+    fn found(&mut self, path: &Path) {
+        if self.max_files.is_none() && self.max_bytes.is_none() {
+            return;
+        }
+        self.files += 1;
+        if self.max_bytes.is_some() {
+            self.bytes += self.source.metadata(path).map_or(0, |m| m.len);
+        }
+        let passed = match (self.max_files, self.max_bytes) {
+            (Some(max), _) if self.files > max => format!("more than {} files (--max-files)", max),
+            (_, Some(max)) if self.bytes > max => {
+                format!("more than {} bytes of files (--max-bytes)", max)
+            }
+            _ => return,
+        };
+        // Asked once: after a yes, the walk goes on to the end.
+        self.max_files = None;
+        self.max_bytes = None;
+        if !confirm(&format!(
+            "Found {} so far, and still walking. Keep going?",
+            passed
+        )) {
+            error!("Error: Stopping: found {} under the paths given", passed);
+            TOO_BIG.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Memory a file listed before hashing takes besides its path, in the list, its result, and
+/// its manifest line, roughly.
+const LISTED_FILE_BYTES: u64 = 256;
+
+/// About how much memory listing `path` before hashing takes, with its result.
+fn listed_memory(path: &Path) -> u64 {
+    3 * path.as_os_str().len() as u64 + LISTED_FILE_BYTES
+}
+
+/// The files found, for a run that needs every one before hashing starts. Past half of
+/// `--max-memory`, the list stops growing and the run stops with [`TOO_BIG`].
+fn list_within_memory(found: impl Iterator<Item = PathBuf>, opts: &Options) -> Vec<PathBuf> {
+    let Some(max) = opts.max_memory else {
+        return found.collect();
+    };
+    let mut used = 0u64;
+    let mut files = Vec::new();
+    for path in found {
+        used += listed_memory(&path);
+        if used > max / 2 {
+            error!(
+                "Error: Stopping: listing more than {} files before hashing takes more than half of --max-memory. \
+                 Without --detect-clones, --order, --optimize-seek, --cursor, --async-io, --abbrev, or --plan, \
+                 a new manifest is written as it's made, with few files held at once.",
+                files.len()
+            );
+            TOO_BIG.store(true, Ordering::Relaxed);
+            break;
+        }
+        files.push(path);
+    }
+    files
+}
+
+/// Ask `question` on the terminal and wait for a yes. With no terminal to ask on, or the
+/// `--tui` dashboard on it, the answer is no.
+fn confirm(question: &str) -> bool {
+    if !atty::is(Stream::Stdin) || !atty::is(Stream::Stderr) || DASHBOARD.lock().unwrap().is_some()
+    {
+        return false;
+    }
+    let ask = || {
+        eprint!("{} [y/N] ", question);
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).is_ok()
+            && matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+    };
+    let bar = PROGRESS_BAR.lock().unwrap().clone();
+    match bar {
+        Some(bar) => bar.suspend(ask),
+        None => ask(),
+    }
+}
+
+/// The path recorded in a `--cursor` file, if it exists: where the last run to stop early
+/// got to.
+fn read_cursor(file: &Path) -> Option<PathBuf> {
+    match fs::read_to_string(file) {
+        Ok(contents) => {
+            let path = contents.strip_suffix('\n').unwrap_or(&contents);
+            (!path.is_empty()).then(|| native_separators(Path::new(path)))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            error!("Error: cannot read cursor '{}': {}", file.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// With `--cursor`, put `items` in path order, and if the cursor records where an earlier
+/// run stopped, leave out the ones up to there. Items without a path (malformed lines) go
+/// first, so only a run from the start has them.
+fn resume_at_cursor<T, P>(items: &mut Vec<T>, path_of: P, opts: &Options)
+where
+    P: Fn(&T) -> Option<&Path>,
+{
+    let Some(ref file) = opts.cursor else {
+        return;
+    };
+    items.sort_by(|a, b| path_of(a).cmp(&path_of(b)));
+    if let Some(after) = read_cursor(file) {
+        let before = items.len();
+        items.retain(|item| path_of(item).is_some_and(|p| p > after.as_path()));
+        info!(
+            "Resuming after '{}' (--cursor); {} files were done by earlier runs.",
+            after.display(),
+            before - items.len()
+        );
+    }
+}
+
+/// With `--cursor`, record `last`, the path every item up to which is done, if the run
+/// stopped early; if it `finished`, remove the cursor, so the next run starts over.
+fn save_cursor(opts: &Options, last: Option<&Path>, finished: bool) {
+    let Some(ref file) = opts.cursor else {
+        return;
+    };
+    if finished {
+        match fs::remove_file(file) {
+            Ok(()) => info!("Got to the end; removed cursor '{}'.", file.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("Error: Failed to remove cursor '{}': {}", file.display(), e),
+        }
+        return;
+    }
+    // With nothing done, the cursor stays where it was.
+    let Some(last) = last else {
+        return;
+    };
+    match write_replacing(file, &format!("{}\n", portable_path(last))) {
+        Ok(()) => info!(
+            "Recorded '{}' in cursor '{}'; the next run starts after it.",
+            last.display(),
+            file.display()
+        ),
+        Err(e) => error!("Error: Failed to write cursor '{}': {}", file.display(), e),
+    }
+}
+
+/// On the first Ctrl-C, let in-flight files finish and flush what we have; a second one exits
+/// immediately. Under systemd, SIGTERM, how a service is stopped, does the same.
+fn install_interrupt_handler() {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_sigint(_: libc::c_int) {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                if tui::ACTIVE.load(Ordering::SeqCst) {
+                    let leave = tui::LEAVE.as_bytes();
+                    unsafe { libc::write(2, leave.as_ptr().cast(), leave.len()) };
+                }
+                unsafe { libc::_exit(EXIT_INTERRUPTED) };
+            }
+        }
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+            if systemd::active() {
+                libc::signal(
+                    libc::SIGTERM,
+                    on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                );
+            }
+        }
+    }
+}
+
+/// Raised by the SIGUSR1 handler; the status reporter thread prints a report and clears it.
+#[cfg(unix)]
+static STATUS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Live counters for a run, reported to stderr on SIGUSR1 (e.g., `kill -USR1 <pid>` under
+/// nohup).
+#[cfg_attr(not(unix), allow(dead_code))]
+struct RunStatus {
+    /// Planned so far; it grows while files are still being found.
+    total: AtomicUsize,
+    done: AtomicUsize,
+    /// Failures so far, against `--max-errors`.
+    failures: AtomicUsize,
+    bytes: AtomicU64,
+    /// The files being read, and since when.
+    in_flight: Mutex<Vec<(PathBuf, Instant)>>,
+    latencies: Mutex<Latencies>,
+    started: Instant,
+}
+
+/// How many of the slowest files a run names, in `--summary-json` and with `-v`.
+const SLOWEST_FILES: usize = 10;
+
+/// Files whose times are kept for the latency percentiles; past this many, a uniform random
+/// sample of them is, so a run of millions of files doesn't keep a time for each.
+const LATENCY_SAMPLES: usize = 100_000;
+
+/// How long files took to read, from start to finish with any retries, for spotting storage
+/// that's slowing down before it fails.
+#[derive(Default)]
+struct Latencies {
+    /// Every file's time, or past [`LATENCY_SAMPLES`] files, a sample of them.
+    times: Vec<Duration>,
+    /// Files timed, sampled or not.
+    seen: usize,
+    random: RandomState,
+    /// The [`SLOWEST_FILES`] slowest so far, the fastest of them on top.
+    slowest: BinaryHeap<Reverse<(Duration, PathBuf)>>,
+}
+
+impl Latencies {
+    fn add(&mut self, time: Duration, path: &Path) {
+        // A reservoir: the nth file replaces one of the sample with odds LATENCY_SAMPLES / n.
+        self.seen += 1;
+        if self.times.len() < LATENCY_SAMPLES {
+            self.times.push(time);
+        } else {
+            let slot = (self.random.hash_one(self.seen) % self.seen as u64) as usize;
+            if let Some(sampled) = self.times.get_mut(slot) {
+                *sampled = time;
+            }
+        }
+        if self.slowest.len() < SLOWEST_FILES {
+            self.slowest.push(Reverse((time, path.to_path_buf())));
+        } else if self
+            .slowest
+            .peek()
+            .is_some_and(|Reverse((top, _))| time > *top)
+        {
+            self.slowest.pop();
+            self.slowest.push(Reverse((time, path.to_path_buf())));
+        }
+    }
+
+    /// The percentiles and slowest files, if any file was read.
+    fn summary(&self) -> Option<LatencySummary> {
+        let mut times = self.times.clone();
+        times.sort_unstable();
+        // Nearest rank: the time that `percent`% of the files took at most.
+        let percentile = |percent: usize| {
+            let rank = (times.len() * percent).div_ceil(100).max(1);
+            times.get(rank - 1).copied()
+        };
+        let mut slowest: Vec<(Duration, PathBuf)> =
+            self.slowest.iter().map(|Reverse(s)| s.clone()).collect();
+        slowest.sort_by(|a, b| b.cmp(a));
+        Some(LatencySummary {
+            p50: percentile(50)?,
+            p95: percentile(95)?,
+            p99: percentile(99)?,
+            slowest,
+        })
+    }
+}
+
+/// A run's file latencies, as its summary reports them.
+struct LatencySummary {
+    p50: Duration,
+    p95: Duration,
+    p99: Duration,
+    /// Slowest first.
+    slowest: Vec<(Duration, PathBuf)>,
+}
+
+impl RunStatus {
+    /// Create the counters and start a reporter thread that answers SIGUSR1 for the rest of the process.
+    fn start(total: usize) -> Arc<Self> {
+        let status = Arc::new(RunStatus {
+            total: AtomicUsize::new(total),
+            done: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            in_flight: Mutex::new(Vec::new()),
+            latencies: Mutex::new(Latencies::default()),
+            started: Instant::now(),
+        });
+
+        #[cfg(unix)]
+        {
+            extern "C" fn on_sigusr1(_: libc::c_int) {
+                STATUS_REQUESTED.store(true, Ordering::SeqCst);
+            }
+            unsafe {
+                libc::signal(
+                    libc::SIGUSR1,
+                    on_sigusr1 as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                );
+            }
+            let status = Arc::clone(&status);
+            std::thread::spawn(move || loop {
+                std::thread::sleep(Duration::from_millis(200));
+                if STATUS_REQUESTED.swap(false, Ordering::SeqCst) {
+                    status.report();
+                }
+            });
+        }
+
+        status
+    }
+
+    fn begin(&self, path: &Path) {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .push((path.to_path_buf(), Instant::now()));
+    }
+
+    fn finish(&self, path: &Path) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(pos) = in_flight.iter().position(|(p, _)| p == path) {
+            let (_, since) = in_flight.swap_remove(pos);
+            drop(in_flight);
+            self.latencies.lock().unwrap().add(since.elapsed(), path);
+        }
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The latencies of the files read so far.
+    fn latency(&self) -> Option<LatencySummary> {
+        self.latencies.lock().unwrap().summary()
+    }
+
+    /// Count an entry that needed no reading (e.g., a malformed manifest line).
+    fn skip(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg_attr(not(unix), allow(dead_code))]
+    fn report(&self) {
+        let mut report = format!("Status: {}", self.line("files"));
+        for (path, _) in self.in_flight.lock().unwrap().iter() {
+            report.push_str(&format!("\n  reading: {}", path.display()));
+        }
+        info!("{}", report);
+    }
+
+    /// The counters as JSON members for `--progress json`.
+    fn json_fields(&self) -> String {
+        format!(
+            "\"done\":{},\"total\":{},\"bytes\":{},\"elapsed\":{:.3}",
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+            self.started.elapsed().as_secs_f64()
+        )
+    }
+
+    /// "123/456 files, 7.8 files/s, 9.01 MB/s, ETA 2h13m", counting in `unit`.
+    fn line(&self, unit: &str) -> String {
+        let done = self.done.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let secs = self.started.elapsed().as_secs_f64().max(1e-9);
+        let rate = done as f64 / secs;
+        let eta = if rate > 0.0 {
+            format_duration(Duration::from_secs_f64(
+                self.total.load(Ordering::Relaxed).saturating_sub(done) as f64 / rate,
+            ))
+        } else {
+            "unknown".to_string()
+        };
+        format!(
+            "{}/{} {}, {:.1} {}/s, {:.2} MB/s, ETA {}",
+            done,
+            self.total.load(Ordering::Relaxed),
+            unit,
+            rate,
+            unit,
+            bytes as f64 / 1e6 / secs,
+            eta
+        )
+    }
+}
+
+/// Feeds library events into the SIGUSR1 status counters and the progress bar.
+struct CliEvents {
+    status: Arc<RunStatus>,
+    bar: Option<ProgressBar>,
+    /// Where `--progress json` events go.
+    json: Option<Arc<JsonEvents>>,
+    dashboard: Option<Arc<tui::Dashboard>>,
+    max_errors: Option<usize>,
+    /// Read errors that `--skip-errors` or `--skip-locked` skip don't count as failures.
+    skip_errors: SkipErrors,
+    skip_locked: bool,
+    /// Dropped by `finish` to stop the `--progress plain` or `json` ticker.
+    ticker: Mutex<Option<mpsc::Sender<()>>>,
+    /// Set by [`CliEvents::alert_as`].
+    alert: Option<Alert>,
+    /// Set by [`CliEvents::expecting`].
+    expected_failures: HashSet<String>,
+}
+
+/// A `--notify-threshold` alert, sent once that many files have failed.
+struct Alert {
+    notify: notify::Notify,
+    threshold: usize,
+    mode: &'static str,
+    target: String,
+    labels: Vec<(String, String)>,
+    /// What has failed so far.
+    failed: Mutex<Vec<String>>,
+}
+
+impl CliEvents {
+    /// Start counting `total` items of `unit` ("files", "lines") for `opts.progress`.
+    fn new(total: usize, bar: Option<ProgressBar>, opts: &Options, unit: &'static str) -> Self {
+        *PROGRESS_BAR.lock().unwrap() = bar.clone();
+        let status = RunStatus::start(total);
+        #[cfg(unix)]
+        systemd::track(Some((Arc::clone(&status), unit)));
+        if let Some(listener) = opts.metrics_listener.as_ref() {
+            match listener.try_clone() {
+                Ok(listener) => metrics::serve(listener, Arc::clone(&status)),
+                Err(e) => warning!("Warning: cannot serve --metrics-listen: {}", e),
+            }
+        }
+        let json = match opts.progress {
+            Progress::Json(_) => Some(Arc::new(JsonEvents::open(opts.progress_file.as_deref()))),
+            _ => None,
+        };
+        let dashboard = (opts.progress == Progress::Tui)
+            .then(|| tui::Dashboard::open(Arc::clone(&status), unit));
+        *DASHBOARD.lock().unwrap() = dashboard.clone();
+        if let Some(ref json) = json {
+            json.emit(
+                "start",
+                &format!(
+                    "\"total\":{},\"unit\":\"{}\",\"labels\":{}",
+                    total,
+                    unit,
+                    json_labels(&opts.labels)
+                ),
+            );
+        }
+        let ticker = match opts.progress {
+            Progress::Plain(interval) | Progress::Json(interval) => {
+                let (tx, rx) = mpsc::channel::<()>();
+                let status = Arc::clone(&status);
+                let json = json.clone();
+                std::thread::spawn(move || {
+                    while let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                        match json {
+                            Some(ref json) => json.emit("progress", &status.json_fields()),
+                            None => info!("{}", status.line(unit)),
+                        }
+                    }
+                });
+                Some(tx)
+            }
+            _ => None,
+        };
+        CliEvents {
+            status,
+            bar,
+            json,
+            dashboard,
+            max_errors: opts.max_errors,
+            skip_errors: opts.skip_errors.clone(),
+            skip_locked: opts.skip_locked,
+            ticker: Mutex::new(ticker),
+            alert: None,
+            expected_failures: HashSet::new(),
+        }
+    }
+
+    /// Don't count failures of these (portable) paths, read from as given, toward
+    /// `--max-errors` or `--notify-threshold`: `--known-failures` lists them.
+    fn expecting(mut self, paths: HashSet<String>) -> Self {
+        self.expected_failures = paths;
+        self
+    }
+
+    /// Send the `--notify-threshold` alert, if one is asked for, as being about a run of
+    /// `mode` over `target`.
+    fn alert_as(mut self, opts: &Options, mode: &'static str, target: String) -> Self {
+        if let (true, Some(threshold)) = (opts.notify.is_set(), opts.notify.threshold) {
+            self.alert = Some(Alert {
+                notify: opts.notify.clone(),
+                threshold,
+                mode,
+                target,
+                labels: opts.labels.clone(),
+                failed: Mutex::new(Vec::new()),
+            });
+        }
+        self
+    }
+
+    /// Send a `--progress json` event about `path`, if that's the progress style.
+    fn emit_for(&self, event: &str, path: &Path, extra: &str) {
+        if let Some(ref json) = self.json {
+            json.emit(
+                event,
+                &format!(
+                    "\"path\":{}{}",
+                    json_string(&path.display().to_string()),
+                    extra
+                ),
+            );
+        }
+    }
+
+    /// Count `what`, a file that will be reported as failed, and give up on the run once there
+    /// have been `--max-errors` of them.
+    fn failed(&self, what: &str) {
+        if self.expected_failures.contains(what) {
+            return;
+        }
+        let failures = self.status.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(ref alert) = self.alert {
+            let mut failed = alert.failed.lock().unwrap();
+            failed.push(what.to_string());
+            if failures == alert.threshold {
+                let report = notify::Report {
+                    event: "threshold",
+                    mode: alert.mode,
+                    target: &alert.target,
+                    counts: vec![
+                        ("done", self.status.done.load(Ordering::Relaxed)),
+                        ("planned", self.status.total.load(Ordering::Relaxed)),
+                        ("failed", failures),
+                    ],
+                    bytes_read: self.status.bytes.load(Ordering::Relaxed),
+                    elapsed: self.status.started.elapsed(),
+                    complete: false,
+                    failures: &failed,
+                    labels: &alert.labels,
+                };
+                send_notifications(&alert.notify, &report);
+            }
+        }
+        if self.max_errors == Some(failures) {
+            GAVE_UP.store(true, Ordering::Relaxed);
+            error!(
+                "Error: Stopping after {} failures (--max-errors); finishing files in flight",
+                failures
+            );
+        }
+    }
+
+    /// Plan one more item, found after the run started.
+    fn found(&self) {
+        self.status.total.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref bar) = self.bar {
+            bar.inc_length(1);
+        }
+    }
+
+    /// Count an entry that needed no reading (e.g., a malformed manifest line).
+    fn skip(&self) {
+        self.status.skip();
+        if let Some(ref bar) = self.bar {
+            bar.inc(1);
+        }
+    }
+
+    fn file_done(&self, path: &Path) {
+        self.status.finish(path);
+        if let Some(ref bar) = self.bar {
+            bar.inc(1);
+        }
+    }
+
+    fn finish(&self) {
+        self.ticker.lock().unwrap().take();
+        #[cfg(unix)]
+        systemd::track(None);
+        if let Some(ref json) = self.json {
+            json.emit("finish", &self.status.json_fields());
+        }
+        if let Some(ref dashboard) = self.dashboard {
+            *DASHBOARD.lock().unwrap() = None;
+            dashboard.close();
+        }
+        if let Some(ref bar) = self.bar {
+            bar.finish_and_clear();
+        }
+        *PROGRESS_BAR.lock().unwrap() = None;
+    }
+}
+
+impl EventSink for CliEvents {
+    fn file_started(&self, path: &Path) {
+        debug!("Reading '{}'", path.display());
+        self.emit_for("started", path, "");
+        if let Some(ref dashboard) = self.dashboard {
+            dashboard.started(path);
+        }
+        if let Some(ref bar) = self.bar {
+            // Keep the file name: cut long paths from the left.
+            let width = (Term::stderr().size().1 as usize).saturating_sub(2);
+            bar.set_message(truncate_left(&path.display().to_string(), width));
+        }
+        self.status.begin(path);
+    }
+
+    fn file_hashed(&self, path: &Path, hash: &FileHash) {
+        verbose!("Hashed '{}' ({} bytes read)", path.display(), hash.bytes);
+        self.emit_for("hashed", path, &format!(",\"bytes\":{}", hash.bytes));
+        if let Some(ref dashboard) = self.dashboard {
+            dashboard.finished(path, None);
+        }
+        self.file_done(path);
+    }
+
+    fn file_failed(&self, path: &Path, error: &HashError) {
+        verbose!("Failed '{}': {}", path.display(), error);
+        self.emit_for(
+            "failed",
+            path,
+            &format!(",\"error\":{}", json_string(&error.to_string())),
+        );
+        if let Some(ref dashboard) = self.dashboard {
+            dashboard.finished(path, Some(error));
+        }
+        if !(self.skip_errors.covers(error.class()) || (self.skip_locked && error.is_locked())) {
+            self.failed(&portable_path(path));
+        }
+        self.file_done(path);
+    }
+
+    fn bytes_read(&self, _path: &Path, bytes: u64) {
+        self.status.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn file_retrying(&self, path: &Path, error: &HashError) {
+        warning!("Retrying file '{}': {}", path.display(), error);
+        self.emit_for(
+            "retrying",
+            path,
+            &format!(",\"error\":{}", json_string(&error.to_string())),
+        );
+        if let Some(ref dashboard) = self.dashboard {
+            dashboard.retrying();
+        }
+    }
+
+    fn file_rereading(&self, path: &Path) {
+        warning!(
+            "Re-reading file '{}': changed while being read",
+            path.display()
+        );
+        self.emit_for("rereading", path, "");
+        if let Some(ref dashboard) = self.dashboard {
+            dashboard.rereading();
+        }
+    }
+}
+
+/// `--progress json` output: one `{"event":...}` object per line, on stderr or
+/// `--progress-file`, flushed as it's written so a frontend reading a pipe sees it at once.
+struct JsonEvents {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonEvents {
+    /// Write to `path` (e.g., a named pipe, which blocks until a reader opens it), else stderr.
+    fn open(path: Option<&Path>) -> Self {
+        let out: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .unwrap_or_else(|e| {
+                        error!("Error: cannot open '{}': {}", path.display(), e);
+                        std::process::exit(1);
+                    }),
+            ),
+            None => Box::new(std::io::stderr()),
+        };
+        JsonEvents {
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Write `{"event":"<event>",<fields>}`; `fields` is already-encoded JSON members.
+    fn emit(&self, event: &str, fields: &str) {
+        let mut line = format!("{{\"event\":\"{}\"", event);
+        if !fields.is_empty() {
+            line.push(',');
+            line.push_str(fields);
+        }
+        line.push_str("}\n");
+        let mut out = self.out.lock().unwrap();
+        // A frontend that went away shouldn't stop the run.
+        let _ = out.write_all(line.as_bytes()).and_then(|()| out.flush());
+    }
+}
+
+/// `labels` as a JSON object.
+fn json_labels(labels: &[(String, String)]) -> String {
+    let fields: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// `failures` as a JSON array of `{"path", "offset", "error", "device"}` objects.
+fn json_read_failures(failures: &[ReadFailure]) -> String {
+    let failures: Vec<String> = failures
+        .iter()
+        .map(|f| {
+            format!(
+                "{{\"path\":{},\"offset\":{},\"error\":{},\"device\":{}}}",
+                json_string(&f.path),
+                f.offset,
+                json_string(&f.error),
+                f.device.as_deref().map_or("null".to_string(), json_string)
+            )
+        })
+        .collect();
+    format!("[{}]", failures.join(","))
+}
+
+/// `latency` as a JSON object of `p50_ms`, `p95_ms`, `p99_ms`, and the `slowest` files as
+/// `{"path", "ms"}` objects.
+fn json_latency(latency: &LatencySummary) -> String {
+    let ms = |d: Duration| format!("{:.3}", d.as_secs_f64() * 1e3);
+    let slowest: Vec<String> = latency
+        .slowest
+        .iter()
+        .map(|(time, path)| {
+            format!(
+                "{{\"path\":{},\"ms\":{}}}",
+                json_string(&portable_path(path)),
+                ms(*time)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"p50_ms\":{},\"p95_ms\":{},\"p99_ms\":{},\"slowest\":[{}]}}",
+        ms(latency.p50),
+        ms(latency.p95),
+        ms(latency.p99),
+        slowest.join(",")
+    )
+}
+
+/// `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Parse a duration like "2h", "90m", "1h30m", "45s", or plain seconds ("3600").
+fn parse_duration(s: &str) -> Option<Duration> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in s.chars() {
+        match c {
+            '0'..='9' => digits.push(c),
+            'h' | 'm' | 's' if !digits.is_empty() => {
+                let unit = match c {
+                    'h' => 3600,
+                    'm' => 60,
+                    _ => 1,
+                };
+                total += digits.parse::<u64>().ok()? * unit;
+                digits.clear();
+            }
+            _ => return None,
+        }
+    }
+    (digits.is_empty() && total > 0).then(|| Duration::from_secs(total))
+}
+
+/// A size in bytes, with an optional K, M, G, T, or P suffix for a power of 1024 (`500G`,
+/// `2TiB`, `64k`).
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number = number.parse::<u64>().ok()?;
+    let shift = match suffix
+        .trim_end_matches("iB")
+        .trim_end_matches('B')
+        .to_ascii_uppercase()
+        .as_str()
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        "P" => 50,
+        _ => return None,
+    };
+    number.checked_mul(1 << shift)
+}
+
+/// `s`, or its last `width - 1` characters after a '…' if it's longer than `width`.
+fn truncate_left(s: &str, width: usize) -> String {
+    let len = s.chars().count();
+    if len <= width {
+        return s.to_string();
+    }
+    let keep = width.saturating_sub(1);
+    format!("…{}", s.chars().skip(len - keep).collect::<String>())
+}
+
+/// "1.23 GB", in powers of 1000 like the MB/s of the throughput lines.
+fn format_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    for unit in ["bytes", "kB", "MB", "GB", "TB"] {
+        if value < 1000.0 {
+            return format!("{:.2} {}", value, unit).replace(".00 bytes", " bytes");
+        }
+        value /= 1000.0;
+    }
+    format!("{:.2} PB", value)
+}
+
+/// Render a duration the way people read ETAs: "2h13m", "4m05s", "12s".
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// How a run shows its progress on stderr (`--progress`).
+#[derive(Clone, Copy, PartialEq)]
+enum Progress {
+    None,
+    /// An indicatif bar, redrawn in place.
+    Bar,
+    /// A status line printed at this interval, for logs that aren't terminals.
+    Plain(Duration),
+    /// JSON events per file, plus a heartbeat at this interval, for frontends.
+    Json(Duration),
+    /// The full-screen `--tui` dashboard.
+    Tui,
+}
+
+/// `--on-fail`: set aside files whose content fails verification, so a good copy can be
+/// put in their place.
+#[derive(Clone)]
+enum OnFail {
+    /// `move:DIR`: move them under DIR, keeping their paths (`/data/a.txt` goes to `DIR/data/a.txt`).
+    Move(PathBuf),
+    /// `rename-suffix:SUFFIX`: rename them in place, e.g. `a.txt` to `a.txt.corrupt`.
+    RenameSuffix(String),
+}
+
+impl OnFail {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            Some(("move", dir)) if !dir.is_empty() => Ok(OnFail::Move(PathBuf::from(dir))),
+            Some(("rename-suffix", suffix)) if !suffix.is_empty() => {
+                Ok(OnFail::RenameSuffix(suffix.to_string()))
+            }
+            _ => Err(format!(
+                "'{}' (expected move:DIR or rename-suffix:SUFFIX)",
+                s
+            )),
+        }
+    }
+}
+
+/// Move or rename `path` for `--on-fail`, never replacing an existing file. Returns whether
+/// it was moved (or, with `dry_run`, would have been).
+fn quarantine(path: &Path, on_fail: &OnFail, dry_run: bool) -> bool {
+    let dest = match on_fail {
+        OnFail::Move(dir) => dir.join(
+            path.components()
+                .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                .collect::<PathBuf>(),
+        ),
+        OnFail::RenameSuffix(suffix) => {
+            let mut name = path.as_os_str().to_os_string();
+            name.push(suffix);
+            PathBuf::from(name)
+        }
+    };
+    if dry_run && !dest.exists() {
+        info!("Would move '{}' to '{}'", path.display(), dest.display());
+        return true;
+    }
+    let moved = if dest.exists() {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("'{}' already exists", dest.display()),
+        ))
+    } else {
+        dest.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| {
+                // Across filesystems, rename fails; copy and remove instead.
+                fs::rename(path, &dest).or_else(|e| {
+                    if fs::copy(path, &dest).is_err() {
+                        let _ = fs::remove_file(&dest);
+                        return Err(e);
+                    }
+                    fs::remove_file(path)
+                })
+            })
+    };
+    match moved {
+        Ok(()) => {
+            info!("Moved '{}' to '{}'", path.display(), dest.display());
+            true
+        }
+        Err(e) => {
+            error!("Error: cannot move '{}' aside: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+/// `--repair-from`: if `mirror` matches `entry`, copy it over `path` (after setting `path`
+/// aside per `--on-fail`, if given), keeping the mirror's modtime. Returns whether `path` was
+/// repaired (or, with `--dry-run`, would have been).
+fn repair(path: &Path, mirror: &Path, entry: &ManifestEntry, opts: &Options) -> bool {
+    match hash_file(mirror, &opts.hash) {
+        Ok(FileHash {
+            hash,
+            unstable: false,
+            ..
+        }) if verify::digest_matches(&hash, &entry.hash, &opts.hash) => {}
+        Ok(_) => {
+            error!(
+                "Error: cannot repair '{}': the mirror copy '{}' doesn't match the manifest either",
+                path.display(),
+                mirror.display()
+            );
+            return false;
+        }
+        Err(e) => {
+            error!(
+                "Error: cannot repair '{}' from '{}': {}",
+                path.display(),
+                mirror.display(),
+                e
+            );
+            return false;
+        }
+    }
+    if opts.dry_run {
+        info!(
+            "Would repair '{}' from '{}'",
+            path.display(),
+            mirror.display()
+        );
+        return true;
+    }
+    if let Some(ref on_fail) = opts.on_fail {
+        if !quarantine(path, on_fail, false) {
+            return false;
+        }
+    }
+
+    // Copy next to the damaged file and rename over it, so it's never half-written.
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".gustasum-repair");
+    let partial = PathBuf::from(partial);
+    let copied = fs::copy(mirror, &partial)
+        .and_then(|_| fs::metadata(mirror)?.modified())
+        .and_then(|modtime| {
+            fs::File::options()
+                .write(true)
+                .open(&partial)?
+                .set_modified(modtime)
+        })
+        .and_then(|()| fs::rename(&partial, path));
+    match copied {
+        Ok(()) => {
+            info!("Repaired '{}' from '{}'", path.display(), mirror.display());
+            true
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            error!(
+                "Error: cannot repair '{}' from '{}': {}",
+                path.display(),
+                mirror.display(),
+                e
+            );
+            false
+        }
+    }
+}
+
+/// `--skip-errors`: the kinds of read errors that skip a file instead of failing it.
+#[derive(Clone, Debug, Default)]
+struct SkipErrors(Vec<ErrorClass>);
+
+impl SkipErrors {
+    /// What a bare `--skip-errors` covers.
+    const ALL: [ErrorClass; 3] = [ErrorClass::Permission, ErrorClass::Missing, ErrorClass::Io];
+
+    /// `all` (as the bare flag gives), a comma-separated list of kinds, or, from the
+    /// environment or a configuration file, `true` or `false`.
+    fn parse(kinds: &str) -> Result<SkipErrors, String> {
+        match kinds.trim() {
+            "" | "false" | "no" | "off" | "0" => Ok(SkipErrors::default()),
+            "all" | "true" | "yes" | "on" | "1" => Ok(SkipErrors(SkipErrors::ALL.to_vec())),
+            kinds => kinds
+                .split(',')
+                .map(|kind| kind.trim().parse())
+                .collect::<Result<_, _>>()
+                .map(SkipErrors),
+        }
+    }
+
+    fn covers(&self, class: ErrorClass) -> bool {
+        self.0.contains(&class)
+    }
+
+    fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// The `--skip-errors` argument that means the same, if any.
+    fn arg(&self) -> Option<String> {
+        if SkipErrors::ALL.iter().all(|&class| self.covers(class)) {
+            return Some("--skip-errors".into());
+        }
+        let kinds: Vec<&str> = self.0.iter().map(|class| class.name()).collect();
+        self.is_set()
+            .then(|| format!("--skip-errors={}", kinds.join(",")))
+    }
+}
+
+/// CLI settings shared by generate and verify mode.
+#[derive(Clone)]
+struct Options {
+    skip_errors: SkipErrors,
+    progress: Progress,
+    /// `--progress-file`: where `--progress json` events go instead of stderr.
+    progress_file: Option<PathBuf>,
+    /// `--summary-file`: where the per-directory breakdown is written as TSV.
+    summary_file: Option<PathBuf>,
+    /// `--summary-json`: where the run's totals are written as JSON.
+    summary_json: Option<PathBuf>,
+    /// `--label`s, in order, each key once.
+    labels: Vec<(String, String)>,
+    /// `--metrics-textfile`: where the run's totals are written as Prometheus metrics.
+    metrics_textfile: Option<PathBuf>,
+    /// `--metrics-listen`: bound up front, and served from once the run starts.
+    metrics_listener: Option<Arc<TcpListener>>,
+    /// `--max-errors`: stop picking up files after this many failures.
+    max_errors: Option<usize>,
+    /// `--notify-webhook`, `--notify-email`, and `--notify-threshold`.
+    notify: notify::Notify,
+    /// `--on-fail`: what to do with files that fail verification.
+    on_fail: Option<OnFail>,
+    /// `--repair-from`: a mirror to restore files that fail verification from.
+    repair_from: Option<PathBuf>,
+    /// `--dry-run`: report what `on_fail` and `repair_from` would do, without doing it.
+    dry_run: bool,
+    /// `--record-metadata`: write each file's size and modtime above its entry.
+    record_metadata: bool,
+    /// `--note`: the text to annotate new entries with.
+    note: Option<String>,
+    /// `--metadata-only`: compare recorded sizes and modtimes instead of hashing.
+    metadata_only: bool,
+    /// `--changed-only`: hash only files whose recorded size or modtime differs.
+    changed_only: bool,
+    /// `--two-phase`: the percentage of files with unchanged metadata to hash, after all the rest.
+    two_phase: Option<f64>,
+    /// `--db`: the verification history to append results to.
+    history_db: Option<PathBuf>,
+    /// `--oldest-first`: check the files `history_db` says were verified longest ago first.
+    oldest_first: bool,
+    /// `--known-failures`: the manifest paths whose failures don't fail the run.
+    known_failures: HashSet<String>,
+    /// `--group-output`: repeat the failures together before the summary.
+    group_output: bool,
+    /// `--smart`: add the SMART sector counts of disks with failed reads to the report.
+    smart: bool,
+    /// `--max-memory`: the bytes a run should keep to, about.
+    max_memory: Option<u64>,
+    /// `--plan`: list the filesystems the files are on before hashing them.
+    plan: bool,
+    /// `--transition`: the algorithm a manifest is checked with, by name, and the one a new
+    /// manifest of the files verified OK is made with from the same reads.
+    transition: Option<(String, Algorithm)>,
+    /// `--transition-output`: where that manifest goes, instead of next to the old one.
+    transition_output: Option<PathBuf>,
+    /// `--cursor`: where the last path a run stopped early at is kept, to resume after it.
+    cursor: Option<PathBuf>,
+    /// `--max-duration`: stop starting files after this long.
+    max_duration: Option<Duration>,
+    /// `--max-files`: how many files a walk may find before asking whether to go on.
+    max_files: Option<usize>,
+    /// `--max-bytes`: how much the files a walk finds may add up to before asking.
+    max_bytes: Option<u64>,
+    /// `--paths`, or [`PathStyle::Absolute`] for `--no-canonicalize`: how walked paths are
+    /// recorded.
+    path_style: PathStyle,
+    /// `--order`, or [`Order::Physical`] for `--optimize-seek`: the order files are started in.
+    order: Order,
+    /// `--detect-clones`: hash one file per group of reflink clones.
+    detect_clones: bool,
+    /// Files in flight on the tokio backend, if `--async-io` was given.
+    async_io: Option<usize>,
+    /// Files matching any of these are left out of generate and update runs.
+    exclude: Vec<glob::Pattern>,
+    /// `--include-ads` (Windows): list alternate data streams as entries of their own.
+    include_ads: bool,
+    /// `--skip-locked` (Windows): treat sharing and lock violations like `skip_errors`.
+    skip_locked: bool,
+    /// `--include-rsrc` (macOS): list resource forks and Finder info as entries of their own.
+    include_rsrc: bool,
+    /// `--include-quarantine` (macOS): with `include_rsrc`, also list the quarantine attribute.
+    include_quarantine: bool,
+    /// `--archive` kinds, to pass on to a remote gustasum.
+    archive: Vec<String>,
+    /// `--ssh-command`: the program that reaches ssh:// paths.
+    ssh_command: String,
+    /// `--remote-gustasum`: the gustasum to hash ssh:// paths with on their host, unless `none`.
+    remote_gustasum: Option<String>,
+    /// `--anonymize-paths`: the key to hash the names in printed paths with.
+    anonymize_paths: Option<PathKey>,
+    /// `generate --structure-only`: list paths, kinds, and sizes without reading content.
+    structure_only: bool,
+    hash: HashOptions,
+}
+
+/// An existing manifest that [`generate_mode`] works from.
+#[derive(Clone, Copy)]
+enum Existing<'a> {
+    /// `update`: its entries for files that still exist are printed unchanged, and only
+    /// files it doesn't list are hashed.
+    Update(&'a Manifest),
+    /// `generate --skip-existing`: only files it doesn't list are hashed and printed, with
+    /// headers of their own.
+    OnlyNew(&'a Manifest),
+    /// `generate --append FILE`: the files under the paths are hashed and added to FILE,
+    /// whose text is `contents`. Files it lists already are refused, unless `replace`: then
+    /// they're hashed again and their old entries dropped.
+    Append {
+        manifest: &'a Manifest,
+        file: &'a str,
+        contents: &'a str,
+        replace: bool,
+    },
+    /// `generate --within`: the files it lists, only those under the paths if there are
+    /// any, are hashed into a new manifest, their paths and notes as it has them.
+    Within(&'a Manifest),
+}
+
+impl<'a> Existing<'a> {
+    fn manifest(self) -> &'a Manifest {
+        match self {
+            Existing::Update(manifest)
+            | Existing::OnlyNew(manifest)
+            | Existing::Within(manifest)
+            | Existing::Append { manifest, .. } => manifest,
+        }
+    }
+}
+
+/// The algorithm, provenance, and labels headers a manifest generated with `opts` starts with.
+fn manifest_headers(opts: &Options) -> Vec<String> {
+    let mut headers = vec![format!("{}{}", ALGORITHM_HEADER, opts.hash.algorithm.name)];
+    let mut provenance = vec![("at", utc_timestamp(SystemTime::now()))];
+    // Host and user names can give away as much as the paths.
+    if opts.anonymize_paths.is_none() {
+        provenance.push(("host", hostname()));
+        provenance.push(("user", username()));
+    }
+    provenance.extend([
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("partial_bytes", opts.hash.partial_bytes.to_string()),
+        ("include_modtime", opts.hash.include_modtime.to_string()),
+        ("fat_compat", opts.hash.fat_compat.to_string()),
+        ("sparse", opts.hash.sparse.to_string()),
+        ("include_extents", opts.hash.include_extents.to_string()),
+        (TRAILER_FIELD, "true".to_string()),
+    ]);
+    if let Some(ref salt) = opts.hash.salt {
+        provenance.push(("salt", salt.id()));
+    }
+    if let Some(digits) = opts.hash.abbrev {
+        provenance.push(("abbrev", digits.to_string()));
+    }
+    if let Some(ref key) = opts.anonymize_paths {
+        provenance.push((ANONYMIZED_FIELD, key.id()));
+    }
+    headers.push(provenance_line(&provenance));
+    if !opts.labels.is_empty() {
+        headers.push(labels_line(&opts.labels));
+    }
+    headers
+}
+
+/// Generate checksums for all files in the given paths, ignoring modtime by default.
+/// Use `include_modtime = true` if the user provided --include-modtime.
+///
+/// With an `existing` manifest, update it or append to it; see [`Existing`].
+fn generate_mode(paths: &[PathBuf], existing: Option<Existing>, opts: &Options) {
+    if existing.is_none() {
+        remote_generate(paths, opts);
+    }
+    let started = Instant::now();
+    let skip_errors = &opts.skip_errors;
+    let source = opts.hash.source.as_ref();
+    let previous = match existing {
+        Some(Existing::Update(manifest)) => Some(manifest),
+        _ => None,
+    };
+
+    // The kept entries' files, as they were hashed if the manifest recorded their sizes.
+    let kept_bytes = Cell::new(0u64);
+    let (kept, removed): (Vec<&ManifestEntry>, Vec<&ManifestEntry>) = previous
+        .into_iter()
+        .flat_map(Manifest::entries)
+        .partition(|e| match source.metadata(&e.path) {
+            Ok(meta) => {
+                let recorded = previous.and_then(|m| m.metadata.get(&e.path));
+                kept_bytes.set(kept_bytes.get() + recorded.map_or(meta.len, |m| m.len));
+                true
+            }
+            Err(_) => false,
+        });
+    // Files not to hash: those whose entries are kept, or with --skip-existing, all listed.
+    let known: HashSet<&Path> = match existing {
+        Some(Existing::OnlyNew(manifest)) => manifest.entries().map(|e| e.path.as_path()).collect(),
+        _ => kept.iter().map(|e| e.path.as_path()).collect(),
+    };
+    let skipped_existing = Cell::new(0usize);
+    let mut scan_limit = ScanLimit::new(source, opts);
+    // Files are read at the paths the walk gives, and written and matched against the
+    // manifest's entries as --paths records them.
+    let recorded = match existing {
+        Some(Existing::Within(_)) => Recorded::new(&[], opts.path_style),
+        _ => Recorded::new(paths, opts.path_style),
+    };
+
+    let found: Box<dyn Iterator<Item = PathBuf>> = match existing {
+        // Listed paths are recorded as they're listed, and PATHS matched against them so.
+        Some(Existing::Within(manifest)) => Box::new(
+            manifest
+                .entries()
+                .filter(|e| paths.is_empty() || paths.iter().any(|p| e.path.starts_with(p)))
+                .map(|e| e.path.clone()),
+        ),
+        _ => Box::new(
+            walk::files_under(source, paths)
+                .take_while(|_| !interrupted())
+                .flat_map(|p| with_streams(p, opts)),
+        ),
+    };
+    let mut walked = found
+        .filter(|p| {
+            let listed = known.contains(recorded.path(p).as_path());
+            if listed && matches!(existing, Some(Existing::OnlyNew(_))) {
+                debug!("Already listed: '{}'", p.display());
+                skipped_existing.set(skipped_existing.get() + 1);
+            }
+            !listed
+        })
+        .filter(|p| {
+            let excluded = walk::is_excluded(p, &opts.exclude);
+            if excluded {
+                debug!("Excluded '{}'", p.display());
+            }
+            !excluded
+        })
+        .inspect(|p| scan_limit.found(p));
+    // A new manifest can be written as it's made: hashing starts with the first file found,
+    // and entries are printed once they're done, in walk order, with only so many files in
+    // between. Everything else needs every file first (clone groups, an order, a cursor, the
+    // odds of an --abbrev collision) or a manifest to rewrite.
+    let streaming = existing.is_none()
+        && !opts.detect_clones
+        && opts.order == Order::Given
+        && opts.cursor.is_none()
+        && opts.async_io.is_none()
+        && opts.hash.abbrev.is_none()
+        && !opts.plan;
+    let mut files: Vec<PathBuf> = if streaming {
+        Vec::new()
+    } else {
+        list_within_memory(walked.by_ref(), opts)
+    };
+    // Nothing has been hashed yet, so there's nothing to write.
+    if TOO_BIG.load(Ordering::Relaxed) {
+        std::process::exit(1);
+    }
+    resume_at_cursor(&mut files, |p| Some(p.as_path()), opts);
+
+    // Appending: the files listed already, which are refused or replaced.
+    let (listed, replaced): (HashSet<&Path>, HashSet<&Path>) = match existing {
+        Some(Existing::Append {
+            manifest,
+            file,
+            replace,
+            ..
+        }) => {
+            let listed: HashSet<&Path> = manifest.entries().map(|e| e.path.as_path()).collect();
+            let hashing: HashSet<PathBuf> = files.iter().map(|p| recorded.path(p)).collect();
+            let replaced: HashSet<&Path> = listed
+                .iter()
+                .copied()
+                .filter(|p| hashing.contains(*p))
+                .collect();
+            if !replace && !replaced.is_empty() {
+                error!(
+                    "Error: {} files are in '{}' already; give --replace to hash them again:",
+                    replaced.len(),
+                    file
+                );
+                for path in files.iter().map(|p| recorded.path(p)) {
+                    if replaced.contains(path.as_path()) {
+                        error!("  {}", path.display());
+                    }
+                }
+                std::process::exit(1);
+            }
+            (listed, replaced)
+        }
+        _ => Default::default(),
+    };
+
+    let mut total_files = files.len();
+    if let Some(digits) = opts.hash.abbrev {
+        check_abbrev(
+            digits,
+            kept.len() + listed.len() - replaced.len() + total_files,
+            true,
+        );
+    }
+    if streaming {
+        info!("Computing partial checksums as files are found...");
+    } else {
+        info!(
+            "Found {} files. Computing partial checksums...",
+            total_files
+        );
+    }
+
+    // Clones have identical content, so unless modtimes go into the digest, the first file
+    // of each group (its representative) is hashed and the rest reuse its hash.
+    let clone_groups = if opts.detect_clones {
+        gustasum::clones::clone_groups(&files)
+    } else {
+        Vec::new()
+    };
+    // With a cursor, a clone past it is hashed when the run gets to it, not with its
+    // representative, so a run that stops early has done every file up to where it stopped.
+    let reuse_clone_hashes = !opts.hash.include_modtime && opts.cursor.is_none();
+    // An updated or appended-to manifest keeps recording metadata if it did before.
+    let record_metadata =
+        opts.record_metadata || existing.is_some_and(|e| !e.manifest().metadata.is_empty());
+    let metadata_of = |meta: Option<&SourceMetadata>, regions: &[(Region, String)]| {
+        meta.filter(|_| record_metadata)
+            .map(|meta| metadata_line(meta, regions))
+    };
+    // A file hashed again keeps its note; one new to the manifest gets the --note.
+    let note_of = |path: &Path| {
+        existing
+            .and_then(|e| e.manifest().notes.get(path))
+            .or(opts.note.as_ref())
+            .map(|note| note_line(note))
+    };
+    let mut clone_of: HashMap<&Path, &Path> = HashMap::new();
+    let mut reused_by: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    for group in &clone_groups {
+        let representative = files[group[0]].as_path();
+        for &member in &group[1..] {
+            clone_of.insert(&files[member], representative);
+            if reuse_clone_hashes {
+                reused_by
+                    .entry(representative)
+                    .or_default()
+                    .push(&files[member]);
+            }
+        }
+    }
+    let files: Vec<PathBuf> = files
+        .iter()
+        .filter(|p| !(reuse_clone_hashes && clone_of.contains_key(p.as_path())))
+        .cloned()
+        .collect();
+    if !clone_groups.is_empty() {
+        info!(
+            "Found {} reflink clones in {} groups.",
+            clone_of.len(),
+            clone_groups.len()
+        );
+    }
+    if opts.plan {
+        print_plan(files.iter().map(PathBuf::as_path), opts);
+    }
+
+    let pb = if opts.progress == Progress::Bar {
+        let bar = ProgressBar::new(files.len() as u64);
+        bar.set_draw_target(ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} files ({eta} remaining)\n  {wide_msg}",
+            )
+            .unwrap()
+            .progress_chars("=>-"),
+        );
+        Some(bar)
+    } else {
+        None
+    };
+
+    let mode = match existing {
+        Some(Existing::Update(_)) => "update",
+        Some(Existing::Append { .. }) => "append",
+        Some(Existing::OnlyNew(_) | Existing::Within(_)) | None => "generate",
+    };
+    let target: Vec<String> = paths.iter().map(|p| portable_path(p)).collect();
+    let target = target.join(",");
+    start_time_limit(opts);
+    let events =
+        CliEvents::new(files.len(), pb, opts, "files").alert_as(opts, mode, target.clone());
+
+    // The manifest's lines, printed (or appended) once everything is hashed, or as they're
+    // ready when streaming.
+    let mut lines: Vec<String> = Vec::new();
+    // What's appended goes under the manifest's own headers, and a --full manifest has none,
+    // so it's the same as sha256sum's.
+    let appending = matches!(existing, Some(Existing::Append { .. }));
+    if !appending && !opts.hash.full {
+        lines.extend(manifest_headers(opts));
+    }
+
+    let mut successes = 0usize;
+    let mut failures = 0usize;
+    let mut skipped = 0usize;
+    let mut unstable = 0usize;
+    let mut bytes_read = 0u64;
+    let mut clone_bytes_saved = 0u64;
+    // What the files of the entries written add up to, for the trailer.
+    let mut listed_bytes = kept_bytes.get();
+    let mut counted: Vec<(PathBuf, Counted, u64)> = Vec::new();
+    let mut read_failures: Vec<ReadFailure> = Vec::new();
+
+    // Diagnostics and reports keep the paths walked; only what's printed is recorded as
+    // --paths says, and anonymized.
+    let shown = |path: &Path| match opts.anonymize_paths {
+        Some(ref key) => key.anonymize(&recorded.path(path)),
+        None => recorded.path(path),
+    };
+    for entry in &kept {
+        lines.extend(
+            previous
+                .and_then(|m| m.notes.get(&entry.path))
+                .map(|note| note_line(note)),
+        );
+        lines.extend(metadata_of(
+            previous.and_then(|m| m.metadata.get(&entry.path)),
+            previous
+                .and_then(|m| m.regions.get(&entry.path))
+                .map_or(&[], Vec::as_slice),
+        ));
+        lines.push(entry.to_string());
+    }
+
+    // Count the outcome for `path`, and the clones reusing its hash, and add their entries
+    // to `lines`.
+    let mut record =
+        |path: PathBuf, result: Result<FileHash, HashError>, lines: &mut Vec<String>| {
+            let clones = reused_by.remove(path.as_path()).unwrap_or_default();
+            match result {
+                Ok(FileHash {
+                    bytes,
+                    unstable: true,
+                    ..
+                }) => {
+                    // The digest mixes old and new content, so it's not worth recording.
+                    error!(
+                        "Error: File '{}' is UNSTABLE (changed while being read)",
+                        path.display()
+                    );
+                    unstable += 1;
+                    bytes_read += bytes;
+                    counted.push((path.clone(), Counted::Failed, bytes));
+                    for clone in clones {
+                        error!(
+                        "Error: File '{}' is UNSTABLE (its clone '{}' changed while being read)",
+                        clone.display(),
+                        path.display()
+                    );
+                        unstable += 1;
+                        counted.push((clone.to_path_buf(), Counted::Failed, 0));
+                    }
+                }
+                Ok(FileHash {
+                    hash,
+                    bytes,
+                    metadata,
+                    regions,
+                    ..
+                }) => {
+                    let regions: Vec<(Region, String)> =
+                        regions.into_iter().map(|r| (r.region, r.digest)).collect();
+                    // output to stdout
+                    if let Some(representative) = clone_of.get(path.as_path()) {
+                        lines.push(format!(
+                            "{}{}",
+                            CLONE_HEADER,
+                            portable_path(&shown(representative))
+                        ));
+                    }
+                    lines.extend(note_of(&shown(&path)));
+                    lines.extend(metadata_of(Some(&metadata), &regions));
+                    lines.push(
+                        ManifestEntry {
+                            hash: hash.clone(),
+                            path: shown(&path),
+                        }
+                        .to_string(),
+                    );
+                    successes += 1;
+                    bytes_read += bytes;
+                    listed_bytes += metadata.len;
+                    counted.push((path.clone(), Counted::Ok, bytes));
+                    for clone in clones {
+                        lines.push(format!("{}{}", CLONE_HEADER, portable_path(&shown(&path))));
+                        lines.extend(note_of(&shown(clone)));
+                        lines.extend(metadata_of(source.metadata(clone).ok().as_ref(), &regions));
+                        listed_bytes += metadata.len;
+                        lines.push(
+                            ManifestEntry {
+                                hash: hash.clone(),
+                                path: shown(clone),
+                            }
+                            .to_string(),
+                        );
+                        successes += 1;
+                        clone_bytes_saved += bytes;
+                        counted.push((clone.to_path_buf(), Counted::Ok, 0));
+                    }
+                }
+                Err(e) => {
+                    read_failures.extend(ReadFailure::from_error(
+                        &path.display().to_string(),
+                        &path,
+                        &e,
+                    ));
+                    let locked = opts.skip_locked && e.is_locked();
+                    let class = e.class();
+                    for file in std::iter::once(path.as_path()).chain(clones) {
+                        let e = if file == path {
+                            e.to_string()
+                        } else {
+                            format!("its clone '{}' failed: {}", path.display(), e)
+                        };
+                        if skip_errors.covers(class) || locked {
+                            warning!("Warning: Skipping file '{}': {}", file.display(), e);
+                            skipped += 1;
+                            counted.push((file.to_path_buf(), Counted::Skipped, 0));
+                        } else {
+                            error!("Error: Could not process file '{}': {}", file.display(), e);
+                            failures += 1;
+                            counted.push((file.to_path_buf(), Counted::Error(class), 0));
+                        }
+                    }
+                }
+            }
+        };
+
+    let hash_one = |path: &PathBuf| {
+        if interrupted() {
+            return None;
+        }
+        let result = hash_file_with(path, &opts.hash, &events);
+        // Read errors were counted by `file_failed`.
+        if matches!(result, Ok(FileHash { unstable: true, .. })) {
+            events.failed(&portable_path(path));
+        }
+        Some((path.clone(), result))
+    };
+
+    let planned;
+    let processed;
+    let mut cursor_at = None;
+    let mut entries_printed = 0usize;
+    if streaming {
+        let mut out = Output::new();
+        // Returns how many of the lines were entries, for the trailer.
+        let print = |out: &mut Output, lines: &mut Vec<String>| {
+            let entries = lines.iter().filter(|l| counts_as_entry(l)).count();
+            for line in lines.drain(..) {
+                out.line(line).unwrap_or_else(output_failed);
+            }
+            entries
+        };
+        entries_printed += print(&mut out, &mut lines);
+        out.flush().unwrap_or_else(output_failed);
+        let mut written = 0usize;
+        planned = walk::run_streaming(
+            walked.inspect(|_| events.found()),
+            STREAM_DEPTH_PER_THREAD * rayon::current_num_threads(),
+            hash_one,
+            |(path, result), caught_up| {
+                record(path, result, &mut lines);
+                entries_printed += print(&mut out, &mut lines);
+                // Nothing more is ready, so whoever reads the manifest as it's made gets it
+                // this far while the next files are hashed.
+                if caught_up {
+                    out.flush().unwrap_or_else(output_failed);
+                }
+                written += 1;
+            },
+        );
+        out.flush().unwrap_or_else(output_failed);
+        events.finish();
+        processed = written;
+        total_files = planned;
+    } else {
+        let mut results: Vec<(PathBuf, Result<FileHash, HashError>)> = match opts.async_io {
+            Some(concurrency) if opts.cursor.is_none() => files
+                .iter()
+                .cloned()
+                .zip(hash_async(&files, &opts.hash, concurrency, &events))
+                .filter_map(|(path, result)| result.map(|r| (path, r)))
+                .collect(),
+            _ if opts.cursor.is_some() => walk::run_in_order(&files, hash_one),
+            _ => walk::run_scheduled(&files, opts.order, |p| p.as_path(), hash_one),
+        };
+
+        events.finish();
+
+        // With a cursor, only the unbroken run of files from the start is kept, so the next run,
+        // starting after the last of them, neither misses a file nor lists one twice.
+        if opts.cursor.is_some() {
+            let done = results
+                .iter()
+                .zip(&files)
+                .take_while(|((path, _), file)| path == *file)
+                .count();
+            results.truncate(done);
+        }
+        cursor_at = results.last().map(|(path, _)| path.clone());
+        planned = files.len();
+        processed = results.len();
+        for (path, result) in results {
+            record(path, result, &mut lines);
+        }
+    }
+    let finished = processed == planned;
+
+    // A stop that came after the last file left nothing out.
+    let was_interrupted = interrupted() && !finished;
+    if was_interrupted {
+        let marker = format!(
+            "{}: interrupted after {} of {} files",
+            PARTIAL_MARKER, processed, planned
+        );
+        // Streamed entries are out already, so there the marker comes last.
+        if streaming {
+            lines.push(marker);
+        } else {
+            lines.insert(0, marker);
+        }
+    }
+    for entry in &removed {
+        info!("Removed: '{}' no longer exists", entry.path.display());
+    }
+
+    // The trailer comes last, so a manifest cut short anywhere loses it. A --full manifest
+    // has none, like sha256sum's.
+    let with_trailer = !opts.hash.full;
+    match existing {
+        Some(Existing::Append {
+            manifest,
+            file,
+            contents,
+            ..
+        }) => {
+            let mut appended: String = without_entries(contents, &replaced)
+                .lines()
+                .filter(|l| !l.trim().starts_with(TOTALS_TRAILER.trim_end()))
+                .map(|l| format!("{}\n", l))
+                .collect();
+            // The runs before this one left the manifest partial, and it's finished it.
+            if opts.cursor.is_some() && finished {
+                appended = appended
+                    .lines()
+                    .filter(|l| !l.starts_with(PARTIAL_MARKER))
+                    .map(|l| format!("{}\n", l))
+                    .collect();
+            }
+            if !appended.is_empty() && !appended.ends_with('\n') {
+                appended.push('\n');
+            }
+            for line in &lines {
+                appended.push_str(line);
+                appended.push('\n');
+            }
+            if with_trailer {
+                // The entries there already add up to what the last trailer said, unless some
+                // were replaced; then only what's recorded or on disk can say.
+                let before = manifest
+                    .totals
+                    .filter(|_| replaced.is_empty() && manifest.trailer_mismatch().is_none())
+                    .and_then(|totals| totals.bytes)
+                    .or_else(|| {
+                        manifest
+                            .entries()
+                            .filter(|e| !replaced.contains(e.path.as_path()))
+                            .map(|e| match manifest.metadata.get(&e.path) {
+                                Some(meta) => Some(meta.len),
+                                None => source.metadata(&e.path).ok().map(|m| m.len),
+                            })
+                            .sum::<Option<u64>>()
+                    });
+                let totals = Totals {
+                    entries: appended.lines().filter(|l| counts_as_entry(l)).count(),
+                    bytes: before.map(|bytes| bytes + listed_bytes),
+                };
+                appended.push_str(&totals_line(&totals));
+                appended.push('\n');
+            }
+            if let Err(e) = write_replacing(Path::new(file), &appended) {
+                error!("Error: Failed to write '{}': {}", file, e);
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            if with_trailer {
+                let entries = lines.iter().filter(|l| counts_as_entry(l)).count();
+                lines.push(totals_line(&Totals {
+                    entries: entries_printed + entries,
+                    bytes: Some(listed_bytes),
+                }));
+            }
+            let mut out = Output::new();
+            for line in &lines {
+                out.line(line).unwrap_or_else(output_failed);
+            }
+            out.flush().unwrap_or_else(output_failed);
+        }
+    }
+    save_cursor(opts, cursor_at.as_deref(), finished);
+
+    info!(
+        "\nSummary: total files = {}, succeeded = {}, errors = {}, skipped = {}, unstable = {}",
+        total_files, successes, failures, skipped, unstable
+    );
+    if failures > 0 {
+        info!("Errors: {}", error_breakdown(&counted));
+    }
+    print_breakdown(&counted, opts.summary_file.as_deref());
+    if let Some(report) = device::report(&read_failures, opts.smart) {
+        warning!("{}", report);
+    }
+    if previous.is_some() {
+        info!(
+            "Updated: kept = {}, removed = {}, added = {}",
+            kept.len(),
+            removed.len(),
+            successes
+        );
+    }
+    if let Some(Existing::OnlyNew(_)) = existing {
+        info!(
+            "Skipped {} files the manifest lists already.",
+            skipped_existing.get()
+        );
+    }
+    if let Some(Existing::Append { file, .. }) = existing {
+        info!(
+            "Appended to '{}': added = {}, replaced = {}",
+            file,
+            successes,
+            replaced.len()
+        );
+    }
+    if !clone_groups.is_empty() {
+        if reuse_clone_hashes {
+            info!(
+                "Clones: {} files in {} groups recorded without re-reading ({} sampled bytes saved)",
+                clone_of.len(),
+                clone_groups.len(),
+                clone_bytes_saved
+            );
+        } else {
+            info!(
+                "Clones: {} files in {} groups, each hashed for its own modtime",
+                clone_of.len(),
+                clone_groups.len()
+            );
+        }
+    }
+    print_throughput(processed, bytes_read, started.elapsed());
+    let latency = events.status.latency();
+    print_latency(latency.as_ref());
+
+    let exit_code = if GAVE_UP.load(Ordering::Relaxed) {
+        info!(
+            "Stopped: {} of {} files processed before --max-errors; manifest is partial.",
+            processed, total_files
+        );
+        1
+    } else if TOO_BIG.load(Ordering::Relaxed) {
+        info!(
+            "Stopped: {} files processed before the walk passed --max-files or --max-bytes; manifest is partial.",
+            processed
+        );
+        1
+    } else if was_interrupted
+        && TIME_UP.load(Ordering::Relaxed)
+        && !INTERRUPTED.load(Ordering::Relaxed)
+    {
+        info!(
+            "Stopped: {} of {} files processed within --max-duration; manifest is partial.",
+            processed, total_files
+        );
+        i32::from(failures > 0 || unstable > 0)
+    } else if was_interrupted {
+        info!(
+            "Interrupted: {} of {} files processed; manifest is partial.",
+            processed, total_files
+        );
+        EXIT_INTERRUPTED
+    } else if failures > 0 || unstable > 0 {
+        1
+    } else {
+        0
+    };
+    report_run(
+        opts,
+        &RunReport {
+            mode,
+            target: &target,
+            total: total_files,
+            counted: &counted,
+            malformed: 0,
+            read_failures: &read_failures,
+            latency: latency.as_ref(),
+            elapsed: started.elapsed(),
+            exit_code,
+        },
+    );
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// Generate for `paths` on another host, when they're all `ssh://` paths on the same one and
+/// it has the same version of gustasum: run that with the options affecting the manifest,
+/// and print its entries with the `ssh://` prefix put back. Its diagnostics and summary
+/// go straight to stderr, and its exit code becomes ours. Returns only if it doesn't apply.
+fn remote_generate(paths: &[PathBuf], opts: &Options) {
+    let Some(program) = &opts.remote_gustasum else {
+        return;
+    };
+    // The salt and key files are here, not there, and so is the cursor.
+    if opts.hash.salt.is_some() || opts.anonymize_paths.is_some() || opts.cursor.is_some() {
+        return;
+    }
+    let Some(urls) = paths
+        .iter()
+        .map(|p| SshUrl::parse(p))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return;
+    };
+    let Some(host) = urls.first() else {
+        return;
+    };
+    if urls.iter().any(|u| u.prefix() != host.prefix()) {
+        return;
+    }
+    let remote = |args: &[String]| {
+        let mut command =
+            host.remote_command(&opts.ssh_command, std::iter::once(program).chain(args));
+        command.stdin(std::process::Stdio::null());
+        command
+    };
+
+    // Any other version may hash differently or not know an option.
+    let version = remote(&["--version".to_string()])
+        .stderr(std::process::Stdio::null())
+        .output();
+    let ours = cli().render_version();
+    match version {
+        Ok(out) if out.status.success() && String::from_utf8_lossy(&out.stdout) == ours => {}
+        _ => {
+            verbose!(
+                "No '{}' matching {} on {}; reading the files over SFTP",
+                program,
+                ours.trim(),
+                host.destination
+            );
+            return;
+        }
+    }
+    info!("Hashing on {} with its '{}'...", host.destination, program);
+
+    let mut args: Vec<String> = vec![
+        "generate".into(),
+        "--algorithm".into(),
+        opts.hash.algorithm.name.to_string(),
+        "--partial-bytes".into(),
+        opts.hash.partial_bytes.to_string(),
+    ];
+    for (set, flag) in [
+        (opts.hash.include_modtime, "--include-modtime"),
+        (opts.hash.fat_compat, "--fat-compat"),
+        (opts.hash.sparse, "--sparse"),
+        (opts.hash.include_extents, "--include-extents"),
+        (opts.hash.full, "--full"),
+        (opts.record_metadata, "--record-metadata"),
+        (opts.detect_clones, "--detect-clones"),
+    ] {
+        if set {
+            args.push(flag.into());
+        }
+    }
+    args.extend(opts.skip_errors.arg());
+    if let Some(max) = opts.max_errors {
+        args.extend(["--max-errors".into(), max.to_string()]);
+    }
+    if let Some(digits) = opts.hash.abbrev {
+        args.extend(["--abbrev".into(), digits.to_string()]);
+    }
+    for pattern in &opts.exclude {
+        args.extend(["--exclude".into(), pattern.as_str().to_string()]);
+    }
+    for kind in &opts.archive {
+        args.extend(["--archive".into(), kind.clone()]);
+    }
+    for (key, value) in &opts.labels {
+        args.extend(["--label".into(), format!("{}={}", key, value)]);
+    }
+    if let Some(ref note) = opts.note {
+        args.extend(["--note".into(), note.clone()]);
+    }
+    if let Some(limit) = opts.max_duration {
+        args.extend(["--max-duration".into(), format!("{}s", limit.as_secs())]);
+    }
+    args.push("--".into());
+    args.extend(urls.iter().map(|u| u.path.clone()));
+
+    let mut child = remote(&args)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| {
+            error!("Error: cannot run '{}': {}", opts.ssh_command, e);
+            std::process::exit(1);
+        });
+    let prefix = host.prefix();
+    let mut out = Output::new();
+    let mut relayed = BufReader::new(child.stdout.take().unwrap());
+    while let Some(Ok(line)) = relayed.by_ref().lines().next() {
+        let line = if let Some(path) = line.strip_prefix(CLONE_HEADER) {
+            format!("{}{}{}", CLONE_HEADER, prefix, path)
+        } else if line.starts_with('#') {
+            line
+        } else {
+            match parse_line(&line) {
+                Some(entry) => ManifestEntry {
+                    path: PathBuf::from(format!("{}{}", prefix, portable_path(&entry.path))),
+                    ..entry
+                }
+                .to_string(),
+                None => line,
+            }
+        };
+        out.line(line).unwrap_or_else(output_failed);
+        // Everything the remote run has printed so far is through.
+        if relayed.buffer().is_empty() {
+            out.flush().unwrap_or_else(output_failed);
+        }
+    }
+    out.flush().unwrap_or_else(output_failed);
+    let status = child.wait();
+    std::process::exit(match status.ok().and_then(|s| s.code()) {
+        Some(code) => code,
+        None if interrupted() => EXIT_INTERRUPTED,
+        None => 1,
+    });
+}
+
+/// `path`, followed by its alternate data streams if `include_ads` is set and its resource
+/// fork and Finder attributes if `include_rsrc` is.
+fn with_streams(path: PathBuf, opts: &Options) -> Vec<PathBuf> {
+    let mut streams = Vec::new();
+    if opts.include_ads {
+        match gustasum::ads::alternate_streams(&path) {
+            Ok(s) => streams.extend(s),
+            Err(e) => warning!(
+                "Warning: Cannot list alternate data streams of '{}': {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    if opts.include_rsrc {
+        match gustasum::forks::named_forks(&path, opts.include_quarantine) {
+            Ok(s) => streams.extend(s),
+            Err(e) => warning!(
+                "Warning: Cannot read resource fork or Finder info of '{}': {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    std::iter::once(path).chain(streams).collect()
+}
+
+/// Verify checksums from `--check`, with optional path remapping & modtime usage.
+fn verify_mode(check_file: &str, manifest: &Manifest, verify_opts: &VerifyOptions, opts: &Options) {
+    let started = Instant::now();
+    let skip_errors = &opts.skip_errors;
+    if manifest.partial {
+        warning!(
+            "Warning: '{}' is a partial manifest from an interrupted run; files it doesn't list go unchecked.",
+            check_file
+        );
+    }
+    // Checking what's there still goes ahead, but the run fails.
+    if let Some(mismatch) = manifest.trailer_mismatch() {
+        error!(
+            "Error: '{}' may have been cut short: {}; files past the cut go unchecked.",
+            check_file, mismatch
+        );
+    }
+
+    if !manifest.generated.is_empty() {
+        info!("{}", describe_provenance(manifest));
+    }
+    if let Some(digits) = verify_opts.hash.abbrev {
+        check_abbrev(digits, manifest.entries().count(), false);
+    }
+    if !opts.labels.is_empty() {
+        let labels: Vec<String> = opts
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        info!("Labels: {}", labels.join(", "));
+    }
+
+    if manifest.generated(STRUCTURE_FIELD) == Some("true") {
+        return structure_check_mode(check_file, manifest, verify_opts, opts);
+    }
+    if opts.metadata_only {
+        return metadata_check_mode(check_file, manifest, verify_opts, opts);
+    }
+    if opts.changed_only && opts.history_db.is_none() && manifest.metadata.is_empty() {
+        warning!(
+            "Warning: '{}' has no recorded metadata (see --record-metadata); checking every file.",
+            check_file
+        );
+    }
+
+    // Remap up front, so the read order can be chosen before any hashing starts.
+    let checks: Vec<(&Result<ManifestEntry, String>, PathBuf)> = manifest
+        .lines
+        .iter()
+        .map(|line| {
+            let path = match line {
+                Ok(entry) => verify::resolve_path(entry, verify_opts),
+                Err(_) => PathBuf::new(),
+            };
+            (line, path)
+        })
+        .collect();
+
+    // Files that can't be stat'ed count as changed, so hashing reports them as errors.
+    let source = verify_opts.hash.source.as_ref();
+    let unchanged = |(line, path): &(&Result<ManifestEntry, String>, PathBuf)| {
+        let Ok(entry) = line else { return false };
+        match (manifest.metadata.get(&entry.path), source.metadata(path)) {
+            (Some(recorded), Ok(actual)) => {
+                verify::metadata_change(recorded, &actual, &verify_opts.hash).is_none()
+            }
+            _ => false,
+        }
+    };
+    let history = opts
+        .history_db
+        .as_deref()
+        .filter(|_| opts.changed_only || opts.oldest_first)
+        .map(|db| {
+            History::load(db).unwrap_or_else(|e| {
+                error!("Error: cannot read '{}': {}", db.display(), e);
+                std::process::exit(1);
+            })
+        });
+    let mut checks = checks;
+    if opts.changed_only {
+        let before = checks.len();
+        match history {
+            // Against the last time each file was verified, rather than the manifest.
+            Some(ref history) => {
+                let verified = |(line, path): &(&Result<ManifestEntry, String>, PathBuf)| {
+                    line.as_ref().is_ok_and(|entry| {
+                        Stamp::of(path)
+                            .is_ok_and(|now| history.unchanged_since_verified(&entry.path, &now))
+                    })
+                };
+                let keep: Vec<bool> = checks.par_iter().map(|check| !verified(check)).collect();
+                let mut keep = keep.into_iter();
+                checks.retain(|_| keep.next().unwrap());
+                info!(
+                    "Skipping {} files unchanged since --db last verified them OK.",
+                    before - checks.len()
+                );
+            }
+            None => {
+                checks.retain(|check| !unchanged(check));
+                info!(
+                    "Skipping {} files whose size and modtime are unchanged.",
+                    before - checks.len()
+                );
+            }
+        }
+    }
+
+    // Stalest first, never-verified files before all others, so a time-limited scrub
+    // always gets to the data that's gone longest unchecked.
+    if opts.oldest_first {
+        let history = history.as_ref().unwrap();
+        checks.sort_by_key(|(line, _)| {
+            line.as_ref()
+                .ok()
+                .and_then(|e| history.last_verified(&e.path))
+        });
+    }
+
+    // Suspects go first, all of them; of the rest, only a sample is read, at random or, with
+    // --oldest-first, the stalest.
+    if let Some(percent) = opts.two_phase {
+        let (suspects, rest): (Vec<_>, Vec<_>) = checks.into_iter().partition(|c| !unchanged(c));
+        let unchanged_count = rest.len();
+        let wanted = (unchanged_count as f64 * percent / 100.0).ceil() as usize;
+        let sampled: Vec<_> = if opts.oldest_first {
+            rest.into_iter().take(wanted).collect()
+        } else {
+            let state = RandomState::new();
+            let mut keyed: Vec<_> = rest
+                .into_iter()
+                .map(|c| (state.hash_one(&c.1), c))
+                .collect();
+            keyed.sort_by_key(|(key, _)| *key);
+            keyed.into_iter().take(wanted).map(|(_, c)| c).collect()
+        };
+        info!(
+            "Two-phase: {} files with changed or unrecorded metadata first, then {} of {} unchanged files ({}%).",
+            suspects.len(),
+            sampled.len(),
+            unchanged_count,
+            percent
+        );
+        checks = suspects.into_iter().chain(sampled).collect();
+    }
+    resume_at_cursor(
+        &mut checks,
+        |(line, _)| line.as_ref().ok().map(|e| e.path.as_path()),
+        opts,
+    );
+
+    let total_lines = checks.len();
+    info!("Found {} checks to perform. Verifying...", total_lines);
+    if let Some(max) = opts.max_memory {
+        let listed: u64 = checks.iter().map(|(_, path)| listed_memory(path)).sum();
+        if listed > max / 2 {
+            warning!(
+                "Warning: checking {} files takes about {} of memory for them, more than half of --max-memory; \
+                 every line of a manifest is held while it's checked.",
+                total_lines,
+                format_bytes(listed as f64)
+            );
+        }
+    }
+    if opts.plan {
+        let files = checks.iter().filter(|(line, _)| line.is_ok());
+        print_plan(files.map(|(_, path)| path.as_path()), opts);
+    }
+
+    let pb = if opts.progress == Progress::Bar {
+        let bar = ProgressBar::new(total_lines as u64);
+        bar.set_draw_target(ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} lines ({eta} remaining)\n  {wide_msg}",
+            )
+            .unwrap()
+            .progress_chars("=>-"),
+        );
+        Some(bar)
+    } else {
+        None
+    };
+
+    start_time_limit(opts);
+
+    // Known failures are looked up by the paths the manifest lists, and their reads by the
+    // paths they're read from.
+    let is_known = |path: &Path| opts.known_failures.contains(&portable_path(path));
+    let expected: HashSet<String> = checks
+        .iter()
+        .filter_map(|(line, path)| {
+            let entry = line.as_ref().ok()?;
+            is_known(&entry.path).then(|| portable_path(path))
+        })
+        .collect();
+    let events = CliEvents::new(total_lines, pb, opts, "lines")
+        .alert_as(opts, "check", check_file.to_string())
+        .expecting(expected);
+
+    // Mirror copies are found at the same path relative to the new base of `--remap`, or
+    // else to the directory all entries share.
+    let repair_base = match &verify_opts.remap {
+        Some((_, new_base)) => new_base.clone(),
+        None => common_dir(checks.iter().map(|(_, path)| path.as_path())),
+    };
+    let repaired = AtomicUsize::new(0);
+    // --transition: the new algorithm's hashes of the files that verified OK.
+    let transitioned: Mutex<HashMap<PathBuf, FileHash>> = Mutex::new(HashMap::new());
+    let check = |entry: &ManifestEntry, mut hashed: Result<FileHash, HashError>| {
+        let also = hashed.as_mut().ok().and_then(|h| h.also.take());
+        let result = verify::check_hash(entry, hashed, &verify_opts.hash);
+        if let (CheckOutcome::Ok, Some(also)) = (&result.outcome, also) {
+            transitioned
+                .lock()
+                .unwrap()
+                .insert(entry.path.clone(), *also);
+        }
+        result
+    };
+    let on_failure = |path: &Path, entry: &ManifestEntry| {
+        let restored =
+            opts.repair_from
+                .as_ref()
+                .is_some_and(|mirror| match path.strip_prefix(&repair_base) {
+                    Ok(relative) => repair(path, &mirror.join(relative), entry, opts),
+                    Err(_) => {
+                        error!(
+                            "Error: cannot repair '{}': not under '{}'",
+                            path.display(),
+                            repair_base.display()
+                        );
+                        false
+                    }
+                });
+        if restored {
+            repaired.fetch_add(1, Ordering::Relaxed);
+        } else if let Some(ref on_fail) = opts.on_fail {
+            quarantine(path, on_fail, opts.dry_run);
+        }
+    };
+
+    let check_one = |(line, path): &(&Result<ManifestEntry, String>, PathBuf)| {
+        if interrupted() {
+            return None;
+        }
+        let entry = match line {
+            Ok(entry) => entry,
+            Err(raw) => {
+                events.skip();
+                events.failed(&format!("malformed line: {}", raw));
+                return Some(verify::malformed(raw));
+            }
+        };
+
+        let result = check(entry, hash_file_with(path, &verify_opts.hash, &events));
+        if matches!(
+            result.outcome,
+            CheckOutcome::Mismatch | CheckOutcome::Unstable
+        ) {
+            events.failed(&portable_path(path));
+            on_failure(path, entry);
+        }
+        Some(result)
+    };
+
+    let results: Vec<CheckResult> = match opts.async_io {
+        Some(concurrency) if opts.cursor.is_none() => {
+            let paths: Vec<PathBuf> = checks
+                .iter()
+                .filter(|(line, _)| line.is_ok())
+                .map(|(_, path)| path.clone())
+                .collect();
+            let mut hashed =
+                hash_async(&paths, &verify_opts.hash, concurrency, &events).into_iter();
+            checks
+                .iter()
+                .filter_map(|(line, path)| match line {
+                    Ok(entry) => hashed.next().flatten().map(|r| {
+                        let result = check(entry, r);
+                        if matches!(
+                            result.outcome,
+                            CheckOutcome::Mismatch | CheckOutcome::Unstable
+                        ) {
+                            on_failure(path, entry);
+                        }
+                        result
+                    }),
+                    Err(raw) => {
+                        events.skip();
+                        Some(verify::malformed(raw))
+                    }
+                })
+                .collect()
+        }
+        _ if opts.oldest_first || opts.two_phase.is_some() || opts.cursor.is_some() => {
+            walk::run_in_order(&checks, check_one)
+        }
+        _ => walk::run_scheduled(&checks, opts.order, |(_, p)| p.as_path(), check_one),
+    };
+
+    let processed = results.len();
+
+    events.finish();
+
+    // The unbroken run of checks from the start; one that began as the run was stopping
+    // may have been dropped while a later one went ahead.
+    let done = results
+        .iter()
+        .zip(&checks)
+        .take_while(|(result, (line, _))| match line {
+            Ok(entry) => result.path == entry.path.display().to_string(),
+            Err(raw) => result.path == *raw,
+        })
+        .count();
+    save_cursor(
+        opts,
+        checks[..done]
+            .iter()
+            .rev()
+            .find_map(|(line, _)| line.as_ref().ok())
+            .map(|e| e.path.as_path()),
+        done == checks.len(),
+    );
+
+    let mut ok_count = 0usize;
+    let mut fail_count = 0usize;
+    let mut malformed = 0usize;
+    let mut skipped = 0usize;
+    let mut known_passed = 0usize;
+    let mut bytes_read = 0u64;
+    let mut counted: Vec<(PathBuf, Counted, u64)> = Vec::new();
+    let mut read_failures: Vec<ReadFailure> = Vec::new();
+    let mut grouped: Vec<(Severity, String)> = Vec::new();
+
+    let mut out = Output::new();
+    for CheckResult {
+        path: original_path,
+        bytes,
+        outcome,
+        found,
+    } in results
+    {
+        bytes_read += bytes;
+        let shown = result_path(&original_path);
+        if let CheckOutcome::Error(ref e) = outcome {
+            let entry = ManifestEntry {
+                hash: String::new(),
+                path: PathBuf::from(&original_path),
+            };
+            let read_from = verify::resolve_path(&entry, verify_opts);
+            read_failures.extend(ReadFailure::from_error(&original_path, &read_from, e));
+        }
+        // A known failure is reported as one, as a warning, and doesn't fail the run.
+        let known = is_known(Path::new(&original_path));
+        let mut failed = |message: String| {
+            let (severity, message) = if known {
+                (Severity::Warning, format!("{} (known)", message))
+            } else {
+                (Severity::Error, message)
+            };
+            diagnostic(severity, message.clone());
+            if opts.group_output {
+                grouped.push((severity, message));
+            }
+        };
+        let how = match outcome {
+            CheckOutcome::Ok => {
+                out.line(format_args!("{}: {}", shown, style("OK").green()))
+                    .unwrap_or_else(output_failed);
+                ok_count += 1;
+                if known {
+                    known_passed += 1;
+                }
+                Counted::Ok
+            }
+            CheckOutcome::Mismatch => {
+                let details = found.map_or_else(Vec::new, |found| {
+                    verify::mismatch_details(
+                        manifest,
+                        Path::new(&original_path),
+                        &found,
+                        &verify_opts.hash,
+                    )
+                });
+                if details.is_empty() {
+                    failed(format!("{}: FAILED (mismatch)", shown));
+                } else {
+                    failed(format!(
+                        "{}: FAILED (mismatch: {})",
+                        shown,
+                        details.join("; ")
+                    ));
+                }
+                Counted::Failed
+            }
+            CheckOutcome::Unstable => {
+                failed(format!(
+                    "{}: FAILED (UNSTABLE: changed while being read)",
+                    shown
+                ));
+                Counted::Failed
+            }
+            // --skip-errors only covers files we couldn't read; bad content and bad
+            // manifest lines always count as failures.
+            CheckOutcome::Error(e)
+                if skip_errors.covers(e.class()) || (opts.skip_locked && e.is_locked()) =>
+            {
+                warning!("Warning: Skipping file '{}': {}", original_path, e);
+                skipped += 1;
+                Counted::Skipped
+            }
+            CheckOutcome::Error(e) => {
+                failed(format!("{}: FAILED to compute hash ({})", shown, e));
+                Counted::Error(e.class())
+            }
+            CheckOutcome::Malformed => {
+                let message = format!("{}: FAILED (malformed line)", shown);
+                error!("{}", message);
+                if opts.group_output {
+                    grouped.push((Severity::Error, message));
+                }
+                fail_count += 1;
+                malformed += 1;
+                continue;
+            }
+        };
+        let how = match how {
+            Counted::Failed | Counted::Error(_) if known => Counted::Known,
+            Counted::Failed | Counted::Error(_) => {
+                fail_count += 1;
+                how
+            }
+            how => how,
+        };
+        if matches!(how, Counted::Failed | Counted::Error(_) | Counted::Known) {
+            show_note(manifest, Path::new(&original_path));
+        }
+        counted.push((PathBuf::from(original_path), how, bytes));
+    }
+    out.flush().unwrap_or_else(output_failed);
+
+    print_grouped(&grouped);
+    info!(
+        "\nSummary: total checks = {}, OK = {}, FAILED = {}, skipped = {}",
+        total_lines, ok_count, fail_count, skipped
+    );
+    if fail_count > 0
+        || counted
+            .iter()
+            .any(|(_, how, _)| matches!(how, Counted::Known))
+    {
+        info!("Failures: {}", failure_breakdown(&counted, malformed));
+    }
+    if known_passed > 0 {
+        info!(
+            "{} files listed in --known-failures verified OK; they can come off the list.",
+            known_passed
+        );
+    }
+    // Nothing matching a header-less manifest suggests it's from sha256sum and friends.
+    if ok_count == 0
+        && manifest.generated.is_empty()
+        && !opts.hash.full
+        && counted.iter().any(|(_, c, _)| matches!(c, Counted::Failed))
+    {
+        info!("No file matched and the manifest has no '# gustasum generated:' header; if it's from sha256sum or sha512sum, check it with --full.");
+    }
+    if let Some(ref mirror) = opts.repair_from {
+        info!(
+            "{}: {} of {} failed files from '{}'",
+            if opts.dry_run {
+                "Would repair"
+            } else {
+                "Repaired"
+            },
+            repaired.load(Ordering::Relaxed),
+            fail_count,
+            mirror.display()
+        );
+    }
+    print_breakdown(&counted, opts.summary_file.as_deref());
+    if let Some(report) = device::report(&read_failures, opts.smart) {
+        warning!("{}", report);
+    }
+    print_throughput(processed, bytes_read, started.elapsed());
+    let latency = events.status.latency();
+    print_latency(latency.as_ref());
+    if let Some((_, ref to)) = opts.transition {
+        let output = opts
+            .transition_output
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("{}.{}", check_file, to.name)));
+        write_transitioned(
+            manifest,
+            &transitioned.into_inner().unwrap(),
+            &output,
+            to,
+            opts,
+        );
+    }
+    if let Some(ref db) = opts.history_db {
+        record_history(db, &opts.labels, &counted, |path| {
+            let entry = ManifestEntry {
+                hash: String::new(),
+                path: path.to_path_buf(),
+            };
+            Stamp::of(&verify::resolve_path(&entry, verify_opts)).ok()
+        });
+    }
+
+    let exit_code = if GAVE_UP.load(Ordering::Relaxed) {
+        info!(
+            "Stopped: {} of {} checks performed before --max-errors.",
+            processed, total_lines
+        );
+        1
+    } else if TIME_UP.load(Ordering::Relaxed) && !INTERRUPTED.load(Ordering::Relaxed) {
+        // Running out of time is the plan for a scrub, not a failure.
+        info!(
+            "Stopped: {} of {} checks performed within --max-duration.",
+            processed, total_lines
+        );
+        i32::from(fail_count > 0)
+    } else if interrupted() {
+        info!(
+            "Interrupted: {} of {} checks performed.",
+            processed, total_lines
+        );
+        EXIT_INTERRUPTED
+    } else {
+        i32::from(fail_count > 0 || manifest.trailer_mismatch().is_some())
+    };
+    report_run(
+        opts,
+        &RunReport {
+            mode: "check",
+            target: check_file,
+            total: total_lines,
+            counted: &counted,
+            malformed,
+            read_failures: &read_failures,
+            latency: latency.as_ref(),
+            elapsed: started.elapsed(),
+            exit_code,
+        },
+    );
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// How much of stdout is buffered when it's a pipe or a file.
+const OUTPUT_BUFFER: usize = 64 * 1024;
+
+/// The longest a buffered line waits for its block to fill while more lines keep coming.
+const OUTPUT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `path` as a check result shows it: escaped as in a manifest, and then with a backslash in
+/// front, as `sha256sum -c` prints it, so that each result stays on one line.
+fn result_path(path: &str) -> String {
+    escape_path(path).map_or_else(|| path.to_string(), |escaped| format!("\\{}", escaped))
+}
+
+/// Manifest lines and check results, on stdout. To a pipe or a file they're written in
+/// blocks, since a write per line slows down a run of millions of files; to a terminal, where
+/// they mix with messages on stderr, a line at a time. What's buffered is written out by
+/// [`Output::flush`], at least every [`OUTPUT_FLUSH_INTERVAL`] while lines come, and last of
+/// all by whoever's printing, since what's still buffered on drop can fail unnoticed. A write
+/// that fails goes to [`output_failed`].
+struct Output<W: Write = io::Stdout> {
+    out: BufWriter<W>,
+    terminal: bool,
+    flushed: Instant,
+}
+
+impl Output {
+    fn new() -> Output {
+        Output::to(io::stdout(), atty::is(Stream::Stdout))
+    }
+}
+
+impl<W: Write> Output<W> {
+    fn to(out: W, terminal: bool) -> Output<W> {
+        Output {
+            out: BufWriter::with_capacity(OUTPUT_BUFFER, out),
+            terminal,
+            flushed: Instant::now(),
+        }
+    }
+
+    fn line(&mut self, line: impl std::fmt::Display) -> io::Result<()> {
+        writeln!(self.out, "{}", line)?;
+        if self.terminal || self.flushed.elapsed() >= OUTPUT_FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write out the lines so far, for whoever is reading them as they come.
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.flushed = Instant::now();
+        Ok(())
+    }
+}
+
+/// Exit after writing to stdout failed: quietly if whoever was reading it went away, as with
+/// `| head`, and with an error otherwise, since a run whose output was lost didn't succeed.
+fn output_failed(e: io::Error) {
+    if let Some(dashboard) = DASHBOARD.lock().unwrap().take() {
+        dashboard.close();
+    }
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        std::process::exit(0);
+    }
+    error!("Error: Failed to write to stdout: {}", e);
+    std::process::exit(1);
+}
+
+/// The `--plan` report: the filesystems `files` are on, and whether their kind of storage
+/// would be read better some other way.
+fn print_plan<'a>(files: impl Iterator<Item = &'a Path>, opts: &Options) {
+    let source = opts.hash.source.as_ref();
+    let sized: Vec<(&Path, u64)> = files
+        .map(|path| (path, source.metadata(path).map_or(0, |meta| meta.len)))
+        .collect();
+    let planned = device::plan(sized.iter().copied());
+    // One pool reads for every filesystem, so each gets as many reads at once as there are.
+    let reads = match opts.order {
+        Order::Physical => 1,
+        _ => opts.async_io.unwrap_or_else(rayon::current_num_threads),
+    };
+    info!(
+        "Plan: {} files, {}, on {} filesystems, up to {} read at once:",
+        sized.len(),
+        format_bytes(sized.iter().map(|(_, len)| *len as f64).sum()),
+        planned.len(),
+        reads
+    );
+    for fs in &planned {
+        let device = fs.device.as_deref().unwrap_or("unknown device");
+        let mount = fs.mount.as_ref().map_or(String::new(), |(point, fstype)| {
+            format!(" ({} on {})", fstype, point.display())
+        });
+        let advice = match fs.storage {
+            Storage::Hdd if reads > 1 => {
+                "; --optimize-seek reads it one file at a time in disk order"
+            }
+            Storage::Network if opts.async_io.is_none() && opts.hash.cpu_gate.is_none() => {
+                "; --io-concurrency keeps more reads waiting on the server"
+            }
+            _ => "",
+        };
+        info!(
+            "  {}{}, {}: {} files, {}{}",
+            device,
+            mount,
+            fs.storage,
+            fs.files,
+            format_bytes(fs.bytes as f64),
+            advice
+        );
+    }
+}
+
+/// `--group-output`: the failures printed along the way, again, so they needn't be looked for
+/// among every file's OK.
+fn print_grouped(failures: &[(Severity, String)]) {
+    if failures.is_empty() {
+        return;
+    }
+    info!("\n{} failed:", failures.len());
+    for (severity, line) in failures {
+        echo(*severity, line);
+    }
+}
+
+/// `check --transition`: write to `output` a manifest of `to`'s hashes of the files of
+/// `manifest` that verified OK, in its order and with its notes, settings, and labels. It's
+/// marked partial if any are left out, as those that failed or weren't gotten to.
+fn write_transitioned(
+    manifest: &Manifest,
+    hashes: &HashMap<PathBuf, FileHash>,
+    output: &Path,
+    to: &Algorithm,
+    opts: &Options,
+) {
+    let total = manifest.entries().count();
+    let mut lines: Vec<String> = Vec::new();
+    if hashes.len() < total && !opts.hash.full {
+        lines.push(format!(
+            "{}: {} of {} files verified OK and carried over",
+            PARTIAL_MARKER,
+            hashes.len(),
+            total
+        ));
+    }
+    let mut new_opts = opts.clone();
+    new_opts.hash.algorithm = *to;
+    if !opts.hash.full {
+        lines.extend(manifest_headers(&new_opts));
+    }
+    let mut listed_bytes = 0u64;
+    for entry in manifest.entries() {
+        let Some(found) = hashes.get(&entry.path) else {
+            continue;
+        };
+        if let Some(note) = manifest.notes.get(&entry.path) {
+            lines.push(note_line(note));
+        }
+        if !manifest.metadata.is_empty() {
+            let regions: Vec<(Region, String)> = found
+                .regions
+                .iter()
+                .map(|r| (r.region, r.digest.clone()))
+                .collect();
+            lines.push(metadata_line(&found.metadata, &regions));
+        }
+        listed_bytes += found.metadata.len;
+        lines.push(
+            ManifestEntry {
+                hash: found.hash.clone(),
+                path: entry.path.clone(),
+            }
+            .to_string(),
+        );
+    }
+    if !opts.hash.full {
+        lines.push(totals_line(&Totals {
+            entries: hashes.len(),
+            bytes: Some(listed_bytes),
+        }));
+    }
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    if let Err(e) = write_replacing(output, &contents) {
+        error!("Error: Failed to write '{}': {}", output.display(), e);
+        std::process::exit(1);
+    }
+    info!(
+        "Wrote {} of {} files as {} to '{}'.",
+        hashes.len(),
+        total,
+        to.name,
+        output.display()
+    );
+    if hashes.len() < total {
+        warning!(
+            "Warning: {} files didn't verify OK, so '{}' leaves them out; check them and generate their entries apart.",
+            total - hashes.len(),
+            output.display()
+        );
+    }
+}
+
+/// "Manifest generated 2024-03-01T09:30:00.000Z on backup01 by alice with gustasum 0.3.0
+/// (partial_bytes=4096, ...)", from its
+/// [`GENERATED_HEADER`](gustasum::manifest::GENERATED_HEADER).
+fn describe_provenance(manifest: &Manifest) -> String {
+    let mut line = "Manifest generated".to_string();
+    for (key, prefix) in [
+        ("at", " "),
+        ("host", " on "),
+        ("user", " by "),
+        ("version", " with gustasum "),
+    ] {
+        if let Some(value) = manifest.generated(key).filter(|v| !v.is_empty()) {
+            line.push_str(prefix);
+            line.push_str(value);
+        }
+    }
+    let settings: Vec<String> = manifest
+        .generated
+        .iter()
+        .filter(|(key, _)| {
+            !matches!(key.as_str(), "at" | "host" | "user" | "version") && key != TRAILER_FIELD
+        })
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    if !settings.is_empty() {
+        line.push_str(&format!(" ({})", settings.join(", ")));
+    }
+    line
+}
+
+/// `check --metadata-only`: compare sizes and modtimes recorded by `--record-metadata` to the
+/// files', without reading any content.
+fn metadata_check_mode(
+    check_file: &str,
+    manifest: &Manifest,
+    verify_opts: &VerifyOptions,
+    opts: &Options,
+) {
+    let started = Instant::now();
+    if manifest.metadata.is_empty() {
+        error!(
+            "Error: '{}' has no recorded metadata; generate it with --record-metadata",
+            check_file
+        );
+        std::process::exit(1);
+    }
+    let source = verify_opts.hash.source.as_ref();
+    info!(
+        "Found {} checks to perform. Comparing metadata...",
+        manifest.lines.len()
+    );
+
+    let results: Vec<(String, io::Result<Option<String>>)> = manifest
+        .lines
+        .par_iter()
+        .map(|line| match line {
+            Ok(entry) => {
+                let result = match manifest.metadata.get(&entry.path) {
+                    None => Err(io::Error::other(NO_METADATA)),
+                    Some(recorded) => source
+                        .metadata(&verify::resolve_path(entry, verify_opts))
+                        .map(|actual| {
+                            verify::metadata_change(recorded, &actual, &verify_opts.hash)
+                        }),
+                };
+                (entry.path.display().to_string(), result)
+            }
+            Err(raw) => (raw.clone(), Ok(Some("malformed line".to_string()))),
+        })
+        .collect();
+    finish_stat_check(check_file, manifest, &results, started, opts);
+}
+
+/// Why `check --metadata-only` skips an entry: it has nothing to compare with.
+const NO_METADATA: &str = "no recorded metadata";
+
+/// Print and report the `results` of a check of `manifest` that only stat'ed files: for each
+/// entry, the change found if any, or why the file couldn't be stat'ed.
+fn finish_stat_check(
+    check_file: &str,
+    manifest: &Manifest,
+    results: &[(String, io::Result<Option<String>>)],
+    started: Instant,
+    opts: &Options,
+) {
+    let mut ok_count = 0usize;
+    let mut fail_count = 0usize;
+    let mut skipped = 0usize;
+    let mut counted: Vec<(PathBuf, Counted, u64)> = Vec::new();
+    let mut grouped: Vec<(Severity, String)> = Vec::new();
+    let mut out = Output::new();
+    for (path, result) in results {
+        let known = opts
+            .known_failures
+            .contains(&portable_path(Path::new(path)));
+        let mut failed = |message: String| {
+            let (severity, message) = if known {
+                (Severity::Warning, format!("{} (known)", message))
+            } else {
+                (Severity::Error, message)
+            };
+            diagnostic(severity, message.clone());
+            if opts.group_output {
+                grouped.push((severity, message));
+            }
+        };
+        let shown = result_path(path);
+        let how = match result {
+            Ok(None) => {
+                out.line(format_args!("{}: {}", shown, style("OK").green()))
+                    .unwrap_or_else(output_failed);
+                ok_count += 1;
+                Counted::Ok
+            }
+            Ok(Some(change)) => {
+                failed(format!("{}: FAILED ({})", shown, change));
+                Counted::Failed
+            }
+            Err(e)
+                if e.to_string() == NO_METADATA
+                    || opts.skip_errors.covers(ErrorClass::of(e.kind())) =>
+            {
+                warning!("Warning: Skipping file '{}': {}", path, e);
+                skipped += 1;
+                Counted::Skipped
+            }
+            Err(e) => {
+                failed(format!("{}: FAILED to read metadata ({})", shown, e));
+                Counted::Error(ErrorClass::of(e.kind()))
+            }
+        };
+        let how = match how {
+            Counted::Failed | Counted::Error(_) if known => Counted::Known,
+            Counted::Failed | Counted::Error(_) => {
+                fail_count += 1;
+                how
+            }
+            how => how,
+        };
+        if matches!(how, Counted::Failed | Counted::Error(_) | Counted::Known) {
+            show_note(manifest, Path::new(path));
+        }
+        counted.push((PathBuf::from(path), how, 0));
+    }
+    out.flush().unwrap_or_else(output_failed);
+
+    print_grouped(&grouped);
+    info!(
+        "\nSummary: total checks = {}, OK = {}, FAILED = {}, skipped = {}",
+        results.len(),
+        ok_count,
+        fail_count,
+        skipped
+    );
+    if fail_count > 0
+        || counted
+            .iter()
+            .any(|(_, how, _)| matches!(how, Counted::Known))
+    {
+        info!("Failures: {}", failure_breakdown(&counted, 0));
+    }
+    info!(
+        "Compared metadata only in {:.2}s; no content was read.",
+        started.elapsed().as_secs_f64()
+    );
+    let exit_code = i32::from(fail_count > 0 || manifest.trailer_mismatch().is_some());
+    report_run(
+        opts,
+        &RunReport {
+            mode: "check",
+            target: check_file,
+            total: results.len(),
+            counted: &counted,
+            malformed: 0,
+            read_failures: &[],
+            latency: None,
+            elapsed: started.elapsed(),
+            exit_code,
+        },
+    );
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// `generate --structure-only`: print an entry for everything under `paths`, with its kind
+/// and, for files, its size, without opening any of it.
+fn structure_generate_mode(paths: &[PathBuf], opts: &Options) {
+    let started = Instant::now();
+    if let Some(path) = paths
+        .iter()
+        .find(|p| SshUrl::parse(p).is_some() || remote::is_web_url(&p.to_string_lossy()))
+    {
+        error!(
+            "Error: --structure-only needs local paths, not '{}'",
+            path.display()
+        );
+        std::process::exit(1);
+    }
+
+    let mut provenance = vec![("at", utc_timestamp(SystemTime::now()))];
+    if opts.anonymize_paths.is_none() {
+        provenance.push(("host", hostname()));
+        provenance.push(("user", username()));
+    }
+    provenance.push(("version", env!("CARGO_PKG_VERSION").to_string()));
+    provenance.push((STRUCTURE_FIELD, "true".to_string()));
+    if let Some(ref key) = opts.anonymize_paths {
+        provenance.push((ANONYMIZED_FIELD, key.id()));
+    }
+    let mut out = Output::new();
+    out.line(provenance_line(&provenance))
+        .unwrap_or_else(output_failed);
+    if !opts.labels.is_empty() {
+        out.line(labels_line(&opts.labels))
+            .unwrap_or_else(output_failed);
+    }
+    let recorded = Recorded::new(paths, opts.path_style);
+    let shown = |path: &Path| match opts.anonymize_paths {
+        Some(ref key) => key.anonymize(&recorded.path(path)),
+        None => recorded.path(path),
+    };
+
+    let (mut files, mut dirs, mut symlinks, mut failures, mut skipped) = (0, 0, 0, 0, 0);
+    let mut counted: Vec<(PathBuf, Counted, u64)> = Vec::new();
+    for root in paths {
+        let root = root.canonicalize().unwrap_or_else(|_| root.clone());
+        let entries = WalkDir::new(&root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|e| !walk::is_excluded(e.path(), &opts.exclude));
+        for entry in entries.take_while(|_| !interrupted()) {
+            let (path, kind) = match entry.and_then(|e| {
+                let meta = e.metadata()?;
+                Ok((gustasum::source::display_path(e.into_path()), meta))
+            }) {
+                Ok((path, meta)) => (path, Kind::of(&meta)),
+                Err(e) => {
+                    let path = e.path().unwrap_or(&root).to_path_buf();
+                    let class =
+                        ErrorClass::of(e.io_error().map_or(io::ErrorKind::Other, io::Error::kind));
+                    if opts.skip_errors.covers(class) {
+                        warning!("Warning: Skipping '{}': {}", path.display(), e);
+                        skipped += 1;
+                        counted.push((path, Counted::Skipped, 0));
+                    } else {
+                        error!("Error: Could not list '{}': {}", path.display(), e);
+                        failures += 1;
+                        counted.push((path, Counted::Error(class), 0));
+                    }
+                    continue;
+                }
+            };
+            let Some(kind) = kind else {
+                debug!("Not a file, directory, or symlink: '{}'", path.display());
+                continue;
+            };
+            match kind {
+                Kind::File(_) => files += 1,
+                Kind::Dir => dirs += 1,
+                Kind::Symlink => symlinks += 1,
+            }
+            out.line(ManifestEntry {
+                hash: kind.to_string(),
+                path: shown(&path),
+            })
+            .unwrap_or_else(output_failed);
+            counted.push((path, Counted::Ok, 0));
+        }
+    }
+    if interrupted() {
+        out.line(format_args!(
+            "{}: interrupted after {} entries",
+            PARTIAL_MARKER,
+            counted.len()
+        ))
+        .unwrap_or_else(output_failed);
+    }
+    out.flush().unwrap_or_else(output_failed);
+
+    info!(
+        "\nSummary: files = {}, directories = {}, symlinks = {}, errors = {}, skipped = {}",
+        files, dirs, symlinks, failures, skipped
+    );
+    info!(
+        "Listed structure only in {:.2}s; no content was read.",
+        started.elapsed().as_secs_f64()
+    );
+    let exit_code = if interrupted() {
+        EXIT_INTERRUPTED
+    } else {
+        i32::from(failures > 0)
+    };
+    let target: Vec<String> = paths.iter().map(|p| portable_path(p)).collect();
+    report_run(
+        opts,
+        &RunReport {
+            mode: "generate",
+            target: &target.join(","),
+            total: counted.len(),
+            counted: &counted,
+            malformed: 0,
+            read_failures: &[],
+            latency: None,
+            elapsed: started.elapsed(),
+            exit_code,
+        },
+    );
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// `check` of a structure-only manifest: compare each entry's kind and size to what's there
+/// now, without reading any content.
+fn structure_check_mode(
+    check_file: &str,
+    manifest: &Manifest,
+    verify_opts: &VerifyOptions,
+    opts: &Options,
+) {
+    let started = Instant::now();
+    info!(
+        "Found {} checks to perform. Comparing structure...",
+        manifest.lines.len()
+    );
+    let results: Vec<(String, io::Result<Option<String>>)> = manifest
+        .lines
+        .par_iter()
+        .map(|line| match line {
+            Ok(entry) => {
+                let result = match entry.hash.parse::<Kind>() {
+                    Err(()) => Ok(Some("malformed line".to_string())),
+                    Ok(recorded) => {
+                        let path = verify::resolve_path(entry, verify_opts);
+                        fs::symlink_metadata(gustasum::source::native_path(&path)).map(|meta| {
+                            match Kind::of(&meta) {
+                                Some(now) => recorded.change(now),
+                                None => Some("no longer a file, directory, or symlink".to_string()),
+                            }
+                        })
+                    }
+                };
+                (entry.path.display().to_string(), result)
+            }
+            Err(raw) => (raw.clone(), Ok(Some("malformed line".to_string()))),
+        })
+        .collect();
+    finish_stat_check(check_file, manifest, &results, started, opts);
+}
+
+/// Sections of gustasum(1) that clap doesn't know about, in roff.
+const MANPAGE_SECTIONS: &str = r#".SH "MANIFEST FORMAT"
+A manifest has one entry per line: the hex digest, two spaces, and the path, as with
+.BR sha256sum (1).
+As there, a path containing a backslash or a line break has them escaped as \fB\e\e\fR,
+\fB\en\fR, and \fB\er\fR, and its line starts with a backslash, and an asterisk in place of the
+second space (binary mode) is accepted. Lines starting with
+.B #
+are comments. Some are meaningful:
+.TP
+.B # gustasum algorithm: \fINAME\fR
+The digest algorithm that produced the entries. Manifests without it are sha256.
+.TP
+.B # gustasum generated: \fIKEY\fB=\fIVALUE\fR ...
+When, where, by whom, and how the manifest was generated:
+.BR at ,
+.BR host ,
+.BR user ,
+.BR version ,
+and the settings that affect hashes,
+.BR partial_bytes ,
+.BR include_modtime ,
+.BR fat_compat ,
+.BR sparse ,
+and
+.BR include_extents ,
+which \fBcheck\fR and \fBupdate\fR follow. With \fB\-\-salt\-file\fR,
+.B salt
+is a fingerprint of the salt, which they must be given. With \fB\-\-abbrev\fR,
+.B abbrev
+is the number of hex digits kept, which they compare. With \fB\-\-anonymize\-paths\fR,
+.B anonymized
+is a fingerprint of the key, \fBhost\fR and \fBuser\fR are left out, and the manifest must be put
+through \fBdeanonymize\fR before it can be checked. With \fB\-\-structure\-only\fR,
+.B structure_only=true
+marks a manifest whose entries are \fBfile:\fISIZE\fR, \fBdir\fR, or \fBsymlink\fR in place of digests;
+\fBcheck\fR compares them without reading content, and \fBupdate\fR refuses it.
+.B trailer=true
+promises a \fB# gustasum totals:\fR line at the end. Spaces and \fB%\fR in values are %-escaped.
+.TP
+.B # gustasum labels: \fIKEY\fB=\fIVALUE\fR ...
+The \fB\-\-label\fRs of the generating run, escaped the same way. Runs on the manifest carry them over.
+.TP
+.B # gustasum partial manifest
+Written first by a run that was interrupted (or, where entries were printed as they were
+hashed, last before the totals); files it doesn't list were never hashed.
+.TP
+.B # gustasum totals: entries=\fIN\fB bytes=\fIBYTES\fR
+The last line: how many entries there are and what their files' sizes add up to (left out
+when not all are known). \fBcheck\fR fails a manifest whose count is off, or that promises
+this line and lacks it, as cut short by a full disk or a broken pipe; \fBlist\fR reports it as
+a problem. \fBupdate\fR, \fBgenerate \-\-append\fR, and \fBnormalize\fR write it afresh.
+.TP
+.B # gustasum metadata: size=\fIBYTES\fR mtime=\fISECONDS\fR first=\fIHEX\fR middle=\fIHEX\fR last=\fIHEX\fR
+Written above an entry by \fB\-\-record\-metadata\fR: the file's size and modtime when it was
+hashed, for \fBcheck \-\-metadata\-only\fR and \fB\-\-changed\-only\fR, and a short digest of each
+region read (small files have no middle or last one). When the file fails its check,
+\fBcheck\fR reports which of these changed, with the offsets of the regions that differ.
+.TP
+.B # gustasum note: \fITEXT\fR
+Written above an entry by \fB\-\-note\fR, or by hand: free text about the file, such as where
+it came from. Several above one entry are read as one, joined by \fB; \fR. \fBupdate\fR,
+\fBgenerate \-\-append\fR, and \fBnormalize\fR keep it with the entry, \fBcheck\fR and \fBdiff\fR
+print it under the file's failure or change, and \fBgrep \-\-note\fR finds entries by it.
+.PP
+With \fB\-\-archive\fR, each member of an archive is an entry of its own, with the path
+\fIARCHIVE\fB!\fIMEMBER\fR.
+.PP
+Each digest covers, in order: the modification time in whole seconds as 8 little-endian
+bytes (zero unless \fB\-\-include\-modtime\fR), the file size as 8 little-endian bytes, the
+first \fIN\fR bytes, the \fIN\fR bytes at offset size/2 if the file is larger than 2\fIN\fR,
+and the last \fIN\fR bytes if it is larger than \fIN\fR, where \fIN\fR is
+\fB\-\-partial\-bytes\fR. With \fB\-\-full\fR, a digest covers the whole content and nothing
+else, and the manifest has no comments at all, so it is what \fBsha256sum\fR (or \fBsha512sum\fR)
+prints; such manifests, and theirs, are checked with \fB\-\-full\fR. \fB\-\-algorithm md5\fR and
+\fBsha1\fR are there for the files of \fBmd5sum\fR and \fBsha1sum\fR, which \fBimport\fR also reads.
+.SH "MATCHING PARAMETERS"
+A manifest can only be checked with the parameters it was generated with.
+\fB\-\-partial\-bytes\fR and \fB\-\-include\-modtime\fR are not recorded in the manifest;
+checking with different values makes every file a mismatch rather than an error. The algorithm
+is recorded and \fBcheck\fR and \fBupdate\fR use it automatically; asking for a different one
+with \fB\-\-algorithm\fR is an error. Keep shared settings in a configuration file so every
+invocation agrees.
+.SH FILES
+.TP
+.I ~/.config/gustasum/config.toml
+User defaults (under \fB$XDG_CONFIG_HOME\fR if set). Keys are option names without the dashes.
+.TP
+.I ./.gustasum.toml
+Project defaults; override the user file.
+.SH ENVIRONMENT
+Each option can be set with a variable named after it, such as
+\fBGUSTASUM_PARTIAL_BYTES\fR or \fBGUSTASUM_ALGORITHM\fR; \fB\-\-threads\fR is
+\fBGUSTASUM_JOBS\fR. Variables override the configuration files; command-line flags override both.
+.SH "EXIT STATUS"
+.TP
+.B 0
+Every file was hashed, or every check passed.
+.TP
+.B 1
+At least one file failed, was unstable, or did not match; or \fBdiff\fR found differences.
+.TP
+.B 130
+Interrupted with Ctrl-C; the output is partial.
+"#;
+
+/// Render gustasum(1) to stdout, or it and a page per command into `out_dir`.
+fn manpage_mode(out_dir: Option<&Path>) {
+    let mut cmd = cli().disable_help_subcommand(true);
+    cmd.build();
+
+    let render = |w: &mut dyn std::io::Write| -> std::io::Result<()> {
+        let man = clap_mangen::Man::new(cmd.clone());
+        man.render_title(w)?;
+        man.render_name_section(w)?;
+        man.render_synopsis_section(w)?;
+        man.render_description_section(w)?;
+        man.render_options_section(w)?;
+        man.render_subcommands_section(w)?;
+        man.render_extra_section(w)?;
+        w.write_all(MANPAGE_SECTIONS.as_bytes())?;
+        man.render_version_section(w)
+    };
+
+    let result = match out_dir {
+        None => render(&mut std::io::stdout().lock()),
+        Some(dir) => fs::File::create(dir.join("gustasum.1"))
+            .and_then(|mut f| render(&mut f))
+            .and_then(|_| {
+                cmd.get_subcommands().try_for_each(|sub| {
+                    clap_mangen::Man::new(sub.clone())
+                        .generate_to(dir)
+                        .map(|_| ())
+                })
+            }),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to write man page: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// `--async-io`: hash `paths` on the tokio backend. `None` marks files not started after Ctrl-C.
+#[cfg(feature = "async-io")]
+fn hash_async(
+    paths: &[PathBuf],
+    hash_opts: &HashOptions,
+    concurrency: usize,
+    events: &CliEvents,
+) -> Vec<Option<Result<FileHash, HashError>>> {
+    gustasum::async_io::hash_files_async(paths, hash_opts, concurrency, events, &interrupted)
+}
+
+#[cfg(not(feature = "async-io"))]
+fn hash_async(
+    _: &[PathBuf],
+    _: &HashOptions,
+    _: usize,
+    _: &CliEvents,
+) -> Vec<Option<Result<FileHash, HashError>>> {
+    unreachable!("--async-io is only accepted when built with the async-io feature")
+}
+
+/// `--stdin`: hash the data piped in as a file of that content would be hashed, and print
+/// its entry as `-`. With `size_hint`, the size it must turn out to be, it's sampled as usual,
+/// keeping only the regions sampled; without one, where the middle and end are can't be known
+/// until it's over, so every byte is hashed, as with `--full`.
+fn stdin_mode(size_hint: Option<u64>, opts: &Options) {
+    let started = Instant::now();
+    let sampled = size_hint.is_some() && !opts.hash.full;
+    for (set, option) in [
+        (opts.hash.include_modtime, "--include-modtime"),
+        (opts.hash.sparse, "--sparse"),
+        (opts.hash.include_extents, "--include-extents"),
+        (
+            opts.hash.salt.is_some() && !sampled,
+            "--salt-file without --size-hint",
+        ),
+        (
+            opts.record_metadata && !sampled,
+            "--record-metadata without --size-hint",
+        ),
+    ] {
+        if set {
+            error!("Error: --stdin can't be combined with {}", option);
+            std::process::exit(1);
+        }
+    }
+    if !sampled && !opts.hash.full {
+        info!("No --size-hint given: hashing the whole stream, as with --full.");
+    }
+
+    let hash_opts = HashOptions {
+        full: !sampled,
+        ..opts.hash.clone()
+    };
+    let meta = SourceMetadata {
+        len: size_hint.unwrap_or(0),
+        modified: None,
+    };
+    let mut streaming = hasher::StreamingHash::new(&hash_opts, meta);
+    let mut whole = (!sampled).then(|| hash_opts.algorithm.hasher());
+    let mut stdin = io::stdin().lock();
+    let mut buf = vec![0u8; 1 << 20];
+    let mut read = 0u64;
+    loop {
+        let n = match stdin.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Error: Failed to read stdin after {} bytes: {}", read, e);
+                std::process::exit(1);
+            }
+        };
+        match &mut whole {
+            Some(whole) => whole.update(&buf[..n]),
+            None => streaming.update(&buf[..n]),
+        }
+        read += n as u64;
+    }
+
+    let after = SourceMetadata {
+        len: read,
+        modified: None,
+    };
+    let (hash, regions) = match whole {
+        Some(whole) => {
+            let mut hash = whole.finish();
+            if let Some(digits) = hash_opts.abbrev {
+                hash.truncate(digits);
+            }
+            (hash, Vec::new())
+        }
+        None if Some(read) != size_hint => {
+            error!(
+                "Error: The stream was {} bytes, not the {} of --size-hint, so its samples were taken from the wrong places",
+                read,
+                size_hint.unwrap_or(0)
+            );
+            std::process::exit(1);
+        }
+        None => {
+            let hash = streaming.finish(&after);
+            let regions: Vec<(Region, String)> = hash
+                .regions
+                .into_iter()
+                .map(|r| (r.region, r.digest))
+                .collect();
+            (hash.hash, regions)
+        }
+    };
+
+    if !hash_opts.full {
+        for line in manifest_headers(opts) {
+            println!("{}", line);
+        }
+    }
+    if let Some(ref note) = opts.note {
+        println!("{}", note_line(note));
+    }
+    if opts.record_metadata {
+        println!("{}", metadata_line(&after, &regions));
+    }
+    println!(
+        "{}",
+        ManifestEntry {
+            hash,
+            path: PathBuf::from("-"),
+        }
+    );
+    if !hash_opts.full {
+        println!(
+            "{}",
+            totals_line(&Totals {
+                entries: 1,
+                bytes: Some(read),
+            })
+        );
+    }
+    print_throughput(1, read, started.elapsed());
+}
+
+/// What became of one file in `copy`.
+enum CopyOutcome {
+    /// Copied, and the copy matches `FileHash`, the source's hash as it was read.
+    Copied(FileHash),
+    /// A copy from an earlier run was there already, and matches.
+    AlreadyThere(FileHash),
+    Failed(String),
+}
+
+/// `copy`: copy every file under `src` to the same path under `dst`, with its modtime, and
+/// print a manifest of the copies. Each file is hashed as it streams past on its way to the
+/// copy, so it's read once; the copy, flushed to disk, is then hashed with the same settings
+/// and only renamed into place if it matches. Files whose copy is there already (same size,
+/// modtime, and hash) are left alone, so an interrupted copy picks up where it stopped.
+fn copy_mode(src: &Path, dst: &Path, opts: &Options) {
+    let started = Instant::now();
+    for (set, option) in [
+        (opts.hash.sparse, "--sparse"),
+        (opts.hash.include_extents, "--include-extents"),
+    ] {
+        if set {
+            error!(
+                "Error: copy can't follow {}: it hashes the content as it's copied",
+                option
+            );
+            std::process::exit(1);
+        }
+    }
+    let source = opts.hash.source.as_ref();
+    // Copies are local files, hashed as such.
+    let local = HashOptions {
+        source: Arc::new(LocalFs),
+        ..opts.hash.clone()
+    };
+    // The walk gives local paths canonicalized.
+    let root = src.canonicalize().unwrap_or_else(|_| src.to_path_buf());
+    if let Err(e) = fs::create_dir_all(dst) {
+        error!("Error: Failed to create '{}': {}", dst.display(), e);
+        std::process::exit(1);
+    }
+    let dst = dst.canonicalize().unwrap_or_else(|_| dst.to_path_buf());
+
+    let files: Vec<(PathBuf, PathBuf)> = walk::files_under(source, std::slice::from_ref(&root))
+        .take_while(|_| !interrupted())
+        .filter(|p| {
+            let excluded = walk::is_excluded(p, &opts.exclude);
+            if excluded {
+                debug!("Excluded '{}'", p.display());
+            }
+            !excluded
+        })
+        .map(|path| {
+            // A file given as SRC goes into DST under its own name.
+            let relative = match path.strip_prefix(&root) {
+                Ok(rest) if !rest.as_os_str().is_empty() => rest.to_path_buf(),
+                _ => PathBuf::from(path.file_name().unwrap_or_default()),
+            };
+            let copy = dst.join(relative);
+            (path, copy)
+        })
+        .collect();
+    if let Some(digits) = opts.hash.abbrev {
+        check_abbrev(digits, files.len(), true);
+    }
+    info!(
+        "Found {} files. Copying and computing partial checksums...",
+        files.len()
+    );
+
+    let pb = if opts.progress == Progress::Bar {
+        let bar = ProgressBar::new(files.len() as u64);
+        bar.set_draw_target(ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} files ({eta} remaining)\n  {wide_msg}",
+            )
+            .unwrap()
+            .progress_chars("=>-"),
+        );
+        Some(bar)
+    } else {
+        None
+    };
+    let target = format!("{} -> {}", portable_path(src), portable_path(&dst));
+    let events = CliEvents::new(files.len(), pb, opts, "files").alert_as(opts, "copy", target);
+    let copy_one = |(path, copy): &(PathBuf, PathBuf)| {
+        if interrupted() {
+            return None;
+        }
+        events.file_started(path);
+        let outcome = copy_file(path, copy, opts, &local);
+        match &outcome {
+            CopyOutcome::Copied(hash) | CopyOutcome::AlreadyThere(hash) => {
+                events.bytes_read(path, hash.bytes);
+                events.file_hashed(path, hash);
+            }
+            CopyOutcome::Failed(_) => {
+                events.failed(&portable_path(path));
+                events.file_done(path);
+            }
+        }
+        Some((copy.clone(), outcome))
+    };
+    let results = walk::run_scheduled(&files, opts.order, |(p, _)| p.as_path(), copy_one);
+    events.finish();
+
+    let mut lines: Vec<String> = Vec::new();
+    if interrupted() {
+        lines.push(format!(
+            "{}: interrupted after {} of {} files",
+            PARTIAL_MARKER,
+            results.len(),
+            files.len()
+        ));
+    }
+    if !opts.hash.full {
+        lines.extend(manifest_headers(opts));
+    }
+    let (mut copied, mut already, mut failed) = (0usize, 0usize, 0usize);
+    let mut bytes_copied = 0u64;
+    let mut listed_bytes = 0u64;
+    for (copy, outcome) in results {
+        let hash = match outcome {
+            CopyOutcome::Copied(hash) => {
+                bytes_copied += hash.metadata.len;
+                copied += 1;
+                hash
+            }
+            CopyOutcome::AlreadyThere(hash) => {
+                verbose!("'{}' is copied already", copy.display());
+                already += 1;
+                hash
+            }
+            CopyOutcome::Failed(why) => {
+                error!("Error: Failed to copy to '{}': {}", copy.display(), why);
+                failed += 1;
+                continue;
+            }
+        };
+        if opts.record_metadata {
+            let regions: Vec<(Region, String)> = hash
+                .regions
+                .into_iter()
+                .map(|r| (r.region, r.digest))
+                .collect();
+            lines.push(metadata_line(&hash.metadata, &regions));
+        }
+        listed_bytes += hash.metadata.len;
+        lines.push(
+            ManifestEntry {
+                hash: hash.hash,
+                path: copy,
+            }
+            .to_string(),
+        );
+    }
+    if !opts.hash.full {
+        lines.push(totals_line(&Totals {
+            entries: copied + already,
+            bytes: Some(listed_bytes),
+        }));
+    }
+    for line in &lines {
+        println!("{}", line);
+    }
+
+    info!(
+        "\nSummary: copied = {} ({} bytes), already copied = {}, failed = {}",
+        copied, bytes_copied, already, failed
+    );
+    print_throughput(files.len(), bytes_copied, started.elapsed());
+
+    if interrupted() {
+        info!("Interrupted: not every file was copied; run the same copy again to finish it.");
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Copy `path` to `copy` for [`copy_mode`], through a temporary file next to it, hashing the
+/// source with `opts.hash` as it's read and the copy with `local` before it's renamed into
+/// place.
+fn copy_file(path: &Path, copy: &Path, opts: &Options, local: &HashOptions) -> CopyOutcome {
+    let source = opts.hash.source.as_ref();
+    let meta = match source.metadata(path) {
+        Ok(meta) => meta,
+        Err(e) => return CopyOutcome::Failed(HashError::Metadata(e).to_string()),
+    };
+
+    // From an earlier run: trusted once the source's hash (a cheap sampled read, as a rule)
+    // matches the copy's.
+    if fs::metadata(copy).is_ok_and(|m| SourceMetadata::from(m) == meta) {
+        if let (Ok(theirs), Ok(ours)) = (hash_file(path, &opts.hash), hash_file(copy, local)) {
+            if !theirs.unstable && theirs.hash == ours.hash {
+                return CopyOutcome::AlreadyThere(theirs);
+            }
+        }
+    }
+
+    let mut partial = copy.as_os_str().to_os_string();
+    partial.push(".gustasum-copy");
+    let partial = PathBuf::from(partial);
+    let copied = (|| -> Result<FileHash, String> {
+        if let Some(parent) = copy.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let file = source
+            .open(path)
+            .map_err(|e| HashError::Open(e).to_string())?;
+        let mut out = fs::File::create(&partial).map_err(|e| e.to_string())?;
+        let mut hash = hasher::StreamingHash::new(&opts.hash, meta.clone());
+        let mut buf = vec![0u8; 1 << 20];
+        let mut offset = 0u64;
+        loop {
+            let n = file
+                .read_at(offset, &mut buf)
+                .map_err(|e| format!("read failed at byte {}: {}", offset, e))?;
+            if n == 0 {
+                break;
+            }
+            hash.update(&buf[..n]);
+            out.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+            offset += n as u64;
+        }
+        let after = file.metadata().map_err(|e| e.to_string())?;
+        let hash = hash.finish(&after);
+        if hash.unstable {
+            return Err("changed while being read".to_string());
+        }
+        if let Some(modtime) = meta.modified {
+            out.set_modified(modtime).map_err(|e| e.to_string())?;
+        }
+        out.sync_all().map_err(|e| e.to_string())?;
+        drop_cached(&out);
+        drop(out);
+        match hash_file(&partial, local) {
+            Ok(theirs) if theirs.hash == hash.hash => {}
+            Ok(_) => return Err("the copy doesn't match the source".to_string()),
+            Err(e) => return Err(format!("the copy can't be read back: {}", e)),
+        }
+        fs::rename(&partial, copy).map_err(|e| e.to_string())?;
+        Ok(hash)
+    })();
+    match copied {
+        Ok(hash) => CopyOutcome::Copied(hash),
+        Err(why) => {
+            let _ = fs::remove_file(&partial);
+            CopyOutcome::Failed(why)
+        }
+    }
+}
+
+/// Ask the kernel to forget its cached pages of `file`, once they're on disk, so reading it
+/// back reads the disk rather than what was just written.
+fn drop_cached(file: &fs::File) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::fd::AsRawFd;
+        unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = file;
+}
+
+/// Compare the replicas under `roots` file by file, matching them by path relative to their
+/// root. Copies that agree with a strict majority are fine; the rest (different, missing, or
+/// unreadable) are reported as the minority, so the bad copy is known rather than just that
+/// the copies differ. Exits 1 unless every file agrees everywhere.
+fn compare_replicas_mode(roots: &[PathBuf], rsync_list: Option<&Path>, opts: &Options) {
+    let started = Instant::now();
+    let source = opts.hash.source.as_ref();
+
+    // Which roots have each relative path.
+    let mut present: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+    for (r, root) in roots.iter().enumerate() {
+        for path in walk::files_under(source, std::slice::from_ref(root))
+            .take_while(|_| !interrupted())
+            .filter(|p| !walk::is_excluded(p, &opts.exclude))
+        {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            present.entry(relative).or_default().push(r);
+        }
+    }
+    let relatives: Vec<&PathBuf> = present.keys().collect();
+    let reads: Vec<(usize, usize, PathBuf)> = present
+        .values()
+        .enumerate()
+        .flat_map(|(f, in_roots)| {
+            let relative = relatives[f];
+            in_roots
+                .iter()
+                .map(move |&r| (f, r, roots[r].join(relative)))
+        })
+        .collect();
+    info!(
+        "Found {} files across {} replicas. Computing partial checksums...",
+        relatives.len(),
+        roots.len()
+    );
+
+    let pb = if opts.progress == Progress::Bar {
+        let bar = ProgressBar::new(reads.len() as u64);
+        bar.set_draw_target(ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} files ({eta} remaining)\n  {wide_msg}",
+            )
+            .unwrap()
+            .progress_chars("=>-"),
+        );
+        Some(bar)
+    } else {
+        None
+    };
+    let events = CliEvents::new(reads.len(), pb, opts, "files");
+    let hash_one = |(f, r, path): &(usize, usize, PathBuf)| {
+        if interrupted() {
+            return None;
+        }
+        Some((*f, *r, hash_file_with(path, &opts.hash, &events)))
+    };
+    let results = walk::run_scheduled(&reads, opts.order, |(_, _, p)| p.as_path(), hash_one);
+    events.finish();
+
+    // Each file's result in each root; `None` where the root doesn't have it.
+    let mut copies: Vec<Vec<Option<Result<FileHash, HashError>>>> = (0..relatives.len())
+        .map(|_| (0..roots.len()).map(|_| None).collect())
+        .collect();
+    let mut bytes_read = 0u64;
+    for (f, r, result) in results {
+        bytes_read += result.as_ref().map_or(0, |h| h.bytes);
+        copies[f][r] = Some(result);
+    }
+
+    let name = |r: usize| roots[r].display().to_string();
+    let names = |rs: &[usize]| rs.iter().map(|&r| name(r)).collect::<Vec<_>>().join(", ");
+    let (mut agreed, mut outvoted, mut undecided) = (0usize, 0usize, 0usize);
+    let mut minority_copies = vec![0usize; roots.len()];
+    // Files that can be repaired from the majority, for --emit-rsync-list.
+    let mut outvoted_files: Vec<&Path> = Vec::new();
+    for (f, relative) in relatives.iter().enumerate() {
+        if copies[f].iter().all(Option::is_none) {
+            // Never read, so the run was interrupted first.
+            continue;
+        }
+        // Roots by the hash they read, in root order.
+        let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+        for (r, copy) in copies[f].iter().enumerate() {
+            if let Some(Ok(FileHash {
+                hash,
+                unstable: false,
+                ..
+            })) = copy
+            {
+                match groups.iter_mut().find(|(h, _)| h == hash) {
+                    Some((_, rs)) => rs.push(r),
+                    None => groups.push((hash, vec![r])),
+                }
+            }
+        }
+        let majority = groups
+            .iter()
+            .map(|(_, rs)| rs)
+            .find(|rs| rs.len() * 2 > roots.len());
+        match majority {
+            Some(majority) if majority.len() == roots.len() => {
+                println!("{}: {}", relative.display(), style("OK").green());
+                agreed += 1;
+            }
+            Some(majority) => {
+                for r in (0..roots.len()).filter(|r| !majority.contains(r)) {
+                    let why = match &copies[f][r] {
+                        None => "missing".to_string(),
+                        Some(Ok(FileHash { unstable: true, .. })) => {
+                            "changed while being read".to_string()
+                        }
+                        Some(Ok(_)) => "differs".to_string(),
+                        Some(Err(e)) => format!("unreadable: {}", e),
+                    };
+                    error!(
+                        "{}: MINORITY in '{}': {} (agreeing: {})",
+                        relative.display(),
+                        name(r),
+                        why,
+                        names(majority)
+                    );
+                    minority_copies[r] += 1;
+                }
+                outvoted_files.push(relative);
+                outvoted += 1;
+            }
+            None => {
+                let groups: Vec<String> = groups
+                    .iter()
+                    .map(|(_, rs)| format!("[{}]", names(rs)))
+                    .collect();
+                error!(
+                    "{}: NO MAJORITY (matching copies: {})",
+                    relative.display(),
+                    if groups.is_empty() {
+                        "none".to_string()
+                    } else {
+                        groups.join(" ")
+                    }
+                );
+                undecided += 1;
+            }
+        }
+    }
+
+    info!(
+        "\nSummary: files = {}, agreed = {}, outvoted copies in {} files, no majority = {}",
+        relatives.len(),
+        agreed,
+        outvoted,
+        undecided
+    );
+    if outvoted > 0 {
+        let per_root: Vec<String> = minority_copies
+            .iter()
+            .enumerate()
+            .map(|(r, n)| format!("'{}' = {}", name(r), n))
+            .collect();
+        info!("Outvoted copies per replica: {}", per_root.join(", "));
+    }
+    print_throughput(reads.len(), bytes_read, started.elapsed());
+    if let Some(list) = rsync_list {
+        write_rsync_list(list, Path::new(""), &outvoted_files);
+        // One list serves every replica only if each file in it is good in the copy-from one.
+        let damaged: Vec<usize> = (0..roots.len())
+            .filter(|&r| minority_copies[r] > 0)
+            .collect();
+        match damaged[..] {
+            [bad] => {
+                let good = (0..roots.len()).find(|&r| r != bad).unwrap();
+                info!(
+                    "To repair '{}': rsync -a --files-from={} {}/ {}/",
+                    name(bad),
+                    list.display(),
+                    name(good),
+                    name(bad)
+                );
+            }
+            [] => {}
+            _ => warning!(
+                "Warning: Outvoted copies are spread over {} replicas; copy each file in '{}' from one that agrees with the majority.",
+                damaged.len(),
+                list.display()
+            ),
+        }
+    }
+
+    if interrupted() {
+        info!("Interrupted: not every file was compared.");
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if outvoted > 0 || undecided > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// `gustasum par2 create|verify|repair`: run `par2` for each file in the manifest. Repair
+/// hashes everything with the manifest's partial checksums first and only hands the files
+/// that fail to `par2`. Exits 1 if any file is damaged (and, for repair, not repaired) or
+/// `par2` fails.
+fn par2_mode(action: &str, sub: &ArgMatches, config: &Config) {
+    let manifest_file = sub.get_one::<String>("manifest").unwrap();
+    let manifest = read_manifest(manifest_file);
+    let dir = sub.get_one::<String>("par2_dir").map_or_else(
+        || PathBuf::from(format!("{}.par2", manifest_file)),
+        PathBuf::from,
+    );
+    let par2 = Par2::new(sub.get_one::<String>("par2_bin").unwrap());
+
+    let files: Vec<PathBuf> = if action == "repair" {
+        let opts = options_for_manifest(sub, config, &manifest, manifest_file);
+        let verify_opts = VerifyOptions {
+            hash: opts.hash,
+            remap: None,
+        };
+        info!(
+            "Verifying {} files in '{}'...",
+            manifest.entries().count(),
+            manifest_file
+        );
+        // The options already follow the manifest, so there's nothing left to conflict.
+        verify::verify_manifest(&manifest, &verify_opts)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|r| matches!(r.outcome, CheckOutcome::Mismatch | CheckOutcome::Unstable))
+            .map(|r| native_separators(Path::new(&r.path)))
+            .collect()
+    } else {
+        manifest
+            .entries()
+            .map(|e| native_separators(&e.path))
+            .collect()
+    };
+    info!("Running par2 {} on {} files...", action, files.len());
+
+    let mut problems = 0usize;
+    for file in &files {
+        if interrupted() {
+            break;
+        }
+        let set = Par2::set_for(&dir, file);
+        let result = match action {
+            "create" => {
+                let redundancy = *sub.get_one::<u32>("redundancy").unwrap();
+                par2.create(file, &set, redundancy).map(|()| None)
+            }
+            "verify" => par2.verify(file, &set).map(Some),
+            _ => par2.repair(file, &set).map(Some),
+        };
+        match result {
+            Ok(None) => verbose!("Created '{}'", set.display()),
+            Ok(Some(Verdict::Intact)) => println!("{}: {}", file.display(), style("OK").green()),
+            Ok(Some(Verdict::Repaired)) => println!("{}: repaired", file.display()),
+            Ok(Some(verdict)) => {
+                error!("{}: FAILED ({})", file.display(), verdict);
+                problems += 1;
+            }
+            Err(e) => {
+                error!("Error: '{}': {}", file.display(), e);
+                problems += 1;
+            }
+        }
+    }
+
+    info!(
+        "\nSummary: par2 {} on {} files, {} problems",
+        action,
+        files.len(),
+        problems
+    );
+    if interrupted() {
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if problems > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Print `file`, anonymized with `key`, with the paths of the files under `paths` put back.
+fn deanonymize_mode(file: &str, key: &PathKey, paths: &[PathBuf], opts: &Options) {
+    let contents = manifest_contents(file);
+    match Manifest::parse(&contents).generated(ANONYMIZED_FIELD) {
+        Some(id) if id == key.id() => {}
+        Some(_) => {
+            error!("Error: '{}' was anonymized with a different key", file);
+            std::process::exit(1);
+        }
+        None => {
+            error!("Error: '{}' has no anonymized paths", file);
+            std::process::exit(1);
+        }
+    }
+
+    let recorded = Recorded::new(paths, opts.path_style);
+    let real = walk::files_under(opts.hash.source.as_ref(), paths)
+        .take_while(|_| !interrupted())
+        .flat_map(|p| with_streams(p, opts))
+        .map(|p| recorded.path(&p));
+    let restored = anonymize::restore(&contents, key, real);
+    print!("{}", restored.contents);
+    if !restored.unmatched.is_empty() {
+        warning!(
+            "Warning: {} paths in '{}' match no file under the given paths and are still anonymized; \
+             give the paths it was generated for, as given then.",
+            restored.unmatched.len(),
+            file
+        );
+        for path in &restored.unmatched {
+            verbose!("Still anonymized: '{}'", path);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Write `contents` to `path` through a temporary file renamed over it, so a reader (or a
+/// run that dies halfway) never leaves a half-written manifest.
+fn write_replacing(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", std::process::id()));
+    fs::write(&temporary, contents)
+        .and_then(|()| fs::rename(&temporary, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temporary);
+        })
+}
+
+/// `normalize`: rewrite `file` with [`normalize::normalize`], to `output` or stdout.
+fn normalize_mode(file: &str, output: Option<&Path>, form: UnicodeForm, on_conflict: OnConflict) {
+    let manifest = read_manifest(file);
+    let normalized = normalize::normalize(&manifest, form, on_conflict).unwrap_or_else(|paths| {
+        error!(
+            "Error: {} paths in '{}' are listed with different digests; resolve them, or keep the last-listed entries with --on-conflict newest:",
+            paths.len(),
+            file
+        );
+        for path in &paths {
+            error!("  {}", path);
+        }
+        std::process::exit(1);
+    });
+    for line in &normalized.malformed {
+        warning!("Warning: Dropping malformed line in '{}': {}", file, line);
+    }
+
+    match output {
+        Some(path) => {
+            if let Err(e) = write_replacing(path, &normalized.contents) {
+                error!("Error: Failed to write '{}': {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        None => print!("{}", normalized.contents),
+    }
+    info!(
+        "Normalized {} entries: {} duplicates removed, {} paths rewritten, {} malformed lines dropped.",
+        normalized.entries,
+        normalized.duplicates,
+        normalized.rewritten,
+        normalized.malformed.len()
+    );
+}
+
+/// Report how `new_file` differs from `old_file`, matching entries by path. Exits 1 if they
+/// differ.
+fn diff_mode(old_file: &str, new_file: &str, rsync_list: Option<&Path>) {
+    let old = read_manifest(old_file);
+    let new = read_manifest(new_file);
+    for (file, manifest) in [(old_file, &old), (new_file, &new)] {
+        if manifest.partial {
+            warning!(
+                "Warning: '{}' is a partial manifest from an interrupted run; files it doesn't list show up as added or removed.",
+                file
+            );
+        }
+        for line in manifest.lines.iter().filter_map(|l| l.as_ref().err()) {
+            warning!("Warning: Ignoring malformed line in '{}': {}", file, line);
+        }
+    }
+
+    let algorithm_name = |m: &Manifest| {
+        m.algorithm
+            .clone()
+            .unwrap_or_else(|| algorithm::SHA256.name.to_string())
+    };
+    if algorithm_name(&old) != algorithm_name(&new) {
+        warning!(
+            "Warning: '{}' and '{}' use different digest algorithms; every file will show up as changed.",
+            old_file, new_file
+        );
+    }
+
+    let changes = old.diff(&new);
+    let (mut added, mut removed, mut changed) = (0usize, 0usize, 0usize);
+    for change in &changes {
+        match change {
+            ManifestChange::Added(e) => {
+                println!("{}: ADDED", e.path.display());
+                added += 1;
+            }
+            ManifestChange::Removed(e) => {
+                println!("{}: REMOVED", e.path.display());
+                removed += 1;
+            }
+            ManifestChange::Changed { new, .. } => {
+                println!("{}: CHANGED", new.path.display());
+                changed += 1;
+            }
+        }
+        // What's known of a file that went or changed is in OLD; of one that came, in NEW.
+        let note = match change {
+            ManifestChange::Added(e) => new.notes.get(&e.path),
+            ManifestChange::Removed(e) | ManifestChange::Changed { old: e, .. } => {
+                old.notes.get(&e.path)
+            }
+        };
+        if let Some(note) = note {
+            println!("  note: {}", note);
+        }
+    }
+
+    info!(
+        "\nSummary: added = {}, removed = {}, changed = {}, unchanged = {}",
+        added,
+        removed,
+        changed,
+        new.entries().count() - added - changed
+    );
+
+    if let Some(list) = rsync_list {
+        let base = common_dir(old.entries().map(|e| e.path.as_path()));
+        let paths: Vec<&Path> = changes
+            .iter()
+            .filter_map(|change| match change {
+                ManifestChange::Added(_) => None,
+                ManifestChange::Removed(e) | ManifestChange::Changed { old: e, .. } => {
+                    Some(e.path.as_path())
+                }
+            })
+            .collect();
+        write_rsync_list(list, &base, &paths);
+        info!(
+            "To copy them from OLD's tree: rsync -a --files-from={} {}/ DEST/",
+            list.display(),
+            base.display()
+        );
+    }
+
+    if !changes.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Write `paths`, relative to `base`, to `list` one per line, as `rsync --files-from` reads
+/// them. Paths with a line break in them can't be listed and are left out with a warning.
+fn write_rsync_list(list: &Path, base: &Path, paths: &[&Path]) {
+    let mut contents = String::new();
+    for path in paths {
+        let relative = portable_path(path.strip_prefix(base).unwrap_or(path));
+        if relative.contains(['\n', '\r']) {
+            warning!(
+                "Warning: Leaving '{}' out of '{}': rsync can't read paths with line breaks",
+                path.display(),
+                list.display()
+            );
+            continue;
+        }
+        contents.push_str(&relative);
+        contents.push('\n');
+    }
+    if let Err(e) = write_replacing(list, &contents) {
+        error!("Error: Failed to write '{}': {}", list.display(), e);
+        std::process::exit(1);
+    }
+    info!(
+        "Wrote {} paths to '{}'.",
+        contents.lines().count(),
+        list.display()
+    );
+}
+
+/// What became of a file listed in a checksum file being imported.
+enum Imported {
+    /// Its content matches the listed digest; with `--rehash`, here is its new hash.
+    Verified(Option<FileHash>),
+    Mismatch,
+    Missing,
+    Unreadable(String),
+}
+
+/// `import`: read the md5sum/sha1sum/sha256sum/sha512sum `files`, check every file they list
+/// against its digest, and print the ones that match as a gustasum manifest. Relative paths
+/// are taken from each file's directory, and printed absolute. Without `rehash` the manifest
+/// keeps the listed digests and is checked with `--full`; with it, every file that matched is
+/// hashed again with `opts` into a manifest of the usual kind.
+fn import_mode(files: &[String], rehash: bool, opts: &Options) {
+    let started = Instant::now();
+    let mut entries: Vec<ForeignEntry> = Vec::new();
+    let mut seen: HashMap<PathBuf, String> = HashMap::new();
+    for file in files {
+        let foreign = import::parse(&manifest_contents(file));
+        for line in &foreign.malformed {
+            warning!("Skipping malformed line in '{}': {}", file, line);
+        }
+        let base = Path::new(file).parent().unwrap_or(Path::new(""));
+        for mut entry in foreign.entries {
+            let path = base.join(&entry.path);
+            entry.path = path.canonicalize().unwrap_or(path);
+            match seen.get(&entry.path) {
+                Some(digest) if *digest != entry.digest => {
+                    warning!(
+                        "'{}' is listed again in '{}' with a different digest; keeping the first",
+                        entry.path.display(),
+                        file
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(entry.path.clone(), entry.digest.clone());
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+
+    // A --full manifest names one algorithm, so that's all it can hold.
+    let mut algorithms: Vec<&str> = entries.iter().map(|e| e.algorithm.name).collect();
+    algorithms.sort_unstable();
+    algorithms.dedup();
+    if !rehash && algorithms.len() > 1 {
+        error!(
+            "Error: The files list digests of more than one algorithm ({}); give --rehash to import them into one manifest",
+            algorithms.join(", ")
+        );
+        std::process::exit(1);
+    }
+    if rehash {
+        if let Some(digits) = opts.hash.abbrev {
+            check_abbrev(digits, entries.len(), true);
+        }
+    }
+    info!(
+        "Found {} files in {} checksum files. Verifying full checksums...",
+        entries.len(),
+        files.len()
+    );
+
+    let pb = if opts.progress == Progress::Bar {
+        let bar = ProgressBar::new(entries.len() as u64);
+        bar.set_draw_target(ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} files ({eta} remaining)\n  {wide_msg}",
+            )
+            .unwrap()
+            .progress_chars("=>-"),
+        );
+        Some(bar)
+    } else {
+        None
+    };
+    let events = CliEvents::new(entries.len(), pb, opts, "files");
+    let bytes_rehashed = AtomicU64::new(0);
+    let import_one = |(i, entry): &(usize, &ForeignEntry)| {
+        if interrupted() {
+            return None;
+        }
+        let full = HashOptions {
+            algorithm: entry.algorithm,
+            full: true,
+            abbrev: None,
+            ..opts.hash.clone()
+        };
+        let outcome = match hash_file_with(&entry.path, &full, &events) {
+            Err(HashError::Metadata(e)) if e.kind() == io::ErrorKind::NotFound => Imported::Missing,
+            Err(e) => Imported::Unreadable(e.to_string()),
+            Ok(FileHash { unstable: true, .. }) => {
+                Imported::Unreadable("changed while being read".to_string())
+            }
+            Ok(hash) if !hash.hash.eq_ignore_ascii_case(&entry.digest) => {
+                events.failed(&portable_path(&entry.path));
+                Imported::Mismatch
+            }
+            // The second read is of a file already counted, so it's not reported again.
+            Ok(_) if rehash => match hash_file(&entry.path, &opts.hash) {
+                Ok(FileHash { unstable: true, .. }) => {
+                    Imported::Unreadable("changed while being hashed again".to_string())
+                }
+                Ok(hash) => {
+                    bytes_rehashed.fetch_add(hash.bytes, Ordering::Relaxed);
+                    Imported::Verified(Some(hash))
+                }
+                Err(e) => Imported::Unreadable(e.to_string()),
+            },
+            Ok(_) => Imported::Verified(None),
+        };
+        Some((*i, outcome))
+    };
+    let reads: Vec<(usize, &ForeignEntry)> = entries.iter().enumerate().collect();
+    let mut results =
+        walk::run_scheduled(&reads, opts.order, |(_, e)| e.path.as_path(), import_one);
+    events.finish();
+    results.sort_by_key(|(i, _)| *i);
+
+    let mut lines: Vec<String> = Vec::new();
+    if interrupted() {
+        lines.push(format!(
+            "{}: interrupted after {} of {} files",
+            PARTIAL_MARKER,
+            results.len(),
+            entries.len()
+        ));
+    }
+    if rehash && !opts.hash.full {
+        lines.extend(manifest_headers(opts));
+    }
+    let (mut imported, mut mismatched, mut missing, mut unreadable) = (0usize, 0, 0, 0);
+    let mut listed_bytes = Some(0u64);
+    for (i, outcome) in results {
+        let entry = &entries[i];
+        match outcome {
+            Imported::Verified(rehashed) => {
+                listed_bytes = listed_bytes
+                    .zip(rehashed.as_ref())
+                    .map(|(sum, h)| sum + h.metadata.len);
+                let hash = rehashed.map_or_else(|| entry.digest.clone(), |h| h.hash);
+                lines.push(
+                    ManifestEntry {
+                        hash,
+                        path: entry.path.clone(),
+                    }
+                    .to_string(),
+                );
+                imported += 1;
+            }
+            Imported::Mismatch => {
+                error!(
+                    "Error: '{}' doesn't match its {} digest; not imported",
+                    entry.path.display(),
+                    entry.algorithm.name
+                );
+                mismatched += 1;
+            }
+            Imported::Missing => {
+                error!(
+                    "Error: '{}' doesn't exist; not imported",
+                    entry.path.display()
+                );
+                missing += 1;
+            }
+            Imported::Unreadable(why) => {
+                error!(
+                    "Error: '{}' couldn't be read ({}); not imported",
+                    entry.path.display(),
+                    why
+                );
+                unreadable += 1;
+            }
+        }
+    }
+    if rehash && !opts.hash.full {
+        lines.push(totals_line(&Totals {
+            entries: imported,
+            bytes: listed_bytes,
+        }));
+    }
+    for line in &lines {
+        println!("{}", line);
+    }
+
+    info!(
+        "\nSummary: imported {} of {} files, mismatched = {}, missing = {}, unreadable = {}",
+        imported,
+        entries.len(),
+        mismatched,
+        missing,
+        unreadable
+    );
+    let bytes_read = events.status.bytes.load(Ordering::Relaxed) + bytes_rehashed.into_inner();
+    print_throughput(entries.len(), bytes_read, started.elapsed());
+
+    if interrupted() {
+        info!("Interrupted: not every file was imported.");
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if imported < entries.len() {
+        std::process::exit(1);
+    }
+}
+
+/// `list`: describe `file` and everything in it that would trip up a check, exiting 1 if
+/// there is any.
+fn list_mode(file: &str) {
+    let manifest = read_manifest(file);
+    let mut problems: Vec<String> = Vec::new();
+
+    println!("Manifest: {}", file);
+    let structure_only = manifest.generated(STRUCTURE_FIELD) == Some("true");
+    let algorithm = manifest.algorithm();
+    println!(
+        "Algorithm: {}",
+        match manifest.algorithm.as_deref() {
+            Some(name) => name,
+            None if structure_only => "none (structure only)",
+            None => "sha256 (no header)",
+        }
+    );
+    if let Err(ref e) = algorithm {
+        problems.push(e.to_string());
+    }
+    if manifest.generated.is_empty() {
+        println!("Generated: (no header)");
+    } else {
+        println!("Generated:");
+        for (key, value) in &manifest.generated {
+            println!("  {} = {}", key, value);
+        }
+    }
+    if !manifest.labels.is_empty() {
+        println!("Labels:");
+        for (key, value) in &manifest.labels {
+            println!("  {} = {}", key, value);
+        }
+    }
+    if manifest.partial {
+        println!("Partial: yes, written by an interrupted run");
+    }
+
+    // What a well-formed digest looks like here: a kind and size for structure-only
+    // manifests, otherwise lowercase hex of the algorithm's length, or of the abbreviation.
+    let digits = match manifest.generated("abbrev") {
+        Some(abbrev) => abbrev.parse::<usize>().ok().or_else(|| {
+            problems.push(format!("abbrev={} in the header isn't a number", abbrev));
+            None
+        }),
+        None => algorithm.as_ref().ok().map(|a| a.hasher().finish().len()),
+    };
+    // Recorded sizes by path, so a duplicated entry isn't counted twice.
+    let mut sizes: HashMap<&Path, u64> = HashMap::new();
+    for entry in manifest.entries() {
+        if structure_only {
+            match entry.hash.parse::<Kind>() {
+                Ok(Kind::File(len)) => {
+                    sizes.insert(&entry.path, len);
+                }
+                Ok(_) => {}
+                Err(()) => problems.push(format!(
+                    "not a kind and size: {}  {}",
+                    entry.hash,
+                    entry.path.display()
+                )),
+            }
+            continue;
+        }
+        if !entry
+            .hash
+            .chars()
+            .all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+        {
+            problems.push(format!(
+                "not a lowercase hex digest: {}  {}",
+                entry.hash,
+                entry.path.display()
+            ));
+        } else if digits.is_some_and(|digits| entry.hash.len() != digits) {
+            problems.push(format!(
+                "digest of {} digits, expected {}: {}",
+                entry.hash.len(),
+                digits.unwrap_or_default(),
+                entry.path.display()
+            ));
+        }
+        if let Some(meta) = manifest.metadata.get(&entry.path) {
+            sizes.insert(&entry.path, meta.len);
+        }
+    }
+    for line in manifest.lines.iter().filter_map(|l| l.as_ref().err()) {
+        problems.push(format!("malformed line: {}", line));
+    }
+    if let Some(mismatch) = manifest.trailer_mismatch() {
+        problems.push(format!("may have been cut short: {}", mismatch));
+    }
+    for (path, entries) in manifest.duplicates() {
+        let conflicting = entries.iter().any(|e| e.hash != entries[0].hash);
+        problems.push(format!(
+            "listed {} times{}: {}",
+            entries.len(),
+            if conflicting {
+                " with different digests"
+            } else {
+                ""
+            },
+            path.display()
+        ));
+    }
+
+    let entries = manifest.entries().count();
+    println!("Entries: {}", entries);
+    if !sizes.is_empty() {
+        println!(
+            "Recorded size: {} bytes, for {} of {} entries",
+            sizes.values().sum::<u64>(),
+            sizes.len(),
+            entries
+        );
+    }
+    if let Some(totals) = manifest.totals {
+        match totals.bytes {
+            Some(bytes) => println!("Trailer: {} entries, {} bytes", totals.entries, bytes),
+            None => println!("Trailer: {} entries", totals.entries),
+        }
+    }
+    if problems.is_empty() {
+        println!("No problems found.");
+        return;
+    }
+    println!("Problems ({}):", problems.len());
+    for problem in &problems {
+        println!("  {}", problem);
+    }
+    std::process::exit(1);
+}
+
+/// `grep`: print the entries of `file` matching the `path` glob, starting with the `hash`
+/// prefix, and with a note containing `note`, as they appear in it (with their notes),
+/// exiting 1 if none do.
+fn grep_mode(file: &str, path: Option<&str>, hash: Option<&str>, note: Option<&str>) {
+    let pattern = path.map(|glob| {
+        glob::Pattern::new(glob).unwrap_or_else(|e| {
+            error!("Error: invalid --path pattern: {}", e);
+            std::process::exit(1);
+        })
+    });
+    let prefix = hash.map(str::to_ascii_lowercase);
+    let manifest = read_manifest(file);
+    // An abbreviated digest matches a longer prefix it's the start of, too.
+    let abbreviated = manifest.generated("abbrev").is_some();
+
+    let mut found = 0usize;
+    for entry in manifest.entries() {
+        let path_matches = pattern
+            .as_ref()
+            .is_none_or(|pattern| glob_matches_path(pattern, &portable_path(&entry.path)));
+        let hash_matches = prefix.as_ref().is_none_or(|prefix| {
+            entry.hash.starts_with(prefix.as_str())
+                || (abbreviated && prefix.starts_with(entry.hash.as_str()))
+        });
+        let entry_note = manifest.notes.get(&entry.path);
+        let note_matches = note.is_none_or(|text| entry_note.is_some_and(|n| n.contains(text)));
+        if path_matches && hash_matches && note_matches {
+            if let Some(entry_note) = entry_note {
+                println!("{}", note_line(entry_note));
+            }
+            println!("{}", entry);
+            found += 1;
+        }
+    }
+    info!(
+        "{} of {} entries matched.",
+        found,
+        manifest.entries().count()
+    );
+    if found == 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Whether `pattern` matches the portable `path`, or, for a pattern not starting with `/`,
+/// the path's last components. `*` doesn't cross a `/`.
+fn glob_matches_path(pattern: &glob::Pattern, path: &str) -> bool {
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..glob::MatchOptions::new()
+    };
+    if pattern.matches_with(path, options) {
+        return true;
+    }
+    !pattern.as_str().starts_with('/')
+        && path
+            .match_indices('/')
+            .any(|(i, _)| pattern.matches_with(&path[i + 1..], options))
+}
+
+/// Under a failure reported for the entry for `path`, the note `manifest` has for it, if any.
+fn show_note(manifest: &Manifest, path: &Path) {
+    if let Some(note) = manifest.notes.get(path) {
+        error!("  note: {}", note);
+    }
+}
+
+/// Read and parse a manifest, exiting with an error if it can't be read.
+fn read_manifest(file: &str) -> Manifest {
+    Manifest::parse(&manifest_contents(file))
+}
+
+/// Manifest `file` for hashing against, as [`read_manifest`] reads it, after
+/// [`require_hashed`].
+fn read_hashed_manifest(file: &str) -> Manifest {
+    let manifest = read_manifest(file);
+    require_hashed(file, &manifest);
+    manifest
+}
+
+/// Exit unless `manifest`, read from `file`, has digests to compare with and the real paths
+/// they go with.
+fn require_hashed(file: &str, manifest: &Manifest) {
+    if manifest.generated(STRUCTURE_FIELD) == Some("true") {
+        error!(
+            "Error: '{}' is a structure-only manifest; generate it again instead",
+            file
+        );
+        std::process::exit(1);
+    }
+    if manifest.generated(ANONYMIZED_FIELD).is_some() {
+        error!(
+            "Error: '{}' has anonymized paths; put them back with 'gustasum deanonymize' first",
+            file
+        );
+        std::process::exit(1);
+    }
+}
+
+/// The text of manifest `file`, a path or a web URL.
+fn manifest_contents(file: &str) -> String {
+    let contents = if remote::is_web_url(file) {
+        remote::fetch(file).and_then(|bytes| {
+            String::from_utf8(bytes).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "stream did not contain valid UTF-8",
+                )
+            })
+        })
+    } else {
+        fs::read_to_string(file)
+    };
+    contents.unwrap_or_else(|e| {
+        error!("Error: Failed to read manifest '{}': {}", file, e);
+        std::process::exit(1);
+    })
+}
+
+/// Manifest `file` for checking, verified against its `--signature` first if there is one.
+fn read_signed_manifest(matches: &ArgMatches, file: &str) -> Manifest {
+    let contents = manifest_contents(file);
+    let signing_key = matches.try_get_one::<String>("signing_key").ok().flatten();
+    let signature = match matches.try_get_one::<String>("signature").ok().flatten() {
+        Some(signature) => signature.clone(),
+        None if signing_key.is_some() => format!("{}.asc", file),
+        None => return Manifest::parse(&contents),
+    };
+    if let Err(e) = verify_signature(&contents, &signature, signing_key.map(Path::new)) {
+        error!(
+            "Error: '{}' failed signature verification against '{}': {}",
+            file, signature, e
+        );
+        std::process::exit(1);
+    }
+    info!("Verified the signature of '{}'.", file);
+    Manifest::parse(&contents)
+}
+
+/// Check `contents` against the detached signature at `signature`, with `gpgv` and only the
+/// keys in `keyring`, or else `gpg` and the user's keys. gpg's complaint is the error.
+fn verify_signature(contents: &str, signature: &str, keyring: Option<&Path>) -> io::Result<()> {
+    // gpg reads the data from stdin, but the signature has to be a file.
+    let fetched = if remote::is_web_url(signature) {
+        let path = std::env::temp_dir().join(format!("gustasum-{}.sig", std::process::id()));
+        fs::write(&path, remote::fetch(signature)?)?;
+        Some(path)
+    } else {
+        None
+    };
+    let signature_file = fetched.as_deref().unwrap_or(Path::new(signature));
+    let mut command = match keyring {
+        Some(keyring) => {
+            let mut gpgv = std::process::Command::new("gpgv");
+            // A bare file name would be looked up in ~/.gnupg.
+            gpgv.arg("--keyring").arg(std::path::absolute(keyring)?);
+            gpgv
+        }
+        None => {
+            let mut gpg = std::process::Command::new("gpg");
+            gpg.args(["--batch", "--verify"]);
+            gpg
+        }
+    };
+    let child = command
+        .arg(signature_file)
+        .arg("-")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn();
+    let result = child.and_then(|mut child| {
+        // A signature gpg can't use makes it stop reading.
+        let _ = child.stdin.take().unwrap().write_all(contents.as_bytes());
+        child.wait_with_output()
+    });
+    if let Some(path) = fetched {
+        let _ = fs::remove_file(path);
+    }
+    let output = result?;
+    let report = String::from_utf8_lossy(&output.stderr);
+    for line in report.lines() {
+        verbose!("{}", line);
+    }
+    if output.status.success() {
+        return Ok(());
+    }
+    let reason = report
+        .lines()
+        .rfind(|l| !l.trim().is_empty())
+        .unwrap_or("gpg failed");
+    Err(io::Error::other(reason.trim().to_string()))
+}
+
+/// Append this run's results to the `--db` history, with `stamp_of` each file that verified
+/// OK, for `--changed-only`. Skipped files aren't recorded.
+fn record_history(
+    db: &Path,
+    labels: &[(String, String)],
+    counted: &[(PathBuf, Counted, u64)],
+    stamp_of: impl Fn(&Path) -> Option<Stamp> + Sync,
+) {
+    let results: Vec<(PathBuf, history::Status, Option<Stamp>)> = counted
+        .par_iter()
+        .filter_map(|(path, how, _)| {
+            let status = match how {
+                Counted::Ok => history::Status::Ok,
+                Counted::Failed => history::Status::Failed,
+                Counted::Error(_) => history::Status::Error,
+                Counted::Known => history::Status::Failed,
+                Counted::Skipped => return None,
+            };
+            let stamp = (status == history::Status::Ok)
+                .then(|| stamp_of(path))
+                .flatten();
+            Some((path.clone(), status, stamp))
+        })
+        .collect();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    match History::append(db, now, labels, &results) {
+        Ok(()) => verbose!("Recorded {} results in '{}'", results.len(), db.display()),
+        Err(e) => error!("Error: cannot record history in '{}': {}", db.display(), e),
+    }
+}
+
+/// `gustasum history`: report trends in the `--db` history, and with a manifest, the
+/// entries it has no results for.
+fn history_mode(db: &Path, manifest_file: Option<&str>) {
+    let history = History::load(db).unwrap_or_else(|e| {
+        error!("Error: cannot read '{}': {}", db.display(), e);
+        std::process::exit(1);
+    });
+    let manifest = manifest_file.map(read_manifest);
+    let report = history.report(manifest.as_ref());
+    let when = |time: u64| utc_timestamp(UNIX_EPOCH + Duration::from_secs(time));
+
+    println!("Newly failing ({}):", report.newly_failing.len());
+    for record in &report.newly_failing {
+        let labels: Vec<String> = history
+            .labels
+            .get(&record.time)
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| format!(", {}={}", key, value))
+            .collect();
+        println!(
+            "  {}  ({} at {}{})",
+            portable_path(&record.path),
+            record.status.name(),
+            when(record.time),
+            labels.concat()
+        );
+    }
+    println!("Flapping ({}):", report.flapping.len());
+    for (path, changes, runs) in &report.flapping {
+        println!(
+            "  {}  ({} changes in {} runs)",
+            portable_path(path),
+            changes,
+            runs
+        );
+    }
+    if manifest.is_some() {
+        println!("Never verified ({}):", report.never_verified.len());
+        for path in &report.never_verified {
+            println!("  {}", portable_path(path));
+        }
+    }
+}
+
+/// How a file counted in the run summary, for [`print_breakdown`].
+#[derive(Clone, Copy)]
+enum Counted {
+    Ok,
+    /// Mismatched or unstable: the content is in question.
+    Failed,
+    /// Couldn't be read, for this reason.
+    Error(ErrorClass),
+    /// Failed or couldn't be read, as `--known-failures` said it would.
+    Known,
+    Skipped,
+}
+
+/// How many of `counted` files failed in each way, and how many manifest lines were
+/// `malformed`, for the line under a check's summary.
+fn failure_breakdown(counted: &[(PathBuf, Counted, u64)], malformed: usize) -> String {
+    let mismatched = counted
+        .iter()
+        .filter(|(_, how, _)| matches!(how, Counted::Failed))
+        .count();
+    let known = counted
+        .iter()
+        .filter(|(_, how, _)| matches!(how, Counted::Known))
+        .count();
+    let mut parts = vec![format!("mismatched = {}", mismatched)];
+    parts.push(error_breakdown(counted));
+    if malformed > 0 {
+        parts.push(format!("malformed lines = {}", malformed));
+    }
+    if known > 0 {
+        parts.push(format!("known = {}", known));
+    }
+    parts.join(", ")
+}
+
+/// How many of `counted` files couldn't be read for each [`ErrorClass`].
+fn error_breakdown(counted: &[(PathBuf, Counted, u64)]) -> String {
+    let count = |class: ErrorClass| {
+        counted
+            .iter()
+            .filter(|(_, how, _)| matches!(how, Counted::Error(c) if *c == class))
+            .count()
+    };
+    format!(
+        "missing = {}, permission denied = {}, I/O errors = {}",
+        count(ErrorClass::Missing),
+        count(ErrorClass::Permission),
+        count(ErrorClass::Io)
+    )
+}
+
+/// A finished run, for [`report_run`].
+struct RunReport<'a> {
+    /// `generate`, `update`, or `check`.
+    mode: &'a str,
+    /// The manifest, or the paths hashed.
+    target: &'a str,
+    /// Files (or manifest lines) the run set out to do.
+    total: usize,
+    counted: &'a [(PathBuf, Counted, u64)],
+    /// Malformed manifest lines aren't in `counted`, but count as failed.
+    malformed: usize,
+    /// Reads that failed partway through a file.
+    read_failures: &'a [ReadFailure],
+    /// How long files took to read, if any were.
+    latency: Option<&'a LatencySummary>,
+    elapsed: Duration,
+    exit_code: i32,
+}
+
+/// Write the `--summary-json` and `--metrics-textfile` and send the `--notify-*`
+/// notifications for `run`, if they were asked for.
+fn report_run(opts: &Options, run: &RunReport) {
+    let RunReport {
+        mode,
+        target,
+        counted,
+        elapsed,
+        ..
+    } = *run;
+    let mut totals = DirTally {
+        failed: run.malformed,
+        ..DirTally::default()
+    };
+    let (mut missing, mut denied) = (0, 0);
+    for (_, how, bytes) in counted {
+        match how {
+            Counted::Ok => totals.ok += 1,
+            Counted::Failed => totals.failed += 1,
+            Counted::Error(class) => {
+                totals.errors += 1;
+                match class {
+                    ErrorClass::Missing => missing += 1,
+                    ErrorClass::Permission => denied += 1,
+                    ErrorClass::Io => {}
+                }
+            }
+            Counted::Known => totals.known += 1,
+            Counted::Skipped => totals.skipped += 1,
+        }
+        totals.bytes += bytes;
+    }
+    let complete = !interrupted();
+    if let Some(path) = &opts.summary_json {
+        let json = format!(
+            "{{\"mode\":{},\"target\":{},\"total\":{},\"ok\":{},\"failed\":{},\"missing\":{},\
+             \"permission_denied\":{},\"errors\":{},\"known\":{},\"skipped\":{},\"bytes\":{},\"duration_seconds\":{:.3},\"complete\":{},\
+             \"exit_code\":{},\"read_errors\":{},\"latency\":{},\"labels\":{}}}\n",
+            json_string(mode),
+            json_string(target),
+            run.total,
+            totals.ok,
+            totals.failed,
+            missing,
+            denied,
+            totals.errors - missing - denied,
+            totals.known,
+            totals.skipped,
+            totals.bytes,
+            elapsed.as_secs_f64(),
+            complete,
+            run.exit_code,
+            json_read_failures(run.read_failures),
+            run.latency.map_or_else(|| "null".to_string(), json_latency),
+            json_labels(&opts.labels)
+        );
+        if let Err(e) = fs::write(path, json) {
+            warning!(
+                "Warning: Failed to write summary to '{}': {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    if let Some(path) = &opts.metrics_textfile {
+        if let Err(e) = metrics::write_textfile(path, mode, target, &totals, elapsed, complete) {
+            warning!(
+                "Warning: Failed to write metrics to '{}': {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    if opts.notify.is_set() {
+        let failures: Vec<String> = counted
+            .iter()
+            .filter(|(_, how, _)| matches!(how, Counted::Failed | Counted::Error(_)))
+            .map(|(path, _, _)| portable_path(path))
+            .collect();
+        let report = notify::Report {
+            event: "finished",
+            mode,
+            target,
+            counts: vec![
+                ("ok", totals.ok),
+                ("failed", totals.failed),
+                ("error", totals.errors),
+                ("known", totals.known),
+                ("skipped", totals.skipped),
+            ],
+            bytes_read: totals.bytes,
+            elapsed,
+            complete,
+            failures: &failures,
+            labels: &opts.labels,
+        };
+        send_notifications(&opts.notify, &report);
+    }
+}
+
+/// Send `report` to the `--notify-webhook` and `--notify-email` recipients.
+fn send_notifications(notify: &notify::Notify, report: &notify::Report) {
+    if let Some(url) = &notify.webhook {
+        if let Err(e) = notify::post(url, report) {
+            warning!("Warning: Failed to notify '{}': {}", url, e);
+        }
+    }
+    if !notify.email.is_empty() {
+        if let Err(e) = notify::mail(&notify.email, report) {
+            warning!("Warning: Failed to mail {}: {}", notify.email.join(", "), e);
+        }
+    }
+}
+
+/// Counts and bytes read for one directory in [`print_breakdown`].
+#[derive(Default)]
+struct DirTally {
+    ok: usize,
+    failed: usize,
+    errors: usize,
+    /// Failures of files listed in `--known-failures`.
+    known: usize,
+    skipped: usize,
+    bytes: u64,
+}
+
+/// The deepest directory that all `paths` are under.
+fn common_dir<'a>(mut paths: impl Iterator<Item = &'a Path>) -> PathBuf {
+    let Some(first) = paths.next() else {
+        return PathBuf::new();
+    };
+    let mut common = first.parent().unwrap_or(Path::new("")).to_path_buf();
+    for path in paths {
+        while !path.starts_with(&common) && common.pop() {}
+    }
+    common
+}
+
+/// Break the summary down by top-level directory: the directories just below the one all
+/// `counted` files share (e.g., each volume of an archive checked in one run). It's printed
+/// when there's more than one, and written to `summary_file` as tab-separated values either way.
+fn print_breakdown(counted: &[(PathBuf, Counted, u64)], summary_file: Option<&Path>) {
+    if counted.is_empty() {
+        return;
+    }
+    let common = common_dir(counted.iter().map(|(path, _, _)| path.as_path()));
+
+    let mut by_dir: BTreeMap<PathBuf, DirTally> = BTreeMap::new();
+    for (path, how, bytes) in counted {
+        // Files directly in the shared directory are counted under it.
+        let mut rest = path.strip_prefix(&common).unwrap_or(path).components();
+        let top = match (rest.next(), rest.next()) {
+            (Some(dir), Some(_)) => common.join(dir),
+            _ => common.clone(),
+        };
+        let tally = by_dir.entry(top).or_default();
+        match how {
+            Counted::Ok => tally.ok += 1,
+            Counted::Failed => tally.failed += 1,
+            Counted::Error(_) => tally.errors += 1,
+            Counted::Known => tally.known += 1,
+            Counted::Skipped => tally.skipped += 1,
+        }
+        tally.bytes += bytes;
+    }
+
+    if by_dir.len() > 1 {
+        let width = by_dir
+            .keys()
+            .map(|dir| dir.display().to_string().chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("DIRECTORY".len());
+        let mut table = format!(
+            "\n{:<width$}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}  {:>14}",
+            "DIRECTORY", "OK", "FAILED", "ERRORS", "KNOWN", "SKIPPED", "BYTES"
+        );
+        for (dir, t) in &by_dir {
+            table.push_str(&format!(
+                "\n{:<width$}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}  {:>14}",
+                dir.display().to_string(),
+                t.ok,
+                t.failed,
+                t.errors,
+                t.known,
+                t.skipped,
+                t.bytes
+            ));
+        }
+        info!("{}", table);
+    }
+
+    if let Some(summary_file) = summary_file {
+        let mut tsv = String::from("directory\tok\tfailed\terrors\tknown\tskipped\tbytes\n");
+        for (dir, t) in &by_dir {
+            tsv.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                dir.display(),
+                t.ok,
+                t.failed,
+                t.errors,
+                t.known,
+                t.skipped,
+                t.bytes
+            ));
+        }
+        if let Err(e) = fs::write(summary_file, tsv) {
+            error!(
+                "Error: cannot write summary to '{}': {}",
+                summary_file.display(),
+                e
+            );
+        }
+    }
+}
+
+/// The p50, p95, and p99 of how long files took, and with `-v` the slowest of them.
+fn print_latency(latency: Option<&LatencySummary>) {
+    let Some(latency) = latency else {
+        return;
+    };
+    let ms = |d: Duration| format!("{:.2} ms", d.as_secs_f64() * 1e3);
+    info!(
+        "Latency per file: p50 = {}, p95 = {}, p99 = {}",
+        ms(latency.p50),
+        ms(latency.p95),
+        ms(latency.p99)
+    );
+    for (time, path) in &latency.slowest {
+        verbose!("  {:>10}  {}", ms(*time), path.display());
+    }
+}
+
+/// Print the second summary line: bytes read, wall time, and the resulting rates.
+fn print_throughput(files: usize, bytes: u64, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64().max(1e-9);
+    info!(
+        "Read {} bytes in {:.2}s ({:.1} files/s, {:.2} MB/s)",
+        bytes,
+        elapsed.as_secs_f64(),
+        files as f64 / secs,
+        bytes as f64 / 1e6 / secs
+    );
+}
+
+/// Hash the built-in synthetic files in every case and report any digest that differs from
+/// the known answer, exiting 1 if one does.
+fn selftest_mode() {
+    let results = selftest::run();
+    let mut checked = 0;
+    let mut mismatches = 0;
+    for result in &results {
+        let matched = result.checked - result.mismatches.len();
+        println!("{}: {} of {} match", result.case, matched, result.checked);
+        for mismatch in &result.mismatches {
+            error!("{}", mismatch);
+        }
+        checked += result.checked;
+        mismatches += result.mismatches.len();
+    }
+    if mismatches > 0 {
+        eprintln!(
+            "Self-test FAILED: {} of {} digests differ from the known answers; this build would not verify manifests made by others.",
+            mismatches, checked
+        );
+        std::process::exit(1);
+    }
+    eprintln!(
+        "Self-test passed: {} digests in {} cases match the known answers.",
+        checked,
+        results.len()
+    );
+}
+
+/// Walk `paths` as `generate` would, hash a random sample of `sample_size` of the files found
+/// with `opts`, and project from it how long hashing them all would take and what it'd read.
+fn estimate_mode(paths: &[PathBuf], sample_size: usize, opts: &Options) {
+    let source = opts.hash.source.as_ref();
+    info!(
+        "Estimating a run with {} (partial-bytes = {}, threads = {})...",
+        opts.hash.algorithm.name,
+        opts.hash.partial_bytes,
+        rayon::current_num_threads()
+    );
+
+    // The files with the smallest random keys are a uniform sample, kept without holding on
+    // to every path.
+    let state = RandomState::new();
+    let start = Instant::now();
+    let mut found = 0usize;
+    let mut sample: BinaryHeap<(u64, PathBuf)> = BinaryHeap::new();
+    for path in walk::files_under(source, paths)
+        .take_while(|_| !interrupted())
+        .flat_map(|p| with_streams(p, opts))
+        .filter(|p| !walk::is_excluded(p, &opts.exclude))
+    {
+        found += 1;
+        let key = state.hash_one(&path);
+        if sample.len() < sample_size {
+            sample.push((key, path));
+        } else if sample.peek().is_some_and(|(top, _)| key < *top) {
+            sample.pop();
+            sample.push((key, path));
+        }
+    }
+    let walk_time = start.elapsed();
+    if found == 0 {
+        error!("Error: no files found to estimate a run over");
+        std::process::exit(1);
+    }
+    let sample: Vec<PathBuf> = sample.into_iter().map(|(_, path)| path).collect();
+
+    let start = Instant::now();
+    let sizes: Vec<u64> = sample
+        .par_iter()
+        .filter_map(|p| source.metadata(p).ok().map(|m| m.len))
+        .collect();
+    let stat_time = start.elapsed();
+
+    // Each file's own time, for the spread, and the whole sample's, for the rate the pool
+    // gets through files at.
+    let start = Instant::now();
+    let hashed: Vec<(f64, Option<u64>)> = sample
+        .par_iter()
+        .map(|p| {
+            let start = Instant::now();
+            let bytes = hash_file(p, &opts.hash).ok().map(|h| h.bytes);
+            (start.elapsed().as_secs_f64(), bytes)
+        })
+        .collect();
+    let hash_time = start.elapsed();
+
+    let n = sample.len() as f64;
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len().max(1) as f64;
+    // Half the width of the 95% confidence interval around a sample's mean, relative to it,
+    // shrunk as the sample nears the whole population.
+    let relative_error = |values: &[f64]| {
+        let average = mean(values);
+        if values.len() < 2 || values.len() == found || average == 0.0 {
+            return 0.0;
+        }
+        let variance =
+            values.iter().map(|v| (v - average).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+        let population = ((found - values.len()) as f64 / (found - 1) as f64).sqrt();
+        1.96 * variance.sqrt() / (values.len() as f64).sqrt() * population / average
+    };
+    let latencies: Vec<f64> = hashed.iter().map(|(secs, _)| *secs).collect();
+    let read: Vec<f64> = hashed
+        .iter()
+        .filter_map(|(_, bytes)| bytes.map(|b| b as f64))
+        .collect();
+    let sizes: Vec<f64> = sizes.iter().map(|&len| len as f64).collect();
+    let unreadable = hashed.iter().filter(|(_, bytes)| bytes.is_none()).count();
+
+    let hashing = found as f64 * hash_time.as_secs_f64() / n;
+    let error = relative_error(&latencies);
+    let projected = |factor: f64| {
+        format_duration(walk_time + Duration::from_secs_f64((hashing * factor).max(0.0)))
+    };
+
+    println!(
+        "Files:      {} (walked in {:.2}s)",
+        found,
+        walk_time.as_secs_f64()
+    );
+    if sample.len() == found {
+        println!("Sampled:    all of them");
+    } else {
+        println!("Sampled:    {} files, chosen at random", sample.len());
+    }
+    println!(
+        "Stat:       {:.0} files/s",
+        n / stat_time.as_secs_f64().max(1e-9)
+    );
+    println!(
+        "Hash:       {:.2} ms per file, {:.0} files/s in parallel",
+        mean(&latencies) * 1e3,
+        n / hash_time.as_secs_f64().max(1e-9)
+    );
+    println!(
+        "Data:       {} in all, {} to read",
+        format_bytes(found as f64 * mean(&sizes)),
+        format_bytes(found as f64 * mean(&read))
+    );
+    if error > 0.0 {
+        println!(
+            "Estimate:   {} ({} to {} at 95% confidence)",
+            projected(1.0),
+            projected(1.0 - error),
+            projected(1.0 + error)
+        );
+    } else {
+        println!("Estimate:   {}", projected(1.0));
+    }
+    if unreadable > 0 {
+        println!(
+            "\nNote: {} of the sampled files couldn't be read; the run would report them as errors.",
+            unreadable
+        );
+    }
+    println!(
+        "\nNote: files already in the page cache make the estimate look better than cold reads."
+    );
+}
+
+/// Measure traversal, stat, read, and hash rates under `root`, then suggest settings for it.
+fn bench_mode(root: &Path, opts: &Options) {
+    /// Stop walking after this long; the rate is what matters, not the full count.
+    const WALK_BUDGET: Duration = Duration::from_secs(10);
+    /// Files hashed per read benchmark run.
+    const SAMPLE_FILES: usize = 200;
+
+    eprintln!("Benchmarking '{}'...", root.display());
+
+    // Traversal
+    let start = Instant::now();
+    let mut entries = 0usize;
+    let mut files = Vec::new();
+    let mut walk_complete = true;
+    for entry in WalkDir::new(root)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        entries += 1;
+        if entry.file_type().is_file() {
+            files.push(entry.into_path());
+        }
+        if start.elapsed() > WALK_BUDGET {
+            walk_complete = false;
+            break;
+        }
+    }
+    let walk_secs = start.elapsed().as_secs_f64();
+
+    if files.is_empty() {
+        eprintln!("No files found under '{}'.", root.display());
+        std::process::exit(1);
+    }
+
+    // Stat
+    let stat_sample = &files[..files.len().min(5000)];
+    let start = Instant::now();
+    let sizes: Vec<u64> = stat_sample
+        .par_iter()
+        .map(|p| fs::metadata(p).map(|m| m.len()).unwrap_or(0))
+        .collect();
+    let stat_secs = start.elapsed().as_secs_f64();
+
+    // Reads, at a few thread counts and then with larger regions at the best of them. Each run
+    // gets its own slice of files so earlier runs don't warm the cache for later ones.
+    let threads = rayon::current_num_threads();
+    let mut thread_counts = vec![1, threads / 2, threads, threads * 2, threads * 4];
+    thread_counts.retain(|&n| n > 0);
+    thread_counts.dedup();
+    let region_sizes = [4096usize, 65536];
+    let per_run =
+        (stat_sample.len() / (thread_counts.len() + region_sizes.len())).clamp(1, SAMPLE_FILES);
+    let mut run_index = 0;
+    let mut read = |run_threads: usize, partial_bytes: usize| {
+        let begin = (run_index * per_run) % stat_sample.len();
+        let end = (begin + per_run).min(stat_sample.len());
+        run_index += 1;
+        let sample = &stat_sample[begin..end];
+        let bytes: u64 = sizes[begin..end]
+            .iter()
+            .map(|&size| hasher::sampled_bytes(size, partial_bytes))
+            .sum();
+
+        let run_opts = HashOptions {
+            partial_bytes,
+            retries: 0,
+            file_timeout: None,
+            ..opts.hash.clone()
+        };
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(run_threads)
+            .build()
+            .expect("failed to build thread pool");
+        let start = Instant::now();
+        pool.install(|| {
+            sample.par_iter().for_each(|p| {
+                let _ = hash_file(p, &run_opts);
+            })
+        });
+        let secs = start.elapsed().as_secs_f64().max(1e-9);
+        (
+            run_threads,
+            partial_bytes,
+            sample.len() as f64 / secs,
+            bytes as f64 / secs,
+        )
+    };
+    let by_threads: Vec<(usize, usize, f64, f64)> =
+        thread_counts.iter().map(|&n| read(n, 100)).collect();
+    let peak = by_threads.iter().map(|r| r.2).fold(0.0, f64::max);
+    // The fewest threads that get within 10% of the best rate; more only add contention.
+    let &(best_threads, _, best_rate, _) = by_threads
+        .iter()
+        .find(|r| r.2 >= peak * 0.9)
+        .unwrap_or(&by_threads[0]);
+    let by_region: Vec<(usize, usize, f64, f64)> = region_sizes
+        .iter()
+        .map(|&partial_bytes| read(best_threads, partial_bytes))
+        .collect();
+    let read_mb_s = by_threads
+        .iter()
+        .chain(&by_region)
+        .map(|r| r.3 / 1e6)
+        .fold(0.0, f64::max);
+
+    // Hashing, in memory, with each algorithm there is
+    let buf = vec![0u8; 64 * 1024 * 1024];
+    let hash_rates: Vec<(Algorithm, f64)> = algorithm::names()
+        .into_iter()
+        .filter_map(|name| algorithm::lookup(name).ok())
+        .map(|algorithm| {
+            let start = Instant::now();
+            let mut hasher = algorithm.hasher();
+            hasher.update(&buf);
+            let _ = hasher.finish();
+            let mb_s = buf.len() as f64 / 1e6 / start.elapsed().as_secs_f64().max(1e-9);
+            (algorithm, mb_s)
+        })
+        .collect();
+
+    println!(
+        "Traversal:  {:.0} entries/s ({} entries{})",
+        entries as f64 / walk_secs.max(1e-9),
+        entries,
+        if walk_complete { "" } else { ", stopped early" }
+    );
+    println!(
+        "Stat:       {:.0} files/s",
+        stat_sample.len() as f64 / stat_secs.max(1e-9)
+    );
+    for &(run_threads, partial_bytes, files_s, bytes_s) in by_threads.iter().chain(&by_region) {
+        println!(
+            "Read:       {:.0} files/s, {:.2} MB/s (threads = {}, partial-bytes = {})",
+            files_s,
+            bytes_s / 1e6,
+            run_threads,
+            partial_bytes
+        );
+    }
+    for (algorithm, mb_s) in &hash_rates {
+        println!("Hash:       {:.0} MB/s ({})", mb_s, algorithm.name);
+    }
+
+    println!("\nRecommendations:");
+    let speedup = best_rate / by_threads[0].2.max(1e-9);
+    if best_threads == 1 {
+        println!(
+            "- More threads don't help; the storage is likely rotational. Try --threads 1 --optimize-seek."
+        );
+    } else {
+        println!(
+            "- Reads level off at {} threads ({:.1}x one thread); use --threads {}.",
+            best_threads, speedup, best_threads
+        );
+    }
+    // Average time each worker spends per file; above ~5 ms, latency rather than bandwidth dominates.
+    if best_threads as f64 / best_rate > 0.005 {
+        println!(
+            "- Per-file latency is high; try --parallel-read-threshold 0 to overlap region reads."
+        );
+    }
+    let best = by_region
+        .iter()
+        .filter(|r| r.2 >= best_rate * 0.8)
+        .map(|r| r.1)
+        .max()
+        .unwrap_or(100);
+    if best > 100 {
+        println!(
+            "- Larger regions are almost free here; consider --partial-bytes {} for stronger sampling.",
+            best
+        );
+    } else {
+        println!(
+            "- Larger regions cost noticeably more; the default --partial-bytes 100 is a good fit."
+        );
+    }
+    // SHA-1 and MD5 are there for checking others' checksum files, not for new manifests.
+    let fastest = hash_rates
+        .iter()
+        .filter(|(a, _)| a.name != algorithm::SHA1.name && a.name != algorithm::MD5.name)
+        .max_by(|a, b| a.1.total_cmp(&b.1));
+    match fastest {
+        Some((algorithm, mb_s)) if *mb_s >= read_mb_s => {
+            let keep = if algorithm.name == opts.hash.algorithm.name {
+                "keep"
+            } else {
+                "use"
+            };
+            println!(
+                "- {} is the fastest digest here and keeps up with reads ({:.0} MB/s against {:.2} MB/s); {} --algorithm {}.",
+                algorithm.name, mb_s, read_mb_s, keep, algorithm.name
+            );
+        }
+        Some((algorithm, mb_s)) => println!(
+            "- No digest keeps up with reads of {:.2} MB/s; the fastest, {} at {:.0} MB/s, limits this storage least. Use --algorithm {}.",
+            read_mb_s, algorithm.name, mb_s, algorithm.name
+        ),
+        None => {}
+    }
+    println!(
+        "\nNote: files already in the page cache make read rates look better than cold reads."
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stdout that's gone, like `/dev/full`.
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("no space left on device"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn config_options_are_arguments() {
+        fn longs(command: &Command, found: &mut Vec<String>) {
+            found.extend(
+                command
+                    .get_arguments()
+                    .filter_map(|a| a.get_long())
+                    .map(str::to_string),
+            );
+            for sub in command.get_subcommands() {
+                longs(sub, found);
+            }
+        }
+        let mut found = Vec::new();
+        longs(&cli(), &mut found);
+        let missing: Vec<String> = CONFIG_OPTIONS
+            .iter()
+            .map(|id| config_key(id))
+            .filter(|key| !found.contains(key))
+            .collect();
+        assert!(missing.is_empty(), "no arguments for {:?}", missing);
+    }
+
+    #[test]
+    fn result_paths_stay_on_one_line() {
+        assert_eq!(result_path("dir/plain name"), "dir/plain name");
+        assert_eq!(result_path("two\nlines"), "\\two\\nlines");
+        assert_eq!(result_path("back\\slash"), "\\back\\\\slash");
+    }
+
+    #[test]
+    fn output_reports_failed_writes() {
+        let mut out = Output::to(Full, true);
+        assert!(out.line("abc  file").is_err());
+
+        // Into a pipe, lines are buffered, so the failure shows when they're flushed.
+        let mut out = Output::to(Full, false);
+        assert!(out.line("abc  file").is_ok());
+        assert_eq!(
+            out.flush().unwrap_err().to_string(),
+            "no space left on device"
+        );
+    }
 }
@@ -0,0 +1,887 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! The manifest format: one `<hash>  <path>` entry per line, like coreutils' `sha256sum`.
+//! Lines starting with `#` are comments or headers. Paths are written with `/` separators on
+//! every platform, so a manifest made on Windows can be checked on Linux and vice versa.
+
+use crate::algorithm::{self, Algorithm, UnknownAlgorithm};
+use crate::anonymize::ANONYMIZED_FIELD;
+use crate::error::Region;
+use crate::hasher::{HashOptions, Salt};
+use crate::source::SourceMetadata;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// Marks a manifest written by an interrupted run: its first line, or where the entries were
+/// printed as they were hashed, the last before the [`TOTALS_TRAILER`].
+pub const PARTIAL_MARKER: &str = "# gustasum partial manifest";
+
+/// Header naming the digest algorithm, followed by its name. Manifests without one are SHA-256.
+pub const ALGORITHM_HEADER: &str = "# gustasum algorithm: ";
+
+/// Comment placed before an entry for a reflink clone, followed by the path of the file it
+/// was found to be a clone of.
+pub const CLONE_HEADER: &str = "# gustasum clone of: ";
+
+/// Comment placed before an entry in manifests made with `--record-metadata`, followed by
+/// the file's size and modtime as `size=<bytes> mtime=<seconds>.<nanoseconds>` (no `mtime`
+/// if the platform didn't report one), then the short digest of each sampled region read, as
+/// `first=<hex> middle=<hex> last=<hex>`.
+pub const METADATA_HEADER: &str = "# gustasum metadata: ";
+
+/// Comment placed before an entry to annotate it, followed by free text (where the file
+/// came from, say). Consecutive notes above one entry are read together.
+pub const NOTE_HEADER: &str = "# gustasum note: ";
+
+/// Header recording when, where, by whom, and how the manifest was generated, followed by
+/// space-separated `key=value` fields (see [`provenance_line`]).
+pub const GENERATED_HEADER: &str = "# gustasum generated: ";
+
+/// Last line of a manifest, counting its entries and adding up the sizes of their files, as
+/// `entries=<n> bytes=<n>` fields (see [`Totals`]). A manifest cut short loses it, and one
+/// whose [`GENERATED_HEADER`] has [`TRAILER_FIELD`] is then known to be incomplete.
+pub const TOTALS_TRAILER: &str = "# gustasum totals: ";
+
+/// The [`GENERATED_HEADER`] field, set to `true`, promising a [`TOTALS_TRAILER`] at the end.
+pub const TRAILER_FIELD: &str = "trailer";
+
+/// Header with the `--label`s of the run that generated the manifest, as `key=value` fields
+/// like the [`GENERATED_HEADER`]'s.
+pub const LABELS_HEADER: &str = "# gustasum labels: ";
+
+/// The [`GENERATED_HEADER`] line for `fields`. Values are `%`-escaped, so they can hold spaces.
+pub fn provenance_line(fields: &[(&str, String)]) -> String {
+    format!("{}{}", GENERATED_HEADER, join_fields(fields))
+}
+
+/// The [`NOTE_HEADER`] line for `note`.
+pub fn note_line(note: &str) -> String {
+    format!("{}{}", NOTE_HEADER, note)
+}
+
+/// The [`LABELS_HEADER`] line for `labels`.
+pub fn labels_line(labels: &[(String, String)]) -> String {
+    format!("{}{}", LABELS_HEADER, join_fields(labels))
+}
+
+/// What a [`TOTALS_TRAILER`] records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Totals {
+    /// Lines other than comments and headers, well-formed or not.
+    pub entries: usize,
+    /// The sizes of the listed files added up, if they were all known.
+    pub bytes: Option<u64>,
+}
+
+/// The [`TOTALS_TRAILER`] line for `totals`.
+pub fn totals_line(totals: &Totals) -> String {
+    let mut fields = vec![("entries", totals.entries.to_string())];
+    fields.extend(totals.bytes.map(|bytes| ("bytes", bytes.to_string())));
+    format!("{}{}", TOTALS_TRAILER, join_fields(&fields))
+}
+
+/// Whether `line` is one a [`Totals`] counts: not blank, and not a comment or header.
+pub fn counts_as_entry(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
+
+/// `key=value` fields, space-separated, with values `%`-escaped.
+pub(crate) fn join_fields<K: AsRef<str>>(fields: &[(K, String)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key.as_ref(), escape_field(value)))
+        .collect();
+    fields.join(" ")
+}
+
+/// The fields of a [`join_fields`] string.
+pub(crate) fn split_fields(fields: &str) -> Vec<(String, String)> {
+    fields
+        .split_whitespace()
+        .filter_map(|field| field.split_once('='))
+        .map(|(key, value)| (key.to_string(), unescape_field(value)))
+        .collect()
+}
+
+fn escape_field(value: &str) -> String {
+    let mut out = String::new();
+    for c in value.chars() {
+        if c == '%' || c.is_whitespace() || c.is_control() {
+            for b in c.to_string().bytes() {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn unescape_field(value: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(decoded) if b == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The [`METADATA_HEADER`] line for `meta` and the short digests of the `regions` read.
+pub fn metadata_line(meta: &SourceMetadata, regions: &[(Region, String)]) -> String {
+    let mut line = format!("{}size={}", METADATA_HEADER, meta.len);
+    if let Some(since) = meta
+        .modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+    {
+        line.push_str(&format!(
+            " mtime={}.{:09}",
+            since.as_secs(),
+            since.subsec_nanos()
+        ));
+    }
+    for (region, digest) in regions {
+        line.push_str(&format!(" {}={}", region.key(), digest));
+    }
+    line
+}
+
+fn parse_metadata(fields: &str) -> Option<(SourceMetadata, Vec<(Region, String)>)> {
+    let mut meta = SourceMetadata {
+        len: 0,
+        modified: None,
+    };
+    let mut has_size = false;
+    let mut regions = Vec::new();
+    for field in fields.split_whitespace() {
+        match field.split_once('=')? {
+            ("size", len) => {
+                meta.len = len.parse().ok()?;
+                has_size = true;
+            }
+            ("mtime", mtime) => {
+                let (secs, nanos) = mtime.split_once('.').unwrap_or((mtime, "0"));
+                meta.modified =
+                    Some(UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?));
+            }
+            (key, digest) => {
+                // Room for more fields later.
+                if let Some(region) = Region::from_key(key) {
+                    regions.push((region, digest.to_string()));
+                }
+            }
+        }
+    }
+    has_size.then_some((meta, regions))
+}
+
+/// One recorded file: its partial hash and the path it was read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub hash: String,
+    pub path: PathBuf,
+}
+
+impl fmt::Display for ManifestEntry {
+    /// `<hash>  <path>`, as `sha256sum` writes it: a path with a backslash or line break in
+    /// it has them escaped, and the line starts with a backslash to say so.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = portable_path(&self.path);
+        match escape_path(&path) {
+            Some(escaped) => write!(f, "\\{}  {}", self.hash, escaped),
+            None => write!(f, "{}  {}", self.hash, path),
+        }
+    }
+}
+
+/// `path` with its backslashes and line breaks escaped as `sha256sum` does, or `None` if it
+/// has none and goes as it is.
+pub fn escape_path(path: &str) -> Option<String> {
+    if !path.contains(['\\', '\n', '\r']) {
+        return None;
+    }
+    Some(
+        path.replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r"),
+    )
+}
+
+/// `path` as a manifest records it: with `/` separators, whatever the platform.
+pub fn portable_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    #[cfg(windows)]
+    return path.replace('\\', "/");
+    #[cfg(not(windows))]
+    path.into_owned()
+}
+
+/// A recorded path with this platform's separators. Only Windows needs translating, and
+/// there `/` is never part of a file name, so the round trip is lossless.
+pub fn native_separators(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    return PathBuf::from(path.to_string_lossy().replace('/', "\\"));
+    #[cfg(not(windows))]
+    path.to_path_buf()
+}
+
+/// Why a manifest can't be checked with the options given, as [`Manifest::hash_options`]
+/// finds: checking it anyway would report every file as changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SettingsConflict {
+    /// The caller fixed `key` at `given`, and the manifest was generated with `recorded`.
+    Setting {
+        key: String,
+        recorded: String,
+        given: String,
+    },
+    /// The header records a value for `key` that isn't one.
+    Invalid { key: String, value: String },
+    /// The algorithm the header names isn't registered.
+    Algorithm(UnknownAlgorithm),
+    /// Its paths are anonymized, so they name no files.
+    Anonymized,
+    /// It was generated with a salt, and none was given.
+    SaltMissing,
+    /// It was generated with a salt other than the one given.
+    SaltDiffers,
+    /// A salt was given, and it was generated without one.
+    Unsalted,
+    /// Whole-file digests were asked for, and it has sampled ones.
+    Sampled,
+}
+
+impl fmt::Display for SettingsConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsConflict::Setting {
+                key,
+                recorded,
+                given,
+            } if key == "algorithm" => write!(f, "was made with {}, not {}", recorded, given),
+            SettingsConflict::Setting {
+                key,
+                recorded,
+                given,
+            } => write!(f, "was generated with {}={}, not {}", key, recorded, given),
+            SettingsConflict::Invalid { key, value } => {
+                write!(f, "records an invalid {}={}", key, value)
+            }
+            SettingsConflict::Algorithm(e) => write!(f, "can't be checked: {}", e),
+            SettingsConflict::Anonymized => {
+                f.write_str("has anonymized paths; put them back with 'gustasum deanonymize' first")
+            }
+            SettingsConflict::SaltMissing => {
+                f.write_str("was generated with a salt; give its --salt-file")
+            }
+            SettingsConflict::SaltDiffers => {
+                f.write_str("was generated with a different --salt-file")
+            }
+            SettingsConflict::Unsalted => f.write_str("was generated without a --salt-file"),
+            SettingsConflict::Sampled => {
+                f.write_str("has sampled digests, not the whole-file ones of --full")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SettingsConflict {}
+
+/// A parsed manifest.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    /// Every non-empty, non-comment line in file order; `Err` holds a malformed line verbatim.
+    pub lines: Vec<Result<ManifestEntry, String>>,
+    /// The manifest was written by an interrupted run, so it may be missing files.
+    pub partial: bool,
+    /// Algorithm named in the header, if there is one.
+    pub algorithm: Option<String>,
+    /// Size and modtime recorded by [`METADATA_HEADER`] lines, by entry path. A path listed
+    /// more than once has its last entry's, if that has any.
+    pub metadata: HashMap<PathBuf, SourceMetadata>,
+    /// The short digests of sampled regions recorded by [`METADATA_HEADER`] lines, by entry
+    /// path, if there were any.
+    pub regions: HashMap<PathBuf, Vec<(Region, String)>>,
+    /// The [`NOTE_HEADER`] text above each annotated entry, by entry path, with several
+    /// notes joined by `"; "`. Like metadata, a path listed more than once has its last
+    /// entry's.
+    pub notes: HashMap<PathBuf, String>,
+    /// The fields of the [`GENERATED_HEADER`], in order; empty for manifests without one.
+    pub generated: Vec<(String, String)>,
+    /// The labels in the [`LABELS_HEADER`], in order.
+    pub labels: Vec<(String, String)>,
+    /// The last [`TOTALS_TRAILER`], if there is one.
+    pub totals: Option<Totals>,
+}
+
+impl Manifest {
+    pub fn parse(contents: &str) -> Manifest {
+        let partial = contents.lines().any(|l| l.starts_with(PARTIAL_MARKER));
+        let algorithm = contents
+            .lines()
+            .find_map(|l| l.strip_prefix(ALGORITHM_HEADER))
+            .map(|name| name.trim().to_string());
+        let header = |prefix: &str| {
+            contents
+                .lines()
+                .find_map(|l| l.strip_prefix(prefix))
+                .map(split_fields)
+                .unwrap_or_default()
+        };
+        let generated = header(GENERATED_HEADER);
+        let labels = header(LABELS_HEADER);
+        let totals = contents
+            .lines()
+            .rev()
+            .find_map(|l| l.trim().strip_prefix(TOTALS_TRAILER.trim_end()))
+            .map(|fields| {
+                let fields = split_fields(fields);
+                let field = |key: &str| {
+                    fields
+                        .iter()
+                        .find(|(k, _)| k == key)
+                        .and_then(|(_, value)| value.parse::<u64>().ok())
+                };
+                Totals {
+                    entries: field("entries").unwrap_or(0) as usize,
+                    bytes: field("bytes"),
+                }
+            });
+
+        // Lines starting with '#' are comments/headers, never "<hash>  <path>" entries. A
+        // metadata header, and any notes, belong to the entry right after them.
+        let mut lines = Vec::new();
+        let mut metadata = HashMap::new();
+        let mut regions = HashMap::new();
+        let mut notes = HashMap::new();
+        let mut pending = None;
+        let mut pending_notes: Vec<&str> = Vec::new();
+        for l in contents.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            if let Some(fields) = l.strip_prefix(METADATA_HEADER) {
+                pending = parse_metadata(fields);
+                continue;
+            }
+            if let Some(note) = l.strip_prefix(NOTE_HEADER.trim_end()) {
+                if !note.trim().is_empty() {
+                    pending_notes.push(note.trim());
+                }
+                continue;
+            }
+            if l.starts_with('#') {
+                continue;
+            }
+            let line = parse_line(l).ok_or_else(|| l.to_string());
+            // A path listed again goes by its last entry, metadata and all.
+            match (&line, pending.take()) {
+                (Ok(entry), Some((meta, sampled))) => {
+                    metadata.insert(entry.path.clone(), meta);
+                    if sampled.is_empty() {
+                        regions.remove(&entry.path);
+                    } else {
+                        regions.insert(entry.path.clone(), sampled);
+                    }
+                }
+                (Ok(entry), None) => {
+                    metadata.remove(&entry.path);
+                    regions.remove(&entry.path);
+                }
+                (Err(_), _) => {}
+            }
+            if let Ok(ref entry) = line {
+                if pending_notes.is_empty() {
+                    notes.remove(&entry.path);
+                } else {
+                    notes.insert(entry.path.clone(), pending_notes.join("; "));
+                }
+            }
+            pending_notes.clear();
+            lines.push(line);
+        }
+
+        Manifest {
+            lines,
+            partial,
+            algorithm,
+            metadata,
+            regions,
+            notes,
+            generated,
+            labels,
+            totals,
+        }
+    }
+
+    /// How the manifest disagrees with its [`TOTALS_TRAILER`], if it does: it has none, though
+    /// its header promises one, or the trailer counts other than the entries there are. Either
+    /// way, it was cut short (by a full disk, say, or a broken pipe) or edited since.
+    pub fn trailer_mismatch(&self) -> Option<String> {
+        match self.totals {
+            Some(totals) if totals.entries != self.lines.len() => Some(format!(
+                "its trailer counts {} entries, but it has {}",
+                totals.entries,
+                self.lines.len()
+            )),
+            Some(_) => None,
+            None if self.generated(TRAILER_FIELD) == Some("true") => {
+                Some("the totals trailer its header promises is missing".to_string())
+            }
+            None => None,
+        }
+    }
+
+    /// The [`GENERATED_HEADER`] field named `key`.
+    pub fn generated(&self, key: &str) -> Option<&str> {
+        self.generated
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The registered algorithm the manifest was made with.
+    pub fn algorithm(&self) -> Result<Algorithm, UnknownAlgorithm> {
+        match &self.algorithm {
+            Some(name) => algorithm::lookup(name),
+            None => Ok(algorithm::SHA256),
+        }
+    }
+
+    /// `base` with the settings the manifest was generated with, as its [`GENERATED_HEADER`]
+    /// and [`ALGORITHM_HEADER`] record them, so its digests can be compared: `partial_bytes`,
+    /// `include_modtime`, `fat_compat`, `sparse`, `include_extents`, `abbrev`, and the
+    /// algorithm. A setting `fixed` names (the caller chose it outright) isn't replaced, and
+    /// differing from the manifest's is a conflict. So are settings that can't be taken from
+    /// it: a salt, which only the caller can give, anonymized paths, and `full`, since a
+    /// manifest of whole-file digests has no header.
+    pub fn hash_options(
+        &self,
+        base: HashOptions,
+        fixed: &dyn Fn(&str) -> bool,
+    ) -> Result<HashOptions, SettingsConflict> {
+        let mut opts = base;
+        let recorded = |key: &str, given: String| -> Result<Option<String>, SettingsConflict> {
+            match self.generated(key) {
+                Some(value) if value == given => Ok(None),
+                Some(value) if fixed(key) => Err(SettingsConflict::Setting {
+                    key: key.to_string(),
+                    recorded: value.to_string(),
+                    given,
+                }),
+                value => Ok(value.map(str::to_string)),
+            }
+        };
+        let invalid = |key: &str, value: &str| SettingsConflict::Invalid {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        if let Some(n) = recorded("partial_bytes", opts.partial_bytes.to_string())? {
+            opts.partial_bytes = n.parse().map_err(|_| invalid("partial_bytes", &n))?;
+        }
+        for (key, set) in [
+            ("include_modtime", &mut opts.include_modtime),
+            ("fat_compat", &mut opts.fat_compat),
+            ("sparse", &mut opts.sparse),
+            ("include_extents", &mut opts.include_extents),
+        ] {
+            if let Some(value) = recorded(key, set.to_string())? {
+                *set = value.parse().map_err(|_| invalid(key, &value))?;
+            }
+        }
+        let abbrev = opts.abbrev.map_or("none".to_string(), |n| n.to_string());
+        if let Some(n) = recorded("abbrev", abbrev)? {
+            opts.abbrev = Some(n.parse().map_err(|_| invalid("abbrev", &n))?);
+        }
+        if self.generated(ANONYMIZED_FIELD).is_some() {
+            return Err(SettingsConflict::Anonymized);
+        }
+        if !self.generated.is_empty() {
+            if opts.full {
+                return Err(SettingsConflict::Sampled);
+            }
+            let ours = opts.salt.as_ref().map(Salt::id);
+            match (self.generated("salt"), ours.as_deref()) {
+                (Some(theirs), Some(ours)) if theirs != ours => {
+                    return Err(SettingsConflict::SaltDiffers)
+                }
+                (Some(_), None) => return Err(SettingsConflict::SaltMissing),
+                (None, Some(_)) => return Err(SettingsConflict::Unsalted),
+                _ => {}
+            }
+        }
+        if let Some(name) = &self.algorithm {
+            if fixed("algorithm") && *name != opts.algorithm.name {
+                return Err(SettingsConflict::Setting {
+                    key: "algorithm".to_string(),
+                    recorded: name.clone(),
+                    given: opts.algorithm.name.to_string(),
+                });
+            }
+            opts.algorithm = self.algorithm().map_err(SettingsConflict::Algorithm)?;
+        }
+        Ok(opts)
+    }
+
+    /// The well-formed entries, in file order.
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.lines.iter().filter_map(|l| l.as_ref().ok())
+    }
+
+    /// Paths listed more than once, each with all of its entries, in the order the paths
+    /// first appear.
+    pub fn duplicates(&self) -> Vec<(&Path, Vec<&ManifestEntry>)> {
+        let mut by_path: HashMap<&Path, Vec<&ManifestEntry>> = HashMap::new();
+        let mut order = Vec::new();
+        for entry in self.entries() {
+            let entries = by_path.entry(entry.path.as_path()).or_default();
+            if entries.is_empty() {
+                order.push(entry.path.as_path());
+            }
+            entries.push(entry);
+        }
+        order
+            .into_iter()
+            .filter_map(|path| {
+                let entries = by_path.remove(path)?;
+                (entries.len() > 1).then_some((path, entries))
+            })
+            .collect()
+    }
+
+    /// Compare against a `newer` manifest of the same tree, matching entries by path.
+    /// Added and changed entries come in `newer`'s order, followed by removed ones in ours.
+    pub fn diff<'a>(&'a self, newer: &'a Manifest) -> Vec<ManifestChange<'a>> {
+        let ours: HashMap<&Path, &ManifestEntry> =
+            self.entries().map(|e| (e.path.as_path(), e)).collect();
+        let theirs: HashMap<&Path, &ManifestEntry> =
+            newer.entries().map(|e| (e.path.as_path(), e)).collect();
+
+        let mut changes: Vec<ManifestChange> = newer
+            .entries()
+            .filter_map(|new| match ours.get(new.path.as_path()) {
+                None => Some(ManifestChange::Added(new)),
+                Some(old) if old.hash != new.hash => Some(ManifestChange::Changed { old, new }),
+                Some(_) => None,
+            })
+            .collect();
+        changes.extend(
+            self.entries()
+                .filter(|old| !theirs.contains_key(old.path.as_path()))
+                .map(ManifestChange::Removed),
+        );
+        changes
+    }
+}
+
+/// One difference between two manifests, as found by [`Manifest::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManifestChange<'a> {
+    Added(&'a ManifestEntry),
+    Removed(&'a ManifestEntry),
+    Changed {
+        old: &'a ManifestEntry,
+        new: &'a ManifestEntry,
+    },
+}
+
+/// The manifest text `contents` without the entries for `paths`, or the metadata, clone, and
+/// note comments that go with them. Everything else is kept as it is.
+pub fn without_entries(contents: &str, paths: &HashSet<&Path>) -> String {
+    let mut out = String::new();
+    // Comments belonging to the next entry, kept only if it is.
+    let mut pending = String::new();
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with(METADATA_HEADER)
+            || trimmed.starts_with(CLONE_HEADER)
+            || trimmed.starts_with(NOTE_HEADER.trim_end())
+        {
+            pending.push_str(line);
+            pending.push('\n');
+            continue;
+        }
+        let dropped = !trimmed.starts_with('#')
+            && parse_line(trimmed).is_some_and(|e| paths.contains(e.path.as_path()));
+        if !dropped {
+            out.push_str(&pending);
+            out.push_str(line);
+            out.push('\n');
+        }
+        pending.clear();
+    }
+    out.push_str(&pending);
+    out
+}
+
+/// Split a line "<hash>  <path>" into an entry. Lines as coreutils writes them parse too:
+/// `<hash> *<path>` for files hashed in binary mode, and a leading backslash for a path
+/// with escapes (see [`ManifestEntry`]'s `Display`).
+pub fn parse_line(line: &str) -> Option<ManifestEntry> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hash, rest) = line.split_once(' ')?;
+    let path = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+    let path = if escaped {
+        unescape_path(path)?
+    } else {
+        path.to_string()
+    };
+    Some(ManifestEntry {
+        hash: hash.to_string(),
+        path: PathBuf::from(path),
+    })
+}
+
+/// `path` with `\\`, `\n`, and `\r` turned back into what they stand for, or `None` if it has
+/// any other escape.
+fn unescape_path(path: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            '\\' => '\\',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_parse_as_coreutils_writes_them() {
+        let entry = parse_line("abc123  /data/a file").unwrap();
+        assert_eq!(entry.hash, "abc123");
+        assert_eq!(entry.path, Path::new("/data/a file"));
+        let binary = parse_line("abc123 */data/bin").unwrap();
+        assert_eq!(binary.path, Path::new("/data/bin"));
+        assert_eq!(parse_line("abc123"), None);
+        assert_eq!(parse_line("abc123 /data/one-space"), None);
+    }
+
+    #[test]
+    fn escaped_paths_round_trip() {
+        let entry = ManifestEntry {
+            hash: "abc123".to_string(),
+            path: PathBuf::from("/data/new\nline\\back\rslash"),
+        };
+        let line = entry.to_string();
+        assert_eq!(line, "\\abc123  /data/new\\nline\\\\back\\rslash");
+        assert_eq!(parse_line(&line), Some(entry));
+        assert_eq!(parse_line("\\abc123  /data/bad\\t"), None);
+    }
+
+    #[test]
+    fn header_fields_round_trip() {
+        let fields = [
+            ("user", "Jo Doe".to_string()),
+            ("note", "100%\ttab".to_string()),
+            ("plain", "x=y".to_string()),
+        ];
+        let manifest = Manifest::parse(&provenance_line(&fields));
+        assert_eq!(manifest.generated("user"), Some("Jo Doe"));
+        assert_eq!(manifest.generated("note"), Some("100%\ttab"));
+        assert_eq!(manifest.generated("plain"), Some("x=y"));
+        assert_eq!(
+            split_fields("a=%zz b=%4"),
+            [("a".into(), "%zz".into()), ("b".into(), "%4".into())]
+        );
+    }
+
+    #[test]
+    fn headers_comments_and_malformed_lines() {
+        let manifest = Manifest::parse(
+            "# gustasum partial manifest\n\
+             # gustasum algorithm: sha512\n\
+             # gustasum labels: site=home\n\
+             # a comment\n\
+             \n\
+             aaaa  /data/a\n\
+             not an entry\n",
+        );
+        assert!(manifest.partial);
+        assert_eq!(manifest.algorithm.as_deref(), Some("sha512"));
+        assert_eq!(manifest.labels, [("site".to_string(), "home".to_string())]);
+        assert_eq!(manifest.lines.len(), 2);
+        assert_eq!(manifest.entries().count(), 1);
+        assert_eq!(manifest.lines[1], Err("not an entry".to_string()));
+    }
+
+    #[test]
+    fn metadata_and_notes_belong_to_the_next_entry() {
+        let manifest = Manifest::parse(&format!(
+            "{}size=42 mtime=1000.500000000 first=0011\n\
+             {}checked by hand\n\
+             aaaa  /data/a\n\
+             bbbb  /data/b\n",
+            METADATA_HEADER, NOTE_HEADER
+        ));
+        let meta = &manifest.metadata[Path::new("/data/a")];
+        assert_eq!(meta.len, 42);
+        assert_eq!(
+            meta.modified,
+            Some(UNIX_EPOCH + Duration::new(1000, 500_000_000))
+        );
+        assert_eq!(
+            manifest.regions[Path::new("/data/a")],
+            [(Region::First, "0011".to_string())]
+        );
+        assert_eq!(manifest.notes[Path::new("/data/a")], "checked by hand");
+        assert!(!manifest.metadata.contains_key(Path::new("/data/b")));
+        assert!(!manifest.notes.contains_key(Path::new("/data/b")));
+    }
+
+    #[test]
+    fn a_path_listed_again_goes_by_its_last_entry() {
+        let manifest = Manifest::parse(&format!(
+            "{}size=1\naaaa  /data/a\nbbbb  /data/a\n",
+            METADATA_HEADER
+        ));
+        assert!(manifest.metadata.is_empty());
+        let duplicates = manifest.duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].1.len(), 2);
+    }
+
+    #[test]
+    fn recorded_settings_replace_the_defaults() {
+        let manifest = Manifest::parse(&provenance_line(&[
+            ("partial_bytes", "4096".to_string()),
+            ("include_modtime", "true".to_string()),
+        ]));
+        let opts = manifest
+            .hash_options(HashOptions::default(), &|_| false)
+            .unwrap();
+        assert_eq!(opts.partial_bytes, 4096);
+        assert!(opts.include_modtime);
+        let fixed = manifest.hash_options(HashOptions::default(), &|key| key == "partial_bytes");
+        assert_eq!(
+            fixed.unwrap_err(),
+            SettingsConflict::Setting {
+                key: "partial_bytes".to_string(),
+                recorded: "4096".to_string(),
+                given: "100".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn totals_trailer_round_trips() {
+        let totals = Totals {
+            entries: 2,
+            bytes: Some(1234),
+        };
+        let manifest = Manifest::parse(&format!(
+            "aaaa  /data/a\nbbbb  /data/b\n{}\n",
+            totals_line(&totals)
+        ));
+        assert_eq!(manifest.totals, Some(totals));
+        assert_eq!(manifest.trailer_mismatch(), None);
+        let unknown = Totals {
+            entries: 0,
+            bytes: None,
+        };
+        assert_eq!(
+            Manifest::parse(&totals_line(&unknown)).totals,
+            Some(unknown)
+        );
+    }
+
+    #[test]
+    fn manifest_cut_short_is_caught() {
+        let header = provenance_line(&[(TRAILER_FIELD, "true".to_string())]);
+        let truncated = Manifest::parse(&format!("{}\naaaa  /data/a\n", header));
+        assert!(truncated.trailer_mismatch().unwrap().contains("missing"));
+
+        let totals = Totals {
+            entries: 3,
+            bytes: None,
+        };
+        let short = Manifest::parse(&format!("aaaa  /data/a\n{}\n", totals_line(&totals)));
+        assert!(short
+            .trailer_mismatch()
+            .unwrap()
+            .contains("counts 3 entries, but it has 1"));
+
+        // Without the header's promise, a manifest with no trailer is just an older one.
+        assert_eq!(Manifest::parse("aaaa  /data/a\n").trailer_mismatch(), None);
+    }
+
+    #[test]
+    fn only_entry_lines_count_toward_the_totals() {
+        assert!(counts_as_entry("aaaa  /data/a"));
+        assert!(counts_as_entry("not an entry"));
+        assert!(!counts_as_entry("  "));
+        assert!(!counts_as_entry("# gustasum note: x"));
+    }
+
+    #[test]
+    fn entries_are_dropped_with_their_comments() {
+        let contents = format!(
+            "{}sha256\n{}size=1\naaaa  /data/a\n# a comment\n{}replaced\nbbbb  /data/b\n",
+            ALGORITHM_HEADER, METADATA_HEADER, NOTE_HEADER
+        );
+        let gone: HashSet<&Path> = [Path::new("/data/b")].into();
+        assert_eq!(
+            without_entries(&contents, &gone),
+            format!(
+                "{}sha256\n{}size=1\naaaa  /data/a\n# a comment\n",
+                ALGORITHM_HEADER, METADATA_HEADER
+            )
+        );
+        let gone: HashSet<&Path> = [Path::new("/data/a")].into();
+        assert_eq!(
+            without_entries(&contents, &gone),
+            format!(
+                "{}sha256\n# a comment\n{}replaced\nbbbb  /data/b\n",
+                ALGORITHM_HEADER, NOTE_HEADER
+            )
+        );
+    }
+
+    #[test]
+    fn nothing_dropped_keeps_the_text() {
+        let contents = "# gustasum algorithm: sha256\naaaa  /data/a\n";
+        assert_eq!(without_entries(contents, &HashSet::new()), contents);
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Prometheus metrics in the text exposition format: `--metrics-textfile` writes a run's
+//! totals for node_exporter's textfile collector when it ends, and `--metrics-listen` serves
+//! the live counters over HTTP while it lasts, for scrapes during a long scrub.
+
+use crate::{DirTally, RunStatus};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Write},
+    net::TcpListener,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Append one gauge sample per `(labels, value)`, under a HELP and TYPE header.
+fn gauge(out: &mut String, name: &str, help: &str, samples: &[(String, String)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// `{key="value",...}`, escaped as the format requires.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Write the totals of a finished run of `mode` (`generate`, `check`) over `target` (the
+/// manifest, or the paths hashed) to `path`. It's written to a temporary file renamed into
+/// place, so the collector never reads half of it.
+pub fn write_textfile(
+    path: &Path,
+    mode: &str,
+    target: &str,
+    totals: &DirTally,
+    elapsed: Duration,
+    complete: bool,
+) -> io::Result<()> {
+    let run = labels(&[("mode", mode), ("target", target)]);
+    let with_result =
+        |result: &str| labels(&[("mode", mode), ("target", target), ("result", result)]);
+    let finished = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    let mut out = String::new();
+    gauge(
+        &mut out,
+        "gustasum_files",
+        "Files in the last run, by result.",
+        &[
+            (with_result("ok"), totals.ok.to_string()),
+            (with_result("failed"), totals.failed.to_string()),
+            (with_result("error"), totals.errors.to_string()),
+            (with_result("known"), totals.known.to_string()),
+            (with_result("skipped"), totals.skipped.to_string()),
+        ],
+    );
+    gauge(
+        &mut out,
+        "gustasum_bytes_read",
+        "Bytes read in the last run.",
+        &[(run.clone(), totals.bytes.to_string())],
+    );
+    gauge(
+        &mut out,
+        "gustasum_duration_seconds",
+        "How long the last run took.",
+        &[(run.clone(), format!("{:.3}", elapsed.as_secs_f64()))],
+    );
+    gauge(
+        &mut out,
+        "gustasum_last_run_timestamp_seconds",
+        "When the last run finished, in seconds since the Unix epoch.",
+        &[(run.clone(), format!("{:.3}", finished))],
+    );
+    gauge(
+        &mut out,
+        "gustasum_last_run_complete",
+        "1 if the last run got through every file; 0 if it stopped early (--max-errors, --max-duration, or an interrupt).",
+        &[(run, u8::from(complete).to_string())],
+    );
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", std::process::id()));
+    fs::write(&temporary, out)?;
+    fs::rename(&temporary, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
+}
+
+/// The live counters of the run in progress.
+fn live(status: &RunStatus) -> String {
+    let mut out = String::new();
+    let sample = |value: String| [(String::new(), value)];
+    gauge(
+        &mut out,
+        "gustasum_run_files_done",
+        "Files (or manifest lines) finished so far.",
+        &sample(status.done.load(Ordering::Relaxed).to_string()),
+    );
+    gauge(
+        &mut out,
+        "gustasum_run_files_planned",
+        "Files (or manifest lines) this run set out to do.",
+        &sample(status.total.load(Ordering::Relaxed).to_string()),
+    );
+    gauge(
+        &mut out,
+        "gustasum_run_failures",
+        "Files failed so far, as counted against --max-errors.",
+        &sample(status.failures.load(Ordering::Relaxed).to_string()),
+    );
+    gauge(
+        &mut out,
+        "gustasum_run_bytes_read",
+        "Bytes read so far.",
+        &sample(status.bytes.load(Ordering::Relaxed).to_string()),
+    );
+    gauge(
+        &mut out,
+        "gustasum_run_elapsed_seconds",
+        "Time since the run started.",
+        &sample(format!("{:.3}", status.started.elapsed().as_secs_f64())),
+    );
+    out
+}
+
+/// Answer every request on `listener` with the live counters in `status`, on a thread of its
+/// own, until the process exits.
+pub fn serve(listener: TcpListener, status: Arc<RunStatus>) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            // Whatever the path, the request is for the metrics; read up to the end of its
+            // headers so the client sees a clean close.
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 16 * 1024 {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let body = live(&status);
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+}
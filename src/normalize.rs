@@ -0,0 +1,313 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Rewriting a manifest into a clean, canonical form: one entry per path, paths with `/`
+//! separators and no empty or `.` components in one Unicode normalization form, entries
+//! sorted by path, and only the headers gustasum itself writes. Hand-merged manifests pick
+//! up duplicates and stray lines that later trip up a check.
+
+use crate::manifest::{
+    join_fields, metadata_line, note_line, portable_path, totals_line, Manifest, ManifestEntry,
+    Totals, ALGORITHM_HEADER, GENERATED_HEADER, LABELS_HEADER, PARTIAL_MARKER,
+};
+use std::{collections::BTreeMap, path::PathBuf, str::FromStr};
+use unicode_normalization::UnicodeNormalization;
+
+/// The Unicode normalization form to rewrite paths in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnicodeForm {
+    /// Composed, as Linux and Windows tools usually write names.
+    Nfc,
+    /// Decomposed, as HFS+ stored them.
+    Nfd,
+    /// Leave paths as they are.
+    Keep,
+}
+
+impl FromStr for UnicodeForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(UnicodeForm::Nfc),
+            "nfd" => Ok(UnicodeForm::Nfd),
+            "keep" => Ok(UnicodeForm::Keep),
+            _ => Err(format!(
+                "unknown Unicode form '{}' (expected nfc, nfd, or keep)",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with a path listed more than once with different digests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Refuse to normalize.
+    Error,
+    /// Keep the entry listed last, the one most recently appended or updated.
+    Newest,
+}
+
+/// A manifest as rewritten by [`normalize`].
+#[derive(Clone, Debug, Default)]
+pub struct Normalized {
+    pub contents: String,
+    /// Entries written.
+    pub entries: usize,
+    /// Entries dropped because another one had the same path.
+    pub duplicates: usize,
+    /// Paths, as written, that differed from how the manifest had them.
+    pub rewritten: usize,
+    /// Malformed lines dropped, verbatim.
+    pub malformed: Vec<String>,
+}
+
+/// `path` with `\` separators in Windows paths (those starting with a drive letter or `\\`)
+/// turned into `/`, empty and `.` components dropped, and in Unicode `form`. On other paths a
+/// `\` is part of a name and is kept.
+pub fn normalize_path(path: &str, form: UnicodeForm) -> String {
+    let windows = path.starts_with("\\\\")
+        || (path.as_bytes().get(1) == Some(&b':') && path.as_bytes()[0].is_ascii_alphabetic());
+    let path = if windows {
+        path.replace('\\', "/")
+    } else {
+        path.to_string()
+    };
+    // A leading `//` names a UNC share, and is kept as it is.
+    let root = if path.starts_with("//") {
+        "//"
+    } else if path.starts_with('/') {
+        "/"
+    } else {
+        ""
+    };
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    let path = format!("{}{}", root, components.join("/"));
+    match form {
+        UnicodeForm::Nfc => path.nfc().collect(),
+        UnicodeForm::Nfd => path.nfd().collect(),
+        UnicodeForm::Keep => path,
+    }
+}
+
+/// Rewrite `manifest` in canonical form. Entries whose paths normalize to the same one are
+/// merged; if their digests differ and `on_conflict` is [`OnConflict::Error`], the
+/// conflicting paths are returned instead.
+pub fn normalize(
+    manifest: &Manifest,
+    form: UnicodeForm,
+    on_conflict: OnConflict,
+) -> Result<Normalized, Vec<String>> {
+    let mut by_path: BTreeMap<String, Vec<&ManifestEntry>> = BTreeMap::new();
+    let mut rewritten = 0;
+    for entry in manifest.entries() {
+        let raw_path = portable_path(&entry.path);
+        let path = normalize_path(&raw_path, form);
+        if path != raw_path {
+            rewritten += 1;
+        }
+        by_path.entry(path).or_default().push(entry);
+    }
+
+    let conflicts: Vec<String> = by_path
+        .iter()
+        .filter(|(_, entries)| entries.iter().any(|e| e.hash != entries[0].hash))
+        .map(|(path, _)| path.clone())
+        .collect();
+    if on_conflict == OnConflict::Error && !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    let mut lines = Vec::new();
+    if manifest.partial {
+        lines.push(PARTIAL_MARKER.to_string());
+    }
+    if let Some(ref algorithm) = manifest.algorithm {
+        lines.push(format!("{}{}", ALGORITHM_HEADER, algorithm));
+    }
+    if !manifest.generated.is_empty() {
+        lines.push(format!(
+            "{}{}",
+            GENERATED_HEADER,
+            join_fields(&manifest.generated)
+        ));
+    }
+    if !manifest.labels.is_empty() {
+        lines.push(format!(
+            "{}{}",
+            LABELS_HEADER,
+            join_fields(&manifest.labels)
+        ));
+    }
+    let mut duplicates = 0;
+    for (path, entries) in &by_path {
+        duplicates += entries.len() - 1;
+        let kept = entries[entries.len() - 1];
+        // The merged entries' notes all still describe the file, so none are lost.
+        let mut notes: Vec<&str> = Vec::new();
+        for note in entries.iter().filter_map(|e| manifest.notes.get(&e.path)) {
+            if !notes.contains(&note.as_str()) {
+                notes.push(note);
+            }
+        }
+        if !notes.is_empty() {
+            lines.push(note_line(&notes.join("; ")));
+        }
+        if let Some(meta) = manifest.metadata.get(&kept.path) {
+            let regions = manifest.regions.get(&kept.path);
+            lines.push(metadata_line(meta, regions.map_or(&[], Vec::as_slice)));
+        }
+        lines.push(
+            ManifestEntry {
+                hash: kept.hash.clone(),
+                path: PathBuf::from(path),
+            }
+            .to_string(),
+        );
+    }
+
+    // The sizes still add up to the trailer's if nothing was merged or dropped; otherwise
+    // only the recorded metadata can say what they come to now.
+    if manifest.totals.is_some() {
+        let bytes = match manifest.totals {
+            Some(totals) if duplicates == 0 && manifest.trailer_mismatch().is_none() => {
+                totals.bytes
+            }
+            _ => by_path
+                .values()
+                .map(|entries| manifest.metadata.get(&entries[entries.len() - 1].path))
+                .map(|meta| meta.map(|m| m.len))
+                .sum(),
+        };
+        lines.push(totals_line(&Totals {
+            entries: by_path.len(),
+            bytes,
+        }));
+    }
+
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    Ok(Normalized {
+        contents,
+        entries: by_path.len(),
+        duplicates,
+        rewritten,
+        malformed: manifest
+            .lines
+            .iter()
+            .filter_map(|l| l.as_ref().err().cloned())
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_cleaned_up() {
+        assert_eq!(
+            normalize_path("/data//./a/b", UnicodeForm::Keep),
+            "/data/a/b"
+        );
+        assert_eq!(
+            normalize_path("C:\\data\\a", UnicodeForm::Keep),
+            "C:/data/a"
+        );
+        assert_eq!(
+            normalize_path("\\\\nas\\share\\a", UnicodeForm::Keep),
+            "//nas/share/a"
+        );
+        // Outside Windows paths a backslash is part of the name.
+        assert_eq!(
+            normalize_path("/data/a\\b", UnicodeForm::Keep),
+            "/data/a\\b"
+        );
+        assert_eq!(normalize_path("./rel/a", UnicodeForm::Keep), "rel/a");
+    }
+
+    #[test]
+    fn unicode_forms() {
+        let decomposed = "/caf\u{65}\u{301}";
+        let composed = "/caf\u{e9}";
+        assert_eq!(normalize_path(decomposed, UnicodeForm::Nfc), composed);
+        assert_eq!(normalize_path(composed, UnicodeForm::Nfd), decomposed);
+        assert_eq!(normalize_path(decomposed, UnicodeForm::Keep), decomposed);
+        assert_eq!("nfd".parse(), Ok(UnicodeForm::Nfd));
+        assert!("nfkc".parse::<UnicodeForm>().is_err());
+    }
+
+    #[test]
+    fn entries_are_sorted_and_deduplicated() {
+        let manifest = Manifest::parse(
+            "# gustasum algorithm: sha256\n\
+             # stray comment\n\
+             bbbb  /data/b\n\
+             aaaa  /data//a\n\
+             aaaa  /data/a\n\
+             not an entry\n",
+        );
+        let normalized = normalize(&manifest, UnicodeForm::Nfc, OnConflict::Error).unwrap();
+        assert_eq!(
+            normalized.contents,
+            "# gustasum algorithm: sha256\naaaa  /data/a\nbbbb  /data/b\n"
+        );
+        assert_eq!(normalized.entries, 2);
+        assert_eq!(normalized.duplicates, 1);
+        assert_eq!(normalized.rewritten, 1);
+        assert_eq!(normalized.malformed, ["not an entry"]);
+    }
+
+    #[test]
+    fn conflicting_digests_are_refused_or_resolved_by_the_newest() {
+        let manifest = Manifest::parse("aaaa  /data/a\nbbbb  /data/./a\n");
+        assert_eq!(
+            normalize(&manifest, UnicodeForm::Keep, OnConflict::Error).unwrap_err(),
+            ["/data/a"]
+        );
+        let newest = normalize(&manifest, UnicodeForm::Keep, OnConflict::Newest).unwrap();
+        assert_eq!(newest.contents, "bbbb  /data/a\n");
+    }
+
+    #[test]
+    fn notes_of_merged_entries_are_kept_and_totals_recounted() {
+        let manifest = Manifest::parse(
+            "# gustasum note: first\n\
+             # gustasum metadata: size=3\n\
+             aaaa  /caf\u{e9}\n\
+             # gustasum note: second\n\
+             # gustasum metadata: size=3\n\
+             aaaa  /cafe\u{301}\n\
+             # gustasum totals: entries=2 bytes=6\n",
+        );
+        let normalized = normalize(&manifest, UnicodeForm::Nfc, OnConflict::Error).unwrap();
+        assert_eq!(
+            normalized.contents,
+            "# gustasum note: first; second\n\
+             # gustasum metadata: size=3\n\
+             aaaa  /caf\u{e9}\n\
+             # gustasum totals: entries=1 bytes=3\n"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! `--notify-webhook` and `--notify-email`: a summary of the run, and the files that failed,
+//! sent when it ends, and once more mid-run if failures reach `--notify-threshold`, so a long
+//! unattended scrub reaches someone when something goes wrong.
+//!
+//! The webhook gets the summary as JSON, POSTed with curl; mail goes through `sendmail -t`.
+
+use crate::{hostname, json_labels, json_string};
+use gustasum::remote;
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+/// At most this many failed files are listed; the rest are only counted.
+const MAX_LISTED: usize = 100;
+
+/// Where notifications go.
+#[derive(Clone, Default)]
+pub struct Notify {
+    pub webhook: Option<String>,
+    pub email: Vec<String>,
+    /// `--notify-threshold`: also notify as soon as this many files have failed.
+    pub threshold: Option<usize>,
+}
+
+impl Notify {
+    pub fn is_set(&self) -> bool {
+        self.webhook.is_some() || !self.email.is_empty()
+    }
+}
+
+/// What a notification says.
+pub struct Report<'a> {
+    /// `finished`, or `threshold` for the mid-run alert.
+    pub event: &'a str,
+    /// `generate`, `update`, or `check`.
+    pub mode: &'a str,
+    /// The manifest, or the paths hashed.
+    pub target: &'a str,
+    /// Files by result (`ok`, `failed`, ...) when finished; done, planned, and failed so far
+    /// for the alert.
+    pub counts: Vec<(&'a str, usize)>,
+    pub bytes_read: u64,
+    pub elapsed: Duration,
+    /// Whether the run got through every file; `false` for the alert.
+    pub complete: bool,
+    /// The files that failed.
+    pub failures: &'a [String],
+    /// The run's `--label`s.
+    pub labels: &'a [(String, String)],
+}
+
+impl Report<'_> {
+    fn failed(&self) -> usize {
+        self.counts
+            .iter()
+            .filter(|(name, _)| matches!(*name, "failed" | "error"))
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// The webhook's body.
+    fn json(&self) -> String {
+        let counts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(name, n)| format!("{}:{}", json_string(name), n))
+            .collect();
+        let listed: Vec<String> = self
+            .failures
+            .iter()
+            .take(MAX_LISTED)
+            .map(|f| json_string(f))
+            .collect();
+        format!(
+            "{{\"event\":{},\"host\":{},\"mode\":{},\"target\":{},\"counts\":{{{}}},\"bytes_read\":{},\
+             \"elapsed_seconds\":{:.3},\"complete\":{},\"failures\":[{}],\"failures_not_listed\":{},\"labels\":{}}}",
+            json_string(self.event),
+            json_string(&hostname()),
+            json_string(self.mode),
+            json_string(self.target),
+            counts.join(","),
+            self.bytes_read,
+            self.elapsed.as_secs_f64(),
+            self.complete,
+            listed.join(","),
+            self.failures.len().saturating_sub(MAX_LISTED),
+            json_labels(self.labels)
+        )
+    }
+
+    /// The mail's subject, on one line however the target is named.
+    fn subject(&self) -> String {
+        let what = format!(
+            "gustasum {} of {} on {}",
+            self.mode,
+            self.target,
+            hostname()
+        );
+        let subject = match (self.event, self.failed()) {
+            ("threshold", n) => format!("{}: {} failures so far", what, n),
+            (_, 0) if self.complete => format!("{}: OK", what),
+            (_, 0) => format!("{}: stopped early", what),
+            (_, n) => format!("{}: {} failed", what, n),
+        };
+        header_value(&subject)
+    }
+
+    /// The mail's body.
+    fn text(&self) -> String {
+        let mut out = String::new();
+        for (key, value) in self.labels {
+            let _ = writeln!(out, "{:<10}{}", format!("{}:", key), value);
+        }
+        if !self.labels.is_empty() {
+            let _ = writeln!(out);
+        }
+        for (name, n) in &self.counts {
+            let _ = writeln!(out, "{:<10}{}", format!("{}:", name), n);
+        }
+        let _ = writeln!(out, "{:<10}{}", "bytes:", self.bytes_read);
+        let _ = writeln!(out, "{:<10}{:.1}s", "elapsed:", self.elapsed.as_secs_f64());
+        if self.event != "threshold" && !self.complete {
+            let _ = writeln!(out, "\nThe run stopped before checking every file.");
+        }
+        if !self.failures.is_empty() {
+            let _ = writeln!(out, "\nFailed:");
+            for failure in self.failures.iter().take(MAX_LISTED) {
+                let _ = writeln!(out, "  {}", failure);
+            }
+            if self.failures.len() > MAX_LISTED {
+                let _ = writeln!(out, "  ... and {} more", self.failures.len() - MAX_LISTED);
+            }
+        }
+        out
+    }
+}
+
+/// POST `report` to `url` as JSON.
+pub fn post(url: &str, report: &Report) -> io::Result<()> {
+    remote::post(url, "application/json", &report.json()).map(drop)
+}
+
+/// `s` with control characters, CR and LF among them, turned into spaces, so that it can't
+/// end its header and start another.
+fn header_value(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// Mail `report` to `to` with `sendmail`.
+pub fn mail(to: &[String], report: &Report) -> io::Result<()> {
+    let mut child = Command::new("sendmail")
+        .args(["-t", "-oi"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot run 'sendmail': {}", e)))?;
+    let message = format!(
+        "To: {}\nSubject: {}\nContent-Type: text/plain; charset=utf-8\n\n{}",
+        header_value(&to.join(", ")),
+        report.subject(),
+        report.text()
+    );
+    child.stdin.take().unwrap().write_all(message.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("sendmail failed ({})", status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subjects_stay_one_header() {
+        let report = Report {
+            event: "finished",
+            mode: "check",
+            target: "evil\r\nBcc: someone@example.com\n.manifest",
+            counts: vec![("ok", 1)],
+            bytes_read: 0,
+            elapsed: Duration::ZERO,
+            complete: true,
+            failures: &[],
+            labels: &[],
+        };
+        let subject = report.subject();
+        assert!(!subject.contains(['\r', '\n']), "{:?}", subject);
+        assert!(
+            subject.starts_with("gustasum check of evil  Bcc: someone@example.com .manifest on ")
+        );
+        assert!(subject.ends_with(": OK"));
+        assert_eq!(header_value("a@b\n,c@d"), "a@b ,c@d");
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! `gustasum par2`: PAR2 recovery data for the files in a manifest, made and used by running
+//! `par2` from par2cmdline. Partial checksums find rot cheaply; parity can then fix it
+//! without a second full copy.
+//!
+//! Each file gets its own recovery set, so repairing one file never needs the others. The
+//! sets live in a separate directory, at the file's path with `.par2` appended (plus the
+//! `.volNN+NN.par2` volumes `par2` writes next to it).
+
+use std::{
+    ffi::OsString,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// What `par2 verify` or `par2 repair` found for one file.
+pub enum Verdict {
+    Intact,
+    /// Damaged, with enough recovery data to repair it.
+    Repairable,
+    /// Damaged beyond what the recovery data can repair.
+    Unrepairable,
+    Repaired,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Intact => "intact",
+            Verdict::Repairable => "damaged, repairable",
+            Verdict::Unrepairable => "damaged, NOT repairable",
+            Verdict::Repaired => "repaired",
+        })
+    }
+}
+
+/// The `par2` program to run.
+pub struct Par2 {
+    program: OsString,
+}
+
+impl Par2 {
+    pub fn new(program: impl Into<OsString>) -> Self {
+        Par2 {
+            program: program.into(),
+        }
+    }
+
+    /// Where the recovery set for `file` goes under `dir`: `/data/a.txt` maps to
+    /// `dir/data/a.txt.par2`.
+    pub fn set_for(dir: &Path, file: &Path) -> PathBuf {
+        let mut set = dir
+            .join(
+                file.components()
+                    .filter(|c| matches!(c, Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            )
+            .into_os_string();
+        set.push(".par2");
+        PathBuf::from(set)
+    }
+
+    /// Write a recovery set for `file` at `set`, able to repair `redundancy` percent of it.
+    pub fn create(&self, file: &Path, set: &Path, redundancy: u32) -> io::Result<()> {
+        if let Some(parent) = set.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut redundancy_arg = OsString::from("-r");
+        redundancy_arg.push(redundancy.to_string());
+        match self.run("create", file, set, &[redundancy_arg])? {
+            0 => Ok(()),
+            code => Err(failed("create", code)),
+        }
+    }
+
+    pub fn verify(&self, file: &Path, set: &Path) -> io::Result<Verdict> {
+        match self.run("verify", file, set, &[])? {
+            0 => Ok(Verdict::Intact),
+            1 => Ok(Verdict::Repairable),
+            2 => Ok(Verdict::Unrepairable),
+            code => Err(failed("verify", code)),
+        }
+    }
+
+    /// Repair `file` in place if it's damaged. `par2` keeps the damaged copy as `file.1`.
+    pub fn repair(&self, file: &Path, set: &Path) -> io::Result<Verdict> {
+        match self.run("repair", file, set, &[])? {
+            0 => Ok(Verdict::Repaired),
+            2 | 5 => Ok(Verdict::Unrepairable),
+            code => Err(failed("repair", code)),
+        }
+    }
+
+    /// Run `par2 <command> -q -B<dir of file> [extra] <set> <file>` and return its exit code.
+    fn run(&self, command: &str, file: &Path, set: &Path, extra: &[OsString]) -> io::Result<i32> {
+        if !set.exists() && command != "create" {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no recovery set at '{}'", set.display()),
+            ));
+        }
+        let mut base = OsString::from("-B");
+        base.push(file.parent().unwrap_or(Path::new(".")));
+        let status = Command::new(&self.program)
+            .arg(command)
+            .arg("-q")
+            .arg(base)
+            .args(extra)
+            .arg(set)
+            .arg(file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "cannot run '{}' (is par2cmdline installed?): {}",
+                        Path::new(&self.program).display(),
+                        e
+                    ),
+                )
+            })?;
+        // A signal leaves no code; treat it like par2's own logic error.
+        Ok(status.code().unwrap_or(7))
+    }
+}
+
+fn failed(command: &str, code: i32) -> io::Error {
+    io::Error::other(format!("par2 {} failed with exit code {}", command, code))
+}
@@ -0,0 +1,220 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Python bindings (the `python` feature), built into a `gustasum` extension module with
+//! maturin. They call the same sampling and hashing code as the command-line tool, so
+//! manifests written from Python and from the CLI are interchangeable.
+
+use crate::algorithm::{self, Algorithm};
+use crate::hasher::{hash_file, HashOptions};
+use crate::manifest::{Manifest, SettingsConflict};
+use crate::verify::{verify_manifest as verify, CheckOutcome, VerifyOptions};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use std::{fs, path::PathBuf, time::Duration};
+
+create_exception!(
+    gustasum,
+    UnstableFileError,
+    PyOSError,
+    "The file changed while it was being read, so its hash can't be trusted."
+);
+
+#[allow(clippy::too_many_arguments)]
+fn hash_options(
+    algorithm: Algorithm,
+    partial_bytes: usize,
+    include_modtime: bool,
+    fat_compat: bool,
+    parallel_read_threshold: Option<u64>,
+    retries: usize,
+    retry_delay_ms: u64,
+    retry_network: bool,
+    file_timeout: Option<f64>,
+    reread_unstable: usize,
+) -> HashOptions {
+    HashOptions {
+        algorithm,
+        partial_bytes,
+        include_modtime,
+        fat_compat,
+        parallel_read_threshold,
+        retries,
+        retry_delay: Duration::from_millis(retry_delay_ms),
+        retry_network,
+        file_timeout: file_timeout.map(Duration::from_secs_f64),
+        reread_unstable,
+        ..HashOptions::default()
+    }
+}
+
+/// Partial hash of the file at `path`, as a hex string.
+///
+/// Raises `OSError` if the file can't be read and `UnstableFileError` if it changed while
+/// being read.
+#[pyfunction(name = "hash_file")]
+#[pyo3(signature = (
+    path,
+    partial_bytes = 100,
+    include_modtime = false,
+    *,
+    fat_compat = false,
+    algorithm = "sha256",
+    parallel_read_threshold = None,
+    retries = 2,
+    retry_delay_ms = 100,
+    retry_network = true,
+    file_timeout = None,
+    reread_unstable = 0,
+))]
+#[allow(clippy::too_many_arguments)]
+fn hash_file_py(
+    py: Python<'_>,
+    path: PathBuf,
+    partial_bytes: usize,
+    include_modtime: bool,
+    fat_compat: bool,
+    algorithm: &str,
+    parallel_read_threshold: Option<u64>,
+    retries: usize,
+    retry_delay_ms: u64,
+    retry_network: bool,
+    file_timeout: Option<f64>,
+    reread_unstable: usize,
+) -> PyResult<String> {
+    let opts = hash_options(
+        lookup(algorithm)?,
+        partial_bytes,
+        include_modtime,
+        fat_compat,
+        parallel_read_threshold,
+        retries,
+        retry_delay_ms,
+        retry_network,
+        file_timeout,
+        reread_unstable,
+    );
+    let result = py.allow_threads(|| hash_file(&path, &opts));
+    match result {
+        Ok(h) if h.unstable => Err(UnstableFileError::new_err(format!(
+            "'{}' changed while being read",
+            path.display()
+        ))),
+        Ok(h) => Ok(h.hash),
+        Err(e) => Err(PyOSError::new_err(e.to_string())),
+    }
+}
+
+/// Check every entry of the manifest file at `manifest` and return a list of
+/// `(path, status, error)` tuples in manifest order. `status` is one of "OK", "MISMATCH",
+/// "UNSTABLE", "ERROR", or "MALFORMED"; `error` describes an "ERROR" and is None otherwise.
+///
+/// `remap` is an optional `(old_base, new_base)` pair, like the CLI's `--remap`. The digest
+/// algorithm and the other settings the manifest header records are used when left as None;
+/// passing a different one, or checking a salted or anonymized manifest, raises `ValueError`.
+#[pyfunction]
+#[pyo3(signature = (
+    manifest,
+    partial_bytes = None,
+    include_modtime = None,
+    *,
+    fat_compat = None,
+    remap = None,
+    algorithm = None,
+    parallel_read_threshold = None,
+    retries = 2,
+    retry_delay_ms = 100,
+    retry_network = true,
+    file_timeout = None,
+    reread_unstable = 0,
+))]
+#[allow(clippy::too_many_arguments)]
+fn verify_manifest(
+    py: Python<'_>,
+    manifest: PathBuf,
+    partial_bytes: Option<usize>,
+    include_modtime: Option<bool>,
+    fat_compat: Option<bool>,
+    remap: Option<(PathBuf, PathBuf)>,
+    algorithm: Option<&str>,
+    parallel_read_threshold: Option<u64>,
+    retries: usize,
+    retry_delay_ms: u64,
+    retry_network: bool,
+    file_timeout: Option<f64>,
+    reread_unstable: usize,
+) -> PyResult<Vec<(String, &'static str, Option<String>)>> {
+    let contents = fs::read_to_string(&manifest)?;
+    let parsed = Manifest::parse(&contents);
+    let defaults = HashOptions::default();
+    let base = hash_options(
+        algorithm.map_or(Ok(defaults.algorithm), lookup)?,
+        partial_bytes.unwrap_or(defaults.partial_bytes),
+        include_modtime.unwrap_or(defaults.include_modtime),
+        fat_compat.unwrap_or(defaults.fat_compat),
+        parallel_read_threshold,
+        retries,
+        retry_delay_ms,
+        retry_network,
+        file_timeout,
+        reread_unstable,
+    );
+    let fixed = |key: &str| match key {
+        "algorithm" => algorithm.is_some(),
+        "partial_bytes" => partial_bytes.is_some(),
+        "include_modtime" => include_modtime.is_some(),
+        "fat_compat" => fat_compat.is_some(),
+        _ => false,
+    };
+    let conflict = |e: SettingsConflict| PyValueError::new_err(format!("manifest {}", e));
+    let opts = VerifyOptions {
+        hash: parsed.hash_options(base, &fixed).map_err(conflict)?,
+        remap,
+    };
+    let results = py
+        .allow_threads(|| verify(&parsed, &opts))
+        .map_err(conflict)?;
+    Ok(results
+        .into_iter()
+        .map(|r| {
+            let (status, error) = match r.outcome {
+                CheckOutcome::Ok => ("OK", None),
+                CheckOutcome::Mismatch => ("MISMATCH", None),
+                CheckOutcome::Unstable => ("UNSTABLE", None),
+                CheckOutcome::Error(e) => ("ERROR", Some(e.to_string())),
+                CheckOutcome::Malformed => ("MALFORMED", None),
+            };
+            (r.path, status, error)
+        })
+        .collect())
+}
+
+fn lookup(name: &str) -> PyResult<Algorithm> {
+    algorithm::lookup(name).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+#[pymodule]
+fn gustasum(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(hash_file_py, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_manifest, m)?)?;
+    m.add("UnstableFileError", m.py().get_type::<UnstableFileError>())?;
+    Ok(())
+}
@@ -0,0 +1,905 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Files on web servers and S3-compatible object storage, read by running `curl` with HTTP
+//! range requests: stat'ing is a HEAD (or comes from an S3 listing), and each sampled region
+//! is one ranged GET, so content is never downloaded in full. A server that ignores ranges
+//! is read with one streamed GET instead, which is dropped once the last sample is read.
+//!
+//! S3 objects are named `s3://bucket/key`. Their requests are signed with AWS Signature
+//! Version 4 (curl's `--aws-sigv4`) when `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` are
+//! set, and sent anonymously otherwise. Credentials go to curl on its standard input, never
+//! on its command line, and never with requests for `http://` and `https://` URLs.
+
+use crate::source::{civil_time, Source, SourceFile, SourceMetadata};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The scheme of S3 object paths.
+pub const S3_SCHEME: &str = "s3://";
+
+/// Where S3 requests go and how they're signed.
+#[derive(Clone, Default)]
+pub struct S3Settings {
+    /// Base URL of an S3-compatible service (MinIO, Ceph, ...), addressed path-style as
+    /// `<endpoint>/<bucket>/<key>`. AWS itself, addressed as `<bucket>.s3.<region>.amazonaws.com`,
+    /// if `None`.
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
+impl fmt::Debug for S3Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Settings")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("signed", &self.access_key.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl S3Settings {
+    /// Region and credentials from the usual `AWS_*` environment variables.
+    pub fn from_env(endpoint: Option<String>) -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        S3Settings {
+            endpoint: endpoint.map(|e| e.trim_end_matches('/').to_string()),
+            region: var("AWS_REGION")
+                .or_else(|| var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            access_key: var("AWS_ACCESS_KEY_ID"),
+            secret_key: var("AWS_SECRET_ACCESS_KEY"),
+            session_token: var("AWS_SESSION_TOKEN"),
+        }
+    }
+
+    /// The URL of `key` in `bucket`, or of the bucket itself for an empty key.
+    fn url(&self, bucket: &str, key: &str) -> String {
+        match &self.endpoint {
+            Some(endpoint) => format!("{}/{}/{}", endpoint, bucket, encode(key, false)),
+            None => format!(
+                "https://{}.s3.{}.amazonaws.com/{}",
+                bucket,
+                self.region,
+                encode(key, false)
+            ),
+        }
+    }
+
+    /// curl configuration, fed on standard input, that signs requests.
+    fn curl_config(&self) -> String {
+        let (Some(access), Some(secret)) = (&self.access_key, &self.secret_key) else {
+            return String::new();
+        };
+        let mut config = format!(
+            "user = \"{}:{}\"\naws-sigv4 = \"aws:amz:{}:s3\"\n",
+            quote(access),
+            quote(secret),
+            quote(&self.region)
+        );
+        if let Some(token) = &self.session_token {
+            config.push_str(&format!(
+                "header = \"x-amz-security-token: {}\"\n",
+                quote(token)
+            ));
+        }
+        config
+    }
+}
+
+/// What a listing or HEAD request said about a file.
+#[derive(Clone, Debug)]
+struct Object {
+    meta: SourceMetadata,
+    etag: Option<String>,
+    /// Not ruled out by `Accept-Ranges: none`.
+    ranges: bool,
+}
+
+/// A path this source reads remotely.
+enum Target {
+    S3 { bucket: String, key: String },
+    Http(String),
+}
+
+impl Target {
+    /// `s3://bucket/key` or an `http://` or `https://` URL, or `None` for any other path.
+    fn of(path: &Path) -> Option<Target> {
+        // Native separators may have been applied to the whole entry.
+        let s = path.to_str()?.replace('\\', "/");
+        if let Some(rest) = s.strip_prefix(S3_SCHEME) {
+            let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+            return Some(Target::S3 {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+            });
+        }
+        is_web_url(&s).then_some(Target::Http(s))
+    }
+
+    fn url(&self, s3: &S3Settings) -> String {
+        match self {
+            Target::S3 { bucket, key } => s3.url(bucket, key),
+            Target::Http(url) => encode_url(url),
+        }
+    }
+
+    fn is_s3(&self) -> bool {
+        matches!(self, Target::S3 { .. })
+    }
+}
+
+/// The curl program and how it signs S3 requests.
+struct Client {
+    curl: OsString,
+    s3: S3Settings,
+}
+
+impl Client {
+    /// Start curl on `url`, signing the request if `s3` and following redirects if not.
+    /// `-f` makes HTTP errors fail it.
+    fn spawn(&self, url: &str, s3: bool, args: &[&str]) -> io::Result<Child> {
+        let config = if s3 {
+            self.s3.curl_config()
+        } else {
+            String::new()
+        };
+        self.spawn_with(url, s3, args, &config)
+    }
+
+    /// [`Client::spawn`], with `config` as curl's configuration.
+    fn spawn_with(&self, url: &str, s3: bool, args: &[&str], config: &str) -> io::Result<Child> {
+        let mut child = Command::new(&self.curl)
+            .args(["-sS", "-f", "-K", "-"])
+            .args(if s3 { &[][..] } else { &["-L"][..] })
+            .args(args)
+            .arg("--url")
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "cannot run '{}' (is curl installed?): {}",
+                        Path::new(&self.curl).display(),
+                        e
+                    ),
+                )
+            })?;
+        child.stdin.take().unwrap().write_all(config.as_bytes())?;
+        Ok(child)
+    }
+
+    /// Run curl on `url` and return what it wrote to standard output.
+    fn run(&self, url: &str, s3: bool, args: &[&str]) -> io::Result<Vec<u8>> {
+        finish(self.spawn(url, s3, args)?)
+    }
+}
+
+/// What curl, `child`, wrote to standard output, once it succeeds.
+fn finish(child: Child) -> io::Result<Vec<u8>> {
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        return Ok(output.stdout);
+    }
+    let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    Err(curl_error(output.status.code(), message))
+}
+
+/// Whether `s` is an `http://` or `https://` URL.
+pub fn is_web_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+/// All of `url`, a web URL, in one GET that follows redirects.
+pub fn fetch(url: &str) -> io::Result<Vec<u8>> {
+    let client = Client {
+        curl: "curl".into(),
+        s3: S3Settings::default(),
+    };
+    client.run(&encode_url(url), false, &[])
+}
+
+/// POST `body`, of `content_type`, to `url`, a web URL, and return the response.
+pub fn post(url: &str, content_type: &str, body: &str) -> io::Result<Vec<u8>> {
+    let client = Client {
+        curl: "curl".into(),
+        s3: S3Settings::default(),
+    };
+    let header = format!("Content-Type: {}", content_type);
+    // In the configuration rather than on the command line, where it could be too long.
+    let config = format!(
+        "data-binary = \"{}\"\n",
+        quote(body).replace('\n', "\\n").replace('\r', "\\r")
+    );
+    let args = ["-X", "POST", "-H", &header, "--max-time", "60"];
+    finish(client.spawn_with(&encode_url(url), false, &args, &config)?)
+}
+
+/// `inner`, with `s3://bucket/key` paths read from S3 and `http://` and `https://` URLs from
+/// their web servers. Other paths pass through unchanged.
+pub struct RemoteSource {
+    inner: Arc<dyn Source>,
+    client: Arc<Client>,
+    /// Files seen in listings or HEAD requests, so each is stat'ed once.
+    known: Mutex<HashMap<PathBuf, Object>>,
+}
+
+impl fmt::Debug for RemoteSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteSource")
+            .field("inner", &self.inner)
+            .field("s3", &self.client.s3)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RemoteSource {
+    /// Read remote files by running `curl`, the program given.
+    pub fn new(inner: Arc<dyn Source>, curl: impl Into<OsString>, s3: S3Settings) -> Self {
+        RemoteSource {
+            inner,
+            client: Arc::new(Client {
+                curl: curl.into(),
+                s3,
+            }),
+            known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stat `target` with a HEAD request.
+    fn head(&self, target: &Target) -> io::Result<Object> {
+        let url = target.url(&self.client.s3);
+        let response = self.client.run(&url, target.is_s3(), &["-I"])?;
+        head_object(&String::from_utf8_lossy(&response), &url)
+    }
+
+    fn object(&self, path: &Path) -> Option<io::Result<(Target, Object)>> {
+        let target = Target::of(path)?;
+        if let Some(object) = self.known.lock().unwrap().get(path) {
+            return Some(Ok((target, object.clone())));
+        }
+        Some(self.head(&target).map(|object| {
+            self.known
+                .lock()
+                .unwrap()
+                .insert(path.to_path_buf(), object.clone());
+            (target, object)
+        }))
+    }
+
+    /// Every object under `prefix` in `bucket`, through as many ListObjectsV2 pages as it
+    /// takes. `prefix` matches whole path components, as a directory would.
+    fn list(&self, bucket: &str, prefix: &str) -> io::Result<Vec<(String, Object)>> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut url = format!(
+                "{}?list-type=2&prefix={}",
+                self.client.s3.url(bucket, ""),
+                encode(prefix, true)
+            );
+            if let Some(token) = &token {
+                url.push_str(&format!("&continuation-token={}", encode(token, true)));
+            }
+            let page = String::from_utf8_lossy(&self.client.run(&url, true, &[])?).into_owned();
+            token = list_page(&page, prefix, &mut objects);
+            if token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+}
+
+/// The object a HEAD `response` for `url` describes.
+fn head_object(response: &str, url: &str) -> io::Result<Object> {
+    // Redirects followed each add a block of headers; the file's own come last.
+    let headers = response
+        .split("\r\n\r\n")
+        .filter(|block| !block.trim().is_empty())
+        .last()
+        .unwrap_or_default();
+    let header = |name: &str| {
+        headers.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().to_string())
+        })
+    };
+    let len = header("content-length")
+        .and_then(|l| l.parse().ok())
+        .ok_or_else(|| io::Error::other(format!("no Content-Length for {}", url)))?;
+    Ok(Object {
+        meta: SourceMetadata {
+            len,
+            modified: header("last-modified").and_then(|d| http_date(&d)),
+        },
+        etag: header("etag"),
+        ranges: header("accept-ranges").is_none_or(|r| !r.eq_ignore_ascii_case("none")),
+    })
+}
+
+/// Add the objects under `prefix` on one ListObjectsV2 `page` to `objects`, returning the
+/// token for the next page if there is one.
+fn list_page(page: &str, prefix: &str, objects: &mut Vec<(String, Object)>) -> Option<String> {
+    for contents in elements(page, "Contents") {
+        let (Some(key), Some(len)) = (
+            element(contents, "Key").map(unescape),
+            element(contents, "Size").and_then(|s| s.parse().ok()),
+        ) else {
+            continue;
+        };
+        let in_tree = prefix.is_empty()
+            || prefix.ends_with('/')
+            || key == prefix
+            || key[prefix.len()..].starts_with('/');
+        // Keys ending in '/' are the empty markers consoles make for folders.
+        if in_tree && !key.ends_with('/') {
+            let modified = element(contents, "LastModified").and_then(iso_time);
+            let etag = element(contents, "ETag").map(unescape);
+            objects.push((
+                key,
+                Object {
+                    meta: SourceMetadata { len, modified },
+                    etag,
+                    ranges: true,
+                },
+            ));
+        }
+    }
+    (element(page, "IsTruncated") == Some("true"))
+        .then(|| element(page, "NextContinuationToken").map(unescape))
+        .flatten()
+}
+
+impl Source for RemoteSource {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        match self.object(path) {
+            Some(found) => found.map(|(_, object)| object.meta),
+            None => self.inner.metadata(path),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>> {
+        let Some(found) = self.object(path) else {
+            return self.inner.open(path);
+        };
+        let (target, object) = found?;
+        Ok(Box::new(RemoteFile {
+            client: self.client.clone(),
+            url: target.url(&self.client.s3),
+            s3: target.is_s3(),
+            ranges: AtomicBool::new(object.ranges),
+            object,
+            stream: Mutex::new(None),
+        }))
+    }
+
+    /// Lists `s3://bucket/prefix` roots. One whose listing fails is passed on as is, so the
+    /// error is reported when it's hashed. Web servers can't be listed, so a URL is one file.
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        let (bucket, prefix) = match Target::of(root) {
+            Some(Target::S3 { bucket, key }) => (bucket, key),
+            Some(Target::Http(_)) => return Box::new(std::iter::once(root.to_path_buf())),
+            None => return self.inner.walk(root),
+        };
+        match self.list(&bucket, &prefix) {
+            Ok(objects) => {
+                let mut known = self.known.lock().unwrap();
+                let paths: Vec<PathBuf> = objects
+                    .into_iter()
+                    .map(|(key, object)| {
+                        let path = PathBuf::from(format!("{}{}/{}", S3_SCHEME, bucket, key));
+                        known.insert(path.clone(), object);
+                        path
+                    })
+                    .collect();
+                Box::new(paths.into_iter())
+            }
+            Err(_) => Box::new(std::iter::once(root.to_path_buf())),
+        }
+    }
+}
+
+/// A remote file, read a range at a time.
+struct RemoteFile {
+    client: Arc<Client>,
+    url: String,
+    s3: bool,
+    object: Object,
+    /// Cleared once the server answers a ranged GET with the whole file.
+    ranges: AtomicBool,
+    /// Without ranges, the whole-file GET being read through.
+    stream: Mutex<Option<Stream>>,
+}
+
+/// A GET in progress, past its headers.
+struct Stream {
+    child: Child,
+    body: BufReader<ChildStdout>,
+    /// Offset in the file of the next byte of `body`.
+    pos: u64,
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Stream {
+    /// Read the header blocks up to the final response's (the ones before it are for
+    /// interim responses and redirects followed) and return its status code.
+    fn status(&mut self) -> io::Result<Option<u16>> {
+        let mut status = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.body.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if line.starts_with("HTTP/") {
+                status = line.split_whitespace().nth(1).and_then(|c| c.parse().ok());
+            } else if line.is_empty() {
+                match status {
+                    Some(100..=199) | Some(301..=308) | None => status = None,
+                    code => return Ok(code),
+                }
+            }
+        }
+    }
+
+    /// Skip ahead to `offset`, then fill `buf`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let skip = offset - self.pos;
+        if io::copy(&mut (&mut self.body).take(skip), &mut io::sink())? == skip {
+            self.pos = offset;
+            if self.body.read_exact(buf).is_ok() {
+                self.pos += buf.len() as u64;
+                return Ok(());
+            }
+        }
+        Err(self.failure())
+    }
+
+    /// Why the response ended early, curl having stopped.
+    fn failure(&mut self) -> io::Error {
+        let _ = io::copy(&mut self.body, &mut io::sink());
+        let mut message = String::new();
+        if let Some(mut stderr) = self.child.stderr.take() {
+            let _ = stderr.read_to_string(&mut message);
+        }
+        match self.child.wait() {
+            Ok(status) if !status.success() => {
+                curl_error(status.code(), message.trim().to_string())
+            }
+            Ok(_) => io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the response ended before the file did",
+            ),
+            Err(e) => e,
+        }
+    }
+}
+
+impl RemoteFile {
+    /// Start a GET of `range`, inclusive, or of the whole file. Says whether what's coming
+    /// is just the range.
+    fn get(&self, range: Option<(u64, u64)>) -> io::Result<(Stream, bool)> {
+        let range = range.map(|(first, last)| format!("{}-{}", first, last));
+        // With the ETag the object was stat'ed with, a replaced object fails the read rather
+        // than mixing old and new content. Web servers' ETags are too often per replica.
+        let if_match = (self.object.etag.as_ref())
+            .filter(|_| self.s3)
+            .map(|e| format!("If-Match: {}", e));
+        let mut args = vec!["-D", "-"];
+        if let Some(range) = &range {
+            args.extend(["-r", range]);
+        }
+        if let Some(header) = &if_match {
+            args.extend(["-H", header]);
+        }
+        let mut child = self.client.spawn(&self.url, self.s3, &args)?;
+        let body = BufReader::new(child.stdout.take().unwrap());
+        let mut stream = Stream {
+            child,
+            body,
+            pos: 0,
+        };
+        match stream.status()? {
+            Some(206) if range.is_some() => Ok((stream, true)),
+            Some(200) => Ok((stream, false)),
+            Some(code) if code < 400 => Err(io::Error::other(format!(
+                "unexpected HTTP status {} from {}",
+                code, self.url
+            ))),
+            _ => Err(stream.failure()),
+        }
+    }
+}
+
+impl SourceFile for RemoteFile {
+    /// One ranged GET, or, from a server that ignores ranges, the next stretch of the
+    /// one whole-file GET. Reading backwards means starting that GET over.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.object.meta.len;
+        if offset >= len || buf.is_empty() {
+            return Ok(0);
+        }
+        let last = (offset + buf.len() as u64).min(len) - 1;
+        let buf = &mut buf[..(last - offset + 1) as usize];
+        let mut stream = self.stream.lock().unwrap();
+        if self.ranges.load(Ordering::Relaxed) {
+            let (mut response, ranged) = self.get(Some((offset, last)))?;
+            if ranged {
+                response.pos = offset;
+                response.read_at(offset, buf)?;
+                return Ok(buf.len());
+            }
+            self.ranges.store(false, Ordering::Relaxed);
+            *stream = Some(response);
+        }
+        if !matches!(&*stream, Some(s) if s.pos <= offset) {
+            *stream = Some(self.get(None)?.0);
+        }
+        let result = stream.as_mut().unwrap().read_at(offset, buf);
+        if result.is_err() {
+            *stream = None;
+        }
+        result.map(|()| buf.len())
+    }
+
+    fn metadata(&self) -> io::Result<SourceMetadata> {
+        Ok(self.object.meta.clone())
+    }
+}
+
+fn curl_error(code: Option<i32>, message: String) -> io::Error {
+    let kind = match code {
+        // HTTP errors, from -f.
+        Some(22) if message.contains(" 404") => io::ErrorKind::NotFound,
+        Some(22) if message.contains(" 403") => io::ErrorKind::PermissionDenied,
+        Some(7) => io::ErrorKind::ConnectionRefused,
+        Some(28) => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    };
+    let message = if code == Some(22) && message.contains(" 412") {
+        "the object was replaced while being read".to_string()
+    } else if message.is_empty() {
+        format!("curl failed with exit code {:?}", code)
+    } else {
+        message
+    };
+    io::Error::new(kind, message)
+}
+
+/// Percent-encode `s` for a URL, keeping `/` unless `query`.
+fn encode(s: &str, query: bool) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if !query => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Percent-encode what can't appear raw in a URL, such as spaces in file names, leaving
+/// existing escapes alone.
+fn encode_url(url: &str) -> String {
+    let mut out = String::new();
+    for b in url.bytes() {
+        if b.is_ascii_graphic() && !b"\"<>\\^`{|}#".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Escape `s` for a double-quoted curl config value.
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The contents of each `<tag>...</tag>` in `xml`.
+fn elements<'x>(xml: &'x str, tag: &str) -> Vec<&'x str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        let Some(end) = after.find(&close) else {
+            break;
+        };
+        found.push(&after[..end]);
+        rest = &after[end + close.len()..];
+    }
+    found
+}
+
+fn element<'x>(xml: &'x str, tag: &str) -> Option<&'x str> {
+    elements(xml, tag).into_iter().next()
+}
+
+/// Undo XML's character escapes.
+fn unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let Some(semi) = rest[amp..].find(';') else {
+            break;
+        };
+        let entity = &rest[amp + 1..amp + semi];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(|n| n.ok())
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => out.push(c),
+            None => out.push_str(&rest[amp..amp + semi + 1]),
+        }
+        rest = &rest[amp + semi + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// An ISO 8601 UTC time as S3 listings give it, `2024-03-01T12:34:56.000Z`.
+fn iso_time(s: &str) -> Option<std::time::SystemTime> {
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|n| n.parse::<i64>().ok());
+    let time = time.trim_end_matches('Z');
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|n| n.parse::<i64>().ok());
+    civil_time(
+        date.next()??,
+        date.next()??,
+        date.next()??,
+        time.next()??,
+        time.next()??,
+        time.next()??,
+    )
+}
+
+/// An HTTP date, `Wed, 21 Oct 2015 07:28:00 GMT`.
+fn http_date(s: &str) -> Option<std::time::SystemTime> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    let [_, day, month, year, time, ..] = fields[..] else {
+        return None;
+    };
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as i64 + 1;
+    let mut time = time.splitn(3, ':').map(|n| n.parse::<i64>().ok());
+    civil_time(
+        year.parse().ok()?,
+        month,
+        day.parse().ok()?,
+        time.next()??,
+        time.next()??,
+        time.next()??,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// 2015-10-21 07:28:00 UTC.
+    const OCT_21_2015: u64 = 1_445_412_480;
+
+    fn page(objects: &[(&str, u64)], next: Option<&str>) -> String {
+        let mut xml =
+            String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<ListBucketResult>");
+        for (key, size) in objects {
+            xml.push_str(&format!(
+                "<Contents><Key>{}</Key><LastModified>2015-10-21T07:28:00.000Z</LastModified>\
+                 <ETag>&quot;abc&quot;</ETag><Size>{}</Size></Contents>",
+                key, size
+            ));
+        }
+        match next {
+            Some(token) => xml.push_str(&format!(
+                "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                token
+            )),
+            None => xml.push_str("<IsTruncated>false</IsTruncated>"),
+        }
+        xml.push_str("</ListBucketResult>");
+        xml
+    }
+
+    #[test]
+    fn s3_times() {
+        let time = UNIX_EPOCH + Duration::from_secs(OCT_21_2015);
+        assert_eq!(iso_time("2015-10-21T07:28:00.000Z"), Some(time));
+        assert_eq!(iso_time("2015-10-21T07:28:00Z"), Some(time));
+        assert_eq!(iso_time("2015-10-21"), None);
+    }
+
+    #[test]
+    fn keys_are_percent_encoded() {
+        assert_eq!(
+            encode("a b/c+d&e=f%g~h?i#j", false),
+            "a%20b/c%2Bd%26e%3Df%25g~h%3Fi%23j"
+        );
+        assert_eq!(encode("a/b c", true), "a%2Fb%20c");
+        assert_eq!(encode("Ä-_.~", false), "%C3%84-_.~");
+
+        let mut s3 = S3Settings {
+            region: "eu-north-1".to_string(),
+            ..S3Settings::default()
+        };
+        assert_eq!(
+            s3.url("bucket", "dir/a b.txt"),
+            "https://bucket.s3.eu-north-1.amazonaws.com/dir/a%20b.txt"
+        );
+        s3.endpoint = Some("http://localhost:9000".to_string());
+        assert_eq!(
+            s3.url("bucket", "a+b"),
+            "http://localhost:9000/bucket/a%2Bb"
+        );
+    }
+
+    #[test]
+    fn listings_follow_continuation_tokens() {
+        let mut objects = Vec::new();
+        let first = page(
+            &[("dir/a", 1), ("dir/b &amp; c", 2), ("dir/sub/", 0)],
+            Some("t&amp;2"),
+        );
+        assert_eq!(
+            list_page(&first, "dir", &mut objects).as_deref(),
+            Some("t&2")
+        );
+        let last = page(&[("dir/sub/d", 4), ("directory/e", 5)], None);
+        assert_eq!(list_page(&last, "dir", &mut objects), None);
+
+        let keys: Vec<&str> = objects.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["dir/a", "dir/b & c", "dir/sub/d"]);
+        let (_, object) = &objects[1];
+        assert_eq!(object.meta.len, 2);
+        assert_eq!(
+            object.meta.modified,
+            Some(UNIX_EPOCH + Duration::from_secs(OCT_21_2015))
+        );
+        assert_eq!(object.etag.as_deref(), Some("\"abc\""));
+    }
+
+    #[test]
+    fn s3_targets() {
+        let Some(Target::S3 { bucket, key }) = Target::of(Path::new("s3://bucket/dir/a")) else {
+            panic!("not an S3 target");
+        };
+        assert_eq!((bucket.as_str(), key.as_str()), ("bucket", "dir/a"));
+        let Some(Target::S3 { key, .. }) = Target::of(Path::new("s3://bucket")) else {
+            panic!("not an S3 target");
+        };
+        assert_eq!(key, "");
+    }
+
+    #[test]
+    fn http_dates() {
+        assert_eq!(
+            http_date("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(OCT_21_2015))
+        );
+        assert_eq!(http_date("Wed, 21 Foo 2015 07:28:00 GMT"), None);
+        assert_eq!(http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn urls_are_encoded_once() {
+        assert_eq!(
+            encode_url("https://host/a b/%41\"c\"#d?e=f&g"),
+            "https://host/a%20b/%41%22c%22%23d?e=f&g"
+        );
+        assert_eq!(encode_url("http://host/\u{e4}"), "http://host/%C3%A4");
+    }
+
+    #[test]
+    fn heads_use_the_last_response() {
+        let response =
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: /b\r\nContent-Length: 7\r\n\r\n\
+                        HTTP/1.1 200 OK\r\ncontent-length: 42\r\n\
+                        Last-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\nETag: \"x\"\r\n\
+                        Accept-Ranges: none\r\n\r\n";
+        let object = head_object(response, "http://host/a").unwrap();
+        assert_eq!(object.meta.len, 42);
+        assert_eq!(
+            object.meta.modified,
+            Some(UNIX_EPOCH + Duration::from_secs(OCT_21_2015))
+        );
+        assert_eq!(object.etag.as_deref(), Some("\"x\""));
+        assert!(!object.ranges);
+
+        let object = head_object("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n", "u").unwrap();
+        assert!(object.ranges && object.etag.is_none() && object.meta.modified.is_none());
+        assert!(head_object("HTTP/1.1 200 OK\r\n\r\n", "u").is_err());
+    }
+
+    #[test]
+    fn http_targets() {
+        let Some(Target::Http(url)) = Target::of(Path::new("https://host/a\\b")) else {
+            panic!("not an HTTP target");
+        };
+        assert_eq!(url, "https://host/a/b");
+        assert!(Target::of(Path::new("/srv/a")).is_none());
+        assert!(Target::of(Path::new("ftp://host/a")).is_none());
+    }
+
+    #[test]
+    fn curl_errors_keep_their_kind() {
+        let error = curl_error(
+            Some(22),
+            "The requested URL returned error: 404".to_string(),
+        );
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let error = curl_error(
+            Some(22),
+            "The requested URL returned error: 412".to_string(),
+        );
+        assert_eq!(
+            error.to_string(),
+            "the object was replaced while being read"
+        );
+        assert_eq!(
+            curl_error(Some(28), String::new()).kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
@@ -0,0 +1,569 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Known-answer tests of the digest scheme: synthetic files, made the same way on every
+//! platform, hashed with each algorithm and each option that changes a digest, and compared
+//! with the digests this version was released with. A build that hashes differently, however
+//! it came about, would fail every check of the manifests made before it; `gustasum selftest`
+//! says so first.
+
+use crate::{
+    algorithm,
+    hasher::{hash_file, HashOptions, Salt},
+    source::{Source, SourceFile, SourceMetadata},
+};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// How many hex digits of each digest are compared: far more than any change to the scheme
+/// leaves alone, and short enough to keep the table readable.
+const DIGITS: usize = 16;
+
+/// The synthetic files besides [`SPARSE`], by name: their sizes straddle the edges of the
+/// default 100 sampled bytes, and of 4096.
+const FILES: &[(&str, u64)] = &[
+    ("empty", 0),
+    ("one-byte", 1),
+    ("partial-bytes", 100),
+    ("twice-less-one", 199),
+    ("twice", 200),
+    ("twice-plus-one", 201),
+    ("page", 4096),
+    ("two-pages-plus-one", 8193),
+    ("mebibyte-plus-seven", (1 << 20) + 7),
+];
+
+/// The sparse file: a page of data, a hole, and data again, as a filesystem with 4 KiB
+/// blocks would lay it out.
+const SPARSE: (&str, [(u64, u64); 2], u64) =
+    ("sparse", [(0, 4096), (1 << 20, 5000)], (1 << 20) + 5000);
+
+/// Every file's modtime: an odd second with a fraction, so `fat_compat` rounds it.
+const MODTIME: Duration = Duration::from_millis(1_700_000_001_500);
+
+/// `(case, file, first DIGITS hex digits of the digest)`.
+const VECTORS: &[(&str, &str, &str)] = &[
+    ("sha256", "empty", "374708fff7719dd5"),
+    ("sha256", "one-byte", "4f29f4911de783e8"),
+    ("sha256", "partial-bytes", "9b4f05ed35edeb0a"),
+    ("sha256", "twice-less-one", "f1628c29eebe8fe3"),
+    ("sha256", "twice", "94a1e16b567b30e8"),
+    ("sha256", "twice-plus-one", "15d4a0b623a69f18"),
+    ("sha256", "page", "856f2adddbe15f44"),
+    ("sha256", "two-pages-plus-one", "378cda844a3b998f"),
+    ("sha256", "mebibyte-plus-seven", "f21fe9d028f9328c"),
+    ("sha256", "sparse", "4deb969e321d5e21"),
+    ("sha256 full", "empty", "e3b0c44298fc1c14"),
+    ("sha256 full", "one-byte", "2d31936919341244"),
+    ("sha256 full", "partial-bytes", "0732591bcb2acc33"),
+    ("sha256 full", "twice-less-one", "7365018feb85fb02"),
+    ("sha256 full", "twice", "f19be4fc74e9ef51"),
+    ("sha256 full", "twice-plus-one", "6fd7669b530c13ed"),
+    ("sha256 full", "page", "82388378c8425405"),
+    ("sha256 full", "two-pages-plus-one", "ea04c47c52865763"),
+    ("sha256 full", "mebibyte-plus-seven", "6222d8f534be208c"),
+    ("sha256 full", "sparse", "c49f7b1b76019460"),
+    ("sha512", "empty", "0b6cbac838dfe7f4"),
+    ("sha512", "one-byte", "84eeead38775e885"),
+    ("sha512", "partial-bytes", "5f077b081e386b92"),
+    ("sha512", "twice-less-one", "c3ce06e53281e849"),
+    ("sha512", "twice", "b9251b632a1db4c6"),
+    ("sha512", "twice-plus-one", "2b98402ea89dbaec"),
+    ("sha512", "page", "3cef6cecf1a40940"),
+    ("sha512", "two-pages-plus-one", "7a4968b8890608bc"),
+    ("sha512", "mebibyte-plus-seven", "1a71fceae52e8e2f"),
+    ("sha512", "sparse", "3888a047cdc28bd6"),
+    ("sha512 full", "empty", "cf83e1357eefb8bd"),
+    ("sha512 full", "one-byte", "cab7dbdf882c3f74"),
+    ("sha512 full", "partial-bytes", "88a167ce64bb7cc5"),
+    ("sha512 full", "twice-less-one", "a88c5b17873eb95b"),
+    ("sha512 full", "twice", "f94fad4eaf922cd1"),
+    ("sha512 full", "twice-plus-one", "fb7e007361d4ad08"),
+    ("sha512 full", "page", "a1258add3abc9dbf"),
+    ("sha512 full", "two-pages-plus-one", "c27725d929cc3032"),
+    ("sha512 full", "mebibyte-plus-seven", "4cf0de900285be02"),
+    ("sha512 full", "sparse", "56fe1946c3ead544"),
+    ("sha1", "empty", "e129f27c5103bc5c"),
+    ("sha1", "one-byte", "cc6b5a6eb405fb18"),
+    ("sha1", "partial-bytes", "169a2ad1394f578e"),
+    ("sha1", "twice-less-one", "5061dfefe8f0251b"),
+    ("sha1", "twice", "7ef3567eba50b6bf"),
+    ("sha1", "twice-plus-one", "def1bbead87b299b"),
+    ("sha1", "page", "791d9c2cc3c5f469"),
+    ("sha1", "two-pages-plus-one", "d400d1f43618935e"),
+    ("sha1", "mebibyte-plus-seven", "b6298e49eefa875c"),
+    ("sha1", "sparse", "ddbc9c12badbdb95"),
+    ("sha1 full", "empty", "da39a3ee5e6b4b0d"),
+    ("sha1 full", "one-byte", "c4488af0c158e8c2"),
+    ("sha1 full", "partial-bytes", "1375127d9c34f7db"),
+    ("sha1 full", "twice-less-one", "7a9539c1717566c4"),
+    ("sha1 full", "twice", "a773e1a251ca182c"),
+    ("sha1 full", "twice-plus-one", "9456d506bdac1bd6"),
+    ("sha1 full", "page", "6e27a4a5c0034cd8"),
+    ("sha1 full", "two-pages-plus-one", "be1149cceb49ca3c"),
+    ("sha1 full", "mebibyte-plus-seven", "83f6f7806fc568de"),
+    ("sha1 full", "sparse", "5217b4bf4da01cb7"),
+    ("md5", "empty", "4ae71336e44bf9bf"),
+    ("md5", "one-byte", "f638f3888b5aed87"),
+    ("md5", "partial-bytes", "3865a764bf557c53"),
+    ("md5", "twice-less-one", "9ce424d5edfd5b88"),
+    ("md5", "twice", "297dc6414f915e32"),
+    ("md5", "twice-plus-one", "4f59f696fc6dbd35"),
+    ("md5", "page", "401d900ec331d193"),
+    ("md5", "two-pages-plus-one", "d6c74e03970eab0c"),
+    ("md5", "mebibyte-plus-seven", "132b803466e14ce8"),
+    ("md5", "sparse", "11d88518025ceb5f"),
+    ("md5 full", "empty", "d41d8cd98f00b204"),
+    ("md5 full", "one-byte", "1d948537445132eb"),
+    ("md5 full", "partial-bytes", "cb673894e31c5a7f"),
+    ("md5 full", "twice-less-one", "fa5cc23f62161e0d"),
+    ("md5 full", "twice", "24e47cfcfb6d0665"),
+    ("md5 full", "twice-plus-one", "3c00b42269717cce"),
+    ("md5 full", "page", "a537945cdd8dd212"),
+    ("md5 full", "two-pages-plus-one", "f0ff67b717605618"),
+    ("md5 full", "mebibyte-plus-seven", "4193ae7b000878e7"),
+    ("md5 full", "sparse", "a175dc6f5d9550e8"),
+    ("sha256 partial_bytes=4096", "empty", "374708fff7719dd5"),
+    ("sha256 partial_bytes=4096", "one-byte", "4f29f4911de783e8"),
+    (
+        "sha256 partial_bytes=4096",
+        "partial-bytes",
+        "9b4f05ed35edeb0a",
+    ),
+    (
+        "sha256 partial_bytes=4096",
+        "twice-less-one",
+        "70c25c465453b025",
+    ),
+    ("sha256 partial_bytes=4096", "twice", "94a1e16b567b30e8"),
+    (
+        "sha256 partial_bytes=4096",
+        "twice-plus-one",
+        "669f578da1569fac",
+    ),
+    ("sha256 partial_bytes=4096", "page", "3d367ed03d5093f9"),
+    (
+        "sha256 partial_bytes=4096",
+        "two-pages-plus-one",
+        "f63232688d2fc3b2",
+    ),
+    (
+        "sha256 partial_bytes=4096",
+        "mebibyte-plus-seven",
+        "bb4ab5d104c01fc6",
+    ),
+    ("sha256 partial_bytes=4096", "sparse", "4bd06dc0516a6ab0"),
+    ("sha256 include_modtime", "empty", "25998dcc82e59fc7"),
+    ("sha256 include_modtime", "one-byte", "e0905a95591ddf22"),
+    (
+        "sha256 include_modtime",
+        "partial-bytes",
+        "cf8f0f2d8f7aad4f",
+    ),
+    (
+        "sha256 include_modtime",
+        "twice-less-one",
+        "a7a08b25bf182a76",
+    ),
+    ("sha256 include_modtime", "twice", "55408c06acf79b4e"),
+    (
+        "sha256 include_modtime",
+        "twice-plus-one",
+        "b0362399e8c5fbae",
+    ),
+    ("sha256 include_modtime", "page", "ba3125ae7ba2d656"),
+    (
+        "sha256 include_modtime",
+        "two-pages-plus-one",
+        "f7ca5a4418d85bf1",
+    ),
+    (
+        "sha256 include_modtime",
+        "mebibyte-plus-seven",
+        "6ab9406f9e1686bc",
+    ),
+    ("sha256 include_modtime", "sparse", "afdc4fc670c823a1"),
+    (
+        "sha256 include_modtime fat_compat",
+        "empty",
+        "34933cf5f91bff53",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "one-byte",
+        "0a0833945537a3dc",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "partial-bytes",
+        "2e69dd0bee485e61",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "twice-less-one",
+        "a1202b40939d17c5",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "twice",
+        "ea7a55103553ec99",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "twice-plus-one",
+        "010b0385c09d4e8c",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "page",
+        "a3abf7776f157aad",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "two-pages-plus-one",
+        "7ea73b433ce5e6f1",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "mebibyte-plus-seven",
+        "edeee4f2ca8504f3",
+    ),
+    (
+        "sha256 include_modtime fat_compat",
+        "sparse",
+        "471f44aabd542e74",
+    ),
+    ("sha256 sparse", "empty", "374708fff7719dd5"),
+    ("sha256 sparse", "one-byte", "4f29f4911de783e8"),
+    ("sha256 sparse", "partial-bytes", "9b4f05ed35edeb0a"),
+    ("sha256 sparse", "twice-less-one", "f1628c29eebe8fe3"),
+    ("sha256 sparse", "twice", "94a1e16b567b30e8"),
+    ("sha256 sparse", "twice-plus-one", "15d4a0b623a69f18"),
+    ("sha256 sparse", "page", "856f2adddbe15f44"),
+    ("sha256 sparse", "two-pages-plus-one", "378cda844a3b998f"),
+    ("sha256 sparse", "mebibyte-plus-seven", "f21fe9d028f9328c"),
+    ("sha256 sparse", "sparse", "f25864a0b0b7118f"),
+    ("sha256 include_extents", "empty", "e09f5fb84a99e9ba"),
+    ("sha256 include_extents", "one-byte", "2cdf1e6f5d7dec53"),
+    (
+        "sha256 include_extents",
+        "partial-bytes",
+        "a6a052d014880a5f",
+    ),
+    (
+        "sha256 include_extents",
+        "twice-less-one",
+        "ea40ad410295a227",
+    ),
+    ("sha256 include_extents", "twice", "8a9b7d021506169d"),
+    (
+        "sha256 include_extents",
+        "twice-plus-one",
+        "db6797c73093533e",
+    ),
+    ("sha256 include_extents", "page", "478ea87d55868314"),
+    (
+        "sha256 include_extents",
+        "two-pages-plus-one",
+        "e9697d9cf12ba8cf",
+    ),
+    (
+        "sha256 include_extents",
+        "mebibyte-plus-seven",
+        "3766fc9278797c33",
+    ),
+    ("sha256 include_extents", "sparse", "70d2671981fd624e"),
+    ("sha256 salt", "empty", "78621d4c2449548d"),
+    ("sha256 salt", "one-byte", "e1a2a25c03366d2e"),
+    ("sha256 salt", "partial-bytes", "48c3459f0782f882"),
+    ("sha256 salt", "twice-less-one", "f591d3fbae890d12"),
+    ("sha256 salt", "twice", "32cb79888bce47ce"),
+    ("sha256 salt", "twice-plus-one", "7b5ad1e58b791630"),
+    ("sha256 salt", "page", "765030ebc9f5a9ed"),
+    ("sha256 salt", "two-pages-plus-one", "bdd0c9ece5a4bb2f"),
+    ("sha256 salt", "mebibyte-plus-seven", "33b6b4629fea728f"),
+    ("sha256 salt", "sparse", "a5b91f2a9dbb8428"),
+    ("sha256 abbrev=12", "empty", "374708fff771"),
+    ("sha256 abbrev=12", "one-byte", "4f29f4911de7"),
+    ("sha256 abbrev=12", "partial-bytes", "9b4f05ed35ed"),
+    ("sha256 abbrev=12", "twice-less-one", "f1628c29eebe"),
+    ("sha256 abbrev=12", "twice", "94a1e16b567b"),
+    ("sha256 abbrev=12", "twice-plus-one", "15d4a0b623a6"),
+    ("sha256 abbrev=12", "page", "856f2adddbe1"),
+    ("sha256 abbrev=12", "two-pages-plus-one", "378cda844a3b"),
+    ("sha256 abbrev=12", "mebibyte-plus-seven", "f21fe9d028f9"),
+    ("sha256 abbrev=12", "sparse", "4deb969e321d"),
+];
+
+/// One known answer that didn't come out.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub case: String,
+    pub file: &'static str,
+    pub expected: &'static str,
+    /// The digits this build gave, or why it couldn't hash the file.
+    pub got: Result<String, String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.got {
+            Ok(got) => write!(
+                f,
+                "{}, '{}': got {}, expected {}",
+                self.case, self.file, got, self.expected
+            ),
+            Err(e) => write!(f, "{}, '{}': {}", self.case, self.file, e),
+        }
+    }
+}
+
+/// The outcome of one case: an algorithm with a set of options, over every file.
+#[derive(Clone, Debug)]
+pub struct CaseResult {
+    pub case: String,
+    pub checked: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Hash every synthetic file in every case and compare the digests with [`VECTORS`].
+pub fn run() -> Vec<CaseResult> {
+    let source: Arc<dyn Source> = Arc::new(Synthetic::new());
+    cases(&source)
+        .into_iter()
+        .map(|(case, opts)| {
+            let mut result = CaseResult {
+                case: case.clone(),
+                checked: 0,
+                mismatches: Vec::new(),
+            };
+            for &(_, file, expected) in VECTORS.iter().filter(|(c, ..)| *c == case) {
+                result.checked += 1;
+                let got = hash_file(Path::new(file), &opts)
+                    .map(|h| h.hash.chars().take(DIGITS).collect::<String>())
+                    .map_err(|e| e.to_string());
+                if got.as_deref() != Ok(expected) {
+                    result.mismatches.push(Mismatch {
+                        case: case.clone(),
+                        file,
+                        expected,
+                        got,
+                    });
+                }
+            }
+            result
+        })
+        .collect()
+}
+
+/// Each case's name and options: every algorithm with the defaults and with `full`, then
+/// SHA-256 with each option that changes a digest.
+fn cases(source: &Arc<dyn Source>) -> Vec<(String, HashOptions)> {
+    let base = HashOptions {
+        source: Arc::clone(source),
+        retries: 0,
+        ..HashOptions::default()
+    };
+    let mut cases = Vec::new();
+    for algorithm in [
+        algorithm::SHA256,
+        algorithm::SHA512,
+        algorithm::SHA1,
+        algorithm::MD5,
+    ] {
+        let opts = HashOptions {
+            algorithm,
+            ..base.clone()
+        };
+        cases.push((algorithm.name.to_string(), opts.clone()));
+        cases.push((
+            format!("{} full", algorithm.name),
+            HashOptions { full: true, ..opts },
+        ));
+    }
+    let variants: [(&str, HashOptions); 7] = [
+        (
+            "partial_bytes=4096",
+            HashOptions {
+                partial_bytes: 4096,
+                ..base.clone()
+            },
+        ),
+        (
+            "include_modtime",
+            HashOptions {
+                include_modtime: true,
+                ..base.clone()
+            },
+        ),
+        (
+            "include_modtime fat_compat",
+            HashOptions {
+                include_modtime: true,
+                fat_compat: true,
+                ..base.clone()
+            },
+        ),
+        (
+            "sparse",
+            HashOptions {
+                sparse: true,
+                ..base.clone()
+            },
+        ),
+        (
+            "include_extents",
+            HashOptions {
+                include_extents: true,
+                ..base.clone()
+            },
+        ),
+        (
+            "salt",
+            HashOptions {
+                salt: Some(Salt::new(&b"gustasum selftest salt"[..])),
+                ..base.clone()
+            },
+        ),
+        (
+            "abbrev=12",
+            HashOptions {
+                abbrev: Some(12),
+                ..base.clone()
+            },
+        ),
+    ];
+    for (name, opts) in variants {
+        cases.push((format!("sha256 {}", name), opts));
+    }
+    cases
+}
+
+/// The same bytes everywhere: a xorshift stream seeded from the file's name.
+fn content(name: &str, len: u64) -> Vec<u8> {
+    let mut state = name.bytes().fold(0x9e37_79b9_7f4a_7c15u64, |s, b| {
+        (s ^ u64::from(b)).wrapping_mul(0x100_0000_01b3)
+    });
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+/// The synthetic files, in memory. Unlike [`MemorySource`](crate::source::MemorySource), it
+/// can say where a file's holes are, so `sparse` and `include_extents` are tested on any
+/// filesystem.
+#[derive(Debug)]
+struct Synthetic {
+    files: BTreeMap<PathBuf, Arc<SyntheticFile>>,
+}
+
+#[derive(Debug)]
+struct SyntheticFile {
+    data: Vec<u8>,
+    extents: Option<Vec<(u64, u64)>>,
+}
+
+impl Synthetic {
+    fn new() -> Synthetic {
+        let mut files = BTreeMap::new();
+        for &(name, len) in FILES {
+            let file = SyntheticFile {
+                data: content(name, len),
+                // As `SEEK_DATA` finds them: one extent, or none in an empty file.
+                extents: Some((len > 0).then_some((0, len)).into_iter().collect()),
+            };
+            files.insert(PathBuf::from(name), Arc::new(file));
+        }
+        let (name, extents, len) = SPARSE;
+        let mut data = vec![0; len as usize];
+        for (offset, extent_len) in extents {
+            let start = offset as usize;
+            data[start..start + extent_len as usize]
+                .copy_from_slice(&content(&format!("{}@{}", name, offset), extent_len));
+        }
+        let file = SyntheticFile {
+            data,
+            extents: Some(extents.to_vec()),
+        };
+        files.insert(PathBuf::from(name), Arc::new(file));
+        Synthetic { files }
+    }
+
+    fn get(&self, path: &Path) -> io::Result<&Arc<SyntheticFile>> {
+        self.files
+            .get(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such synthetic file"))
+    }
+}
+
+impl SyntheticFile {
+    fn metadata(&self) -> SourceMetadata {
+        SourceMetadata {
+            len: self.data.len() as u64,
+            modified: Some(UNIX_EPOCH + MODTIME),
+        }
+    }
+}
+
+impl Source for Synthetic {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        self.get(path).map(|f| SyntheticFile::metadata(f))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>> {
+        Ok(Box::new(Arc::clone(self.get(path)?)))
+    }
+
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        Box::new(
+            self.files
+                .keys()
+                .filter(move |p| p.starts_with(root))
+                .cloned(),
+        )
+    }
+}
+
+impl SourceFile for Arc<SyntheticFile> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = (offset as usize).min(self.data.len());
+        let n = buf.len().min(self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        Ok(n)
+    }
+
+    fn metadata(&self) -> io::Result<SourceMetadata> {
+        Ok(SyntheticFile::metadata(self))
+    }
+
+    fn data_extents(&self) -> io::Result<Option<Vec<(u64, u64)>>> {
+        Ok(self.extents.clone())
+    }
+}
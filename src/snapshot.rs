@@ -0,0 +1,577 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Read-only filesystem snapshots, so a tree that changes while it's hashed is still hashed
+//! as of one moment: the btrfs subvolume, LVM logical volume, or ZFS dataset each file is on
+//! is snapshotted the first time a file on it is needed, and [`SnapshotSource`] reads from
+//! the snapshot instead, reporting the live paths.
+//!
+//! Snapshots are made and removed with the `btrfs`, `lvcreate`/`lvremove` (and `mount`), and
+//! `zfs` commands, which need root. They outlive the process unless deleted, so
+//! [`release_all`] should run at exit.
+
+use crate::source::{LocalFs, Source, SourceFile, SourceMetadata};
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+use walkdir::WalkDir;
+
+/// The inode number of the root directory of every btrfs subvolume.
+const BTRFS_SUBVOLUME_INO: u64 = 256;
+/// The inode number a nested subvolume's empty stand-in has inside a snapshot.
+const BTRFS_EMPTY_SUBVOLUME_INO: u64 = 2;
+
+/// Snapshots made by this process and not yet deleted.
+static LIVE: Mutex<Vec<Teardown>> = Mutex::new(Vec::new());
+
+/// What kind of snapshot to make.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotKind {
+    Btrfs,
+    Lvm,
+    Zfs,
+}
+
+impl FromStr for SnapshotKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "btrfs" => Ok(SnapshotKind::Btrfs),
+            "lvm" => Ok(SnapshotKind::Lvm),
+            "zfs" => Ok(SnapshotKind::Zfs),
+            _ => Err(format!(
+                "unknown snapshot kind '{}' (expected btrfs, lvm, or zfs)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SnapshotKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SnapshotKind::Btrfs => "btrfs",
+            SnapshotKind::Lvm => "LVM",
+            SnapshotKind::Zfs => "ZFS",
+        })
+    }
+}
+
+/// How to get rid of a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Teardown {
+    /// Delete the snapshot subvolume at this path.
+    Btrfs(PathBuf),
+    /// Unmount the snapshot from `mount`, remove the directory, and remove the volume `lv`
+    /// (as `VG/LV`).
+    Lvm { lv: String, mount: PathBuf },
+    /// Destroy this `DATASET@SNAPSHOT`.
+    Zfs(String),
+}
+
+/// One snapshot, of the filesystem (subvolume, volume, dataset) whose root is `origin`.
+#[derive(Debug)]
+pub struct Snapshot {
+    origin: PathBuf,
+    /// Where the snapshot has `origin`'s root.
+    inside: PathBuf,
+    teardown: Teardown,
+}
+
+/// A filesystem that can be snapshotted, as found by [`SnapshotSource::origin`].
+#[derive(Clone, Debug)]
+struct Origin {
+    /// The live path of its root.
+    root: PathBuf,
+    /// The volume as `VG/LV` (LVM), or the dataset's name (ZFS).
+    name: String,
+    /// Its filesystem type, for the options to mount an LVM snapshot with.
+    fstype: String,
+}
+
+impl Snapshot {
+    fn create(kind: SnapshotKind, origin: &Origin) -> io::Result<Snapshot> {
+        static MADE: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "gustasum-{}-{}",
+            std::process::id(),
+            MADE.fetch_add(1, Ordering::Relaxed)
+        );
+        let (inside, teardown) = match kind {
+            SnapshotKind::Btrfs => {
+                // Inside the subvolume itself, where a snapshot of it shows up as an empty
+                // directory, so it never snapshots its own snapshots.
+                let inside = origin.root.join(format!(".{}", name));
+                run(Command::new("btrfs")
+                    .args(["subvolume", "snapshot", "-r"])
+                    .arg(&origin.root)
+                    .arg(&inside))?;
+                (inside.clone(), Teardown::Btrfs(inside))
+            }
+            SnapshotKind::Lvm => {
+                let attr =
+                    run(Command::new("lvs").args(["--noheadings", "-o", "lv_attr", &origin.name]))?;
+                let vg = origin.name.split('/').next().unwrap_or_default();
+                let mut lvcreate = Command::new("lvcreate");
+                lvcreate.args(["--snapshot", "--permission", "r", "--name", &name]);
+                // Thin volumes' snapshots take space from the pool as needed; others get a
+                // tenth of the origin's size for what changes meanwhile. Thin snapshots are
+                // skipped at activation unless told otherwise.
+                if attr.trim().starts_with('V') {
+                    lvcreate.args(["--setactivationskip", "n"]);
+                } else {
+                    lvcreate.args(["--extents", "10%ORIGIN"]);
+                }
+                run(lvcreate.arg(&origin.name))?;
+                let snapshot = format!("{}/{}", vg, name);
+                let mount = std::env::temp_dir().join(&name);
+                // No journal replay and no duplicate-UUID check: the snapshot is read-only and
+                // its filesystem is a twin of the one still mounted.
+                let options = match origin.fstype.as_str() {
+                    "ext3" | "ext4" => "ro,noload",
+                    "xfs" => "ro,nouuid,norecovery",
+                    _ => "ro",
+                };
+                let mounted = fs::create_dir_all(&mount).and_then(|()| {
+                    run(Command::new("mount")
+                        .args(["-o", options])
+                        .arg(format!("/dev/{}", snapshot))
+                        .arg(&mount))
+                });
+                let teardown = Teardown::Lvm {
+                    lv: snapshot,
+                    mount: mount.clone(),
+                };
+                if let Err(e) = mounted {
+                    let _ = delete_now(&teardown);
+                    return Err(e);
+                }
+                (mount, teardown)
+            }
+            SnapshotKind::Zfs => {
+                let snapshot = format!("{}@{}", origin.name, name);
+                run(Command::new("zfs").arg("snapshot").arg(&snapshot))?;
+                // Mounted on demand under the hidden .zfs directory, visible or not.
+                let inside = origin.root.join(".zfs/snapshot").join(&name);
+                (inside, Teardown::Zfs(snapshot))
+            }
+        };
+        LIVE.lock().unwrap().push(teardown.clone());
+        Ok(Snapshot {
+            origin: origin.root.clone(),
+            inside,
+            teardown,
+        })
+    }
+
+    /// Where the snapshot has `path`, which must be absolute and under its origin.
+    pub fn map(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.origin)
+            .ok()
+            .map(|rest| self.inside.join(rest))
+    }
+
+    /// The live path of `path` from inside the snapshot.
+    pub fn unmap(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.inside)
+            .ok()
+            .map(|rest| self.origin.join(rest))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        delete(&self.teardown);
+    }
+}
+
+/// Delete every snapshot this process made that is still around. For exit paths that skip
+/// destructors, such as `std::process::exit`.
+pub fn release_all() {
+    let live = std::mem::take(&mut *LIVE.lock().unwrap());
+    for teardown in live {
+        if let Err(e) = delete_now(&teardown) {
+            eprintln!("Warning: Cannot delete snapshot {:?}: {}", teardown, e);
+        }
+    }
+}
+
+fn delete(teardown: &Teardown) {
+    let mut live = LIVE.lock().unwrap();
+    let Some(idx) = live.iter().position(|live| live == teardown) else {
+        return;
+    };
+    live.remove(idx);
+    drop(live);
+    if let Err(e) = delete_now(teardown) {
+        eprintln!("Warning: Cannot delete snapshot {:?}: {}", teardown, e);
+    }
+}
+
+fn delete_now(teardown: &Teardown) -> io::Result<String> {
+    match teardown {
+        Teardown::Btrfs(path) => run(Command::new("btrfs")
+            .args(["subvolume", "delete"])
+            .arg(path)),
+        Teardown::Lvm { lv, mount } => {
+            // Not mounted if mounting is what failed.
+            let _ = run(Command::new("umount").arg(mount));
+            let _ = fs::remove_dir(mount);
+            run(Command::new("lvremove").args(["--force", lv]))
+        }
+        Teardown::Zfs(snapshot) => run(Command::new("zfs").arg("destroy").arg(snapshot)),
+    }
+}
+
+/// Run `command`, returning its output, or its error output as the error if it fails.
+fn run(command: &mut Command) -> io::Result<String> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A mounted filesystem, from `/proc/self/mountinfo`: its mount point, type, and device.
+#[derive(Clone, Debug)]
+pub struct Mount {
+    pub point: PathBuf,
+    pub fstype: String,
+    /// What was mounted: `/dev/sdb1`, or `server:/export` for NFS.
+    pub device: String,
+    /// The `MAJOR:MINOR` that files on it have as their `st_dev`.
+    pub number: String,
+}
+
+/// Undo the octal escapes (`\040` for a space) of mount table fields.
+fn unescape_mount_field(field: &str) -> String {
+    let mut out = Vec::new();
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The mounted filesystems, or none where there's no `/proc/self/mountinfo` (off Linux).
+pub fn mounts() -> Vec<Mount> {
+    let Ok(table) = fs::read_to_string("/proc/self/mountinfo") else {
+        return Vec::new();
+    };
+    table
+        .lines()
+        .filter_map(|line| {
+            // ID PARENT MAJOR:MINOR ROOT POINT OPTIONS [OPTIONAL...] - TYPE DEVICE SUPER
+            let (before, after) = line.split_once(" - ")?;
+            let mut before = before.split(' ');
+            let number = before.nth(2)?;
+            let point = before.nth(1)?;
+            let mut after = after.split(' ');
+            Some(Mount {
+                point: PathBuf::from(unescape_mount_field(point)),
+                fstype: after.next()?.to_string(),
+                device: unescape_mount_field(after.next()?),
+                number: number.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// LVM logical volumes as `VG/LV`, by their device-mapper paths, from `lvs`.
+fn lvm_volumes() -> HashMap<String, String> {
+    let Ok(list) =
+        run(Command::new("lvs").args(["--noheadings", "-o", "lv_dm_path,vg_name,lv_name"]))
+    else {
+        return HashMap::new();
+    };
+    list.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (path, vg, lv) = (fields.next()?, fields.next()?, fields.next()?);
+            Some((path.to_string(), format!("{}/{}", vg, lv)))
+        })
+        .collect()
+}
+
+/// ZFS datasets with their mount points, from `zfs list`.
+fn zfs_datasets() -> Vec<(String, PathBuf)> {
+    let Ok(list) =
+        run(Command::new("zfs").args(["list", "-H", "-t", "filesystem", "-o", "name,mountpoint"]))
+    else {
+        return Vec::new();
+    };
+    list.lines()
+        .filter_map(|line| {
+            let (name, point) = line.split_once('\t')?;
+            point
+                .starts_with('/')
+                .then(|| (name.to_string(), PathBuf::from(point)))
+        })
+        .collect()
+}
+
+/// The local filesystem, read through a snapshot of each btrfs subvolume, LVM volume, or ZFS
+/// dataset, of `kind`, that files are on. Files on filesystems of other kinds are read live.
+pub struct SnapshotSource {
+    kind: SnapshotKind,
+    /// The filesystems that could be snapshotted, found once.
+    table: OnceLock<Vec<Origin>>,
+    snapshots: Mutex<HashMap<PathBuf, Result<Arc<Snapshot>, String>>>,
+    /// Whether reading a file live, as it's on no filesystem of this kind, was warned about.
+    warned_live: AtomicBool,
+}
+
+impl fmt::Debug for SnapshotSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SnapshotSource")
+            .field("kind", &self.kind)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SnapshotSource {
+    pub fn new(kind: SnapshotKind) -> Self {
+        SnapshotSource {
+            kind,
+            table: OnceLock::new(),
+            snapshots: Mutex::new(HashMap::new()),
+            warned_live: AtomicBool::new(false),
+        }
+    }
+
+    /// The filesystems of this kind: mounted datasets for ZFS, mounted volumes for LVM, and
+    /// for btrfs its mounts, under which each subvolume is found by [`Self::origin`].
+    fn table(&self) -> &[Origin] {
+        self.table.get_or_init(|| match self.kind {
+            SnapshotKind::Zfs => zfs_datasets()
+                .into_iter()
+                .map(|(name, root)| Origin {
+                    root,
+                    name,
+                    fstype: "zfs".to_string(),
+                })
+                .collect(),
+            SnapshotKind::Btrfs => mounts()
+                .into_iter()
+                .filter(|m| m.fstype == "btrfs")
+                .map(|m| Origin {
+                    root: m.point,
+                    name: m.device,
+                    fstype: m.fstype,
+                })
+                .collect(),
+            SnapshotKind::Lvm => {
+                let volumes = lvm_volumes();
+                mounts()
+                    .into_iter()
+                    .filter_map(|m| {
+                        Some(Origin {
+                            name: volumes.get(&m.device)?.clone(),
+                            root: m.point,
+                            fstype: m.fstype,
+                        })
+                    })
+                    .collect()
+            }
+        })
+    }
+
+    /// The filesystem `path` (absolute) is on, if one of this kind: the one mounted
+    /// deepest above it, and for btrfs, the subvolume within it.
+    fn origin(&self, path: &Path) -> Option<Origin> {
+        let mount = self
+            .table()
+            .iter()
+            .filter(|o| path.starts_with(&o.root))
+            .max_by_key(|o| o.root.components().count())?;
+        if self.kind != SnapshotKind::Btrfs {
+            return Some(mount.clone());
+        }
+        let root = path
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&mount.root))
+            .find(|dir| {
+                fs::symlink_metadata(dir)
+                    .is_ok_and(|m| m.is_dir() && m.ino() == BTRFS_SUBVOLUME_INO)
+            })
+            .unwrap_or(&mount.root);
+        Some(Origin {
+            root: root.to_path_buf(),
+            ..mount.clone()
+        })
+    }
+
+    /// The snapshot covering `path`, made on first use. `None` if `path` isn't on a
+    /// filesystem of this kind.
+    fn snapshot(&self, path: &Path) -> Option<io::Result<Arc<Snapshot>>> {
+        let path = std::path::absolute(path).ok()?;
+        let Some(origin) = self.origin(&path) else {
+            if !self.warned_live.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "Warning: '{}' is on no {} filesystem, so it and others like it are read live",
+                    path.display(),
+                    self.kind
+                );
+            }
+            return None;
+        };
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let snapshot = snapshots.entry(origin.root.clone()).or_insert_with(|| {
+            Snapshot::create(self.kind, &origin)
+                .map(Arc::new)
+                .map_err(|e| {
+                    let e = format!("cannot snapshot {}: {}", origin.root.display(), e);
+                    eprintln!("Warning: {}", e);
+                    e
+                })
+        });
+        Some(snapshot.clone().map_err(io::Error::other))
+    }
+
+    /// Where to read `path` from: inside its filesystem's snapshot if there is one.
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let Some(snapshot) = self.snapshot(path) else {
+            return Ok(path.to_path_buf());
+        };
+        let path = std::path::absolute(path)?;
+        Ok(snapshot?.map(&path).unwrap_or(path))
+    }
+}
+
+impl Source for SnapshotSource {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        LocalFs.metadata(&self.resolve(path)?)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>> {
+        LocalFs.open(&self.resolve(path)?)
+    }
+
+    /// Walks the snapshot, reporting live paths, or the live tree if `root` can't be
+    /// snapshotted. Filesystems of this kind nested below `root` (btrfs subvolumes, ZFS
+    /// datasets, volumes mounted there) aren't in its snapshot, so they're walked in
+    /// snapshots of their own.
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        self.walk_from(root.canonicalize().unwrap_or_else(|_| root.to_path_buf()))
+    }
+}
+
+impl SnapshotSource {
+    fn walk_from<'a>(&'a self, root: PathBuf) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        let snapshot = match self.snapshot(&root) {
+            Some(Ok(snapshot)) => snapshot,
+            _ => return live_walk(root),
+        };
+        let Some(inside) = snapshot.map(&root) else {
+            return live_walk(root);
+        };
+        // Those directly below this snapshot's filesystem; deeper ones are theirs to walk.
+        let mut nested: Vec<PathBuf> = self
+            .table()
+            .iter()
+            .map(|o| o.root.clone())
+            .filter(|r| r.starts_with(&root) && *r != root)
+            .filter(|r| {
+                r.parent()
+                    .and_then(|parent| self.origin(parent))
+                    .is_some_and(|o| o.root == snapshot.origin)
+            })
+            .collect();
+        let btrfs = self.kind == SnapshotKind::Btrfs;
+        let in_snapshot = WalkDir::new(inside)
+            .follow_links(false)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(move |e| {
+                e.file_type().is_file()
+                    || (btrfs
+                        && e.file_type().is_dir()
+                        && e.metadata()
+                            .is_ok_and(|m| m.ino() == BTRFS_EMPTY_SUBVOLUME_INO))
+            })
+            .filter_map(move |e| Some((e.file_type().is_file(), snapshot.unmap(e.path())?)));
+        nested.sort();
+        let below = nested.clone();
+        Box::new(
+            in_snapshot
+                .flat_map(
+                    move |(is_file, live)| -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+                        match is_file {
+                            true => Box::new(std::iter::once(live)),
+                            // A nested subvolume's stand-in, unless it's mounted and so walked below,
+                            // or a snapshot being taken (by this run or another).
+                            false if !nested.contains(&live) && !is_snapshot_dir(&live) => {
+                                self.walk_from(live)
+                            }
+                            false => Box::new(std::iter::empty()),
+                        }
+                    },
+                )
+                .chain(below.into_iter().flat_map(move |r| self.walk_from(r))),
+        )
+    }
+}
+
+/// Whether `dir` is where a btrfs snapshot of ours is, named as [`Snapshot::create`] names them.
+fn is_snapshot_dir(dir: &Path) -> bool {
+    dir.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(".gustasum-"))
+}
+
+/// The regular files under `root`, read live.
+fn live_walk<'a>(root: PathBuf) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+    Box::new(
+        WalkDir::new(root)
+            .follow_links(false)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| e.into_path()),
+    )
+}
@@ -0,0 +1,350 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Where file contents come from. The hashing core only talks to a [`Source`], so it can
+//! run against the local filesystem, an in-memory tree, or other backends.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use walkdir::WalkDir;
+
+/// The subset of file metadata the hashing core uses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceMetadata {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl From<fs::Metadata> for SourceMetadata {
+    fn from(meta: fs::Metadata) -> Self {
+        SourceMetadata {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        }
+    }
+}
+
+/// A UTC calendar date and time as a `SystemTime`, for sources that report modtimes that
+/// way. `None` if it's out of range or before 1970.
+pub(crate) fn civil_time(
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+) -> Option<SystemTime> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since 1970-01-01 in the proleptic Gregorian calendar.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// An open file that supports positional reads. Shared between threads when regions are
+/// read concurrently.
+pub trait SourceFile: Send + Sync {
+    /// Read up to `buf.len()` bytes at `offset`, returning how many were read (0 at EOF).
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    /// Metadata of the open file, used to detect changes made while it was being read.
+    fn metadata(&self) -> io::Result<SourceMetadata>;
+    /// The `(offset, len)` ranges of the file that hold data, in order, with the holes of a
+    /// sparse file left out; a file without holes is one range. `None` if the source can't
+    /// tell, which is the default.
+    fn data_extents(&self) -> io::Result<Option<Vec<(u64, u64)>>> {
+        Ok(None)
+    }
+}
+
+/// A tree of files that can be walked, stat'ed, and read.
+pub trait Source: fmt::Debug + Send + Sync {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata>;
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>>;
+    /// All regular files under `root` (or `root` itself if it's a file), in walk order.
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a>;
+}
+
+/// The local filesystem, through `std::fs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalFs;
+
+impl SourceFile for fs::File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        return std::os::unix::fs::FileExt::read_at(self, buf, offset);
+        #[cfg(windows)]
+        return std::os::windows::fs::FileExt::seek_read(self, buf, offset);
+    }
+
+    fn metadata(&self) -> io::Result<SourceMetadata> {
+        fs::File::metadata(self).map(SourceMetadata::from)
+    }
+
+    /// With `SEEK_DATA` and `SEEK_HOLE`, where the platform and filesystem have them.
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn data_extents(&self) -> io::Result<Option<Vec<(u64, u64)>>> {
+        use std::os::fd::AsRawFd;
+        let fd = self.as_raw_fd();
+        let seek =
+            |offset: u64, whence| match unsafe { libc::lseek(fd, offset as libc::off_t, whence) } {
+                -1 => Err(io::Error::last_os_error()),
+                position => Ok(position as u64),
+            };
+        let mut extents = Vec::new();
+        let mut offset = 0;
+        loop {
+            let data = match seek(offset, libc::SEEK_DATA) {
+                Ok(data) => data,
+                // Nothing but a hole from here to the end.
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+                // The filesystem doesn't report holes.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let hole = seek(data, libc::SEEK_HOLE)?;
+            extents.push((data, hole - data));
+            offset = hole;
+        }
+        Ok(Some(extents))
+    }
+}
+
+impl Source for LocalFs {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        #[cfg(target_os = "macos")]
+        if let Some(file) = xattr_file(path) {
+            return file.map(|f| f.metadata());
+        }
+        fs::metadata(native_path(path)).map(SourceMetadata::from)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>> {
+        #[cfg(target_os = "macos")]
+        if let Some(file) = xattr_file(path) {
+            return Ok(Box::new(file?));
+        }
+        Ok(Box::new(fs::File::open(native_path(path))?))
+    }
+
+    /// Canonicalizes `root` first (falling back to the path as given); symlinks inside the
+    /// tree are not followed.
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        let root = root
+            .canonicalize()
+            .unwrap_or_else(|_| native_path(root).into_owned());
+        Box::new(
+            WalkDir::new(root)
+                .follow_links(false)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| display_path(e.into_path())),
+        )
+    }
+}
+
+/// macOS: a `file/..xattr/name` entry (see [`crate::forks`]) as an in-memory file carrying
+/// `file`'s modtime, or `None` if `path` isn't of that form.
+#[cfg(target_os = "macos")]
+fn xattr_file(path: &Path) -> Option<io::Result<MemoryFile>> {
+    let (file, name) = crate::forks::xattr_target(path)?;
+    let read = || {
+        let modified = fs::symlink_metadata(file)?.modified().ok();
+        Ok(MemoryFile {
+            data: Arc::new(crate::forks::read_xattr(file, name)?),
+            modified,
+        })
+    };
+    Some(read())
+}
+
+/// The form of `path` to hand to the OS. On Windows that's the `\\?\` (verbatim) form, which
+/// lifts the 260-character MAX_PATH limit; elsewhere it's `path` itself.
+pub fn native_path(path: &Path) -> Cow<'_, Path> {
+    #[cfg(windows)]
+    return windows::verbatim(path);
+    #[cfg(not(windows))]
+    Cow::Borrowed(path)
+}
+
+/// Undo [`native_path`], so manifests and messages show ordinary paths.
+pub fn display_path(path: PathBuf) -> PathBuf {
+    #[cfg(windows)]
+    return windows::unverbatim(path);
+    #[cfg(not(windows))]
+    path
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::{
+        borrow::Cow,
+        ffi::OsString,
+        os::windows::ffi::{OsStrExt, OsStringExt},
+        path::{Component, Path, PathBuf, Prefix},
+    };
+
+    fn prefix(path: &Path) -> Option<Prefix<'_>> {
+        match path.components().next() {
+            Some(Component::Prefix(p)) => Some(p.kind()),
+            _ => None,
+        }
+    }
+
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().collect()
+    }
+
+    fn from_parts(head: &str, rest: &[u16]) -> PathBuf {
+        let mut s = OsString::from(head);
+        s.push(OsString::from_wide(rest));
+        PathBuf::from(s)
+    }
+
+    pub fn verbatim(path: &Path) -> Cow<'_, Path> {
+        if matches!(
+            prefix(path),
+            Some(Prefix::Verbatim(_) | Prefix::VerbatimDisk(_) | Prefix::VerbatimUNC(..))
+        ) {
+            return Cow::Borrowed(path);
+        }
+        // Verbatim paths skip `.`/`..` processing, so resolve those first.
+        let Ok(abs) = std::path::absolute(path) else {
+            return Cow::Borrowed(path);
+        };
+        Cow::Owned(match prefix(&abs) {
+            // C:\dir -> \\?\C:\dir
+            Some(Prefix::Disk(_)) => from_parts(r"\\?\", &wide(&abs)),
+            // \\server\share\dir -> \\?\UNC\server\share\dir
+            Some(Prefix::UNC(..)) => from_parts(r"\\?\UNC\", &wide(&abs)[2..]),
+            _ => abs,
+        })
+    }
+
+    pub fn unverbatim(path: PathBuf) -> PathBuf {
+        match prefix(&path) {
+            Some(Prefix::VerbatimDisk(_)) => from_parts("", &wide(&path)[4..]),
+            Some(Prefix::VerbatimUNC(..)) => from_parts(r"\\", &wide(&path)[8..]),
+            _ => path,
+        }
+    }
+}
+
+/// An in-memory tree of files, for tests and for content that never touches a disk.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySource {
+    files: BTreeMap<PathBuf, MemoryFile>,
+}
+
+#[derive(Clone, Debug)]
+struct MemoryFile {
+    data: Arc<Vec<u8>>,
+    modified: Option<SystemTime>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add (or replace) a file with no modtime.
+    pub fn insert(&mut self, path: impl Into<PathBuf>, data: impl Into<Vec<u8>>) {
+        self.insert_with_modtime(path, data, None);
+    }
+
+    pub fn insert_with_modtime(
+        &mut self,
+        path: impl Into<PathBuf>,
+        data: impl Into<Vec<u8>>,
+        modified: Option<SystemTime>,
+    ) {
+        self.files.insert(
+            path.into(),
+            MemoryFile {
+                data: Arc::new(data.into()),
+                modified,
+            },
+        );
+    }
+
+    fn get(&self, path: &Path) -> io::Result<&MemoryFile> {
+        self.files
+            .get(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file in memory source"))
+    }
+}
+
+impl MemoryFile {
+    fn metadata(&self) -> SourceMetadata {
+        SourceMetadata {
+            len: self.data.len() as u64,
+            modified: self.modified,
+        }
+    }
+}
+
+impl SourceFile for MemoryFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = (offset as usize).min(self.data.len());
+        let n = buf.len().min(self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        Ok(n)
+    }
+
+    fn metadata(&self) -> io::Result<SourceMetadata> {
+        Ok(MemoryFile::metadata(self))
+    }
+}
+
+impl Source for MemorySource {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        self.get(path).map(MemoryFile::metadata)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>> {
+        Ok(Box::new(self.get(path)?.clone()))
+    }
+
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        Box::new(
+            self.files
+                .keys()
+                .filter(move |p| p.starts_with(root))
+                .cloned(),
+        )
+    }
+}
@@ -0,0 +1,681 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Files on other machines, named `ssh://[user@]host[:port]/path` and read over SFTP
+//! (version 3, which every OpenSSH server speaks) by running `ssh -s host sftp`. Each host
+//! gets one connection for the whole run; stat'ing is a STAT, listing is READDIR, and each
+//! sampled region is a READ at its offset.
+//!
+//! Logging in is up to `ssh`: keys, agents, `~/.ssh/config`, and password prompts all work
+//! as they do for `sftp`.
+
+use crate::source::{Source, SourceFile, SourceMetadata};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// The scheme of paths on other machines.
+pub const SSH_SCHEME: &str = "ssh://";
+
+/// An `ssh://[user@]host[:port]/path` path, split up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SshUrl {
+    /// `[user@]host`, as `ssh` takes it.
+    pub destination: String,
+    pub port: Option<u16>,
+    /// The path on the host, from its `/`.
+    pub path: String,
+}
+
+impl SshUrl {
+    /// `None` for paths that aren't `ssh://` ones.
+    pub fn parse(path: &Path) -> Option<SshUrl> {
+        // Native separators may have been applied to the whole entry.
+        let s = path.to_str()?.replace('\\', "/");
+        let rest = s.strip_prefix(SSH_SCHEME)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().ok()?)),
+            None => (authority, None),
+        };
+        (!destination.is_empty()).then(|| SshUrl {
+            destination: destination.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// `ssh://[user@]host[:port]`, to put in front of paths on the host.
+    pub fn prefix(&self) -> String {
+        match self.port {
+            Some(port) => format!("{}{}:{}", SSH_SCHEME, self.destination, port),
+            None => format!("{}{}", SSH_SCHEME, self.destination),
+        }
+    }
+
+    /// `path` on the same host, as an `ssh://` path.
+    pub fn with_path(&self, path: &str) -> PathBuf {
+        PathBuf::from(format!("{}{}", self.prefix(), path))
+    }
+
+    /// `ssh [-p port] destination`, for the caller to add the rest of the command to.
+    pub fn command(&self, ssh: impl AsRef<std::ffi::OsStr>) -> Command {
+        let mut command = Command::new(ssh);
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        command.arg(&self.destination);
+        command
+    }
+
+    /// `ssh [-p port] destination` running `args` in the remote shell, each quoted so it
+    /// arrives as the one word it is here.
+    pub fn remote_command<S: AsRef<str>>(
+        &self,
+        ssh: impl AsRef<std::ffi::OsStr>,
+        args: impl IntoIterator<Item = S>,
+    ) -> Command {
+        let line: Vec<String> = args.into_iter().map(|a| shell_quote(a.as_ref())).collect();
+        let mut command = self.command(ssh);
+        command.arg(line.join(" "));
+        command
+    }
+}
+
+/// Quote `arg` for the remote shell, which `ssh` hands its command line to as one string.
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// SFTP version 3 packet types and flags.
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_STAT: u8 = 17;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+const FXP_ATTRS: u8 = 105;
+const FXF_READ: u32 = 0x1;
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+
+/// The most a READ asks for. Servers needn't send more than 32 KiB at a time.
+const READ_CHUNK: usize = 32 * 1024;
+
+/// The parts of a file's attributes that matter here.
+#[derive(Clone, Debug, Default)]
+struct Attrs {
+    size: Option<u64>,
+    permissions: Option<u32>,
+    mtime: Option<u32>,
+}
+
+impl Attrs {
+    fn metadata(&self, path: &str) -> io::Result<SourceMetadata> {
+        let len = self
+            .size
+            .ok_or_else(|| io::Error::other(format!("the server gave no size for {}", path)))?;
+        Ok(SourceMetadata {
+            len,
+            modified: self
+                .mtime
+                .map(|t| SystemTime::UNIX_EPOCH + Duration::from_secs(t.into())),
+        })
+    }
+
+    fn kind(&self) -> Option<u32> {
+        self.permissions.map(|p| p & S_IFMT)
+    }
+}
+
+/// A request being built.
+struct Packet(Vec<u8>);
+
+impl Packet {
+    fn new(kind: u8, id: u32) -> Packet {
+        Packet(vec![kind]).u32(id)
+    }
+
+    fn u32(mut self, n: u32) -> Packet {
+        self.0.extend(n.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, n: u64) -> Packet {
+        self.0.extend(n.to_be_bytes());
+        self
+    }
+
+    fn string(self, s: &[u8]) -> Packet {
+        let mut packet = self.u32(s.len() as u32);
+        packet.0.extend(s);
+        packet
+    }
+}
+
+/// A reply being taken apart.
+struct Reply {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Reply {
+    fn bytes(&mut self, n: usize) -> io::Result<&[u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.data.len());
+        let end = end.ok_or_else(|| io::Error::other("truncated SFTP reply"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    fn attrs(&mut self) -> io::Result<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & ATTR_UIDGID != 0 {
+            self.bytes(8)?;
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            self.u32()?;
+            attrs.mtime = Some(self.u32()?);
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.string()?;
+                self.string()?;
+            }
+        }
+        Ok(attrs)
+    }
+
+    /// The error a STATUS reply stands for, where a reply of `kind` was not the one expected.
+    /// EOF is `UnexpectedEof`.
+    fn status(&mut self, kind: u8) -> io::Error {
+        if kind != FXP_STATUS {
+            return io::Error::other(format!("unexpected SFTP reply {}", kind));
+        }
+        let (code, message) = match (self.u32(), self.string()) {
+            (Ok(code), Ok(message)) => (code, String::from_utf8_lossy(&message).into_owned()),
+            _ => return io::Error::other("truncated SFTP status"),
+        };
+        let kind = match code {
+            FX_EOF => io::ErrorKind::UnexpectedEof,
+            FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+            FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        let message = if message.is_empty() {
+            format!("SFTP error {}", code)
+        } else {
+            message
+        };
+        io::Error::new(kind, message)
+    }
+}
+
+/// One `ssh -s host sftp`, taking one request at a time.
+struct Session {
+    child: Mutex<Child>,
+    channel: Mutex<Channel>,
+}
+
+struct Channel {
+    to_server: ChildStdin,
+    from_server: BufReader<ChildStdout>,
+    next_id: u32,
+}
+
+impl Channel {
+    fn send(&mut self, packet: Packet) -> io::Result<()> {
+        let mut framed = (packet.0.len() as u32).to_be_bytes().to_vec();
+        framed.extend(packet.0);
+        self.to_server.write_all(&framed)?;
+        self.to_server.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<(u8, Reply)> {
+        let closed = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                io::Error::new(io::ErrorKind::BrokenPipe, "the SFTP connection closed")
+            }
+            _ => e,
+        };
+        let mut len = [0; 4];
+        self.from_server.read_exact(&mut len).map_err(closed)?;
+        let mut data = vec![0; u32::from_be_bytes(len) as usize];
+        self.from_server.read_exact(&mut data).map_err(closed)?;
+        if data.is_empty() {
+            return Err(io::Error::other("empty SFTP packet"));
+        }
+        let kind = data[0];
+        Ok((kind, Reply { data, pos: 1 }))
+    }
+
+    /// Send a request of `kind` with `fields` after its id, and wait for the reply.
+    fn request(
+        &mut self,
+        kind: u8,
+        fields: impl FnOnce(Packet) -> Packet,
+    ) -> io::Result<(u8, Reply)> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.send(fields(Packet::new(kind, id)))?;
+        let (kind, mut reply) = self.receive()?;
+        if reply.u32()? != id {
+            return Err(io::Error::other("SFTP reply out of order"));
+        }
+        Ok((kind, reply))
+    }
+}
+
+impl Session {
+    fn start(ssh: &OsString, url: &SshUrl) -> io::Result<Session> {
+        let mut command = url.command(ssh);
+        // `-s` makes `sftp` the subsystem to start rather than a command to run.
+        command.arg("-s").arg("sftp");
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "cannot run '{}' (is ssh installed?): {}",
+                        Path::new(ssh).display(),
+                        e
+                    ),
+                )
+            })?;
+        let mut channel = Channel {
+            to_server: child.stdin.take().unwrap(),
+            from_server: BufReader::new(child.stdout.take().unwrap()),
+            next_id: 0,
+        };
+        channel.send(Packet(vec![FXP_INIT]).u32(3))?;
+        match channel.receive() {
+            Ok((FXP_VERSION, _)) => {}
+            Ok(_) => return Err(io::Error::other("not an SFTP server")),
+            Err(_) => {
+                let status = child.wait()?;
+                return Err(io::Error::other(format!(
+                    "cannot reach {} over SFTP (ssh exited with {})",
+                    url.destination, status
+                )));
+            }
+        }
+        Ok(Session {
+            child: Mutex::new(child),
+            channel: Mutex::new(channel),
+        })
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Attrs> {
+        let (kind, mut reply) = self
+            .channel
+            .lock()
+            .unwrap()
+            .request(FXP_STAT, |p| p.string(path.as_bytes()))?;
+        match kind {
+            FXP_ATTRS => reply.attrs(),
+            _ => Err(reply.status(kind)),
+        }
+    }
+
+    /// Open a file (`FXP_OPEN`) or directory (`FXP_OPENDIR`).
+    fn open(&self, kind: u8, path: &str) -> io::Result<Vec<u8>> {
+        let (reply_kind, mut reply) = self.channel.lock().unwrap().request(kind, |p| {
+            let p = p.string(path.as_bytes());
+            if kind == FXP_OPEN {
+                p.u32(FXF_READ).u32(0)
+            } else {
+                p
+            }
+        })?;
+        match reply_kind {
+            FXP_HANDLE => reply.string(),
+            _ => Err(reply.status(reply_kind)),
+        }
+    }
+
+    fn close(&self, handle: &[u8]) {
+        let _ = self
+            .channel
+            .lock()
+            .unwrap()
+            .request(FXP_CLOSE, |p| p.string(handle));
+    }
+
+    /// Up to `len` bytes at `offset`; fewer at the end of the file, and none after it.
+    fn read(&self, handle: &[u8], offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let (kind, mut reply) = self
+            .channel
+            .lock()
+            .unwrap()
+            .request(FXP_READ, |p| p.string(handle).u64(offset).u32(len as u32))?;
+        match kind {
+            FXP_DATA => reply.string(),
+            _ => match reply.status(kind) {
+                e if e.kind() == io::ErrorKind::UnexpectedEof => Ok(Vec::new()),
+                e => Err(e),
+            },
+        }
+    }
+
+    /// The names in directory `path` with their attributes, `.` and `..` left out.
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Attrs)>> {
+        let handle = self.open(FXP_OPENDIR, path)?;
+        let mut entries = Vec::new();
+        let result = loop {
+            let reply = self
+                .channel
+                .lock()
+                .unwrap()
+                .request(FXP_READDIR, |p| p.string(&handle));
+            let (kind, mut reply) = match reply {
+                Ok(reply) => reply,
+                Err(e) => break Err(e),
+            };
+            if kind != FXP_NAME {
+                let e = reply.status(kind);
+                break if e.kind() == io::ErrorKind::UnexpectedEof {
+                    Ok(())
+                } else {
+                    Err(e)
+                };
+            }
+            let names = (0..reply.u32()?)
+                .map(|_| {
+                    let name = String::from_utf8_lossy(&reply.string()?).into_owned();
+                    let _long_name = reply.string()?;
+                    Ok((name, reply.attrs()?))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.extend(names.into_iter().filter(|(n, _)| n != "." && n != ".."));
+        };
+        self.close(&handle);
+        result.map(|()| entries)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut child = self.child.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// A session, or the error that starting one ended in.
+type Connection = Result<Arc<Session>, (io::ErrorKind, String)>;
+
+/// `inner`, with `ssh://` paths read over SFTP. Other paths pass through unchanged.
+pub struct SshSource {
+    inner: Arc<dyn Source>,
+    ssh: OsString,
+    /// A connection per `ssh://[user@]host[:port]`, or why it couldn't be made.
+    sessions: Mutex<HashMap<String, Connection>>,
+    /// Files seen in listings, so each is stat'ed once.
+    known: Mutex<HashMap<PathBuf, SourceMetadata>>,
+}
+
+impl fmt::Debug for SshSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SshSource")
+            .field("inner", &self.inner)
+            .field("ssh", &self.ssh)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SshSource {
+    /// Reach hosts by running `ssh`, the program given.
+    pub fn new(inner: Arc<dyn Source>, ssh: impl Into<OsString>) -> Self {
+        SshSource {
+            inner,
+            ssh: ssh.into(),
+            sessions: Mutex::new(HashMap::new()),
+            known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The connection to `url`'s host, made on first use. A host that can't be reached is
+    /// tried once, and its error repeated for every file on it.
+    fn session(&self, url: &SshUrl) -> io::Result<Arc<Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(url.prefix()).or_insert_with(|| {
+            Session::start(&self.ssh, url)
+                .map(Arc::new)
+                .map_err(|e| (e.kind(), e.to_string()))
+        });
+        match session {
+            Ok(session) => Ok(session.clone()),
+            Err((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+        }
+    }
+
+    /// Every regular file under directory `dir` on `url`'s host, depth first. Directories
+    /// that can't be read are left out; symlinks are not followed.
+    fn files_under(&self, session: &Session, url: &SshUrl, dir: &str, found: &mut Vec<PathBuf>) {
+        let Ok(entries) = session.list(dir) else {
+            return;
+        };
+        for (name, attrs) in entries {
+            let path = format!("{}/{}", dir.trim_end_matches('/'), name);
+            match attrs.kind() {
+                Some(S_IFDIR) => self.files_under(session, url, &path, found),
+                Some(S_IFREG) => {
+                    let full = url.with_path(&path);
+                    if let Ok(meta) = attrs.metadata(&path) {
+                        self.known.lock().unwrap().insert(full.clone(), meta);
+                    }
+                    found.push(full);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Source for SshSource {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        let Some(url) = SshUrl::parse(path) else {
+            return self.inner.metadata(path);
+        };
+        if let Some(meta) = self.known.lock().unwrap().get(path) {
+            return Ok(meta.clone());
+        }
+        self.session(&url)?.stat(&url.path)?.metadata(&url.path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>> {
+        let Some(url) = SshUrl::parse(path) else {
+            return self.inner.open(path);
+        };
+        let session = self.session(&url)?;
+        let meta = session.stat(&url.path)?.metadata(&url.path)?;
+        let handle = session.open(FXP_OPEN, &url.path)?;
+        Ok(Box::new(SftpFile {
+            session,
+            handle,
+            meta,
+        }))
+    }
+
+    /// A root that can't be stat'ed is passed on as is, so the error is reported when it's
+    /// hashed.
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        let Some(url) = SshUrl::parse(root) else {
+            return self.inner.walk(root);
+        };
+        let session = match self.session(&url) {
+            Ok(session) => session,
+            Err(_) => return Box::new(std::iter::once(root.to_path_buf())),
+        };
+        let mut found = Vec::new();
+        match session.stat(&url.path).map(|a| a.kind()) {
+            Ok(Some(S_IFDIR)) => self.files_under(&session, &url, &url.path, &mut found),
+            Ok(Some(S_IFREG)) | Err(_) => found.push(root.to_path_buf()),
+            Ok(_) => {}
+        }
+        Box::new(found.into_iter())
+    }
+}
+
+/// A file open on the server.
+struct SftpFile {
+    session: Arc<Session>,
+    handle: Vec<u8>,
+    meta: SourceMetadata,
+}
+
+impl SourceFile for SftpFile {
+    /// READs of up to [`READ_CHUNK`] bytes until `buf` is full or the file ends.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let want = (buf.len() - filled).min(READ_CHUNK);
+            let data = self
+                .session
+                .read(&self.handle, offset + filled as u64, want)?;
+            if data.is_empty() {
+                break;
+            }
+            let n = data.len().min(want);
+            buf[filled..filled + n].copy_from_slice(&data[..n]);
+            filled += n;
+        }
+        Ok(filled)
+    }
+
+    fn metadata(&self) -> io::Result<SourceMetadata> {
+        Ok(self.meta.clone())
+    }
+}
+
+impl Drop for SftpFile {
+    fn drop(&mut self) {
+        self.session.close(&self.handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_split() {
+        let url = SshUrl::parse(Path::new("ssh://me@host:2222/srv/a b")).unwrap();
+        assert_eq!(
+            url,
+            SshUrl {
+                destination: "me@host".to_string(),
+                port: Some(2222),
+                path: "/srv/a b".to_string(),
+            }
+        );
+        assert_eq!(url.with_path("/x"), PathBuf::from("ssh://me@host:2222/x"));
+        assert_eq!(SshUrl::parse(Path::new("ssh://host")).unwrap().path, "/");
+        assert_eq!(SshUrl::parse(Path::new("ssh://host:port/a")), None);
+        assert_eq!(SshUrl::parse(Path::new("/srv/a")), None);
+    }
+
+    #[test]
+    fn arguments_are_quoted() {
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+
+        let url = SshUrl::parse(Path::new("ssh://host:2222/")).unwrap();
+        let command = url.remote_command("ssh", ["gustasum", "--root", "/srv/it's $x"]);
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "-p",
+                "2222",
+                "host",
+                "'gustasum' '--root' '/srv/it'\\''s $x'"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_shell_gets_the_arguments_back() {
+        const AWKWARD: [&str; 6] = ["a b", "it's", "\"quoted\"", "$HOME", "`id`; rm -rf x", ""];
+        let line: Vec<String> = AWKWARD.iter().map(|a| shell_quote(a)).collect();
+        let out = Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf '%s\\n' {}", line.join(" ")))
+            .output()
+            .unwrap();
+        let expected: String = AWKWARD.iter().map(|a| format!("{}\n", a)).collect();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), expected);
+    }
+}
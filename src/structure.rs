@@ -0,0 +1,102 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Structure-only manifests, made without reading any content: each entry records what kind
+//! of thing the path is, and a file's size, in place of a digest. Checking one is a stat per
+//! entry, quick enough to run right after a large copy, before the content pass.
+
+use std::{fmt, fs, str::FromStr};
+
+/// The [`GENERATED_HEADER`](crate::manifest::GENERATED_HEADER) field marking a
+/// structure-only manifest.
+pub const STRUCTURE_FIELD: &str = "structure_only";
+
+/// What a structure-only entry records in place of a digest: `file:<size>`, `dir`, or
+/// `symlink`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File(u64),
+    Dir,
+    Symlink,
+}
+
+impl Kind {
+    /// The kind of what `meta` (from `symlink_metadata`, so links aren't followed) describes.
+    /// `None` for anything else, like a device or a socket.
+    pub fn of(meta: &fs::Metadata) -> Option<Kind> {
+        let file_type = meta.file_type();
+        if file_type.is_symlink() {
+            Some(Kind::Symlink)
+        } else if file_type.is_dir() {
+            Some(Kind::Dir)
+        } else if file_type.is_file() {
+            Some(Kind::File(meta.len()))
+        } else {
+            None
+        }
+    }
+
+    /// How `now` differs from this recorded kind, if it does.
+    pub fn change(self, now: Kind) -> Option<String> {
+        match (self, now) {
+            (Kind::File(before), Kind::File(after)) if before != after => {
+                Some(format!("size {} -> {}", before, after))
+            }
+            (before, after) if before.name() != after.name() => {
+                Some(format!("was a {}, now a {}", before.name(), after.name()))
+            }
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::File(_) => "file",
+            Kind::Dir => "directory",
+            Kind::Symlink => "symlink",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::File(size) => write!(f, "file:{}", size),
+            Kind::Dir => f.write_str("dir"),
+            Kind::Symlink => f.write_str("symlink"),
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Kind, ()> {
+        match s {
+            "dir" => Ok(Kind::Dir),
+            "symlink" => Ok(Kind::Symlink),
+            _ => s
+                .strip_prefix("file:")
+                .and_then(|size| size.parse().ok())
+                .map(Kind::File)
+                .ok_or(()),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! `--log syslog` and `--log journald`: diagnostics copied to the system log, at syslog
+//! priorities, each tagged with the run's ID so one run's lines can be pulled out of the rest.
+//!
+//! Both are datagrams on a local socket: syslog's is `/dev/log` (`/var/run/syslog` on macOS),
+//! with messages in the traditional `<PRI>TAG[PID]: MESSAGE` form, and journald's is its
+//! native protocol, which keeps the run ID as a field of its own, `GUSTASUM_RUN_ID`.
+
+use std::{
+    fs, io,
+    os::unix::{fs::FileTypeExt, net::UnixDatagram},
+};
+
+/// The user-level messages facility, as the priority's high bits.
+const FACILITY_USER: u8 = 1 << 3;
+
+const SYSLOG_SOCKETS: [&str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// A connection to the system log.
+pub enum SystemLog {
+    Syslog(UnixDatagram),
+    Journald(UnixDatagram),
+}
+
+impl SystemLog {
+    /// Connect to `kind`, `syslog` or `journald`.
+    pub fn open(kind: &str) -> io::Result<SystemLog> {
+        let connect = |path: &str| {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            Ok::<_, io::Error>(socket)
+        };
+        match kind {
+            "syslog" => {
+                let path = SYSLOG_SOCKETS
+                    .into_iter()
+                    .find(|p| fs::metadata(p).is_ok_and(|m| m.file_type().is_socket()))
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no syslog socket (/dev/log)")
+                    })?;
+                connect(path).map(SystemLog::Syslog)
+            }
+            "journald" => connect(JOURNAL_SOCKET).map(SystemLog::Journald),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown log '{}' (expected syslog or journald)", kind),
+            )),
+        }
+    }
+
+    /// Log `message` at syslog `severity` (3 for errors through 7 for debugging).
+    pub fn send(&self, severity: u8, run_id: &str, message: &str) {
+        match self {
+            SystemLog::Syslog(socket) => {
+                // Traditional syslog has no multi-line messages.
+                for line in message.lines().filter(|l| !l.trim().is_empty()) {
+                    let datagram = format!(
+                        "<{}>gustasum[{}]: [run {}] {}",
+                        FACILITY_USER | severity,
+                        std::process::id(),
+                        run_id,
+                        line
+                    );
+                    let _ = socket.send(datagram.as_bytes());
+                }
+            }
+            SystemLog::Journald(socket) => {
+                let mut datagram = Vec::new();
+                for (key, value) in [
+                    ("PRIORITY", severity.to_string().as_str()),
+                    ("SYSLOG_IDENTIFIER", "gustasum"),
+                    ("SYSLOG_FACILITY", "1"),
+                    ("GUSTASUM_RUN_ID", run_id),
+                    ("MESSAGE", message.trim()),
+                ] {
+                    field(&mut datagram, key, value);
+                }
+                let _ = socket.send(&datagram);
+            }
+        }
+    }
+}
+
+/// Append `KEY=value`. Values with newlines take the binary form: the key, a newline, the
+/// length as a little-endian u64, then the value.
+fn field(datagram: &mut Vec<u8>, key: &str, value: &str) {
+    datagram.extend(key.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend((value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend(value.as_bytes());
+    datagram.push(b'\n');
+}
@@ -0,0 +1,141 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Running as a systemd service: with `Type=notify`, gustasum says when it's ready, keeps
+//! `systemctl status` up to date with its progress, sends `STOPPING=1` once it starts
+//! winding down, and, with `WatchdogSec=`, pings the watchdog for as long as files keep getting
+//! done, so a run stuck on a dead disk gets restarted instead of hanging for good.
+//!
+//! All of it is through `$NOTIFY_SOCKET`; without one, nothing here does anything.
+
+use crate::{interrupted, RunStatus};
+use std::{
+    env,
+    os::unix::{ffi::OsStrExt, net::UnixDatagram},
+    sync::{atomic::Ordering, Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+/// How often `STATUS=` is updated without a watchdog.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+static SOCKET: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+
+/// The run in progress, counting in the given unit, whose progress feeds the watchdog.
+static RUN: Mutex<Option<(Arc<RunStatus>, &'static str)>> = Mutex::new(None);
+
+fn socket() -> Option<&'static UnixDatagram> {
+    SOCKET
+        .get_or_init(|| {
+            let path = env::var_os("NOTIFY_SOCKET")?;
+            let socket = UnixDatagram::unbound().ok()?;
+            match path.as_bytes().strip_prefix(b"@") {
+                #[cfg(target_os = "linux")]
+                Some(name) => {
+                    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                    socket
+                        .connect_addr(&SocketAddr::from_abstract_name(name).ok()?)
+                        .ok()?
+                }
+                #[cfg(not(target_os = "linux"))]
+                Some(_) => return None,
+                None => socket.connect(&path).ok()?,
+            }
+            Some(socket)
+        })
+        .as_ref()
+}
+
+/// Whether gustasum is running under systemd with `Type=notify` (or `NotifyAccess=`).
+pub fn active() -> bool {
+    socket().is_some()
+}
+
+/// Send `state`, newline-separated `KEY=value` assignments, to systemd.
+fn notify(state: &str) {
+    if let Some(socket) = socket() {
+        let _ = socket.send(state.as_bytes());
+    }
+}
+
+/// `WatchdogSec=`, if it's set for this process.
+fn watchdog() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Tell systemd the service is up, and keep it updated from a thread of its own until the
+/// process exits.
+pub fn start() {
+    if !active() {
+        return;
+    }
+    notify("READY=1\nSTATUS=Starting");
+    let watchdog = watchdog();
+    let interval = watchdog.map_or(STATUS_INTERVAL, |w| w / 2);
+    std::thread::spawn(move || {
+        let mut stopping = false;
+        let mut last = None;
+        loop {
+            std::thread::sleep(interval);
+            let mut state = Vec::new();
+            if interrupted() && !stopping {
+                stopping = true;
+                state.push("STOPPING=1".to_string());
+                state.push("STATUS=Stopping: finishing files in flight".to_string());
+            }
+            let run = RUN.lock().unwrap().clone();
+            match run {
+                Some((status, unit)) => {
+                    let progress = (
+                        status.done.load(Ordering::Relaxed),
+                        status.bytes.load(Ordering::Relaxed),
+                    );
+                    // A run that's stopped getting anything done is what the watchdog is for.
+                    if watchdog.is_some() && last != Some(progress) {
+                        state.push("WATCHDOG=1".to_string());
+                    }
+                    last = Some(progress);
+                    if !stopping {
+                        state.push(format!("STATUS={}", status.line(unit)));
+                    }
+                }
+                // Listing files beforehand, or writing results after.
+                None if watchdog.is_some() => state.push("WATCHDOG=1".to_string()),
+                None => {}
+            }
+            if !state.is_empty() {
+                notify(&state.join("\n"));
+            }
+        }
+    });
+}
+
+/// Report the progress of `status`, counting in `unit`, from now on; `None` once it's done.
+pub fn track(run: Option<(Arc<RunStatus>, &'static str)>) {
+    if active() {
+        *RUN.lock().unwrap() = run;
+    }
+}
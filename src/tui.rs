@@ -0,0 +1,348 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! `--tui`: a full-screen dashboard on stderr for long runs. It shows every worker with the
+//! file it's reading and for how long, the slowest files so far, a tally of errors, and
+//! throughput graphs. Stalls on a failing disk show up as a worker whose timer keeps going.
+//!
+//! It's drawn with ratatui on the alternate screen, through its crossterm backend.
+
+use crate::{format_duration, RunStatus};
+use gustasum::HashError;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor, execute,
+        terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph, Sparkline},
+    Frame, Terminal,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Set while the alternate screen is up, so an abrupt exit can restore the terminal.
+pub static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Shows the cursor and leaves the alternate screen, for the signal handler, which can only
+/// write bytes.
+#[cfg(unix)]
+pub const LEAVE: &str = "\x1b[?25h\x1b[?1049l";
+
+/// Redraws per second; throughput is sampled once a second.
+const FRAME: Duration = Duration::from_millis(250);
+/// Seconds of throughput history kept for the graphs.
+const HISTORY: usize = 240;
+/// Files shown in the slowest list.
+const SLOWEST: usize = 5;
+/// Workers reading one file for longer than this are highlighted.
+const STALLED: Duration = Duration::from_secs(5);
+
+/// A file being read.
+struct Active {
+    /// The rayon worker reading it, if it's read on one.
+    worker: Option<usize>,
+    path: PathBuf,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    active: Vec<Active>,
+    /// The slowest finished files, slowest first.
+    slowest: Vec<(Duration, PathBuf)>,
+    /// Failures by `io::ErrorKind`.
+    errors: BTreeMap<String, usize>,
+    retries: usize,
+    rereads: usize,
+    /// MB/s and files/s for each of the last `HISTORY` seconds.
+    mb_per_sec: VecDeque<f64>,
+    files_per_sec: VecDeque<f64>,
+    /// Diagnostics printed during the run, newest last.
+    messages: VecDeque<String>,
+}
+
+pub struct Dashboard {
+    status: Arc<RunStatus>,
+    unit: &'static str,
+    state: Mutex<State>,
+    /// Dropped by `close` to stop the drawing thread.
+    stop: Mutex<Option<mpsc::Sender<()>>>,
+    drawer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Dashboard {
+    /// Switch stderr to the alternate screen and draw `status` until [`Dashboard::close`].
+    pub fn open(status: Arc<RunStatus>, unit: &'static str) -> Arc<Self> {
+        let dashboard = Arc::new(Dashboard {
+            status,
+            unit,
+            state: Mutex::new(State::default()),
+            stop: Mutex::new(None),
+            drawer: Mutex::new(None),
+        });
+        let _ = execute!(io::stderr(), EnterAlternateScreen, cursor::Hide);
+        ACTIVE.store(true, Ordering::SeqCst);
+
+        let (tx, rx) = mpsc::channel::<()>();
+        let drawing = Arc::clone(&dashboard);
+        let drawer = std::thread::spawn(move || {
+            let Ok(mut terminal) =
+                Terminal::new(CrosstermBackend::new(BufWriter::new(io::stderr())))
+            else {
+                return;
+            };
+            let mut last_sample = Instant::now();
+            let mut last_counts = (0, 0);
+            loop {
+                if last_sample.elapsed() >= Duration::from_secs(1) {
+                    last_counts = drawing.sample(last_sample.elapsed(), last_counts);
+                    last_sample = Instant::now();
+                }
+                let _ = terminal.draw(|frame| drawing.draw(frame));
+                if !matches!(rx.recv_timeout(FRAME), Err(mpsc::RecvTimeoutError::Timeout)) {
+                    break;
+                }
+            }
+        });
+        *dashboard.stop.lock().unwrap() = Some(tx);
+        *dashboard.drawer.lock().unwrap() = Some(drawer);
+        dashboard
+    }
+
+    /// Stop drawing and give the terminal back.
+    pub fn close(&self) {
+        self.stop.lock().unwrap().take();
+        if let Some(drawer) = self.drawer.lock().unwrap().take() {
+            let _ = drawer.join();
+        }
+        if ACTIVE.swap(false, Ordering::SeqCst) {
+            let _ = execute!(io::stderr(), cursor::Show, LeaveAlternateScreen);
+        }
+    }
+
+    pub fn started(&self, path: &Path) {
+        self.state.lock().unwrap().active.push(Active {
+            worker: rayon::current_thread_index(),
+            path: path.to_path_buf(),
+            since: Instant::now(),
+        });
+    }
+
+    pub fn finished(&self, path: &Path, error: Option<&HashError>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(pos) = state.active.iter().position(|a| a.path == path) else {
+            return;
+        };
+        let active = state.active.swap_remove(pos);
+        let took = active.since.elapsed();
+        if state.slowest.len() < SLOWEST || took > state.slowest[SLOWEST - 1].0 {
+            let at = state.slowest.partition_point(|(d, _)| *d >= took);
+            state.slowest.insert(at, (took, active.path));
+            state.slowest.truncate(SLOWEST);
+        }
+        if let Some(e) = error {
+            *state.errors.entry(format!("{:?}", e.kind())).or_default() += 1;
+        }
+    }
+
+    pub fn retrying(&self) {
+        self.state.lock().unwrap().retries += 1;
+    }
+
+    pub fn rereading(&self) {
+        self.state.lock().unwrap().rereads += 1;
+    }
+
+    /// Keep a diagnostic for the messages pane instead of printing it over the screen.
+    pub fn message(&self, line: &str) {
+        // ratatui measures and styles text itself, so the colors `echo` added have to go.
+        let line = console::strip_ansi_codes(line);
+        let mut state = self.state.lock().unwrap();
+        state
+            .messages
+            .extend(line.lines().filter(|l| !l.is_empty()).map(str::to_string));
+        while state.messages.len() > 100 {
+            state.messages.pop_front();
+        }
+    }
+
+    /// Record throughput over the `elapsed` since the counts were `(done, bytes)`.
+    fn sample(&self, elapsed: Duration, (done, bytes): (usize, u64)) -> (usize, u64) {
+        let now = (
+            self.status.done.load(Ordering::Relaxed),
+            self.status.bytes.load(Ordering::Relaxed),
+        );
+        let secs = elapsed.as_secs_f64();
+        let mut state = self.state.lock().unwrap();
+        state
+            .mb_per_sec
+            .push_back((now.1 - bytes) as f64 / 1e6 / secs);
+        state.files_per_sec.push_back((now.0 - done) as f64 / secs);
+        if state.mb_per_sec.len() > HISTORY {
+            state.mb_per_sec.pop_front();
+            state.files_per_sec.pop_front();
+        }
+        now
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let state = self.state.lock().unwrap();
+        let [summary, workers, slowest, messages] = Layout::vertical([
+            Constraint::Length(11),
+            Constraint::Length(state.active.len() as u16 + 1),
+            Constraint::Length(state.slowest.len() as u16 + 1),
+            Constraint::Min(0),
+        ])
+        .spacing(1)
+        .areas(frame.area());
+        let [title, status, mb_graph, files_graph, errors] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(1),
+            Constraint::Length(4),
+            Constraint::Length(4),
+            Constraint::Length(1),
+        ])
+        .areas(summary);
+
+        let done = self.status.done.load(Ordering::Relaxed);
+        let total = self.status.total.load(Ordering::Relaxed);
+        let elapsed = self.status.started.elapsed();
+        let percent = if total > 0 {
+            100.0 * done as f64 / total as f64
+        } else {
+            100.0
+        };
+        let heading = format!(
+            "gustasum  {}/{} {} ({:.1}%)  elapsed {}",
+            done,
+            total,
+            self.unit,
+            percent,
+            format_duration(elapsed)
+        );
+        frame.render_widget(Line::from(heading).bold(), title);
+        frame.render_widget(Line::from(self.status.line(self.unit)), status);
+        graph(frame, mb_graph, "MB/s", &state.mb_per_sec);
+        graph(
+            frame,
+            files_graph,
+            &format!("{}/s", self.unit),
+            &state.files_per_sec,
+        );
+
+        let failed: usize = state.errors.values().sum();
+        let mut tally = format!(
+            "Errors: {} failed, {} retries, {} re-reads",
+            failed, state.retries, state.rereads
+        );
+        let mut tally_style = Style::new();
+        if failed > 0 {
+            let kinds: Vec<String> = state
+                .errors
+                .iter()
+                .map(|(kind, n)| format!("{} {}", kind, n))
+                .collect();
+            tally.push_str(&format!(" ({})", kinds.join(", ")));
+            tally_style = tally_style.red();
+        }
+        frame.render_widget(Line::styled(tally, tally_style), errors);
+
+        let mut active: Vec<&Active> = state.active.iter().collect();
+        active.sort_by_key(|a| (a.worker, a.since));
+        let lines: Vec<Line> = active
+            .into_iter()
+            .map(|a| {
+                let took = a.since.elapsed();
+                let line = Line::from(format!(
+                    "{:>4} {:>8}  {}",
+                    a.worker.map_or("-".to_string(), |w| format!("#{}", w)),
+                    format!("{:.1}s", took.as_secs_f64()),
+                    a.path.display()
+                ));
+                if took >= STALLED {
+                    line.yellow()
+                } else {
+                    line
+                }
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(section("Workers")), workers);
+
+        let lines: Vec<Line> = state
+            .slowest
+            .iter()
+            .map(|(took, path)| {
+                Line::from(format!(
+                    "     {:>8}  {}",
+                    format!("{:.1}s", took.as_secs_f64()),
+                    path.display()
+                ))
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(section("Slowest")), slowest);
+
+        // Messages take whatever rows are left, newest at the bottom.
+        let room = messages.height.saturating_sub(1) as usize;
+        let skip = state.messages.len().saturating_sub(room);
+        let lines: Vec<Line> = state
+            .messages
+            .iter()
+            .skip(skip)
+            .map(|m| Line::from(m.as_str()))
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(section("Messages")), messages);
+    }
+}
+
+/// A pane with an underlined heading on its first row.
+fn section(title: &str) -> Block<'_> {
+    Block::new().title(Line::from(title).underlined())
+}
+
+/// The last of `values` that fit in `area` as a bar graph, scaled to the largest, under a
+/// heading with the peak.
+fn graph(frame: &mut Frame, area: Rect, label: &str, values: &VecDeque<f64>) {
+    let shown = values
+        .iter()
+        .skip(values.len().saturating_sub(area.width as usize));
+    let max = shown.clone().copied().fold(0.0, f64::max);
+    // Sparklines take integers; thousandths keep small rates from rounding to nothing.
+    let data: Vec<u64> = shown.map(|v| (v * 1000.0) as u64).collect();
+    let title = format!("{} (peak {:.1})", label, max);
+    frame.render_widget(
+        Sparkline::default()
+            .data(&data)
+            .block(Block::new().title(title)),
+        area,
+    );
+}
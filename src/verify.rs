@@ -0,0 +1,406 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Checking files against a manifest.
+
+use crate::error::{HashError, Region};
+use crate::events::{EventSink, NoEvents};
+use crate::hasher::{digest_modtime, hash_file_with, FileHash, HashOptions};
+use crate::manifest::{native_separators, Manifest, ManifestEntry, SettingsConflict};
+use crate::source::SourceMetadata;
+use rayon::prelude::*;
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Settings for a verification run.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyOptions {
+    /// How to hash files. [`verify_manifest`] takes the settings the manifest records over
+    /// these; [`verify_entry`] uses them as they are, so they must match the manifest's.
+    pub hash: HashOptions,
+    /// Read files recorded under the first path from under the second one instead.
+    pub remap: Option<(PathBuf, PathBuf)>,
+}
+
+/// What became of one manifest line.
+#[derive(Debug)]
+pub enum CheckOutcome {
+    Ok,
+    Mismatch,
+    /// The file changed while it was being read, so the comparison is meaningless.
+    Unstable,
+    /// The hash couldn't be computed.
+    Error(HashError),
+    /// The line isn't a `<hash>  <path>` entry.
+    Malformed,
+}
+
+/// The result of checking one manifest line.
+#[derive(Debug)]
+pub struct CheckResult {
+    /// The path as recorded in the manifest (or the raw text of a malformed line).
+    pub path: String,
+    /// Content bytes read while checking.
+    pub bytes: u64,
+    pub outcome: CheckOutcome,
+    /// For a mismatch, what hashing the file found, to compare with what was recorded (see
+    /// [`mismatch_details`]).
+    pub found: Option<FileHash>,
+}
+
+/// Where to read `entry` from, after applying `--remap`, with native separators.
+pub fn resolve_path(entry: &ManifestEntry, opts: &VerifyOptions) -> PathBuf {
+    let path = match &opts.remap {
+        Some((old_base, new_base)) => remap_path(&entry.path, old_base, new_base),
+        None => entry.path.clone(),
+    };
+    native_separators(&path)
+}
+
+/// Check a single manifest entry.
+pub fn verify_entry(entry: &ManifestEntry, opts: &VerifyOptions) -> CheckResult {
+    verify_entry_with(entry, opts, &NoEvents)
+}
+
+/// Like [`verify_entry`], reporting the file's progress to `events`.
+pub fn verify_entry_with(
+    entry: &ManifestEntry,
+    opts: &VerifyOptions,
+    events: &dyn EventSink,
+) -> CheckResult {
+    let path = resolve_path(entry, opts);
+    check_hash(entry, hash_file_with(&path, &opts.hash, events), &opts.hash)
+}
+
+/// Whether the `computed` digest matches the `recorded` one. An abbreviated digest (see
+/// [`HashOptions::abbrev`]) only has to be its prefix, so a full manifest can be checked
+/// with abbreviated digests too.
+pub fn digest_matches(computed: &str, recorded: &str, opts: &HashOptions) -> bool {
+    match opts.abbrev {
+        Some(_) => recorded.get(..computed.len()) == Some(computed),
+        None => computed == recorded,
+    }
+}
+
+/// Compare an already computed hash result, made with `opts`, against what `entry` recorded.
+pub fn check_hash(
+    entry: &ManifestEntry,
+    actual: Result<FileHash, HashError>,
+    opts: &HashOptions,
+) -> CheckResult {
+    let (bytes, outcome, found) = match actual {
+        Ok(FileHash {
+            unstable: true,
+            bytes,
+            ..
+        }) => (bytes, CheckOutcome::Unstable, None),
+        Ok(FileHash {
+            ref hash, bytes, ..
+        }) if digest_matches(hash, &entry.hash, opts) => (bytes, CheckOutcome::Ok, None),
+        Ok(found) => (found.bytes, CheckOutcome::Mismatch, Some(found)),
+        Err(e) => (0, CheckOutcome::Error(e), None),
+    };
+    CheckResult {
+        path: entry.path.display().to_string(),
+        bytes,
+        outcome,
+        found,
+    }
+}
+
+/// What differs between a file that failed its check, as `found`, and what `manifest`
+/// recorded for it at `path`: its size or modtime, and each sampled region whose digest
+/// changed, with the offsets it was read from. Empty if the manifest recorded nothing to
+/// compare with (it wasn't made with `--record-metadata`).
+pub fn mismatch_details(
+    manifest: &Manifest,
+    path: &Path,
+    found: &FileHash,
+    opts: &HashOptions,
+) -> Vec<String> {
+    let Some(recorded) = manifest.metadata.get(path) else {
+        return Vec::new();
+    };
+    let mut details = Vec::new();
+    if recorded.len != found.metadata.len {
+        details.push(format!("size {} -> {}", recorded.len, found.metadata.len));
+    }
+    // Whether or not the digest includes it, a changed modtime tells an edit from corruption.
+    let seconds = |meta: &SourceMetadata| {
+        let since = meta.modified?.duration_since(UNIX_EPOCH).ok()?;
+        Some(if opts.fat_compat {
+            since.as_secs() & !1
+        } else {
+            since.as_secs()
+        })
+    };
+    let (before, after) = (seconds(recorded), seconds(&found.metadata));
+    if before != after {
+        let show = |s: Option<u64>| s.map_or("unknown".to_string(), |s| s.to_string());
+        details.push(format!("mtime {} -> {}", show(before), show(after)));
+    }
+    let same_size = recorded.len == found.metadata.len;
+    let mut compared = 0;
+    for (region, digest) in manifest.regions.get(path).into_iter().flatten() {
+        let Some(now) = found.regions.iter().find(|r| r.region == *region) else {
+            continue;
+        };
+        // The middle and end move with the size, so only the start still lines up if it changed.
+        if *region != Region::First && !same_size {
+            continue;
+        }
+        compared += 1;
+        if now.digest != *digest {
+            details.push(format!(
+                "{} differ (offsets {}-{})",
+                region,
+                now.offset,
+                (now.offset + now.len).saturating_sub(1)
+            ));
+        }
+    }
+    if compared > 0 && details.is_empty() {
+        details.push(
+            "size, modtime, and every sampled region match what was recorded; \
+             was it generated with other settings?"
+                .to_string(),
+        );
+    }
+    details
+}
+
+/// How `actual` differs from the size and modtime `recorded` with the manifest, if it does.
+/// Modtimes are only compared with `include_modtime`, and as coarsely as the digest sees them.
+pub fn metadata_change(
+    recorded: &SourceMetadata,
+    actual: &SourceMetadata,
+    opts: &HashOptions,
+) -> Option<String> {
+    if recorded.len != actual.len {
+        return Some(format!("size {} -> {}", recorded.len, actual.len));
+    }
+    let (before, after) = (digest_modtime(recorded, opts), digest_modtime(actual, opts));
+    if before != after {
+        return Some(format!("mtime {} -> {}", before, after));
+    }
+    None
+}
+
+/// The result reported for a line that isn't a valid entry.
+pub fn malformed(line: &str) -> CheckResult {
+    CheckResult {
+        path: line.to_string(),
+        bytes: 0,
+        outcome: CheckOutcome::Malformed,
+        found: None,
+    }
+}
+
+/// Check every line of `manifest` in parallel, returning results in manifest order, with the
+/// settings it was generated with (see [`Manifest::hash_options`]) in place of `opts.hash`'s.
+pub fn verify_manifest(
+    manifest: &Manifest,
+    opts: &VerifyOptions,
+) -> Result<Vec<CheckResult>, SettingsConflict> {
+    verify_manifest_with(manifest, opts, &NoEvents)
+}
+
+/// Like [`verify_manifest`], reporting each file's progress to `events`.
+pub fn verify_manifest_with(
+    manifest: &Manifest,
+    opts: &VerifyOptions,
+    events: &dyn EventSink,
+) -> Result<Vec<CheckResult>, SettingsConflict> {
+    let opts = VerifyOptions {
+        hash: manifest.hash_options(opts.hash.clone(), &|_| false)?,
+        remap: opts.remap.clone(),
+    };
+    Ok(manifest
+        .lines
+        .par_iter()
+        .map(|line| match line {
+            Ok(entry) => verify_entry_with(entry, &opts, events),
+            Err(raw) => malformed(raw),
+        })
+        .collect())
+}
+
+/// Remap path if it starts with `old_base`.
+///
+/// `old_base` is compared in manifest form, with `\` read as `/`, so a Windows base such as
+/// `C:\data` or `\\nas\share` matches the `C:/data/...` and `//nas/share/...` entries a
+/// Windows manifest holds even when checked on Linux.
+pub fn remap_path(original: &Path, old_base: &Path, new_base: &Path) -> PathBuf {
+    let old_base = PathBuf::from(old_base.to_string_lossy().replace('\\', "/"));
+    match original.strip_prefix(&old_base) {
+        Ok(stripped) => new_base.join(stripped),
+        Err(_) => original.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::{hash_file, Salt};
+    use crate::manifest::{provenance_line, ALGORITHM_HEADER};
+    use crate::source::MemorySource;
+    use std::sync::Arc;
+
+    fn tree() -> MemorySource {
+        let mut source = MemorySource::new();
+        source.insert("/data/small", "tiny");
+        source.insert("/data/large", vec![9u8; 10_000]);
+        source.insert("/data/empty", "");
+        source
+    }
+
+    fn options(source: MemorySource, partial_bytes: usize) -> HashOptions {
+        HashOptions {
+            source: Arc::new(source),
+            partial_bytes,
+            ..HashOptions::default()
+        }
+    }
+
+    /// A manifest of `paths` as `generate` would write it, with `opts` recorded in its header.
+    fn generate(paths: &[&str], opts: &HashOptions) -> String {
+        let mut out = format!("{}{}\n", ALGORITHM_HEADER, opts.algorithm.name);
+        let mut fields = vec![
+            ("partial_bytes", opts.partial_bytes.to_string()),
+            ("include_modtime", opts.include_modtime.to_string()),
+        ];
+        fields.extend(opts.salt.as_ref().map(|salt| ("salt", salt.id())));
+        out.push_str(&provenance_line(&fields));
+        out.push('\n');
+        for path in paths {
+            let entry = ManifestEntry {
+                hash: hash_file(Path::new(path), opts).unwrap().hash,
+                path: PathBuf::from(path),
+            };
+            out.push_str(&format!("{}\n", entry));
+        }
+        out
+    }
+
+    fn outcomes(manifest: &str, opts: &VerifyOptions) -> Vec<String> {
+        verify_manifest(&Manifest::parse(manifest), opts)
+            .unwrap()
+            .iter()
+            .map(|r| format!("{} {:?}", r.path, r.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn generated_manifest_verifies_with_its_recorded_settings() {
+        let paths = ["/data/small", "/data/large", "/data/empty"];
+        let manifest = generate(&paths, &options(tree(), 10));
+        // Checked with the default partial_bytes, which the header's overrides.
+        let opts = VerifyOptions {
+            hash: options(tree(), 100),
+            remap: None,
+        };
+        assert_eq!(
+            outcomes(&manifest, &opts),
+            ["/data/small Ok", "/data/large Ok", "/data/empty Ok"]
+        );
+    }
+
+    #[test]
+    fn changed_and_missing_files_fail() {
+        let manifest = generate(&["/data/small", "/data/large"], &options(tree(), 100));
+        let mut source = MemorySource::new();
+        source.insert("/data/small", "tinY");
+        let opts = VerifyOptions {
+            hash: options(source, 100),
+            remap: None,
+        };
+        let results = verify_manifest(&Manifest::parse(&manifest), &opts).unwrap();
+        assert!(matches!(results[0].outcome, CheckOutcome::Mismatch));
+        assert!(results[0].found.is_some());
+        assert!(matches!(results[1].outcome, CheckOutcome::Error(_)));
+    }
+
+    #[test]
+    fn malformed_lines_are_reported_in_place() {
+        let manifest = format!(
+            "{}not an entry\n",
+            generate(&["/data/small"], &options(tree(), 100))
+        );
+        let opts = VerifyOptions {
+            hash: options(tree(), 100),
+            remap: None,
+        };
+        assert_eq!(
+            outcomes(&manifest, &opts),
+            ["/data/small Ok", "not an entry Malformed"]
+        );
+    }
+
+    #[test]
+    fn remap_reads_from_the_new_base() {
+        let manifest = generate(&["/data/small"], &options(tree(), 100));
+        let mut moved = MemorySource::new();
+        moved.insert("/backup/small", "tiny");
+        let opts = VerifyOptions {
+            hash: options(moved, 100),
+            remap: Some((PathBuf::from("/data"), PathBuf::from("/backup"))),
+        };
+        assert_eq!(outcomes(&manifest, &opts), ["/data/small Ok"]);
+    }
+
+    #[test]
+    fn salted_manifest_needs_its_salt() {
+        let salted = HashOptions {
+            salt: Some(Salt::new(&b"secret"[..])),
+            ..options(tree(), 100)
+        };
+        let manifest = generate(&["/data/small"], &salted);
+        let opts = VerifyOptions {
+            hash: options(tree(), 100),
+            remap: None,
+        };
+        assert_eq!(
+            verify_manifest(&Manifest::parse(&manifest), &opts).unwrap_err(),
+            SettingsConflict::SaltMissing
+        );
+        let opts = VerifyOptions {
+            hash: salted,
+            remap: None,
+        };
+        assert_eq!(outcomes(&manifest, &opts), ["/data/small Ok"]);
+    }
+
+    #[test]
+    fn abbreviated_digest_matches_by_prefix() {
+        let opts = HashOptions {
+            abbrev: Some(12),
+            ..HashOptions::default()
+        };
+        assert!(digest_matches("0123456789ab", "0123456789abcdef", &opts));
+        assert!(!digest_matches("0123456789ab", "0123456789ac", &opts));
+        assert!(!digest_matches(
+            "0123",
+            "0123456789ab",
+            &HashOptions::default()
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Volume Shadow Copy snapshots, so files other programs keep open without sharing (Outlook
+//! PSTs, databases) can still be read, all as of one moment. [`VssSource`] snapshots each
+//! volume the first time a file on it is needed and reads from the snapshot instead.
+//!
+//! Snapshots are made and removed with PowerShell's CIM cmdlets, which needs an elevated
+//! prompt. They outlive the process unless deleted, so [`release_all`] should run at exit.
+
+use crate::source::{LocalFs, Source, SourceFile, SourceMetadata};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Component, Path, PathBuf, Prefix},
+    process::Command,
+    sync::{Arc, Mutex},
+};
+use walkdir::WalkDir;
+
+/// IDs of the snapshots made by this process and not yet deleted.
+static LIVE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// A shadow copy of one volume.
+#[derive(Debug)]
+pub struct Snapshot {
+    id: String,
+    /// The volume's root, e.g. `C:\`.
+    volume: PathBuf,
+    /// Where the snapshot's root is found, e.g. `\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3`.
+    device: PathBuf,
+}
+
+impl Snapshot {
+    /// Snapshot `volume`, a root such as `C:\`.
+    pub fn create(volume: &Path) -> io::Result<Snapshot> {
+        let script = format!(
+            "$r = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create \
+               -Arguments @{{ Volume = '{}'; Context = 'ClientAccessible' }}; \
+             if ($r.ReturnValue -ne 0) {{ Write-Error \"Win32_ShadowCopy.Create returned $($r.ReturnValue)\"; exit 1 }}; \
+             $s = Get-CimInstance Win32_ShadowCopy | Where-Object ID -eq $r.ShadowID; \
+             Write-Output \"$($s.ID)`t$($s.DeviceObject)\"",
+            volume.display()
+        );
+        let output = powershell(&script)?;
+        let (id, device) = output.trim().split_once('\t').ok_or_else(|| {
+            io::Error::other(format!("unexpected output from PowerShell: {}", output))
+        })?;
+        LIVE.lock().unwrap().push(id.to_string());
+        Ok(Snapshot {
+            id: id.to_string(),
+            volume: volume.to_path_buf(),
+            device: PathBuf::from(device),
+        })
+    }
+
+    /// Where the snapshot has `path`, which must be absolute and on the snapshot's volume.
+    pub fn map(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.volume)
+            .ok()
+            .map(|rest| self.device.join(rest))
+    }
+
+    /// The live path of `path` from inside the snapshot.
+    pub fn unmap(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.device)
+            .ok()
+            .map(|rest| self.volume.join(rest))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        delete(&self.id);
+    }
+}
+
+/// Delete every snapshot this process made that is still around. For exit paths that skip
+/// destructors, such as `std::process::exit`.
+pub fn release_all() {
+    let ids = std::mem::take(&mut *LIVE.lock().unwrap());
+    for id in ids {
+        let _ = delete_now(&id);
+    }
+}
+
+fn delete(id: &str) {
+    let mut live = LIVE.lock().unwrap();
+    let Some(idx) = live.iter().position(|live| live == id) else {
+        return;
+    };
+    live.remove(idx);
+    drop(live);
+    if let Err(e) = delete_now(id) {
+        eprintln!("Warning: Cannot delete shadow copy {}: {}", id, e);
+    }
+}
+
+fn delete_now(id: &str) -> io::Result<String> {
+    powershell(&format!(
+        "Get-CimInstance Win32_ShadowCopy | Where-Object ID -eq '{}' | Remove-CimInstance",
+        id
+    ))
+}
+
+fn powershell(script: &str) -> io::Result<String> {
+    let output = Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "PowerShell failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The root of the drive-letter volume `path` is on, if it is on one.
+fn volume_of(path: &Path) -> Option<PathBuf> {
+    let path = std::path::absolute(path).ok()?;
+    match path.components().next() {
+        Some(Component::Prefix(p)) => match p.kind() {
+            Prefix::Disk(d) | Prefix::VerbatimDisk(d) => {
+                Some(PathBuf::from(format!("{}:\\", d as char)))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The local filesystem, read through a snapshot of each volume. Paths on network shares
+/// and other volumes that can't be snapshotted are read live.
+#[derive(Default)]
+pub struct VssSource {
+    snapshots: Mutex<HashMap<PathBuf, Result<Arc<Snapshot>, String>>>,
+}
+
+impl fmt::Debug for VssSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VssSource").finish_non_exhaustive()
+    }
+}
+
+impl VssSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The snapshot covering `path`, made on first use. `None` if `path` isn't on a
+    /// drive-letter volume.
+    fn snapshot(&self, path: &Path) -> Option<io::Result<Arc<Snapshot>>> {
+        let volume = volume_of(path)?;
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let snapshot = snapshots.entry(volume).or_insert_with_key(|volume| {
+            Snapshot::create(volume).map(Arc::new).map_err(|e| {
+                let e = format!("cannot snapshot {}: {}", volume.display(), e);
+                eprintln!("Warning: {}", e);
+                e
+            })
+        });
+        Some(snapshot.clone().map_err(io::Error::other))
+    }
+
+    /// Where to read `path` from: inside its volume's snapshot if there is one.
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let Some(snapshot) = self.snapshot(path) else {
+            return Ok(path.to_path_buf());
+        };
+        let path = std::path::absolute(path)?;
+        Ok(snapshot?.map(&path).unwrap_or(path))
+    }
+}
+
+impl Source for VssSource {
+    fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        LocalFs.metadata(&self.resolve(path)?)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SourceFile>> {
+        LocalFs.open(&self.resolve(path)?)
+    }
+
+    /// Walks the snapshot, reporting live paths, or the live tree if `root` can't be
+    /// snapshotted.
+    fn walk<'a>(&'a self, root: &'a Path) -> Box<dyn Iterator<Item = PathBuf> + 'a> {
+        let (Some(Ok(snapshot)), Ok(absolute)) = (self.snapshot(root), std::path::absolute(root))
+        else {
+            return LocalFs.walk(root);
+        };
+        let Some(inside) = snapshot.map(&absolute) else {
+            return LocalFs.walk(root);
+        };
+        // Not through `LocalFs::walk`: canonicalizing a shadow copy path can change its form.
+        Box::new(
+            WalkDir::new(inside)
+                .follow_links(false)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter_map(move |e| snapshot.unmap(e.path())),
+        )
+    }
+}
@@ -0,0 +1,410 @@
+/*
+ * Gustasum *
+ Partial Checksumming Done Right!
+
+ Copyright (C) 2024 Gustaf Haglund <contact@ghagl.se>
+
+ This program is free software: you can redistribute it and/or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License, or
+ (at your option) any later version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Finding files to hash, and choosing the order to read them in.
+
+use crate::source::{display_path, Source};
+use rayon::prelude::*;
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::RandomState, HashMap},
+    fs,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc,
+};
+
+/// The order files are started in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Order {
+    /// As they come, in parallel, with no order among the workers.
+    #[default]
+    Given,
+    /// One at a time, in physical on-disk order (`--optimize-seek`).
+    Physical,
+    /// The biggest first, so the run doesn't end with one worker on a big file.
+    LargestFirst,
+    SmallestFirst,
+    /// Sorted by path, so a run that stops early has done everything before some path.
+    Path,
+    /// Shuffled, to spread the reads over the disks of a striped volume.
+    Random,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest-first" => Ok(Order::LargestFirst),
+            "smallest-first" => Ok(Order::SmallestFirst),
+            "path" => Ok(Order::Path),
+            "random" => Ok(Order::Random),
+            _ => Err(format!(
+                "unknown order '{}' (expected largest-first, smallest-first, path, or random)",
+                s
+            )),
+        }
+    }
+}
+
+/// How the paths of walked files are written into a manifest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathStyle {
+    /// With symlinks, `.`, and `..` resolved, as [`Source::walk`] gives local paths.
+    #[default]
+    Canonical,
+    /// Made absolute, with the symlinks on the way to each root kept.
+    Absolute,
+    /// Under each root as it was typed, so relative if it was (as `sha256sum` prints them).
+    AsGiven,
+}
+
+impl FromStr for PathStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "canonical" => Ok(PathStyle::Canonical),
+            "absolute" => Ok(PathStyle::Absolute),
+            "as-given" => Ok(PathStyle::AsGiven),
+            _ => Err(format!(
+                "unknown path style '{}' (expected as-given, absolute, or canonical)",
+                s
+            )),
+        }
+    }
+}
+
+/// Walked paths as a [`PathStyle`] records them. A local walk gives paths under the canonical
+/// form of its root; those are moved under the root as given (or made absolute). Other paths,
+/// and every path with [`PathStyle::Canonical`], are kept.
+#[derive(Clone, Debug, Default)]
+pub struct Recorded {
+    /// `(canonical, recorded)` roots, longest canonical first.
+    roots: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Recorded {
+    pub fn new(roots: &[PathBuf], style: PathStyle) -> Recorded {
+        let mut roots: Vec<(PathBuf, PathBuf)> = roots
+            .iter()
+            .filter(|_| style != PathStyle::Canonical)
+            .filter_map(|root| {
+                let canonical = display_path(root.canonicalize().ok()?);
+                let recorded = match style {
+                    PathStyle::Absolute => std::path::absolute(root).ok()?,
+                    _ => root.clone(),
+                };
+                Some((canonical, recorded)).filter(|(c, r)| c != r)
+            })
+            .collect();
+        roots.sort_by_key(|(canonical, _)| Reverse(canonical.as_os_str().len()));
+        Recorded { roots }
+    }
+
+    /// `path`, walked, as it goes into the manifest.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        self.roots
+            .iter()
+            .find_map(|(canonical, recorded)| {
+                let rest = path.strip_prefix(canonical).ok()?;
+                Some(if rest.as_os_str().is_empty() {
+                    recorded.clone()
+                } else {
+                    recorded.join(rest)
+                })
+            })
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+/// All regular files under `paths` in `source`, in walk order.
+pub fn files_under<'a>(
+    source: &'a dyn Source,
+    paths: &'a [PathBuf],
+) -> impl Iterator<Item = PathBuf> + 'a {
+    paths.iter().flat_map(move |p| source.walk(p))
+}
+
+/// Run `f` over `items`, returning the results in `items` order. Items for which `f`
+/// returns `None` are dropped.
+///
+/// With [`Order::Physical`], items are processed one at a time in the physical on-disk order
+/// of `path_of(item)`, so a disk head sweeps instead of seeking. Otherwise they run in
+/// parallel, started in `order`.
+pub fn run_scheduled<T, R, P, F>(items: &[T], order: Order, path_of: P, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    P: Fn(&T) -> &Path + Sync,
+    F: Fn(&T) -> Option<R> + Sync,
+{
+    if order == Order::Given {
+        return items.par_iter().filter_map(&f).collect();
+    }
+    let paths: Vec<&Path> = items.iter().map(&path_of).collect();
+    let sequence = sequence(&paths, order);
+    let mut done: Vec<(usize, R)> = if order == Order::Physical {
+        sequence
+            .into_iter()
+            .filter_map(|idx| f(&items[idx]).map(|r| (idx, r)))
+            .collect()
+    } else {
+        sequence
+            .into_iter()
+            .par_bridge()
+            .filter_map(|idx| f(&items[idx]).map(|r| (idx, r)))
+            .collect()
+    };
+    done.sort_by_key(|(idx, _)| *idx);
+    done.into_iter().map(|(_, r)| r).collect()
+}
+
+/// Run `f` over `items` in parallel while they're still coming, and hand the results to
+/// `write`, in `items` order, as they're ready, each with whether it's the last of them ready
+/// for now (where a buffered `write` flushes). Returns how many items were taken.
+///
+/// At most `depth` items are between being taken and written, so memory stays bounded
+/// however many there are, and a `write` that blocks (on a slow pipe, say) holds up taking
+/// more rather than letting results pile up. `items` is iterated on the calling thread and
+/// `write` runs on a thread of its own. Items for which `f` returns `None` aren't written.
+pub fn run_streaming<T, R, I, F, W>(items: I, depth: usize, f: F, mut write: W) -> usize
+where
+    T: Send,
+    R: Send,
+    I: Iterator<Item = T>,
+    F: Fn(&T) -> Option<R> + Sync,
+    W: FnMut(R, bool) + Send,
+{
+    // A slot is taken for each item and given back once it's written.
+    let (take_slot, give_back) = mpsc::sync_channel::<()>(depth.max(1));
+    let (queue, queued) = mpsc::channel::<(usize, T)>();
+    let (done, finished) = mpsc::channel::<(usize, Option<R>)>();
+    let f = &f;
+    std::thread::scope(move |scope| {
+        scope.spawn(move || {
+            queued
+                .into_iter()
+                .par_bridge()
+                .for_each_with(done, |done, (idx, item)| {
+                    let _ = done.send((idx, f(&item)));
+                });
+        });
+        scope.spawn(move || {
+            // Finished out of order, waiting for the ones before them.
+            let mut waiting: HashMap<usize, Option<R>> = HashMap::new();
+            let mut next = 0;
+            while let Ok((idx, result)) = finished.recv() {
+                waiting.insert(idx, result);
+                // And whatever else has finished meanwhile, to tell what's ready.
+                waiting.extend(finished.try_iter());
+                while let Some(result) = waiting.remove(&next) {
+                    next += 1;
+                    if let Some(result) = result {
+                        write(result, !waiting.contains_key(&next));
+                    }
+                    let _ = give_back.recv();
+                }
+            }
+        });
+        let mut taken = 0;
+        for item in items {
+            if take_slot.send(()).is_err() || queue.send((taken, item)).is_err() {
+                break;
+            }
+            taken += 1;
+        }
+        // The end of the queue is what lets the workers, then the writer, finish.
+        drop(queue);
+        taken
+    })
+}
+
+/// The indices of `paths` in `order`. Files whose size can't be had go last, for the orders
+/// by size.
+pub fn sequence<P: AsRef<Path> + Sync>(paths: &[P], order: Order) -> Vec<usize> {
+    let mut sequence: Vec<usize> = (0..paths.len()).collect();
+    let sizes = || -> Vec<Option<u64>> {
+        paths
+            .par_iter()
+            .map(|p| fs::metadata(p.as_ref()).ok().map(|m| m.len()))
+            .collect()
+    };
+    match order {
+        Order::Given => {}
+        Order::Physical => sequence = physical_order(paths),
+        Order::LargestFirst => {
+            let sizes = sizes();
+            sequence.sort_by_key(|&idx| (sizes[idx].is_none(), Reverse(sizes[idx])));
+        }
+        Order::SmallestFirst => {
+            let sizes = sizes();
+            sequence.sort_by_key(|&idx| (sizes[idx].is_none(), sizes[idx]));
+        }
+        Order::Path => sequence.sort_by(|&a, &b| paths[a].as_ref().cmp(paths[b].as_ref())),
+        Order::Random => {
+            let state = RandomState::new();
+            sequence.sort_by_key(|&idx| state.hash_one((idx, paths[idx].as_ref())));
+        }
+    }
+    sequence
+}
+
+/// Like [`run_scheduled`] without `optimize_seek`, but the parallel workers take up `items`
+/// strictly in order, so stopping early (e.g., at a time limit) leaves out the end of the
+/// list rather than a scattering of items. Results come in `items` order.
+pub fn run_in_order<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Option<R> + Sync,
+{
+    let mut done: Vec<(usize, R)> = items
+        .iter()
+        .enumerate()
+        .par_bridge()
+        .filter_map(|(idx, item)| f(item).map(|r| (idx, r)))
+        .collect();
+    done.sort_by_key(|(idx, _)| *idx);
+    done.into_iter().map(|(_, r)| r).collect()
+}
+
+/// Return the indices of `paths` sorted by the physical location of each file's first extent.
+/// Files whose location can't be determined (empty, inline, non-Linux, FIEMAP unsupported)
+/// keep their relative order and go last.
+pub fn physical_order<P: AsRef<Path> + Sync>(paths: &[P]) -> Vec<usize> {
+    let offsets: Vec<u64> = paths
+        .par_iter()
+        .map(|p| first_physical_offset(p.as_ref()).unwrap_or(u64::MAX))
+        .collect();
+    let mut order: Vec<usize> = (0..paths.len()).collect();
+    order.sort_by_key(|&idx| offsets[idx]);
+    order
+}
+
+/// Physical byte offset of the first extent of `path`, via the FS_IOC_FIEMAP ioctl.
+#[cfg(target_os = "linux")]
+fn first_physical_offset(path: &Path) -> Option<u64> {
+    let file = std::fs::File::open(path).ok()?;
+    let extents = fiemap::extents::<1>(&file, 0).ok()?;
+    extents.first().map(|e| e.fe_physical)
+}
+
+/// The FS_IOC_FIEMAP ioctl, which maps a file's logical ranges to physical disk locations.
+#[cfg(target_os = "linux")]
+pub(crate) mod fiemap {
+    use std::{fs::File, io, os::unix::io::AsRawFd};
+
+    pub const EXTENT_LAST: u32 = 0x0001;
+    pub const EXTENT_UNKNOWN: u32 = 0x0002;
+    pub const EXTENT_DELALLOC: u32 = 0x0004;
+    pub const EXTENT_ENCODED: u32 = 0x0008;
+    pub const EXTENT_DATA_INLINE: u32 = 0x0200;
+    pub const EXTENT_DATA_TAIL: u32 = 0x0400;
+    pub const EXTENT_SHARED: u32 = 0x2000;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct FiemapExtent {
+        pub fe_logical: u64,
+        pub fe_physical: u64,
+        pub fe_length: u64,
+        fe_reserved64: [u64; 2],
+        pub fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap<const N: usize> {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; N],
+    }
+
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+
+    /// Up to `N` extents of `file` covering bytes from `start` on. Empty past the last extent.
+    pub fn extents<const N: usize>(file: &File, start: u64) -> io::Result<Vec<FiemapExtent>> {
+        let mut fm = Fiemap::<N> {
+            fm_start: start,
+            fm_length: u64::MAX - start,
+            fm_flags: 0,
+            fm_mapped_extents: 0,
+            fm_extent_count: N as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent::default(); N],
+        };
+        let ret = unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                FS_IOC_FIEMAP as _,
+                &mut fm as *mut Fiemap<N>,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fm.fm_extents[..fm.fm_mapped_extents as usize].to_vec())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn first_physical_offset(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// What `--skip-vcs-and-junk` leaves out, as `--exclude` patterns: version control
+/// metadata, package caches that can be rebuilt, and the trash and recovery directories
+/// filesystems and desktops keep.
+pub const JUNK: &[&str] = &[
+    ".git",
+    ".svn",
+    ".hg",
+    ".bzr",
+    "CVS",
+    "_darcs",
+    "node_modules",
+    "__pycache__",
+    ".Trash*",
+    ".Trashes",
+    "$RECYCLE.BIN",
+    "lost+found",
+];
+
+/// Whether `path` matches one of the `--exclude` patterns. A pattern containing `/` is
+/// matched against the whole path; any other pattern against the name of the file and of
+/// each directory above it, so `.git` skips everything inside a `.git` directory.
+pub fn is_excluded(path: &Path, patterns: &[glob::Pattern]) -> bool {
+    patterns.iter().any(|pattern| {
+        if pattern.as_str().contains('/') {
+            pattern.matches_path(path)
+        } else {
+            path.iter()
+                .any(|name| name.to_str().is_some_and(|name| pattern.matches(name)))
+        }
+    })
+}